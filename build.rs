@@ -0,0 +1,48 @@
+//! Builds the programs of the `user` crate, which `src/process/programs.rs` includes in the kernel
+//! image, and copies them to `$OUT_DIR/user`.
+//!
+//! The crate is built by a second cargo with a target directory of its own, since this one holds
+//! the lock of the kernel's. It builds for `user/x86_64-user.json` with its own copy of `core` and
+//! `alloc`, and always in release mode, to keep the kernel image small.
+
+use std::{env, fs, path::PathBuf, process::Command};
+
+/// The binaries of `user/src/bin`.
+const PROGRAMS: [&str; 7] = ["cat", "init", "ls", "mkdir", "sh", "sleep", "wc"];
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let user_dir = manifest_dir.join("user");
+    let target = user_dir.join("x86_64-user.json");
+    let target_dir = out_dir.join("target");
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+
+    let status = Command::new(cargo)
+        .current_dir(&user_dir)
+        .args(["build", "--release", "--bins", "--target"])
+        // Absolute, since cargo looks for relative target paths in the working directory.
+        .arg(&target)
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .args([
+            "-Zbuild-std=core,alloc,compiler_builtins",
+            "-Zbuild-std-features=compiler-builtins-mem",
+        ])
+        // The flags of the kernel's build are meant for the kernel's target.
+        .env_remove("RUSTFLAGS")
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .env_remove("RUSTC_WORKSPACE_WRAPPER")
+        .status()
+        .expect("failed to run cargo for the user programs");
+    assert!(status.success(), "failed to build the user programs");
+
+    let built = target_dir.join("x86_64-user").join("release");
+    let programs = out_dir.join("user");
+    fs::create_dir_all(&programs).unwrap();
+    for program in PROGRAMS.iter() {
+        fs::copy(built.join(program), programs.join(program)).unwrap();
+    }
+
+    println!("cargo:rerun-if-changed=user");
+}
@@ -0,0 +1,331 @@
+//! # acpi
+//!
+//! Just enough of the ACPI tables to find the processors.
+//!
+//! The firmware leaves the Root System Description Pointer (RSDP) in the first KiB of the
+//! Extended BIOS Data Area or in the BIOS area `0xe0000..0x100000`, on a 16 byte boundary. It
+//! points to the RSDT (ACPI 1.0, 32-bit pointers) or XSDT (ACPI 2.0+, 64-bit pointers), a list of
+//! all other tables. Each table starts with the same header, whose signature identifies it.
+//!
+//! The Multiple APIC Description Table (MADT, signature `APIC`) lists the local APIC of every
+//! processor, which [`crate::smp`] needs to start them.
+//!
+//! The parsing functions work on byte slices, so they can be tested with crafted tables. All
+//! tables are accessed through the physical memory map, which only covers RAM, so the firmware
+//! areas are mapped with [`memory::map_firmware`] before they are read.
+
+use crate::memory::{self, paging::PagingError};
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use x86_64::PhysAddr;
+
+/// The size of the header every system description table starts with.
+const HEADER_SIZE: usize = 36;
+
+/// The MADT entry type of a processor's local APIC.
+const MADT_LOCAL_APIC: u8 = 0;
+/// The MADT entry type that overrides the 32-bit local APIC address with a 64-bit one.
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No valid RSDP was found.
+    NoRsdp,
+    /// The table with this signature has an invalid checksum.
+    InvalidChecksum([u8; 4]),
+    /// The table with this signature is shorter than its contents.
+    Truncated([u8; 4]),
+    /// No table with this signature exists.
+    TableNotFound([u8; 4]),
+    /// The firmware memory could not be mapped.
+    Paging(PagingError),
+}
+
+impl From<PagingError> for AcpiError {
+    fn from(err: PagingError) -> Self {
+        AcpiError::Paging(err)
+    }
+}
+
+/// The Root System Description Pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rsdp {
+    pub revision: u8,
+    pub rsdt_address: u32,
+    /// Only present since ACPI 2.0 (revision 2).
+    pub xsdt_address: Option<u64>,
+}
+
+impl Rsdp {
+    /// Parses an RSDP. The slice may be longer than the RSDP.
+    pub fn parse(bytes: &[u8]) -> Result<Rsdp, AcpiError> {
+        const SIGNATURE: &[u8] = b"RSD PTR ";
+        if bytes.len() < 20 || &bytes[..8] != SIGNATURE || !checksum_ok(&bytes[..20]) {
+            return Err(AcpiError::NoRsdp);
+        }
+        let revision = bytes[15];
+        let rsdt_address = read_u32(bytes, 16);
+        if revision < 2 {
+            return Ok(Rsdp {
+                revision,
+                rsdt_address,
+                xsdt_address: None,
+            });
+        }
+
+        if bytes.len() < 36 {
+            return Err(AcpiError::NoRsdp);
+        }
+        let length = read_u32(bytes, 20) as usize;
+        if length < 36 || length > bytes.len() || !checksum_ok(&bytes[..length]) {
+            return Err(AcpiError::NoRsdp);
+        }
+        Ok(Rsdp {
+            revision,
+            rsdt_address,
+            xsdt_address: Some(read_u64(bytes, 24)),
+        })
+    }
+
+    /// Searches the EBDA and the BIOS area for the RSDP.
+    pub fn find() -> Result<Rsdp, AcpiError> {
+        // The real mode segment of the EBDA is stored at 0x40e, in the BIOS data area.
+        let ebda = unsafe { physical_slice(0x40e, 2)? };
+        let ebda = u64::from(u16::from_le_bytes([ebda[0], ebda[1]])) << 4;
+        let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+        for &(start, end) in areas.iter().filter(|(start, _)| *start != 0) {
+            let area = unsafe { physical_slice(start, (end - start) as usize)? };
+            for offset in (0..area.len()).step_by(16) {
+                if let Ok(rsdp) = Rsdp::parse(&area[offset..]) {
+                    return Ok(rsdp);
+                }
+            }
+        }
+        Err(AcpiError::NoRsdp)
+    }
+
+    /// Finds the table with the given signature in the RSDT or XSDT.
+    pub fn find_table(&self, signature: &[u8; 4]) -> Result<&'static [u8], AcpiError> {
+        let (root, entry_size) = match self.xsdt_address {
+            Some(xsdt) => (xsdt, 8),
+            None => (u64::from(self.rsdt_address), 4),
+        };
+        let root = unsafe { table(root)? };
+        for entry in root[HEADER_SIZE..].chunks_exact(entry_size) {
+            let address = match entry_size {
+                8 => read_u64(entry, 0),
+                _ => u64::from(read_u32(entry, 0)),
+            };
+            let table = unsafe { table(address)? };
+            if &table[..4] == signature {
+                return Ok(table);
+            }
+        }
+        Err(AcpiError::TableNotFound(*signature))
+    }
+}
+
+/// A processor's local APIC, from the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Whether the processor can be used. Disabled entries are e.g. for hot-pluggable CPUs.
+    pub enabled: bool,
+}
+
+/// The parsed Multiple APIC Description Table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    /// The physical address of the local APIC registers. It is the same for all processors.
+    pub local_apic_address: PhysAddr,
+    pub local_apics: Vec<LocalApic>,
+}
+
+impl Madt {
+    /// Parses a complete MADT, including the header.
+    pub fn parse(table: &[u8]) -> Result<Madt, AcpiError> {
+        const SIGNATURE: [u8; 4] = *b"APIC";
+        let table = validate(table)?;
+        if table[..4] != SIGNATURE {
+            return Err(AcpiError::TableNotFound(SIGNATURE));
+        }
+        if table.len() < HEADER_SIZE + 8 {
+            return Err(AcpiError::Truncated(SIGNATURE));
+        }
+
+        let mut local_apic_address = u64::from(read_u32(table, HEADER_SIZE));
+        let mut local_apics = Vec::new();
+        let mut entries = &table[HEADER_SIZE + 8..];
+        while !entries.is_empty() {
+            if entries.len() < 2 || entries[1] < 2 || usize::from(entries[1]) > entries.len() {
+                return Err(AcpiError::Truncated(SIGNATURE));
+            }
+            let (entry, rest) = entries.split_at(usize::from(entries[1]));
+            match entry[0] {
+                MADT_LOCAL_APIC if entry.len() >= 8 => local_apics.push(LocalApic {
+                    processor_id: entry[2],
+                    apic_id: entry[3],
+                    enabled: read_u32(entry, 4) & 1 != 0,
+                }),
+                MADT_LOCAL_APIC_ADDRESS_OVERRIDE if entry.len() >= 12 => {
+                    local_apic_address = read_u64(entry, 4);
+                }
+                MADT_LOCAL_APIC | MADT_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                    return Err(AcpiError::Truncated(SIGNATURE));
+                }
+                // I/O APICs, interrupt overrides, ... are not used yet.
+                _ => {}
+            }
+            entries = rest;
+        }
+
+        Ok(Madt {
+            local_apic_address: PhysAddr::new(local_apic_address),
+            local_apics,
+        })
+    }
+
+    /// Finds and parses the MADT of this machine.
+    pub fn find() -> Result<Madt, AcpiError> {
+        let rsdp = Rsdp::find()?;
+        Madt::parse(rsdp.find_table(b"APIC")?)
+    }
+}
+
+/// Checks the length and the checksum of a table and returns it without trailing bytes.
+fn validate(bytes: &[u8]) -> Result<&[u8], AcpiError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(AcpiError::Truncated(*b"????"));
+    }
+    let signature = bytes[..4].try_into().unwrap();
+    let length = read_u32(bytes, 4) as usize;
+    if length < HEADER_SIZE || length > bytes.len() {
+        return Err(AcpiError::Truncated(signature));
+    }
+    let table = &bytes[..length];
+    if !checksum_ok(table) {
+        return Err(AcpiError::InvalidChecksum(signature));
+    }
+    Ok(table)
+}
+
+/// All bytes of an ACPI structure must sum to zero.
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Returns the validated table at the physical address `address`.
+///
+/// # Safety
+///
+/// A table header must be at `address`.
+unsafe fn table(address: u64) -> Result<&'static [u8], AcpiError> {
+    let header = physical_slice(address, HEADER_SIZE)?;
+    let length = read_u32(header, 4) as usize;
+    validate(physical_slice(address, length.max(HEADER_SIZE))?)
+}
+
+/// Maps the firmware memory `address..address + len` and returns it.
+///
+/// # Safety
+///
+/// The physical memory `address..address + len` must not contain devices and must not be mutated
+/// while the slice is used.
+unsafe fn physical_slice(address: u64, len: usize) -> Result<&'static [u8], AcpiError> {
+    let address = PhysAddr::new(address);
+    memory::map_firmware(address, len as u64)?;
+    let ptr = memory::phys_to_virt(address).as_ptr();
+    Ok(core::slice::from_raw_parts(ptr, len))
+}
+
+/// Builds a table with a valid header and checksum around `contents`.
+#[cfg(test)]
+fn build_table(signature: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+    let mut table = Vec::new();
+    table.extend_from_slice(signature);
+    table.extend_from_slice(&((HEADER_SIZE + contents.len()) as u32).to_le_bytes());
+    table.resize(HEADER_SIZE, 0);
+    table.extend_from_slice(contents);
+    let sum = table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    table[9] = 0u8.wrapping_sub(sum);
+    table
+}
+
+#[test_case]
+fn test_parse_rsdp() {
+    let mut rsdp = [0u8; 20];
+    rsdp[..8].copy_from_slice(b"RSD PTR ");
+    rsdp[16..20].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    assert_eq!(Rsdp::parse(&rsdp), Err(AcpiError::NoRsdp));
+
+    let sum = rsdp.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    rsdp[8] = 0u8.wrapping_sub(sum);
+    assert_eq!(
+        Rsdp::parse(&rsdp),
+        Ok(Rsdp {
+            revision: 0,
+            rsdt_address: 0x1234_5678,
+            xsdt_address: None,
+        })
+    );
+}
+
+#[test_case]
+fn test_parse_madt() {
+    let mut contents = Vec::new();
+    contents.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+    contents.extend_from_slice(&1u32.to_le_bytes());
+    // Two processors, the second one disabled.
+    contents.extend_from_slice(&[MADT_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+    contents.extend_from_slice(&[MADT_LOCAL_APIC, 8, 1, 3, 0, 0, 0, 0]);
+    // An I/O APIC entry, which is skipped.
+    contents.extend_from_slice(&[1, 12, 0, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0]);
+
+    let madt = Madt::parse(&build_table(b"APIC", &contents)).unwrap();
+    assert_eq!(madt.local_apic_address, PhysAddr::new(0xfee0_0000));
+    assert_eq!(
+        madt.local_apics,
+        [
+            LocalApic {
+                processor_id: 0,
+                apic_id: 0,
+                enabled: true,
+            },
+            LocalApic {
+                processor_id: 1,
+                apic_id: 3,
+                enabled: false,
+            },
+        ]
+    );
+}
+
+#[test_case]
+fn test_madt_errors() {
+    let mut contents = Vec::new();
+    contents.extend_from_slice(&[0; 8]);
+    contents.extend_from_slice(&[MADT_LOCAL_APIC, 8, 0, 0]);
+    let mut table = build_table(b"APIC", &contents);
+    assert_eq!(Madt::parse(&table), Err(AcpiError::Truncated(*b"APIC")));
+
+    table[20] ^= 1;
+    assert_eq!(
+        Madt::parse(&table),
+        Err(AcpiError::InvalidChecksum(*b"APIC"))
+    );
+}
+
+#[test_case]
+fn test_madt_of_this_machine() {
+    let madt = Madt::find().unwrap();
+    assert!(madt.local_apics.iter().any(|apic| apic.enabled));
+}
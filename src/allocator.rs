@@ -0,0 +1,127 @@
+//! # allocator
+//!
+//! The kernel heap, which makes the `alloc` crate (`Box`, `Vec`, `BTreeMap`, ...) usable.
+//!
+//! The heap is a fixed virtual memory region starting at [`HEAP_START`]. It is managed by the
+//! `linked_list_allocator` crate, which keeps a list of free memory blocks inside the free memory
+//! itself, like the buddy allocator does for physical frames.
+//!
+//! By default the heap is backed by 4 KiB pages. With the `huge-heap` cargo feature, it is backed
+//! by 2 MiB pages instead, which need a single TLB entry per 2 MiB of heap.
+//!
+//! With the `kasan` cargo feature, the global allocator wraps the heap in a
+//! [`KasanAllocator`](crate::kasan::KasanAllocator), which detects out-of-bounds accesses and
+//! uses after free.
+//!
+//! The global allocator disables interrupts while it runs, see [`InterruptSafe`]. So the heap
+//! can be used with interrupts disabled, e.g. by the scheduler.
+
+use crate::memory::{
+    paging::{self, MappingSize, PagingError},
+    protection,
+};
+use core::alloc::{GlobalAlloc, Layout};
+use linked_list_allocator::LockedHeap;
+use x86_64::{instructions::interrupts, structures::paging::PageTableFlags, VirtAddr};
+
+/// The virtual start address of the heap. It is 2 MiB aligned, so huge pages can be used.
+pub const HEAP_START: u64 = 0x_4444_0000_0000;
+
+/// The size of the heap.
+pub const HEAP_SIZE: u64 = 2 * 1024 * 1024;
+
+/// The page size used for the heap mapping, selected by the `huge-heap` cargo feature.
+pub const HEAP_PAGE_SIZE: MappingSize = if cfg!(feature = "huge-heap") {
+    MappingSize::Page2MiB
+} else {
+    MappingSize::Page4KiB
+};
+
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+#[cfg(feature = "kasan")]
+static KASAN_ALLOCATOR: crate::kasan::KasanAllocator<LockedHeap> =
+    crate::kasan::KasanAllocator::new(&ALLOCATOR);
+
+// The `#[global_allocator]` attribute tells the compiler which allocator the `alloc` crate uses.
+#[cfg(not(feature = "kasan"))]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: InterruptSafe<LockedHeap> = InterruptSafe::new(&ALLOCATOR);
+
+#[cfg(feature = "kasan")]
+#[global_allocator]
+static GLOBAL_ALLOCATOR: InterruptSafe<crate::kasan::KasanAllocator<LockedHeap>> =
+    InterruptSafe::new(&KASAN_ALLOCATOR);
+
+/// Runs the wrapped allocator with interrupts disabled.
+///
+/// Otherwise a thread could be preempted while it holds the heap lock, and code that allocates
+/// with interrupts disabled would spin forever, since the holder can't run again.
+pub struct InterruptSafe<A: 'static> {
+    inner: &'static A,
+}
+
+impl<A> InterruptSafe<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        InterruptSafe { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for InterruptSafe<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| self.inner.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.inner.dealloc(ptr, layout))
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        interrupts::without_interrupts(|| self.inner.realloc(ptr, layout, new_size))
+    }
+}
+
+/// Maps the heap region and initializes the allocator with it.
+pub fn init_heap() -> Result<(), PagingError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protection::no_execute();
+    paging::map_anonymous(VirtAddr::new(HEAP_START), HEAP_SIZE, HEAP_PAGE_SIZE, flags)?;
+
+    unsafe {
+        ALLOCATOR
+            .lock()
+            .init(HEAP_START as *mut u8, HEAP_SIZE as usize);
+    }
+
+    #[cfg(feature = "kasan")]
+    crate::kasan::init();
+
+    Ok(())
+}
+
+#[test_case]
+fn test_simple_allocation() {
+    use alloc::boxed::Box;
+
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn test_large_vec() {
+    use alloc::vec::Vec;
+
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+#[test_case]
+fn test_heap_page_size() {
+    let heap = VirtAddr::new(HEAP_START);
+    assert_eq!(paging::mapping_size(heap), Some(HEAP_PAGE_SIZE));
+}
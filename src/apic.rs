@@ -0,0 +1,193 @@
+//! # apic
+//!
+//! The local APIC (Advanced Programmable Interrupt Controller) of each CPU.
+//!
+//! Every CPU has a local APIC, whose registers are memory-mapped at the same physical address on
+//! all CPUs; each CPU sees its own. Hardware interrupts still arrive through the PICs (see
+//! [`crate::interrupts`]), so the local APIC is used to identify the CPUs, to send
+//! inter-processor interrupts (IPIs), e.g. to start the other CPUs (see [`crate::smp`]), and for
+//! its timer, which raises the tick on the BSP once it is set up (see [`crate::time::tick`]).
+//!
+//! The timer counts down in one-shot mode, or fires when the time stamp counter reaches a deadline
+//! in TSC-deadline mode, if the CPU supports it. Both only fire once, so every tick is programmed
+//! explicitly.
+
+use crate::memory::{
+    paging::{self, PagingError},
+    protection,
+};
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{
+    registers::model_specific::Msr, structures::paging::PageTableFlags, PhysAddr, VirtAddr,
+};
+
+/// The virtual address the local APIC registers are mapped at.
+const LAPIC_START: u64 = 0x_7777_0000_0000;
+
+/// The vector of spurious interrupts, which need no end of interrupt.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// The vector of the local APIC timer.
+pub const TIMER_VECTOR: u8 = 0xef;
+
+/// The MSR that holds the deadline in TSC-deadline mode. Writing 0 disarms the timer.
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+/// Whether the registers are mapped.
+static MAPPED: AtomicBool = AtomicBool::new(false);
+
+/// The offsets of the local APIC registers.
+#[derive(Debug, Clone, Copy)]
+#[repr(u64)]
+enum Register {
+    Id = 0x20,
+    EndOfInterrupt = 0xb0,
+    SpuriousInterruptVector = 0xf0,
+    ErrorStatus = 0x280,
+    InterruptCommandLow = 0x300,
+    InterruptCommandHigh = 0x310,
+    LvtTimer = 0x320,
+    TimerInitialCount = 0x380,
+    TimerCurrentCount = 0x390,
+    TimerDivideConfiguration = 0x3e0,
+}
+
+/// The delivery modes of the interrupt command register.
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+enum Delivery {
+    Init = 0b101 << 8,
+    Startup = 0b110 << 8,
+}
+
+/// The "level assert" bit of the interrupt command register.
+const ICR_ASSERT: u32 = 1 << 14;
+/// The "delivery pending" bit of the interrupt command register.
+const ICR_PENDING: u32 = 1 << 12;
+
+/// The "APIC software enable" bit of the spurious interrupt vector register.
+const SVR_ENABLE: u32 = 1 << 8;
+
+/// The "TSC-deadline" timer mode of the LVT timer register; the default mode is one-shot.
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+
+/// The divide configuration for dividing the bus clock by 16 in one-shot mode.
+const DIVIDE_BY_16: u32 = 0b0011;
+
+/// The modes of the local APIC timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// Counts down from an initial count at a frequency that has to be measured.
+    OneShot,
+    /// Fires when the time stamp counter reaches a deadline.
+    TscDeadline,
+}
+
+/// Maps the local APIC registers at `base`, the address from the MADT.
+pub fn init(base: PhysAddr) -> Result<(), PagingError> {
+    if MAPPED.load(Ordering::Acquire) {
+        return Ok(());
+    }
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | protection::no_execute();
+    unsafe { paging::map_physical(VirtAddr::new(LAPIC_START), base, 4096, flags)? };
+    MAPPED.store(true, Ordering::Release);
+    Ok(())
+}
+
+fn read(register: Register) -> u32 {
+    assert!(MAPPED.load(Ordering::Acquire), "local APIC not mapped");
+    unsafe { core::ptr::read_volatile((LAPIC_START + register as u64) as *const u32) }
+}
+
+fn write(register: Register, value: u32) {
+    assert!(MAPPED.load(Ordering::Acquire), "local APIC not mapped");
+    unsafe { core::ptr::write_volatile((LAPIC_START + register as u64) as *mut u32, value) };
+}
+
+/// The ID of the local APIC of the current CPU.
+pub fn id() -> u8 {
+    (read(Register::Id) >> 24) as u8
+}
+
+/// Software-enables the local APIC of the current CPU, so it accepts IPIs.
+pub fn enable() {
+    write(
+        Register::SpuriousInterruptVector,
+        SVR_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+}
+
+/// Signals the end of an interrupt delivered by the local APIC.
+pub fn end_of_interrupt() {
+    write(Register::EndOfInterrupt, 0);
+}
+
+/// Whether the local APIC is mapped, see [`init`].
+pub fn is_mapped() -> bool {
+    MAPPED.load(Ordering::Acquire)
+}
+
+/// Whether the timer supports [`TimerMode::TscDeadline`].
+pub fn tsc_deadline_supported() -> bool {
+    __cpuid(1).ecx & (1 << 24) != 0
+}
+
+/// Sets up the timer of the current CPU in `mode`, disarmed.
+pub fn timer_init(mode: TimerMode) {
+    match mode {
+        TimerMode::OneShot => {
+            write(Register::TimerDivideConfiguration, DIVIDE_BY_16);
+            write(Register::LvtTimer, u32::from(TIMER_VECTOR));
+        }
+        TimerMode::TscDeadline => {
+            write(
+                Register::LvtTimer,
+                LVT_TSC_DEADLINE | u32::from(TIMER_VECTOR),
+            );
+            // The mode must be set before the first deadline is written.
+            unsafe { core::arch::asm!("mfence", options(nostack, preserves_flags)) };
+        }
+    }
+}
+
+/// Starts the countdown from `count` in [`TimerMode::OneShot`]. 0 disarms the timer.
+pub fn timer_start(count: u32) {
+    write(Register::TimerInitialCount, count);
+}
+
+/// The remaining count in [`TimerMode::OneShot`].
+pub fn timer_count() -> u32 {
+    read(Register::TimerCurrentCount)
+}
+
+/// Arms the timer for the TSC value `deadline` in [`TimerMode::TscDeadline`]. 0 disarms it.
+pub fn timer_set_deadline(deadline: u64) {
+    unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline) };
+}
+
+/// Sends an INIT IPI, which resets the CPU with the given APIC ID into a wait-for-SIPI state.
+pub fn send_init(apic_id: u8) {
+    send_ipi(apic_id, Delivery::Init as u32 | ICR_ASSERT);
+}
+
+/// Sends a startup IPI, which starts the CPU in real mode at physical address `page * 4096`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(
+        apic_id,
+        Delivery::Startup as u32 | ICR_ASSERT | u32::from(page),
+    );
+}
+
+fn send_ipi(apic_id: u8, command: u32) {
+    write(Register::ErrorStatus, 0);
+    write(Register::InterruptCommandHigh, u32::from(apic_id) << 24);
+    // Writing the low half sends the IPI.
+    write(Register::InterruptCommandLow, command);
+    while read(Register::InterruptCommandLow) & ICR_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
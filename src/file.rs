@@ -0,0 +1,94 @@
+//! # file
+//!
+//! Open files of processes: the console, pipes and the files of the file system.
+//!
+//! A process refers to its open files by *file descriptors*, small numbers that index its
+//! [`FdTable`]. Several descriptors can refer to the same open [`File`]: `dup` and `fork` share
+//! it, and it is closed when its last descriptor is closed. New processes get the console as the
+//! descriptors 0, 1 and 2 (stdin, stdout and stderr): reading it reads the keyboard (see
+//! [`crate::task::keyboard`]), and writing it prints to the VGA text buffer.
+//!
+//! A [`pipe()`] connects two processes, usually a parent and a child after a `fork`: what one
+//! writes to the writing end, the other reads from the reading end. Files and directories of the
+//! file system are opened with [`crate::fs::open`].
+//!
+//! Reads and writes can block. They take an `interrupted` function like
+//! [`crate::sync::sleep_interruptible`], so a signal to the process ends the wait.
+
+pub mod pipe;
+mod table;
+
+use crate::{fs, print, task::keyboard};
+use alloc::string::String;
+pub use table::{FdError, FdTable, MAX_FDS};
+
+/// An open file.
+pub enum File {
+    /// The keyboard for reading, and the VGA text buffer for writing.
+    Console,
+    PipeReader(pipe::Reader),
+    PipeWriter(pipe::Writer),
+    /// A file or directory of the file system.
+    Regular(fs::OpenFile),
+}
+
+/// Errors of reads and writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoError {
+    /// The file can't be read, or can't be written, e.g. the reading end of a pipe.
+    WrongDirection,
+    /// The pipe has no reader left.
+    BrokenPipe,
+    /// `interrupted` returned `true` before anything was transferred.
+    Interrupted,
+    /// The file can't grow any more, see [`fs::MAX_FILE_SIZE`] and [`fs::MAX_TOTAL_SIZE`].
+    NoSpace,
+}
+
+impl File {
+    /// Blocks until there is something to read, and moves as much as fits to `buffer`. Returns
+    /// the number of bytes read, which is 0 at the end of file or for an empty `buffer`.
+    pub fn read(
+        &self,
+        buffer: &mut [u8],
+        interrupted: impl Fn() -> bool,
+    ) -> Result<usize, IoError> {
+        match self {
+            File::Console => {
+                keyboard::read_interruptible(buffer, interrupted).ok_or(IoError::Interrupted)
+            }
+            File::PipeReader(reader) => reader.read(buffer, interrupted),
+            File::PipeWriter(_) => Err(IoError::WrongDirection),
+            File::Regular(file) => file.read(buffer),
+        }
+    }
+
+    /// Writes `bytes`, blocking while they don't fit. Returns the number of bytes written.
+    pub fn write(&self, bytes: &[u8], interrupted: impl Fn() -> bool) -> Result<usize, IoError> {
+        match self {
+            File::Console => {
+                print!("{}", String::from_utf8_lossy(bytes));
+                Ok(bytes.len())
+            }
+            File::PipeWriter(writer) => writer.write(bytes, interrupted),
+            File::PipeReader(_) => Err(IoError::WrongDirection),
+            File::Regular(file) => file.write(bytes),
+        }
+    }
+
+    /// What the file is, e.g. for listing the open files of a process.
+    pub fn name(&self) -> &'static str {
+        match self {
+            File::Console => "console",
+            File::PipeReader(_) => "pipe (read)",
+            File::PipeWriter(_) => "pipe (write)",
+            File::Regular(_) => "file",
+        }
+    }
+}
+
+/// Creates a pipe and returns its reading and writing end as files.
+pub fn pipe() -> (File, File) {
+    let (reader, writer) = pipe::pipe();
+    (File::PipeReader(reader), File::PipeWriter(writer))
+}
@@ -0,0 +1,211 @@
+//! # pipe
+//!
+//! Anonymous pipes: a bounded buffer with a writing and a reading end.
+//!
+//! [`pipe`] returns the two ends. Reading blocks while the buffer is empty, and writing while it
+//! is full. Once every [`Writer`] is dropped, reading drains the buffer and then returns 0, the end
+//! of file. Once every [`Reader`] is dropped, writing fails with [`IoError::BrokenPipe`]. Writes of
+//! up to [`PIPE_BUF`] bytes are atomic: they are never interleaved with other writes.
+
+use super::IoError;
+use crate::sync::{self, IrqMutex, WaitList};
+use alloc::{collections::VecDeque, sync::Arc};
+
+/// The most bytes a pipe buffers.
+pub const CAPACITY: usize = 4096;
+
+/// The largest write that is atomic, like on Linux.
+pub const PIPE_BUF: usize = 4096;
+
+struct Pipe {
+    state: IrqMutex<State>,
+}
+
+struct State {
+    buffer: VecDeque<u8>,
+    /// The number of [`Reader`]s and [`Writer`]s.
+    readers: usize,
+    writers: usize,
+    /// The threads waiting for bytes, or for the end of file.
+    reading: WaitList,
+    /// The threads waiting for room, or for the last reader to go away.
+    writing: WaitList,
+}
+
+/// The reading end of a pipe.
+pub struct Reader {
+    pipe: Arc<Pipe>,
+}
+
+/// The writing end of a pipe.
+pub struct Writer {
+    pipe: Arc<Pipe>,
+}
+
+/// Creates a pipe and returns its two ends.
+pub fn pipe() -> (Reader, Writer) {
+    let pipe = Arc::new(Pipe {
+        state: IrqMutex::named(
+            "PIPE",
+            State {
+                buffer: VecDeque::new(),
+                readers: 1,
+                writers: 1,
+                reading: WaitList::new(),
+                writing: WaitList::new(),
+            },
+        ),
+    });
+    (Reader { pipe: pipe.clone() }, Writer { pipe })
+}
+
+impl Reader {
+    /// Blocks until the pipe has bytes, and moves as many as fit to `buffer`. Returns the number
+    /// of bytes read, which is 0 at the end of file. Fails once `interrupted` returns `true`
+    /// while it waits.
+    pub fn read(
+        &self,
+        buffer: &mut [u8],
+        interrupted: impl Fn() -> bool,
+    ) -> Result<usize, IoError> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let mut state = self.pipe.state.lock();
+        while state.buffer.is_empty() {
+            if state.writers == 0 {
+                return Ok(0);
+            }
+            sync::sleep_interruptible(
+                &self.pipe.state,
+                state,
+                |state| &mut state.reading,
+                None,
+                &interrupted,
+            );
+            if interrupted() {
+                return Err(IoError::Interrupted);
+            }
+            state = self.pipe.state.lock();
+        }
+        let len = buffer.len().min(state.buffer.len());
+        for (byte, buffered) in buffer.iter_mut().zip(state.buffer.drain(..len)) {
+            *byte = buffered;
+        }
+        state.writing.wake_all();
+        Ok(len)
+    }
+}
+
+impl Writer {
+    /// Blocks until all of `bytes` are in the pipe. Returns the number of bytes written, which is
+    /// less than `bytes.len()` only if `interrupted` returned `true` or the pipe broke after some
+    /// were written. Fails with [`IoError::BrokenPipe`] if there is no reader left.
+    pub fn write(&self, bytes: &[u8], interrupted: impl Fn() -> bool) -> Result<usize, IoError> {
+        let atomic = bytes.len() <= PIPE_BUF;
+        let mut written = 0;
+        let mut state = self.pipe.state.lock();
+        loop {
+            if state.readers == 0 {
+                return if written > 0 {
+                    Ok(written)
+                } else {
+                    Err(IoError::BrokenPipe)
+                };
+            }
+            let room = CAPACITY - state.buffer.len();
+            let remaining = bytes.len() - written;
+            if room >= remaining || (room > 0 && !atomic) {
+                let len = room.min(remaining);
+                state.buffer.extend(&bytes[written..written + len]);
+                written += len;
+                state.reading.wake_all();
+                if written == bytes.len() {
+                    return Ok(written);
+                }
+            }
+            sync::sleep_interruptible(
+                &self.pipe.state,
+                state,
+                |state| &mut state.writing,
+                None,
+                &interrupted,
+            );
+            if interrupted() {
+                return if written > 0 {
+                    Ok(written)
+                } else {
+                    Err(IoError::Interrupted)
+                };
+            }
+            state = self.pipe.state.lock();
+        }
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        let mut state = self.pipe.state.lock();
+        state.readers -= 1;
+        if state.readers == 0 {
+            state.writing.wake_all();
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        let mut state = self.pipe.state.lock();
+        state.writers -= 1;
+        if state.writers == 0 {
+            state.reading.wake_all();
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::thread;
+
+#[test_case]
+fn test_pipe_ends_report_eof_and_broken_pipe() {
+    let (reader, writer) = pipe();
+    assert_eq!(writer.write(b"hi", || false), Ok(2));
+    drop(writer);
+    let mut buffer = [0; 4];
+    assert_eq!(reader.read(&mut buffer, || false), Ok(2));
+    assert_eq!(&buffer[..2], b"hi");
+    assert_eq!(reader.read(&mut buffer, || false), Ok(0), "end of file");
+
+    let (reader, writer) = pipe();
+    assert_eq!(reader.read(&mut buffer, || true), Err(IoError::Interrupted));
+    drop(reader);
+    assert_eq!(writer.write(b"hi", || false), Err(IoError::BrokenPipe));
+}
+
+#[test_case]
+fn test_pipe_blocks_the_writer_until_read() {
+    const LEN: usize = 3 * CAPACITY + 100;
+    let (reader, writer) = pipe();
+    let handle = thread::spawn("pipe writer", move || {
+        let bytes: alloc::vec::Vec<u8> = (0..LEN).map(|i| i as u8).collect();
+        writer.write(&bytes, || false)
+    })
+    .unwrap();
+    let mut read = 0;
+    let mut buffer = [0; 1000];
+    loop {
+        match reader.read(&mut buffer, || false) {
+            Ok(0) => break,
+            Ok(len) => {
+                assert!(buffer[..len]
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &byte)| byte == (read + i) as u8));
+                read += len;
+            }
+            Err(err) => panic!("read failed: {:?}", err),
+        }
+    }
+    assert_eq!(read, LEN);
+    assert_eq!(handle.join().unwrap(), Ok(LEN));
+}
@@ -0,0 +1,191 @@
+//! # table
+//!
+//! The file descriptor table of a process.
+
+use super::File;
+use alloc::{sync::Arc, vec::Vec};
+use core::convert::TryFrom;
+
+/// The most file descriptors a process can have open.
+pub const MAX_FDS: usize = 64;
+
+/// The open files of a process, by file descriptor.
+#[derive(Clone, Default)]
+pub struct FdTable {
+    /// Indexed by file descriptor, without trailing free slots.
+    slots: Vec<Option<Slot>>,
+}
+
+#[derive(Clone)]
+struct Slot {
+    file: Arc<File>,
+    /// Whether `exec` closes the descriptor.
+    close_on_exec: bool,
+}
+
+/// Errors of the file descriptor table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdError {
+    /// The descriptor is not open, or out of range.
+    BadDescriptor,
+    /// All [`MAX_FDS`] descriptors are open.
+    TooManyOpen,
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        FdTable { slots: Vec::new() }
+    }
+
+    /// A table with the console as the descriptors 0, 1 and 2, for new processes.
+    pub fn console() -> Self {
+        let console = Arc::new(File::Console);
+        let mut table = FdTable::new();
+        for _ in 0..3 {
+            table.slots.push(Some(Slot {
+                file: console.clone(),
+                close_on_exec: false,
+            }));
+        }
+        table
+    }
+
+    /// The file of `fd`.
+    pub fn get(&self, fd: u64) -> Result<Arc<File>, FdError> {
+        self.slot(fd).map(|slot| slot.file.clone())
+    }
+
+    /// Opens `file` as the lowest free descriptor and returns it.
+    pub fn insert(&mut self, file: Arc<File>, close_on_exec: bool) -> Result<u64, FdError> {
+        let fd = self
+            .slots
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(self.slots.len());
+        if fd >= MAX_FDS {
+            return Err(FdError::TooManyOpen);
+        }
+        self.set(fd, file, close_on_exec);
+        Ok(fd as u64)
+    }
+
+    /// Closes `fd`. Returns its file, which the caller should drop without holding any locks,
+    /// since closing the last descriptor of a pipe end wakes the other end.
+    pub fn close(&mut self, fd: u64) -> Result<Arc<File>, FdError> {
+        self.slot(fd)?;
+        let slot = self.slots[fd as usize].take().unwrap();
+        self.trim();
+        Ok(slot.file)
+    }
+
+    /// Opens the file of `fd` as the lowest free descriptor too, which `exec` doesn't close.
+    pub fn dup(&mut self, fd: u64) -> Result<u64, FdError> {
+        let file = self.get(fd)?;
+        self.insert(file, false)
+    }
+
+    /// Opens the file of `fd` as `new_fd` too, which `exec` doesn't close. Returns the file that
+    /// was open as `new_fd`, for the caller to drop like after [`FdTable::close`]. Nothing
+    /// changes if `fd` is `new_fd`.
+    pub fn dup2(&mut self, fd: u64, new_fd: u64) -> Result<Option<Arc<File>>, FdError> {
+        let file = self.get(fd)?;
+        if new_fd >= MAX_FDS as u64 {
+            return Err(FdError::BadDescriptor);
+        }
+        if fd == new_fd {
+            return Ok(None);
+        }
+        let new_fd = new_fd as usize;
+        let old = self
+            .slots
+            .get_mut(new_fd)
+            .and_then(Option::take)
+            .map(|slot| slot.file);
+        self.set(new_fd, file, false);
+        Ok(old)
+    }
+
+    /// Whether `exec` closes `fd`.
+    pub fn close_on_exec(&self, fd: u64) -> Result<bool, FdError> {
+        self.slot(fd).map(|slot| slot.close_on_exec)
+    }
+
+    pub fn set_close_on_exec(&mut self, fd: u64, close_on_exec: bool) -> Result<(), FdError> {
+        self.slot(fd)?;
+        self.slots[fd as usize].as_mut().unwrap().close_on_exec = close_on_exec;
+        Ok(())
+    }
+
+    /// Closes the descriptors marked close-on-exec, for `exec`. Returns their files, for the
+    /// caller to drop like after [`FdTable::close`].
+    pub fn exec(&mut self) -> Vec<Arc<File>> {
+        let closed = self
+            .slots
+            .iter_mut()
+            .filter(|slot| slot.as_ref().is_some_and(|slot| slot.close_on_exec))
+            .filter_map(|slot| slot.take().map(|slot| slot.file))
+            .collect();
+        self.trim();
+        closed
+    }
+
+    /// The open descriptors and their files, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &File)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(fd, slot)| Some((fd as u64, &*slot.as_ref()?.file)))
+    }
+
+    fn slot(&self, fd: u64) -> Result<&Slot, FdError> {
+        usize::try_from(fd)
+            .ok()
+            .and_then(|fd| self.slots.get(fd)?.as_ref())
+            .ok_or(FdError::BadDescriptor)
+    }
+
+    fn set(&mut self, fd: usize, file: Arc<File>, close_on_exec: bool) {
+        if fd >= self.slots.len() {
+            self.slots.resize(fd + 1, None);
+        }
+        self.slots[fd] = Some(Slot {
+            file,
+            close_on_exec,
+        });
+    }
+
+    /// Drops the trailing free slots.
+    fn trim(&mut self) {
+        while let Some(None) = self.slots.last() {
+            self.slots.pop();
+        }
+    }
+}
+
+#[test_case]
+fn test_descriptors_are_allocated_lowest_first() {
+    let mut table = FdTable::console();
+    assert_eq!(table.dup(1), Ok(3));
+    assert!(table.close(0).is_ok());
+    assert_eq!(table.close(0).err(), Some(FdError::BadDescriptor));
+    assert_eq!(table.dup(3), Ok(0));
+
+    assert!(table.dup2(1, 9).unwrap().is_none());
+    table.set_close_on_exec(9, true).unwrap();
+    assert!(table.dup2(9, 9).unwrap().is_none());
+    assert_eq!(table.close_on_exec(9), Ok(true));
+    assert_eq!(table.dup2(1, 2).unwrap().map(|_| ()), Some(()));
+    assert_eq!(table.exec().len(), 1);
+    let fds: Vec<u64> = table.iter().map(|(fd, _)| fd).collect();
+    assert_eq!(fds, [0, 1, 2, 3]);
+    assert_eq!(table.get(9).err(), Some(FdError::BadDescriptor));
+
+    for fd in 4..MAX_FDS as u64 {
+        assert_eq!(table.dup(0), Ok(fd));
+    }
+    assert_eq!(table.dup(0), Err(FdError::TooManyOpen));
+    assert_eq!(
+        table.dup2(0, MAX_FDS as u64).err(),
+        Some(FdError::BadDescriptor)
+    );
+}
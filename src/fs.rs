@@ -0,0 +1,403 @@
+//! # fs
+//!
+//! An in-memory file system.
+//!
+//! There is no disk driver yet, so files live on the kernel heap and are gone after a reboot. The
+//! file system is a tree of directories and regular files under `/`, which starts out empty.
+//! Paths are absolute here: [`absolute`] resolves a path against the current directory of a
+//! process (see [`crate::process::current_dir`]), which the `chdir` system call changes.
+//!
+//! [`open`] opens a file as an [`OpenFile`], which reads and writes at an offset that moves along,
+//! like a file descriptor on Linux (see [`crate::file::File`]). Reading a directory returns the
+//! names of its entries, one per line, with a `/` after the ones of directories. Writes never
+//! block, and files grow up to [`MAX_FILE_SIZE`], all files together up to [`MAX_TOTAL_SIZE`], so
+//! the file system can't use up the kernel heap. Nothing removes files yet. Processes can also map
+//! a file privately with `mmap`, whose pages are filled with [`read_at`].
+
+use crate::{file::IoError, sync::IrqMutex};
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::convert::TryFrom;
+
+/// The largest size of a file.
+pub const MAX_FILE_SIZE: usize = 256 * 1024;
+
+/// The largest size of all files together, a quarter of [`crate::allocator::HEAP_SIZE`].
+pub const MAX_TOTAL_SIZE: usize = 512 * 1024;
+
+/// The longest name of a file or directory, like on Linux.
+pub const MAX_NAME: usize = 255;
+
+/// The inode number of the root directory.
+const ROOT: usize = 0;
+
+static TREE: IrqMutex<Tree> = IrqMutex::named(
+    "FS",
+    Tree {
+        nodes: Vec::new(),
+        used: 0,
+    },
+);
+
+/// The nodes of the file system, indexed by their inode numbers.
+struct Tree {
+    /// Empty until the root directory is created on first use, see [`Tree::lookup`].
+    nodes: Vec<Node>,
+    /// The size of all files together, at most [`MAX_TOTAL_SIZE`].
+    used: usize,
+}
+
+enum Node {
+    /// The inode numbers of the entries, by name.
+    Directory(BTreeMap<String, usize>),
+    File(Vec<u8>),
+}
+
+/// Errors of the file system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// The path doesn't exist.
+    NotFound,
+    /// A directory in the path is a file, or the path itself where a directory is needed.
+    NotADirectory,
+    /// The path is a directory, but it's opened for writing.
+    IsADirectory,
+    /// The path exists already.
+    AlreadyExists,
+    /// The path is empty, or a name in it is longer than [`MAX_NAME`].
+    InvalidPath,
+}
+
+/// How [`open`] opens a file, like the flags of the `open` system call.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    /// Creates the file if it doesn't exist.
+    pub create: bool,
+    /// Empties the file, if it is opened for writing.
+    pub truncate: bool,
+    /// Writes at the end of the file, wherever the offset is.
+    pub append: bool,
+    /// Only opens directories.
+    pub directory: bool,
+}
+
+/// An open file or directory.
+pub struct OpenFile {
+    inode: usize,
+    options: OpenOptions,
+    /// Where the next read or write starts.
+    offset: IrqMutex<usize>,
+}
+
+impl Tree {
+    /// The inode number of the absolute path `path`.
+    fn lookup(&mut self, path: &str) -> Result<usize, FsError> {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::Directory(BTreeMap::new()));
+        }
+        let mut inode = ROOT;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            inode = match &self.nodes[inode] {
+                Node::Directory(entries) => *entries.get(name).ok_or(FsError::NotFound)?,
+                Node::File(_) => return Err(FsError::NotADirectory),
+            };
+        }
+        Ok(inode)
+    }
+
+    /// Adds `node` as the last name of the absolute path `path`, whose directory must exist.
+    /// Returns its inode number.
+    fn insert(&mut self, path: &str, node: Node) -> Result<usize, FsError> {
+        let (directory, name) = path.rsplit_once('/').ok_or(FsError::InvalidPath)?;
+        if name.is_empty() {
+            // Only `/` is absolute and ends with a `/`.
+            return Err(FsError::AlreadyExists);
+        }
+        let parent = self.lookup(directory)?;
+        let inode = self.nodes.len();
+        match &mut self.nodes[parent] {
+            Node::Directory(entries) if entries.contains_key(name) => {
+                return Err(FsError::AlreadyExists)
+            }
+            Node::Directory(entries) => entries.insert(name.to_string(), inode),
+            Node::File(_) => return Err(FsError::NotADirectory),
+        };
+        self.nodes.push(node);
+        Ok(inode)
+    }
+
+    /// What reading the directory with `entries` returns.
+    fn listing(&self, entries: &BTreeMap<String, usize>) -> Vec<u8> {
+        let mut listing = Vec::new();
+        for (name, &inode) in entries {
+            listing.extend_from_slice(name.as_bytes());
+            if let Node::Directory(_) = self.nodes[inode] {
+                listing.push(b'/');
+            }
+            listing.push(b'\n');
+        }
+        listing
+    }
+}
+
+/// The absolute path of `path`: `path` itself if it starts with a `/`, else `path` in the
+/// directory `dir`, which must be absolute. Empty names and `.` are removed, and `..` removes the
+/// name before it, so the result is like `/a/b`, or `/` for the root directory.
+pub fn absolute(dir: &str, path: &str) -> Result<String, FsError> {
+    if path.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    let start = if path.starts_with('/') { "" } else { dir };
+    let mut names = Vec::new();
+    for name in start.split('/').chain(path.split('/')) {
+        match name {
+            "" | "." => {}
+            ".." => {
+                names.pop();
+            }
+            name if name.len() > MAX_NAME => return Err(FsError::InvalidPath),
+            name => names.push(name),
+        }
+    }
+    if names.is_empty() {
+        return Ok("/".to_string());
+    }
+    let mut absolute = String::new();
+    for name in names {
+        absolute.push('/');
+        absolute.push_str(name);
+    }
+    Ok(absolute)
+}
+
+/// Opens the file or directory at the absolute path `path`. Directories can't be opened for
+/// writing.
+pub fn open(path: &str, options: OpenOptions) -> Result<OpenFile, FsError> {
+    let mut tree = TREE.lock();
+    let inode = match tree.lookup(path) {
+        Ok(inode) => inode,
+        Err(FsError::NotFound) if options.create => tree.insert(path, Node::File(Vec::new()))?,
+        Err(err) => return Err(err),
+    };
+    let tree = &mut *tree;
+    match &mut tree.nodes[inode] {
+        Node::Directory(_) if options.write => return Err(FsError::IsADirectory),
+        Node::File(_) if options.directory => return Err(FsError::NotADirectory),
+        Node::File(bytes) if options.write && options.truncate => {
+            tree.used -= bytes.len();
+            *bytes = Vec::new();
+        }
+        _ => {}
+    }
+    Ok(OpenFile {
+        inode,
+        options,
+        offset: IrqMutex::named("FILE OFFSET", 0),
+    })
+}
+
+/// Creates a directory at the absolute path `path`.
+pub fn create_dir(path: &str) -> Result<(), FsError> {
+    TREE.lock()
+        .insert(path, Node::Directory(BTreeMap::new()))
+        .map(drop)
+}
+
+/// Whether the absolute path `path` is a directory rather than a file.
+pub fn is_dir(path: &str) -> Result<bool, FsError> {
+    let mut tree = TREE.lock();
+    let inode = tree.lookup(path)?;
+    Ok(matches!(tree.nodes[inode], Node::Directory(_)))
+}
+
+/// Copies the bytes of the file `inode` from `offset` on to `buffer`, as many as fit, and returns
+/// their number, which is less than `buffer.len()` at the end of the file.
+pub fn read_at(inode: usize, offset: u64, buffer: &mut [u8]) -> usize {
+    let tree = TREE.lock();
+    let bytes = match tree.nodes.get(inode) {
+        Some(Node::File(bytes)) => bytes,
+        _ => return 0,
+    };
+    let start = usize::try_from(offset).map_or(bytes.len(), |offset| offset.min(bytes.len()));
+    let len = buffer.len().min(bytes.len() - start);
+    buffer[..len].copy_from_slice(&bytes[start..start + len]);
+    len
+}
+
+impl OpenFile {
+    /// The inode number of the file, for [`read_at`], or `None` for a directory.
+    pub fn file_inode(&self) -> Option<usize> {
+        match TREE.lock().nodes[self.inode] {
+            Node::File(_) => Some(self.inode),
+            Node::Directory(_) => None,
+        }
+    }
+
+    /// Whether the file was opened for reading.
+    pub fn readable(&self) -> bool {
+        self.options.read
+    }
+
+    /// Moves the bytes after the offset to `buffer`, as many as fit, and moves the offset past
+    /// them. Returns the number of bytes read, which is 0 at the end of the file.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, IoError> {
+        if !self.options.read {
+            return Err(IoError::WrongDirection);
+        }
+        let mut offset = self.offset.lock();
+        let tree = TREE.lock();
+        let listing;
+        let bytes = match &tree.nodes[self.inode] {
+            Node::File(bytes) => bytes.as_slice(),
+            Node::Directory(entries) => {
+                listing = tree.listing(entries);
+                listing.as_slice()
+            }
+        };
+        let start = (*offset).min(bytes.len());
+        let len = buffer.len().min(bytes.len() - start);
+        buffer[..len].copy_from_slice(&bytes[start..start + len]);
+        *offset = start + len;
+        Ok(len)
+    }
+
+    /// Writes `bytes` at the offset, or at the end of the file when appending, and moves the
+    /// offset past them. Returns the number of bytes written, which is less than `bytes.len()`
+    /// if the file reached [`MAX_FILE_SIZE`] or the file system [`MAX_TOTAL_SIZE`]. Fails with
+    /// [`IoError::NoSpace`] if nothing fits, also when the heap has no memory left for the file.
+    pub fn write(&self, bytes: &[u8]) -> Result<usize, IoError> {
+        if !self.options.write {
+            return Err(IoError::WrongDirection);
+        }
+        let mut offset = self.offset.lock();
+        let mut tree = TREE.lock();
+        let tree = &mut *tree;
+        let file = match &mut tree.nodes[self.inode] {
+            Node::File(file) => file,
+            Node::Directory(_) => return Err(IoError::WrongDirection),
+        };
+        let start = if self.options.append {
+            file.len()
+        } else {
+            *offset
+        };
+        let available = file.len() + (MAX_TOTAL_SIZE - tree.used);
+        let end = MAX_FILE_SIZE
+            .min(available)
+            .min(start.saturating_add(bytes.len()));
+        if end <= start && !bytes.is_empty() {
+            return Err(IoError::NoSpace);
+        }
+        if file.len() < end {
+            let growth = end - file.len();
+            file.try_reserve(growth).map_err(|_| IoError::NoSpace)?;
+            file.resize(end, 0);
+            tree.used += growth;
+        }
+        file[start..end].copy_from_slice(&bytes[..end - start]);
+        *offset = end;
+        Ok(end - start)
+    }
+}
+
+#[test_case]
+fn test_absolute_paths() {
+    assert_eq!(absolute("/a", "b/../c/./d").unwrap(), "/a/c/d");
+    assert_eq!(absolute("/a", "//x//y/").unwrap(), "/x/y");
+    assert_eq!(absolute("/a/b", "../..").unwrap(), "/");
+    assert_eq!(absolute("/", "..").unwrap(), "/");
+    assert_eq!(absolute("/", ""), Err(FsError::InvalidPath));
+}
+
+#[test_case]
+fn test_files_are_created_written_and_read() {
+    let write = OpenOptions {
+        write: true,
+        create: true,
+        ..OpenOptions::default()
+    };
+    let read = OpenOptions {
+        read: true,
+        ..OpenOptions::default()
+    };
+    assert_eq!(open("/fs-test/file", write).err(), Some(FsError::NotFound));
+    create_dir("/fs-test").unwrap();
+    assert_eq!(create_dir("/fs-test"), Err(FsError::AlreadyExists));
+    assert_eq!(is_dir("/fs-test"), Ok(true));
+
+    let file = open("/fs-test/file", write).unwrap();
+    assert_eq!(file.write(b"hello"), Ok(5));
+    let append = OpenOptions {
+        append: true,
+        ..write
+    };
+    assert_eq!(open("/fs-test/file", append).unwrap().write(b"!"), Ok(1));
+    assert_eq!(file.write(b" world"), Ok(6));
+    assert_eq!(file.read(&mut [0; 4]), Err(IoError::WrongDirection));
+
+    let file = open("/fs-test/file", read).unwrap();
+    let mut buffer = [0; 16];
+    assert_eq!(file.read(&mut buffer), Ok(11));
+    assert_eq!(&buffer[..11], b"hello world");
+    assert_eq!(file.read(&mut buffer), Ok(0));
+    assert_eq!(is_dir("/fs-test/file"), Ok(false));
+    assert_eq!(
+        open("/fs-test/file/x", read).err(),
+        Some(FsError::NotADirectory)
+    );
+
+    create_dir("/fs-test/dir").unwrap();
+    assert_eq!(open("/fs-test", write).err(), Some(FsError::IsADirectory));
+    let directory = OpenOptions {
+        directory: true,
+        ..read
+    };
+    assert_eq!(
+        open("/fs-test/file", directory).err(),
+        Some(FsError::NotADirectory)
+    );
+    let listing = open("/fs-test", directory).unwrap();
+    assert_eq!(listing.read(&mut buffer), Ok(10));
+    assert_eq!(&buffer[..10], b"dir/\nfile\n");
+}
+
+#[test_case]
+fn test_full_file_system_fails_writes() {
+    use alloc::format;
+
+    let write = OpenOptions {
+        write: true,
+        create: true,
+        ..OpenOptions::default()
+    };
+    create_dir("/fs-full").unwrap();
+    let chunk = [b'x'; 4096];
+    // Each file stops growing at MAX_FILE_SIZE, so it takes a few to fill the file system.
+    let mut files = 0;
+    while TREE.lock().used < MAX_TOTAL_SIZE {
+        let file = open(&format!("/fs-full/{}", files), write).unwrap();
+        files += 1;
+        while file.write(&chunk) == Ok(chunk.len()) {}
+    }
+    let file = open(&format!("/fs-full/{}", files), write).unwrap();
+    files += 1;
+    assert_eq!(file.write(&chunk), Err(IoError::NoSpace));
+    assert_eq!(TREE.lock().used, MAX_TOTAL_SIZE);
+
+    // Emptying the files gives the space back.
+    let truncate = OpenOptions {
+        truncate: true,
+        ..write
+    };
+    for i in 0..files {
+        open(&format!("/fs-full/{}", i), truncate).unwrap();
+    }
+    let file = open("/fs-full/0", write).unwrap();
+    assert_eq!(file.write(&chunk), Ok(chunk.len()));
+    open("/fs-full/0", truncate).unwrap();
+}
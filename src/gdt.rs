@@ -0,0 +1,135 @@
+//! # gdt
+//!
+//! The Global Descriptor Table (GDT) and the Task State Segment (TSS).
+//!
+//! In 64-bit mode segmentation is mostly unused, but the GDT still holds the code and data
+//! segments of the kernel and of user mode, whose privilege levels decide the ring the CPU runs
+//! in, and it is needed to load a TSS. The segments are in the same order on every CPU, so their
+//! selectors are constants. The user data segment comes right before the user code segment, the
+//! order `sysret` expects.
+//!
+//! The TSS holds two kinds of stacks:
+//!
+//! - The Interrupt Stack Table (IST): a list of known-good stacks the CPU switches to before
+//!   invoking an exception handler whose IDT entry selects an IST index. We need this for the
+//!   double fault handler. When a kernel stack overflows into its guard page, the CPU can't push
+//!   the frame of the page fault onto that stack anymore, which results in a double fault.
+//!   Without a stack switch, this results in a triple fault, which resets the machine. The page
+//!   fault handler itself runs on the current stack, since it may block or be preempted while it
+//!   resolves a copy-on-write fault (see [`crate::memory::address_space`]), and an IST stack is
+//!   shared by everything on the CPU.
+//! - `RSP0`, the kernel stack the CPU switches to when an interrupt or exception arrives in user
+//!   mode (see [`set_kernel_stack`]). Every thread that runs user code has its own, so it is
+//!   switched with the thread (see [`crate::thread`]).
+//!
+//! Every CPU needs its own TSS with its own stacks, and therefore its own GDT. [`init`] creates
+//! them, and keeps a pointer to the TSS in the CPU's per-CPU area.
+
+use crate::{memory::stack, per_cpu, percpu};
+use alloc::boxed::Box;
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use x86_64::instructions::segmentation::{Segment, CS, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+/// The IST index of the stack used by the double fault handler.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+/// The number of pages of each interrupt stack.
+const IST_STACK_PAGES: u64 = 5;
+
+per_cpu! {
+    /// The TSS of each CPU, once [`init`] was called.
+    static TSS: AtomicPtr<TaskStateSegment> = AtomicPtr::new(ptr::null_mut());
+}
+
+fn new_tss() -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stack("double fault");
+    tss
+}
+
+fn new_gdt(tss: &'static TaskStateSegment) -> GlobalDescriptorTable {
+    let mut gdt = GlobalDescriptorTable::new();
+    let selectors = [
+        gdt.add_entry(Descriptor::kernel_code_segment()),
+        gdt.add_entry(Descriptor::kernel_data_segment()),
+        gdt.add_entry(Descriptor::user_data_segment()),
+        gdt.add_entry(Descriptor::user_code_segment()),
+        gdt.add_entry(Descriptor::tss_segment(tss)),
+    ];
+    assert_eq!(
+        selectors,
+        [
+            KERNEL_CODE_SELECTOR,
+            KERNEL_DATA_SELECTOR,
+            USER_DATA_SELECTOR,
+            USER_CODE_SELECTOR,
+            TSS_SELECTOR
+        ]
+    );
+    gdt
+}
+
+fn ist_stack(name: &'static str) -> VirtAddr {
+    stack::allocate(name, IST_STACK_PAGES)
+        .expect("failed to allocate interrupt stack")
+        .top
+}
+
+/// Creates and loads the GDT and TSS of the current CPU and reloads the segment registers. Called
+/// on every CPU, after its per-CPU area was initialized.
+pub fn init() {
+    // The CPU keeps using both tables, so they are never freed. The TSS is only written through
+    // the pointer in the per-CPU area afterwards.
+    let tss: *mut TaskStateSegment = Box::leak(Box::new(new_tss()));
+    let gdt: &'static GlobalDescriptorTable = Box::leak(Box::new(new_gdt(unsafe { &*tss })));
+    TSS.with(|pointer| pointer.store(tss, Ordering::Release));
+
+    gdt.load();
+    unsafe {
+        CS::set_reg(KERNEL_CODE_SELECTOR);
+        SS::set_reg(KERNEL_DATA_SELECTOR);
+        load_tss(TSS_SELECTOR);
+    }
+}
+
+/// Sets the stack the current CPU switches to when an interrupt, an exception or a `syscall`
+/// arrives in user mode.
+pub fn set_kernel_stack(top: VirtAddr) {
+    percpu::set_kernel_stack(top);
+    TSS.with(|tss| {
+        let tss = tss.load(Ordering::Acquire);
+        assert!(!tss.is_null(), "the TSS is not initialized");
+        // The TSS is packed, so its fields are copied instead of borrowed.
+        unsafe {
+            let mut stacks = (*tss).privilege_stack_table;
+            stacks[0] = top;
+            (*tss).privilege_stack_table = stacks;
+        }
+    });
+}
+
+/// The stack the current CPU switches to when an interrupt or exception arrives in user mode, see
+/// [`set_kernel_stack`].
+pub fn kernel_stack() -> VirtAddr {
+    TSS.with(|tss| {
+        let tss = tss.load(Ordering::Acquire);
+        if tss.is_null() {
+            return VirtAddr::zero();
+        }
+        let stacks = unsafe { (*tss).privilege_stack_table };
+        stacks[0]
+    })
+}
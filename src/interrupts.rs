@@ -0,0 +1,340 @@
+//! # interrupts
+//!
+//! The Interrupt Descriptor Table (IDT), the CPU exception handlers and the hardware interrupt
+//! handlers.
+//!
+//! The handlers use the `x86-interrupt` calling convention, which saves all registers and returns
+//! with `iretq`. The double fault handler runs on its own interrupt stack (see [`crate::gdt`]), so
+//! it still works when a kernel stack overflowed into its guard page. Every CPU loads an IDT of its
+//! own with the same handlers (see [`init_idt`]).
+//!
+//! Hardware interrupts arrive through the two chained 8259 PICs. Their vectors are remapped to
+//! [`PIC_1_OFFSET`]`..`[`PIC_2_OFFSET`]` + 8`, right after the 32 CPU exception vectors. Interrupt
+//! handlers must not block: the keyboard and serial handlers only push the received bytes to
+//! lock-free queues, which are consumed by async tasks (see [`crate::task`]).
+//!
+//! Every handler that can interrupt user mode starts with a [`InterruptEntry`], which switches to
+//! the kernel's GS base (see [`crate::percpu`]), counts the hardware interrupts of each CPU and
+//! ends the CPU's idle period (see [`crate::time::tick`]).
+//!
+//! An exception in user mode is the process's fault, not the kernel's: the handlers raise a signal
+//! in the process instead of panicking, which kills it unless it handles the signal (see
+//! [`crate::process::fault`]). Page faults on copy-on-write pages
+//! and on mapped pages that were never touched are resolved first, in user mode or not (see
+//! [`crate::memory::address_space::handle_fault`] and [`crate::process::fault_in`]).
+//! Any other page fault in kernel mode is only survived when the kernel copied from or to user
+//! memory (see [`crate::memory::user`]). The system call gate is installed by [`crate::syscall`].
+//!
+//! The timer interrupt comes from the PIT at boot, and from the local APIC timer once it took
+//! over. Both handlers do the same work, see [`tick`]. When they interrupted user code, they also
+//! deliver the signals of its process (see [`crate::process::interrupt_return`]), so even a
+//! process that never makes a system call gets them.
+
+use crate::{
+    apic, gdt,
+    memory::{address_space, protection::Section, stack, user},
+    percpu::InterruptEntry,
+    println,
+    process::{self, Fault},
+    sync::IrqMutex,
+    syscall, task, time,
+};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU64, Ordering};
+use pic8259::ChainedPics;
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::registers::{control::Cr2, rflags::RFlags};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+/// The first interrupt vector of the primary PIC.
+pub const PIC_1_OFFSET: u8 = 32;
+
+/// The first interrupt vector of the secondary PIC.
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+/// The chained primary and secondary PIC.
+pub static PICS: IrqMutex<ChainedPics> = IrqMutex::named("PICS", unsafe {
+    ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
+});
+
+/// The interrupt vectors of the hardware interrupts we handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    /// IRQ 0, the programmable interval timer.
+    Timer = PIC_1_OFFSET,
+    /// IRQ 1, the PS/2 keyboard.
+    Keyboard,
+    /// IRQ 4, the first serial port (COM1).
+    Serial = PIC_1_OFFSET + 4,
+}
+
+impl InterruptIndex {
+    fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
+
+fn new_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+    idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+    idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+    idt[apic::TIMER_VECTOR as usize].set_handler_fn(lapic_timer_interrupt_handler);
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+    syscall::install(&mut idt);
+    idt
+}
+
+/// The number of timer interrupts since the PICs were initialized.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Creates and loads the IDT of the current CPU. Called on every CPU, after the heap was
+/// initialized.
+pub fn init_idt() {
+    // The CPU keeps using the table, so it is never freed.
+    let idt: &'static InterruptDescriptorTable = Box::leak(Box::new(new_idt()));
+    idt.load();
+}
+
+/// Remaps the PICs and unmasks the interrupts we handle. Interrupts stay disabled on the CPU until
+/// they are enabled with `x86_64::instructions::interrupts::enable`.
+pub fn init_pics() {
+    // Unmask the timer, the keyboard, the cascade to the secondary PIC and the serial port.
+    let unmasked = [0, 1, 2, 4];
+    let primary_mask = unmasked.iter().fold(0xff, |mask, irq| mask & !(1u8 << irq));
+
+    let mut pics = PICS.lock();
+    unsafe {
+        pics.initialize();
+        pics.write_masks(primary_mask, 0xff);
+    }
+}
+
+/// Masks the interrupt of the PIT, once the local APIC timer raises the tick instead.
+pub fn disable_pit() {
+    let mut pics = PICS.lock();
+    unsafe {
+        let [primary, secondary] = pics.read_masks();
+        pics.write_masks(primary | 1, secondary);
+    }
+}
+
+/// Signals the end of the interrupt to the PIC, so it delivers the next one.
+fn end_of_interrupt(index: InterruptIndex) {
+    unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::exception(&stack_frame);
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn divide_error_handler(mut stack_frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::exception(&stack_frame);
+    if process::from_user(&stack_frame) {
+        process::fault(Fault::DivideError, format_args!(""), &mut stack_frame);
+        return;
+    }
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(mut stack_frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::exception(&stack_frame);
+    if process::from_user(&stack_frame) {
+        process::fault(Fault::InvalidOpcode, format_args!(""), &mut stack_frame);
+        return;
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+/// Raised for privileged instructions and for invalid segment selectors, among others. The error
+/// code is the selector involved, if any.
+extern "x86-interrupt" fn general_protection_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _entry = InterruptEntry::exception(&stack_frame);
+    if process::from_user(&stack_frame) {
+        process::fault(
+            Fault::GeneralProtection,
+            format_args!(" (error code {:#x})", error_code),
+            &mut stack_frame,
+        );
+        return;
+    }
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT (error code {:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+/// A double fault happens when the CPU fails to invoke an exception handler, e.g. when it can't
+/// push the exception frame of a page fault. Since we can't recover from it, it never returns.
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    // CR2 still holds the address of the page fault that led to the double fault.
+    if let Some(stack) = stack::find_overflow(Cr2::read()) {
+        panic!("stack overflow in {}", stack.name);
+    }
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+}
+
+/// The CPU writes the accessed virtual address that caused the page fault to the CR2 register.
+extern "x86-interrupt" fn page_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let _entry = InterruptEntry::exception(&stack_frame);
+    let addr = Cr2::read();
+    // A write to a copy-on-write page of the current process, by itself or by a copy on its
+    // behalf. Only if the faulting code ran with interrupts enabled, since resolving it takes the
+    // paging locks, which interrupt handlers must not take.
+    let interrupts_enabled =
+        RFlags::from_bits_truncate(stack_frame.cpu_flags).contains(RFlags::INTERRUPT_FLAG);
+    if interrupts_enabled {
+        let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+        interrupts::enable();
+        let resolved = address_space::handle_fault(addr, write) || process::fault_in(addr, write);
+        interrupts::disable();
+        if resolved {
+            return;
+        }
+    }
+    if process::from_user(&stack_frame) {
+        process::fault(
+            Fault::PageFault,
+            format_args!(
+                " at {:#x} ({})",
+                addr.as_u64(),
+                page_fault_access(error_code)
+            ),
+            &mut stack_frame,
+        );
+        return;
+    }
+    // A copy from or to user memory on behalf of a process, which fails instead.
+    if user::fixup(&mut stack_frame, addr) {
+        return;
+    }
+    if let Some(stack) = stack::find_overflow(addr) {
+        panic!("stack overflow in {}", stack.name);
+    }
+
+    // The page is mapped, but its permissions forbid the access, e.g. writing to `.text`.
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        let section = Section::containing(addr).map_or("outside the kernel image", Section::name);
+        panic!(
+            "protection violation: {} at {:#x} ({})\n{:#?}",
+            page_fault_access(error_code),
+            addr.as_u64(),
+            section,
+            stack_frame
+        );
+    }
+
+    panic!(
+        "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\n{:#?}",
+        addr, error_code, stack_frame
+    );
+}
+
+/// The kind of access that caused a page fault.
+fn page_fault_access(error_code: PageFaultErrorCode) -> &'static str {
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "execute"
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    }
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::interrupt(&stack_frame);
+    // Before switching: the next thread may run for a while before this handler returns.
+    end_of_interrupt(InterruptIndex::Timer);
+    tick();
+    if process::from_user(&stack_frame) {
+        process::interrupt_return(&mut stack_frame);
+    }
+}
+
+extern "x86-interrupt" fn lapic_timer_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::interrupt(&stack_frame);
+    apic::end_of_interrupt();
+    tick();
+    if process::from_user(&stack_frame) {
+        process::interrupt_return(&mut stack_frame);
+    }
+}
+
+/// The work of every timer interrupt, after its end was signaled.
+fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    time::tick::on_tick();
+    // Timers may wake threads, which the scheduler then sees.
+    time::timer::on_tick();
+    crate::thread::preempt();
+}
+
+/// The number of timer interrupts so far. The timer runs at [`crate::time::TICK_HZ`], except while
+/// the CPU is idle.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// The local APIC raises a spurious interrupt when an interrupt vanished before it was accepted.
+/// It needs no end of interrupt.
+extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::interrupt(&stack_frame);
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::interrupt(&stack_frame);
+    // The keyboard controller won't send another interrupt until we read the scancode.
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    task::keyboard::add_scancode(scancode);
+
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+/// Reads all received bytes from the first serial port.
+///
+/// The data and line status registers are read directly instead of through
+/// [`crate::serial::SERIAL1`], whose lock may be held by the interrupted code.
+extern "x86-interrupt" fn serial_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::interrupt(&stack_frame);
+    let mut data = Port::<u8>::new(0x3f8);
+    let mut line_status = Port::<u8>::new(0x3f8 + 5);
+    // Bit 0 of the line status register: data ready.
+    while unsafe { line_status.read() } & 1 != 0 {
+        task::serial::add_byte(unsafe { data.read() });
+    }
+
+    end_of_interrupt(InterruptIndex::Serial);
+}
+
+#[test_case]
+fn test_breakpoint_exception() {
+    // Invoke a breakpoint exception; the test passes if execution continues afterwards.
+    x86_64::instructions::interrupts::int3();
+}
@@ -0,0 +1,477 @@
+//! # kasan
+//!
+//! A kernel address sanitizer (KASAN) for the heap, enabled by the `kasan` cargo feature.
+//!
+//! The compiler instruments every load and store with a call to one of the `__asan_load*_noabort`
+//! or `__asan_store*_noabort` functions below. These consult *shadow memory*: one shadow byte
+//! describes 8 bytes (a granule) of memory.
+//!
+//! - `0` means all 8 bytes are accessible.
+//! - `1..=7` means only the first `n` bytes are accessible.
+//! - Values with the high bit set mean the granule is poisoned; the value tells why (see the
+//!   `POISON_*` constants).
+//!
+//! Shadow memory is only mapped for the kernel heap, all other accesses are not checked. The shadow byte of `addr` is at `(addr >> 3) + KASAN_SHADOW_OFFSET`.
+//!
+//! The [`KasanAllocator`] wraps the heap allocator: every allocation gets poisoned redzones on
+//! both sides, which hold a header with a backtrace of the allocation. Freed memory is poisoned
+//! and kept in a quarantine for a while before it can be reused, so accesses to it are reported
+//! as use-after-free instead of silently hitting a new allocation.
+//!
+//! A report prints the kind of bug, the access and the allocation and free backtraces over serial
+//! and then panics, which fails the running `#[test_case]`.
+//!
+//! ## Building
+//!
+//! The instrumentation needs a few compiler flags, and the backtraces need frame pointers. The
+//! `kasan-test` alias in `.cargo/config.toml` passes them together with the feature:
+//!
+//! ```shell
+//! cargo kasan-test
+//! ```
+//!
+//! `-asan-instrumentation-with-call-threshold=0` makes the compiler call the functions below
+//! instead of reading the shadow memory inline. So accesses to memory without shadow (e.g. the VGA
+//! buffer) are fine, and the shadow offset is up to us: LLVM's default offset is meant for a kernel
+//! in the higher half and would give non-canonical shadow addresses for our heap. Without the flags,
+//! the allocator still poisons its redzones and freed memory, but nothing checks the accesses.
+//!
+//! ## Limitations
+//!
+//! Only the heap is covered. Stack variables and globals are not instrumented (`-asan-stack=0`,
+//! `-asan-globals=0`): the compiler poisons the redzones of stack variables with inline stores to
+//! the shadow memory, so the shadow of every stack would have to be mapped before the first
+//! instrumented function runs, which is before the kernel has a page table to map it with.
+//! Overflows of kernel stacks are caught by their guard pages instead (see
+//! [`crate::memory::stack`]).
+//!
+//! This whole module is compiled without instrumentation (see `lib.rs`). It assumes a single CPU:
+//! while it runs, the `IN_KASAN` flag disables all checks, so the code it calls into (the heap
+//! allocator, the serial port, the page table functions) doesn't recurse into it. The shadow memory
+//! and the quarantine aren't locked either, so [`crate::smp::init`] doesn't start the other CPUs
+//! with this feature.
+
+use crate::allocator::{HEAP_SIZE, HEAP_START};
+use crate::memory::{
+    paging::{self, MappingSize},
+    protection,
+};
+use crate::serial_println;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+/// The offset of the shadow memory. The shadow of the heap starts at `0x_6888_8000_0000`.
+pub const KASAN_SHADOW_OFFSET: u64 = 0x_6000_0000_0000;
+
+/// The number of bytes described by one shadow byte.
+const GRANULE: u64 = 8;
+
+/// Heap memory that is not part of any allocation.
+const POISON_HEAP_FREE: u8 = 0xfc;
+/// The left redzone of a heap allocation, which holds its [`Header`].
+const POISON_LEFT_REDZONE: u8 = 0xfa;
+/// The right redzone of a heap allocation.
+const POISON_RIGHT_REDZONE: u8 = 0xfb;
+/// A freed heap allocation in quarantine.
+const POISON_FREED: u8 = 0xfd;
+
+/// The minimum size of the left redzone, which must fit the [`Header`].
+const LEFT_REDZONE: usize = 96;
+/// The size of the right redzone.
+const RIGHT_REDZONE: usize = 32;
+/// The number of return addresses recorded per backtrace.
+const TRACE_DEPTH: usize = 4;
+/// The number of freed allocations kept in quarantine.
+const QUARANTINE_SIZE: usize = 128;
+/// Marks a valid [`Header`].
+const HEADER_MAGIC: u64 = 0x4b41_5341_4e48_4452;
+/// The maximum distance searched for the header of an allocation in a report.
+const MAX_OBJECT_SEARCH: u64 = 64 * 1024;
+
+/// Whether shadow memory is set up and accesses are checked.
+static mut ACTIVE: bool = false;
+/// Set while this module runs, to disable checks for the code it calls.
+static mut IN_KASAN: bool = false;
+
+/* REGION_START: SHADOW MEMORY */
+
+/// Returns the shadow byte of `addr`.
+fn shadow(addr: u64) -> *mut u8 {
+    ((addr >> 3) + KASAN_SHADOW_OFFSET) as *mut u8
+}
+
+/// Whether the shadow memory of `addr` is mapped.
+fn is_tracked(addr: u64) -> bool {
+    (HEAP_START..HEAP_START + HEAP_SIZE).contains(&addr)
+}
+
+/// Sets the shadow of `start..start + size` (both granule aligned) to `value`.
+fn poison(start: u64, size: u64, value: u8) {
+    unsafe { core::ptr::write_bytes(shadow(start), value, (size / GRANULE) as usize) };
+}
+
+/// Marks the `size` bytes at `start` (granule aligned) as accessible.
+fn unpoison(start: u64, size: u64) {
+    poison(start, size / GRANULE * GRANULE, 0);
+    if !size.is_multiple_of(GRANULE) {
+        unsafe { *shadow(start + size / GRANULE * GRANULE) = (size % GRANULE) as u8 };
+    }
+}
+
+/// Returns the shadow byte of the first poisoned byte in `addr..addr + size`, if any.
+fn first_poisoned(addr: u64, size: u64) -> Option<(u64, u8)> {
+    (addr..addr + size).find_map(|byte| {
+        let value = unsafe { *shadow(byte) };
+        let poisoned = value != 0 && (value >= 0x80 || byte % GRANULE >= value as u64);
+        if poisoned {
+            Some((byte, value))
+        } else {
+            None
+        }
+    })
+}
+
+/// Whether any byte in `addr..addr + size` is poisoned. Memory without shadow is never poisoned.
+pub fn is_poisoned(addr: *const u8, size: usize) -> bool {
+    let addr = addr as u64;
+    let active = unsafe { *addr_of!(ACTIVE) };
+    active && is_tracked(addr) && first_poisoned(addr, size as u64).is_some()
+}
+
+/// Maps the shadow memory of the heap and poisons it. Called by [`crate::allocator::init_heap`].
+pub fn init() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protection::no_execute();
+    let start = VirtAddr::from_ptr(shadow(HEAP_START));
+    paging::map_anonymous(start, HEAP_SIZE / GRANULE, MappingSize::Page4KiB, flags)
+        .expect("failed to map the KASAN shadow memory");
+    poison(HEAP_START, HEAP_SIZE, POISON_HEAP_FREE);
+    unsafe { *addr_of_mut!(ACTIVE) = true };
+}
+
+/* REGION_END: SHADOW MEMORY */
+
+/* REGION_START: CHECKS */
+
+/// Checks an access of `size` bytes at `addr`. Called by the compiler instrumentation.
+fn check(addr: usize, size: usize, write: bool) {
+    unsafe {
+        if !*addr_of!(ACTIVE) || *addr_of!(IN_KASAN) {
+            return;
+        }
+        *addr_of_mut!(IN_KASAN) = true;
+    }
+
+    let addr = addr as u64;
+    if is_tracked(addr) {
+        if let Some((bad, value)) = first_poisoned(addr, size as u64) {
+            let access = if write { "Write" } else { "Read" };
+            let access = format_args!("{} of size {} at addr {:#x}", access, size, addr);
+            // Skip `report`, `check` and the `__asan_*` callback.
+            report(bug_kind(value), bad, access, 3);
+        }
+    }
+
+    unsafe { *addr_of_mut!(IN_KASAN) = false };
+}
+
+/// Runs `f` with checks disabled, e.g. while the heap allocator touches poisoned memory.
+fn without_checks<R>(f: impl FnOnce() -> R) -> R {
+    unsafe {
+        let previous = *addr_of!(IN_KASAN);
+        *addr_of_mut!(IN_KASAN) = true;
+        let result = f();
+        *addr_of_mut!(IN_KASAN) = previous;
+        result
+    }
+}
+
+macro_rules! asan_callbacks {
+    ($($load:ident, $store:ident => $size:expr;)*) => {
+        $(
+            #[no_mangle]
+            pub extern "C" fn $load(addr: usize) {
+                check(addr, $size, false);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn $store(addr: usize) {
+                check(addr, $size, true);
+            }
+        )*
+    };
+}
+
+asan_callbacks! {
+    __asan_load1_noabort, __asan_store1_noabort => 1;
+    __asan_load2_noabort, __asan_store2_noabort => 2;
+    __asan_load4_noabort, __asan_store4_noabort => 4;
+    __asan_load8_noabort, __asan_store8_noabort => 8;
+    __asan_load16_noabort, __asan_store16_noabort => 16;
+}
+
+#[no_mangle]
+pub extern "C" fn __asan_loadN_noabort(addr: usize, size: usize) {
+    check(addr, size, false);
+}
+
+#[no_mangle]
+pub extern "C" fn __asan_storeN_noabort(addr: usize, size: usize) {
+    check(addr, size, true);
+}
+
+/// Called before functions that never return. Only relevant for stack instrumentation.
+#[no_mangle]
+pub extern "C" fn __asan_handle_no_return() {}
+
+/* REGION_END: CHECKS */
+
+/* REGION_START: REPORTS */
+
+/// Collects return addresses by walking the frame pointer chain, skipping the innermost `skip`
+/// frames (those of this module).
+fn backtrace(skip: usize) -> [u64; TRACE_DEPTH] {
+    let mut trace = [0; TRACE_DEPTH];
+    let mut rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+
+    let start = rbp;
+    let mut frame = 0;
+    while frame < TRACE_DEPTH + skip {
+        // Stop at the end of the chain or at a frame that doesn't look like one.
+        if rbp == 0 || !rbp.is_multiple_of(8) || rbp < start || rbp - start > 1024 * 1024 {
+            break;
+        }
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if frame >= skip {
+            trace[frame - skip] = ret;
+        }
+        rbp = next;
+        frame += 1;
+    }
+
+    trace
+}
+
+fn print_trace(trace: &[u64; TRACE_DEPTH]) {
+    for (i, addr) in trace.iter().take_while(|&&addr| addr != 0).enumerate() {
+        serial_println!("  #{} {:#x}", i, addr);
+    }
+}
+
+/// Describes a poisoned shadow byte.
+fn bug_kind(value: u8) -> &'static str {
+    match value {
+        POISON_FREED => "use-after-free",
+        POISON_LEFT_REDZONE | POISON_RIGHT_REDZONE => "heap-out-of-bounds",
+        POISON_HEAP_FREE => "wild-heap-access",
+        _ => "heap-out-of-bounds",
+    }
+}
+
+/// Finds the header of the heap allocation `bad` belongs to, or is closest to.
+fn find_object(bad: u64) -> Option<&'static Header> {
+    let value = |addr: u64| unsafe { *shadow(addr) };
+    let in_heap = |addr: u64| (HEAP_START..HEAP_START + HEAP_SIZE).contains(&addr);
+    let granule = bad & !(GRANULE - 1);
+    let near = |addr: u64| in_heap(addr) && addr.abs_diff(granule) < MAX_OBJECT_SEARCH;
+
+    // In a left redzone, the allocation is the one after it. Otherwise, it is the one after the
+    // closest left redzone below.
+    let mut start = granule;
+    if value(start) == POISON_LEFT_REDZONE {
+        while near(start) && value(start) == POISON_LEFT_REDZONE {
+            start += GRANULE;
+        }
+    } else {
+        while near(start - GRANULE) && value(start - GRANULE) != POISON_LEFT_REDZONE {
+            start -= GRANULE;
+        }
+    }
+
+    if !near(start) || !near(start - GRANULE) || value(start - GRANULE) != POISON_LEFT_REDZONE {
+        return None;
+    }
+    let header = unsafe { &*((start - size_of::<Header>() as u64) as *const Header) };
+    if header.magic == HEADER_MAGIC {
+        Some(header)
+    } else {
+        None
+    }
+}
+
+/// Prints a report about the bad address `bad` over serial and panics. `skip` is the number of
+/// frames of this module on the stack, which are left out of the backtrace.
+fn report(kind: &str, bad: u64, access: fmt::Arguments, skip: usize) -> ! {
+    // The bad access may have happened while printing.
+    crate::sync::start_panic();
+    serial_println!("\n==================================================================");
+    serial_println!("BUG: KASAN: {} at {:#x}", kind, bad);
+    serial_println!("{} by:", access);
+    print_trace(&backtrace(skip));
+
+    if let Some(header) = find_object(bad) {
+        let start = header as *const Header as u64 + size_of::<Header>() as u64;
+        serial_println!(
+            "The buggy address belongs to the object of size {} at {:#x}",
+            header.size,
+            start
+        );
+        serial_println!("Allocated by:");
+        print_trace(&header.alloc_trace);
+        if header.free_trace[0] != 0 {
+            serial_println!("Freed by:");
+            print_trace(&header.free_trace);
+        }
+    }
+    serial_println!("==================================================================");
+
+    // Checks stay disabled, the panic handler must be able to run.
+    panic!("KASAN: {} at {:#x}", kind, bad);
+}
+
+/* REGION_END: REPORTS */
+
+/* REGION_START: ALLOCATOR */
+
+/// Stored at the end of the left redzone of every allocation, directly before the returned pointer.
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    alloc_trace: [u64; TRACE_DEPTH],
+    free_trace: [u64; TRACE_DEPTH],
+}
+
+/// A freed allocation waiting in quarantine.
+#[derive(Clone, Copy)]
+struct Quarantined {
+    /// The start of the inner block, including the left redzone.
+    block: *mut u8,
+    /// The layout of the inner block.
+    layout: Layout,
+    /// The header of the allocation.
+    header: *mut Header,
+}
+
+/// The quarantine, a ring buffer of freed allocations.
+static mut QUARANTINE: [Option<Quarantined>; QUARANTINE_SIZE] = [None; QUARANTINE_SIZE];
+static mut QUARANTINE_NEXT: usize = 0;
+
+/// A [`GlobalAlloc`] adding redzones, poisoning and a quarantine to an inner allocator.
+pub struct KasanAllocator<A: 'static> {
+    inner: &'static A,
+}
+
+impl<A: GlobalAlloc> KasanAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        KasanAllocator { inner }
+    }
+
+    /// The size of the left redzone and the layout of the inner block for `layout`.
+    fn inner_layout(layout: Layout) -> (usize, Layout) {
+        let align = layout.align().max(GRANULE as usize);
+        let left = LEFT_REDZONE.div_ceil(align) * align;
+        let user = layout.size().div_ceil(GRANULE as usize) * GRANULE as usize;
+        let size = left + user + RIGHT_REDZONE;
+        (left, Layout::from_size_align(size, align).unwrap())
+    }
+
+    /// Puts a freed allocation into the quarantine. If the quarantine is full, the oldest
+    /// allocation in it is really freed.
+    unsafe fn quarantine(&self, entry: Quarantined) {
+        let next = *addr_of!(QUARANTINE_NEXT);
+        if let Some(oldest) = (*addr_of_mut!(QUARANTINE))[next].replace(entry) {
+            (*oldest.header).magic = 0;
+            without_checks(|| self.inner.dealloc(oldest.block, oldest.layout));
+            poison(
+                oldest.block as u64,
+                oldest.layout.size() as u64,
+                POISON_HEAP_FREE,
+            );
+        }
+        *addr_of_mut!(QUARANTINE_NEXT) = (next + 1) % QUARANTINE_SIZE;
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for KasanAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (left, inner) = Self::inner_layout(layout);
+        let block = without_checks(|| self.inner.alloc(inner));
+        if block.is_null() {
+            return block;
+        }
+
+        let ptr = block.add(left);
+        let start = ptr as u64;
+        let user_end = start + (layout.size() as u64).div_ceil(GRANULE) * GRANULE;
+        poison(block as u64, left as u64, POISON_LEFT_REDZONE);
+        unpoison(start, layout.size() as u64);
+        poison(
+            user_end,
+            block as u64 + inner.size() as u64 - user_end,
+            POISON_RIGHT_REDZONE,
+        );
+
+        (ptr as *mut Header).sub(1).write(Header {
+            magic: HEADER_MAGIC,
+            size: layout.size(),
+            alloc_trace: backtrace(1),
+            free_trace: [0; TRACE_DEPTH],
+        });
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = (ptr as *mut Header).sub(1);
+        let freed = *shadow(ptr as u64) == POISON_FREED;
+        if freed || (*header).magic != HEADER_MAGIC {
+            *addr_of_mut!(IN_KASAN) = true;
+            let kind = if freed { "double-free" } else { "invalid-free" };
+            report(
+                kind,
+                ptr as u64,
+                format_args!("Free of addr {:#x}", ptr as u64),
+                2,
+            );
+        }
+
+        (*header).free_trace = backtrace(1);
+        let (left, inner) = Self::inner_layout(layout);
+        let size = (layout.size() as u64).div_ceil(GRANULE) * GRANULE;
+        poison(ptr as u64, size, POISON_FREED);
+
+        self.quarantine(Quarantined {
+            block: ptr.sub(left),
+            layout: inner,
+            header,
+        });
+    }
+}
+
+/* REGION_END: ALLOCATOR */
+
+#[test_case]
+fn test_redzones_are_poisoned() {
+    use alloc::boxed::Box;
+
+    let boxed = Box::new([1u8; 13]);
+    let ptr = boxed.as_ptr();
+    assert!(!is_poisoned(ptr, 13));
+    assert!(is_poisoned(ptr.wrapping_add(13), 1));
+    assert!(is_poisoned(ptr.wrapping_sub(1), 1));
+}
+
+#[test_case]
+fn test_freed_memory_is_quarantined() {
+    use alloc::vec::Vec;
+
+    let vec: Vec<u64> = Vec::with_capacity(4);
+    let ptr = vec.as_ptr() as *const u8;
+    drop(vec);
+    assert!(is_poisoned(ptr, 1));
+    assert_eq!(bug_kind(unsafe { *shadow(ptr as u64) }), "use-after-free");
+}
@@ -0,0 +1,227 @@
+#![no_std] // Don't link the Rust standard library.
+#![cfg_attr(test, no_main)]
+#![feature(custom_test_frameworks)] // The `custom_test_frameworks` feature allows the use of `#[test_case]` and `#![test_runner]`.
+#![feature(abi_x86_interrupt)] // The `x86-interrupt` calling convention for exception handlers.
+#![cfg_attr(feature = "kasan", feature(sanitize))] // `#[sanitize(address = "off")]` for KASAN itself.
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// The kernel is split into a library and the `main.rs` binary. Integration tests in the `tests`
+// directory are separate executables; they link against this library to reuse the test framework
+// and the kernel initialization, without pulling in the `_start` function of `main.rs`.
+
+extern crate alloc;
+
+use bootloader::BootInfo;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::time::Duration;
+
+pub mod acpi;
+pub mod allocator;
+pub mod apic;
+pub mod file;
+pub mod fs;
+pub mod gdt;
+pub mod interrupts;
+#[cfg(feature = "kasan")]
+#[sanitize(address = "off")]
+pub mod kasan;
+pub mod memory;
+pub mod percpu;
+pub mod process;
+pub mod serial;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod thread;
+pub mod time;
+pub mod vga_buffer;
+
+/// Initializes the kernel: memory protection, memory management, the heap, kernel stacks, the GDT,
+/// the IDT, the PICs, the clock and timers, and threads. Then enables interrupts, which starts
+/// preemption and timers, starts the other CPUs and moves the tick to the local APIC timer.
+///
+/// Must be called exactly once, before anything else, with the boot info passed by the bootloader.
+pub fn init(boot_info: &'static BootInfo) {
+    memory::protection::enable_cpu_features();
+    unsafe { memory::init(boot_info) };
+    unsafe { memory::protection::protect_kernel() }.expect("failed to protect the kernel image");
+    allocator::init_heap().expect("heap initialization failed");
+    percpu::init(0);
+    memory::stack::init_boot_stack();
+    gdt::init();
+    syscall::init();
+    interrupts::init_idt();
+    interrupts::init_pics();
+    time::init();
+    thread::init(thread::scheduler::Policy::boot());
+    x86_64::instructions::interrupts::enable();
+    if let Err(err) = smp::init() {
+        serial_println!("smp: only using the bootstrap processor: {:?}", err);
+    }
+    time::tick::init_lapic();
+}
+
+/// How long a single test may run.
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Manually adding print statements for every test we write is cumbersome, so let’s update our
+/// test_runner to print these messages automatically.
+///
+/// The function name now includes the full path to the function, which is useful when test functions
+/// in different modules have the same name.
+pub trait Testable {
+    fn run(&self);
+}
+
+// The trick now is to implement this trait for all types T that implement the Fn() trait:
+impl<T> Testable for T
+where
+    T: Fn(),
+{
+    /// The `run` function prints the function name using `any::type_name` and adds alignment to
+    /// the [ok] messages using the \t character.
+    /// Test function is invoked through self() after printing function name because self implements
+    /// the Fn() trait.
+    /// [ok] is printed after the test function returns to indicate it did not panic.
+    ///
+    /// A test that doesn't return within [`TEST_TIMEOUT`] fails, instead of hanging the test run.
+    fn run(&self) {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        let timeout = time::timer::after(TEST_TIMEOUT, || {
+            panic!("test timed out after {:?}", TEST_TIMEOUT)
+        });
+        self();
+        timeout.cancel();
+        serial_println!("[ok]");
+    }
+}
+
+/// Our runner just prints a short debug message and then calls each test function in the list.
+///
+/// The argument type &[&dyn Fn()] is a slice of trait object references of the Fn() trait. It is basically
+/// a list of references to types that can be called like a function.
+//
+// ARCHIVED: `fn test_runner(tests: &[&dyn Fn()])`
+pub fn test_runner(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len()); // println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run(); // test();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// To exit QEMU with an error message on a panic, the panic handlers of all test executables call
+/// this function.
+//
+// Now QEMU also exits for failed tests and prints a useful error message on the console
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    // Don't switch to another thread while reporting, and print even if the panic interrupted a
+    // print.
+    sync::start_panic();
+    serial_println!("[failed]\n");
+    serial_println!("Error in thread '{}': {}\n", thread::CurrentName, info);
+    exit_qemu(QemuExitCode::Failed);
+
+    // We still need an endless loop after the exit_qemu call because the compiler does not know that
+    // the isa-debug-exit device causes a program exit.
+    hlt_loop();
+}
+
+/// The panic handler of integration tests that expect a panic, e.g. because execution can't
+/// continue after the tested fault. Exits QEMU with success if the panic message starts with
+/// `expected`, and fails like [`test_panic_handler`] otherwise.
+pub fn expect_panic(info: &PanicInfo, expected: &str) -> ! {
+    sync::start_panic();
+    // The message is formatted into a fixed-size buffer, so this works without a heap.
+    let mut message = MessageBuffer {
+        buf: [0; 128],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info.message());
+
+    if message.buf[..message.len].starts_with(expected.as_bytes()) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        hlt_loop();
+    }
+    test_panic_handler(info)
+}
+
+/// The start of a formatted message, truncated to the size of the buffer.
+struct MessageBuffer {
+    buf: [u8; 128],
+    len: usize,
+}
+
+impl fmt::Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = (self.len + s.len()).min(self.buf.len());
+        let n = end - self.len;
+        self.buf[self.len..end].copy_from_slice(&s.as_bytes()[..n]);
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// An endless loop that halts the CPU until the next interrupt arrives, instead of burning CPU time.
+pub fn hlt_loop() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// To specify the exit status, we create a [`QemuExitCode`] enum. The idea is to exit with the success
+/// exit code if all tests succeeded and with the failure exit code otherwise. The enum is marked as
+/// #[repr(u32)] to represent each variant by a u32 integer. We use the exit code 0x10 for success and
+/// 0x11 for failure.
+///
+/// # Usage Example
+///
+/// The actual exit codes don’t matter much, as long as they don’t clash with the default exit codes
+/// of QEMU. For example, using exit code 0 for success is not a good idea because it becomes (0 <<
+/// 1) | 1 = 1 after the transformation, which is the default exit code when QEMU fails to run. So we
+/// could not differentiate a QEMU error from a successful test run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// The function creates a new `Port` at 0xf4, which is the iobase of the isa-debug-exit device. Then
+/// it writes the passed exit code to the port. We use u32 because we specified the iosize of the isa
+/// -debug-exit device as 4 bytes. Both operations are unsafe because writing to an I/O port can generally
+/// result in arbitrary behavior.
+///
+// Note: The problem is that cargo test considers all error codes other than 0 as failure.
+// To work around this, bootimage provides a test-success-exit-code configuration key that maps a specified
+// exit code to the exit code 0:
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        let mut port = Port::new(0xf4);
+        port.write(exit_code as u32);
+    }
+}
+
+// Entry point for `cargo test --lib`.
+#[cfg(test)]
+bootloader::entry_point!(test_kernel_main);
+
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init(boot_info);
+    test_main();
+
+    hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info)
+}
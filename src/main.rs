@@ -1,7 +1,7 @@
 #![no_std] // Don't link the Rust standard library.
 #![no_main]
 #![feature(custom_test_frameworks)] // The `custom_test_frameworks` feature allows the use of `#[test_case]` and `#![test_runner]`.
-#![test_runner(crate::test_runner)]
+#![test_runner(os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
 // Turning off Rust’s safety checks allows you to do [five additional things](https://doc.rust-lang.org/stable/book/ch19-01-unsafe-rust.html#unsafe-superpowers).
@@ -11,28 +11,47 @@
 // - Implement an unsafe trait
 // - Access fields of unions
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-
-mod serial;
-mod vga_buffer;
+use os::println;
+use os::task::{executor::Executor, keyboard, serial, Task};
 
 // static HELLO: &[u8] = b"Hello, world!";
 
-/// - We disable name mangling to ensure that the Rust compiler really outputs a function with the
-///   name `_start`.
-/// - Name of function is _start as this is the default entry point name for most
-///   systems.
+// The `entry_point` macro defines the real lower level `_start` function for us and checks the
+// signature of `kernel_main`, so that the bootloader can pass us the `BootInfo` struct (the memory
+// map and the physical memory offset) through a type-checked Rust function.
+entry_point!(kernel_main);
+
+/// - The bootloader calls `_start` (generated by `entry_point!`), which calls this function.
 /// - The ! return type means that the function is diverging, i.e. not allowed to ever return.
 ///
 /// TODO: create a VGA buffer type that encapsulates all unsafety and ensures that it is impossible to do anything wrong from the outside.
-#[no_mangle] // Prevents mangling the name of this function during compilation.
-pub extern "C" fn _start() -> ! {
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello Wörld{}", "!"); // panic!("Some panic message");
 
+    os::init(boot_info);
+    os::memory::print_frame_stats();
+    os::process::programs::register_user_programs();
+    // Outside of tests, init starts the shell. Tests get the minimal init instead, which leaves the
+    // keyboard to them.
+    #[cfg(test)]
+    let init = None;
+    #[cfg(not(test))]
+    let init = os::process::programs::find("init");
+    os::process::spawn_init(init).expect("failed to start the init process");
+
     #[cfg(test)]
     test_main();
 
-    loop {}
+    os::vga_buffer::blink_cursor();
+
+    // Instead of spinning in an endless loop, the executor halts the CPU until an interrupt wakes
+    // one of the tasks.
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(serial::print_lines()));
+    executor.run();
 
     // use core::fmt::Write;
     // vga_buffer::WRITER.lock().write_str("Hello again").unwrap(); // vga_buffer::print_something();
@@ -56,69 +75,21 @@ pub extern "C" fn _start() -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    os::sync::start_panic();
+    println!("thread '{}' {}", os::thread::CurrentName, info);
 
     loop {}
 }
 
-/// Manually adding print statements for every test we write is cumbersome, so let’s update our
-/// test_runner to print these messages automatically.
-///
-/// The function name now includes the full path to the function, which is useful when test functions
-/// in different modules have the same name.
-pub trait Testable {
-    fn run(&self);
-}
-
-// The trick now is to implement this trait for all types T that implement the Fn() trait:
-impl<T> Testable for T
-where
-    T: Fn(),
-{
-    /// The `run` function prints the function name using `any::type_name` and adds alignment to
-    /// the [ok] messages using the \t character.
-    /// Test function is invoked through self() after printing function name because self implements
-    /// the Fn() trait.
-    /// [ok] is printed after the test function returns to indicate it did not panic.
-    fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
-    }
-}
-
-/// To exit QEMU with an error message on a panic, we can use conditional compilation to use a different
-/// panic handler in testing mode
-//
-// Now QEMU also exits for failed tests and prints a useful error message on the console
+/// To exit QEMU with an error message on a panic, we use a different panic handler in testing mode.
+/// The shared implementation lives in the library, see [`os::test_panic_handler`].
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
-
-    // We still need an endless loop after the exit_qemu call because the compiler does not know that
-    // the isa-debug-exit device causes a program exit.
-    loop {}
+    os::test_panic_handler(info)
 }
 
-/// Our runner just prints a short debug message and then calls each test function in the list.
-///
-/// The argument type &[&dyn Fn()] is a slice of trait object references of the Fn() trait. It is basically
-/// a list of references to types that can be called like a function. Since the function is useless
-/// for non-test runs, we use the #[cfg(test)] attribute to include it only for tests.
-//
-// ARCHIVED: `fn test_runner(tests: &[&dyn Fn()])`
-#[cfg(test)]
-fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len()); // println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run(); // test();
-    }
-    exit_qemu(QemuExitCode::Success);
-}
-// [`Testable`] allows us to automatically print tests so we can now remove the print statements from
+// [`os::Testable`] allows us to automatically print tests so we can now remove the print statements from
 // our trivial_assertion test since they’re now printed:
 // /* serial_print!("trivial assertion... "); // print!("trivial assertion... "); */
 // ...
@@ -128,41 +99,6 @@ fn trivial_assertion() {
     assert_eq!(1, 2);
 }
 
-/// To specify the exit status, we create a [`QemuExitCode`] enum. The idea is to exit with the success
-/// exit code if all tests succeeded and with the failure exit code otherwise. The enum is marked as
-/// #[repr(u32)] to represent each variant by a u32 integer. We use the exit code 0x10 for success and
-/// 0x11 for failure.
-///
-/// # Usage Example
-///
-/// The actual exit codes don’t matter much, as long as they don’t clash with the default exit codes
-/// of QEMU. For example, using exit code 0 for success is not a good idea because it becomes (0 <<
-/// 1) | 1 = 1 after the transformation, which is the default exit code when QEMU fails to run. So we
-/// could not differentiate a QEMU error from a successful test run.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
-pub enum QemuExitCode {
-    Success = 0x10,
-    Failed = 0x11,
-}
-
-/// The function creates a new `Port` at 0xf4, which is the iobase of the isa-debug-exit device. Then
-/// it writes the passed exit code to the port. We use u32 because we specified the iosize of the isa
-/// -debug-exit device as 4 bytes. Both operations are unsafe because writing to an I/O port can generally
-/// result in arbitrary behavior.
-///
-// Note: The problem is that cargo test considers all error codes other than 0 as failure.
-// To work around this, bootimage provides a test-success-exit-code configuration key that maps a specified
-// exit code to the exit code 0:
-pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
-
-    unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
-    }
-}
-
 ///////////////////////////////////////////////////
 
 // In a typical Rust binary that links the standard library, execution starts in a C runtime
@@ -0,0 +1,310 @@
+//! # memory
+//!
+//! Physical memory management.
+//!
+//! The bootloader passes us a memory map (through the [`BootInfo`] struct) that tells which
+//! physical memory regions are usable and which are used by the kernel, the page tables, the
+//! bootloader or reserved by the firmware. With the `map_physical_memory` feature of the
+//! `bootloader` crate, the complete physical memory is also mapped to virtual memory starting at
+//! `boot_info.physical_memory_offset`, so the kernel can access any physical address.
+//!
+//! The bootloader's mapping uses small pages, so once the page table is accessible, we map the
+//! RAM regions of the memory map a second time with the largest available page size and switch to
+//! that mapping (see [`paging`]). Reserved regions and holes are left out, since they can hold
+//! devices that must not be cached; devices like the local APIC are mapped uncached on their own,
+//! and firmware data like the ACPI tables is mapped with [`map_firmware`] when needed.
+//!
+//! All usable regions are handed to a [`BuddyAllocator`], which is available through the global
+//! [`FRAME_ALLOCATOR`] instance. The active page table is available through the global [`MAPPER`]
+//! instance, an `OffsetPageTable` that accesses the page table frames through the physical memory
+//! mapping.
+//!
+//! All data mappings are non-executable. The kernel image itself is remapped with W^X
+//! permissions by [`protection::protect_kernel`].
+//!
+//! User processes get their own page tables, which share the kernel's mappings (see
+//! [`address_space`]), and their frames copy-on-write with each other (see [`refcount`]). Which
+//! user memory a process has mapped is kept as [`vma`] areas. The kernel accesses their memory
+//! only through [`user`].
+//!
+//! Lock order: [`MAPPER`] is always locked before [`FRAME_ALLOCATOR`].
+
+pub mod address_space;
+pub mod buddy;
+pub mod paging;
+pub mod protection;
+pub mod refcount;
+pub mod stack;
+pub mod user;
+pub mod vma;
+
+use self::{
+    buddy::{BuddyAllocator, BuddyStats, Zone, FRAME_SIZE},
+    paging::PagingError,
+};
+use crate::{serial_print, serial_println, sync::SpinMutex};
+use bootloader::bootinfo::{BootInfo, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// The global physical frame allocator. It is `None` until [`init`] was called.
+pub static FRAME_ALLOCATOR: SpinMutex<Option<BuddyAllocator>> =
+    SpinMutex::named("FRAME_ALLOCATOR", None);
+
+/// The active page table. It is `None` until [`init`] was called.
+pub static MAPPER: SpinMutex<Option<OffsetPageTable<'static>>> = SpinMutex::named("MAPPER", None);
+
+/// The virtual address at which the bootloader mapped the complete physical memory.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The physical address of the kernel's level 4 table, the one [`MAPPER`] edits.
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// Initializes the global [`FRAME_ALLOCATOR`] with all usable regions of the memory map.
+///
+/// The metadata of the buddy allocator (one byte per frame) is carved out of the first usable
+/// region that is large enough.
+///
+/// # Safety
+///
+/// Must be called only once, with the boot info passed by the bootloader.
+pub unsafe fn init(boot_info: &'static BootInfo) {
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYSICAL_MEMORY_OFFSET.store(offset.as_u64(), Ordering::Relaxed);
+    let (level_4_table_frame, _) = Cr3::read();
+    KERNEL_PAGE_TABLE.store(
+        level_4_table_frame.start_address().as_u64(),
+        Ordering::Relaxed,
+    );
+
+    let usable = || {
+        boot_info
+            .memory_map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+    };
+
+    let frame_count = usable()
+        .map(|r| r.range.end_frame_number)
+        .max()
+        .unwrap_or(0);
+    let meta_size = frame_count.div_ceil(FRAME_SIZE) * FRAME_SIZE;
+    let meta_start = usable()
+        .find(|r| r.range.end_addr() - r.range.start_addr() >= meta_size)
+        .expect("no usable region large enough for the frame allocator metadata")
+        .range
+        .start_addr();
+
+    let meta_ptr = (offset + meta_start).as_mut_ptr::<u8>();
+    let meta = core::slice::from_raw_parts_mut(meta_ptr, frame_count as usize);
+    let mut allocator = BuddyAllocator::new(offset, meta);
+
+    for region in usable() {
+        let mut start = region.range.start_addr();
+        if start == meta_start {
+            start += meta_size;
+        }
+        allocator.add_region(PhysAddr::new(start), PhysAddr::new(region.range.end_addr()));
+    }
+
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+    *MAPPER.lock() = Some(OffsetPageTable::new(active_level_4_table(offset), offset));
+
+    remap_physical_memory(boot_info);
+}
+
+/// Whether the physical memory map covers regions of this type. Reserved regions and the holes
+/// between regions can hold memory-mapped devices (like the local APIC) that must not be
+/// accessed through a cached mapping, so they are left out. Firmware data there is mapped on
+/// demand with [`map_firmware`].
+fn is_ram(region_type: MemoryRegionType) -> bool {
+    !matches!(
+        region_type,
+        MemoryRegionType::Reserved | MemoryRegionType::BadMemory | MemoryRegionType::Empty
+    )
+}
+
+/// Maps the RAM regions of the memory map again with huge pages and switches to the new mapping.
+///
+/// The new mapping is placed in the first unused level 4 entry of the higher half, which covers
+/// 512 GiB. Adjacent regions are mapped together, so the huge pages can span region boundaries.
+///
+/// # Safety
+///
+/// Must be called only once, from [`init`].
+unsafe fn remap_physical_memory(boot_info: &'static BootInfo) {
+    let index = {
+        let mut mapper = MAPPER.lock();
+        let level_4_table = mapper.as_mut().unwrap().level_4_table();
+        (256..512)
+            .find(|&i| level_4_table[i].is_unused())
+            .expect("no unused level 4 entry for the physical memory map")
+    };
+    let offset = VirtAddr::new_truncate((index as u64) << 39);
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protection::no_execute();
+    let map = |start: u64, end: u64| {
+        assert!(end <= 1 << 39, "physical memory larger than 512 GiB");
+        paging::map_physical(offset + start, PhysAddr::new(start), end - start, flags)
+            .expect("failed to map the physical memory");
+    };
+
+    let mut run: Option<(u64, u64)> = None;
+    let regions = boot_info
+        .memory_map
+        .iter()
+        .filter(|region| is_ram(region.region_type));
+    for region in regions {
+        let (start, end) = (region.range.start_addr(), region.range.end_addr());
+        run = match run {
+            Some((run_start, run_end)) if run_end == start => Some((run_start, end)),
+            Some((run_start, run_end)) => {
+                map(run_start, run_end);
+                Some((start, end))
+            }
+            None => Some((start, end)),
+        };
+    }
+    if let Some((start, end)) = run {
+        map(start, end);
+    }
+
+    PHYSICAL_MEMORY_OFFSET.store(offset.as_u64(), Ordering::Relaxed);
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        allocator.set_physical_memory_offset(offset);
+    }
+    *MAPPER.lock() = Some(OffsetPageTable::new(active_level_4_table(offset), offset));
+}
+
+/// Makes the firmware data at `phys..phys + size` readable through the physical memory map.
+///
+/// This is for data outside of the RAM regions, like the ACPI tables and the BIOS areas. The
+/// pages that are not mapped yet are mapped read-only.
+///
+/// # Safety
+///
+/// The range must not contain memory-mapped devices.
+pub unsafe fn map_firmware(phys: PhysAddr, size: u64) -> Result<(), PagingError> {
+    let start = phys.align_down(FRAME_SIZE);
+    let end = (phys + size).align_up(FRAME_SIZE);
+    let flags = PageTableFlags::PRESENT | protection::no_execute();
+    for frame in (start.as_u64()..end.as_u64()).step_by(FRAME_SIZE as usize) {
+        let frame = PhysAddr::new(frame);
+        let virt = phys_to_virt(frame);
+        if paging::mapping_size(virt).is_none() {
+            match paging::map_physical(virt, frame, FRAME_SIZE, flags) {
+                Ok(()) | Err(PagingError::PageAlreadyMapped) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns a mutable reference to the active level 4 table.
+///
+/// The `CR3` register holds the physical address of the level 4 table, which we can access through
+/// the physical memory mapping.
+///
+/// # Safety
+///
+/// The page tables must be mapped at `physical_memory_offset`. No other reference to the level 4
+/// table may exist, to avoid aliasing `&mut` references.
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let virt = physical_memory_offset + level_4_table_frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr()
+}
+
+/// Maps `page` to a newly allocated frame with the given flags.
+pub fn map_page(page: Page, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    let mut mapper = MAPPER.lock();
+    let mut frames = FRAME_ALLOCATOR.lock();
+    let (mapper, frames) = match (mapper.as_mut(), frames.as_mut()) {
+        (Some(mapper), Some(frames)) => (mapper, frames),
+        _ => return Err(MapToError::FrameAllocationFailed),
+    };
+
+    let frame = frames
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    unsafe { mapper.map_to(page, frame, flags, frames)?.flush() };
+
+    Ok(())
+}
+
+/// Converts a physical address to the virtual address it is mapped at.
+///
+/// Only RAM is mapped there, other addresses must be mapped with [`map_firmware`] first.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// The frame of the kernel's level 4 table, which is active whenever no user process runs.
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::Relaxed)))
+}
+
+/// Allocates `2^order` physically contiguous frames below the end of `limit`.
+///
+/// Convenience wrapper around [`BuddyAllocator::allocate`] on the global allocator.
+pub fn allocate_frames(order: usize, limit: Zone) -> Option<PhysAddr> {
+    FRAME_ALLOCATOR.lock().as_mut()?.allocate(order, limit)
+}
+
+/// Frees frames allocated by [`allocate_frames`].
+///
+/// # Safety
+///
+/// The block must have been allocated with the same `order` and must no longer be used.
+pub unsafe fn deallocate_frames(addr: PhysAddr, order: usize) {
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        allocator.deallocate(addr, order);
+    }
+}
+
+/// Returns the current statistics of the global frame allocator.
+pub fn frame_stats() -> Option<BuddyStats> {
+    FRAME_ALLOCATOR.lock().as_ref().map(BuddyAllocator::stats)
+}
+
+/// Prints the free block counts and fragmentation of all zones over serial.
+pub fn print_frame_stats() {
+    if let Some(stats) = frame_stats() {
+        serial_print!("{}", stats);
+        serial_println!("{} frames shared copy-on-write", refcount::shared_frames());
+    } else {
+        serial_println!("frame allocator not initialized");
+    }
+}
+
+#[test_case]
+fn test_allocate_huge_block() {
+    let two_mib = allocate_frames(9, Zone::Normal).expect("no 2 MiB block available");
+    assert!(two_mib.is_aligned(2 * 1024 * 1024u64));
+
+    let low = allocate_frames(0, Zone::Dma).expect("no frame below 1 MiB");
+    assert!(low.as_u64() < Zone::Dma.end());
+
+    unsafe {
+        // The memory is mapped and writable through the physical memory mapping.
+        phys_to_virt(low).as_mut_ptr::<u64>().write_volatile(42);
+        deallocate_frames(low, 0);
+        deallocate_frames(two_mib, 9);
+    }
+}
+
+#[test_case]
+fn test_devices_are_not_in_the_physical_memory_map() {
+    // The default address of the local APIC, which is mapped uncached on its own.
+    let lapic = phys_to_virt(PhysAddr::new(0xfee0_0000));
+    assert_eq!(paging::mapping_size(lapic), None);
+}
@@ -0,0 +1,855 @@
+//! # address_space
+//!
+//! The address spaces of user processes.
+//!
+//! Every process has its own level 4 page table. Its entries in the *user range*
+//! [`USER_START`]`..`[`USER_END`] point to page tables that belong to the process alone. All other
+//! entries are copies of the kernel's level 4 entries (see [`super::kernel_page_table`]), so the
+//! kernel is mapped in every address space, with the very same lower-level tables: a kernel
+//! mapping made while a process runs shows up in all address spaces at once. Only a new level 4
+//! entry of the kernel would be missing, so the kernel entries are copied again whenever an
+//! address space is activated (see [`activate`]).
+//!
+//! The kernel entries lack the `USER_ACCESSIBLE` flag, so user mode can't touch kernel memory,
+//! and with SMEP and SMAP (see [`super::protection`]) the kernel can't accidentally execute or
+//! access user memory either.
+//!
+//! ```text
+//!  0             512 GiB                          64 TiB     0x4444_0000_0000
+//!  | kernel image | user range                    |          | heap, stacks, ... (kernel)
+//! ```
+//!
+//! The kernel is linked at 2 MiB, in the first level 4 entry, so the user range starts at the
+//! second entry instead of 0, and ends well below the kernel's regions for the heap, the stacks
+//! and the local APIC. The kernel must never map anything in the user range itself.
+//!
+//! User memory is mapped with 4 KiB pages only. Dropping an [`AddressSpace`] frees all its frames
+//! and page tables.
+//!
+//! ## Copy-on-write
+//!
+//! Memory is allocated lazily. A new mapping points all its pages to the *zero frame*, a single
+//! frame of zeros shared by everything, and a writable one is mapped read-only with the
+//! [`COPY_ON_WRITE`] flag instead. The first write faults, and [`handle_fault`] gives the page a
+//! zeroed frame of its own and makes it writable, so a large BSS, heap or stack only costs the
+//! pages that are actually written. [`AddressSpace::duplicate`] works the same way for `fork`:
+//! both address spaces share all frames, which are reference counted (see [`super::refcount`]),
+//! and the writable pages become copy-on-write in both. The first write to such a page copies
+//! the frame, unless the other address space dropped its reference already. A private mapping of a
+//! file (see [`AreaKind::File`]) is lazy too, but each page gets a frame of its own with a copy of
+//! the file right away.
+
+use super::{
+    buddy::Zone,
+    phys_to_virt, refcount,
+    vma::{Area, AreaKind, Areas, Protection},
+    FRAME_ALLOCATOR,
+};
+use crate::{memory::paging::PagingError, sync::Lazy};
+use alloc::vec::Vec;
+use core::{ops::Range, slice};
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        mapper::TranslateResult, page_table::PageTableEntry, Mapper, OffsetPageTable, Page,
+        PageTable, PageTableFlags, PageTableIndex, PhysFrame, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// The first address of user memory, the start of the second level 4 entry.
+pub const USER_START: u64 = 1 << 39;
+
+/// The end of user memory (exclusive), after 127 level 4 entries (64 TiB minus 512 GiB).
+pub const USER_END: u64 = 128 << 39;
+
+/// The level 4 entries of the user range.
+const USER_ENTRIES: Range<usize> = 1..128;
+
+/// Where [`AddressSpace::map_anonymous`] places memory, in the middle of the user range, far from
+/// the program and the stack.
+pub const MMAP_START: u64 = 64 << 39;
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Marks a page that is mapped read-only, but may be written after it got a frame of its own.
+/// One of the bits the CPU leaves to the operating system.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// The frame of zeros that new mappings share, see the [module documentation](self).
+static ZERO_FRAME: Lazy<PhysFrame> =
+    Lazy::new(|| allocate_zeroed_frame().expect("no frame for the zero page"));
+
+/// Whether `start..start + len` lies completely in the user range.
+pub fn is_user_range(start: VirtAddr, len: u64) -> bool {
+    start.as_u64() >= USER_START
+        && start
+            .as_u64()
+            .checked_add(len)
+            .is_some_and(|end| end <= USER_END)
+}
+
+/// Where [`AddressSpace::map_anonymous`] maps memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Wherever there is room.
+    Anywhere,
+    /// At this address if there is room, or else anywhere.
+    Hint(VirtAddr),
+    /// At this address, replacing whatever is mapped there.
+    Fixed(VirtAddr),
+}
+
+/// The page tables of a user process, and its memory areas.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_table: PhysFrame,
+    areas: Areas,
+}
+
+impl AddressSpace {
+    /// Creates an address space without any user memory.
+    pub fn new() -> Result<Self, PagingError> {
+        let level_4_table = allocate_zeroed_frame()?;
+        copy_kernel_entries(level_4_table);
+        Ok(AddressSpace {
+            level_4_table,
+            areas: Areas::new(),
+        })
+    }
+
+    /// The frame of the level 4 table, for `CR3`.
+    pub fn page_table(&self) -> PhysFrame {
+        self.level_4_table
+    }
+
+    /// Maps `start..start + size` to zeroed memory, with the given flags and
+    /// `PRESENT | USER_ACCESSIBLE`, as an area of `kind`. The range must be page aligned, in the
+    /// user range and not mapped yet.
+    ///
+    /// The pages only get frames of their own when they are first written to, see
+    /// [`handle_fault`], so untouched memory costs nothing but the page tables.
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+        kind: AreaKind,
+    ) -> Result<(), PagingError> {
+        let range = check_range(start, size)?;
+        if self.areas.overlaps(range.clone()) {
+            return Err(PagingError::PageAlreadyMapped);
+        }
+        self.areas.insert(Area {
+            start: range.start,
+            end: range.end,
+            protection: Protection::from_page_table_flags(flags),
+            kind,
+        });
+        let mut flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags.remove(PageTableFlags::WRITABLE);
+            flags.insert(COPY_ON_WRITE);
+        }
+        let zero = *ZERO_FRAME;
+        for offset in (0..size).step_by(PAGE_SIZE as usize) {
+            self.map_frame(Page::containing_address(start + offset), zero, flags)?;
+        }
+        Ok(())
+    }
+
+    /// Creates a copy of this address space, e.g. for `fork`. Both share all frames, and the
+    /// writable pages become copy-on-write in both, so the copy is cheap: only the page tables
+    /// are duplicated.
+    pub fn duplicate(&mut self) -> Result<Self, PagingError> {
+        let zero = *ZERO_FRAME;
+        let mut copy = AddressSpace::new()?;
+        copy.areas = self.areas.clone();
+        for (page, frame, mut flags) in self.user_pages() {
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(COPY_ON_WRITE);
+                let entry = leaf_entry(self.level_4_table, page.start_address())
+                    .expect("user page vanished");
+                entry.set_flags(flags);
+            }
+            copy.map_frame(page, frame, flags)?;
+            if frame != zero {
+                refcount::share(frame);
+            }
+        }
+        // The pages that just lost `WRITABLE` may still be writable in the TLB.
+        if Cr3::read().0 == self.level_4_table {
+            tlb::flush_all();
+        }
+        Ok(copy)
+    }
+
+    /// Copies `data` to `start` in this address space, through the physical memory mapping, so
+    /// it works for read-only pages and for inactive address spaces too. The written pages get
+    /// frames of their own first, if they share one.
+    pub fn write(&mut self, start: VirtAddr, data: &[u8]) -> Result<(), PagingError> {
+        let zero = *ZERO_FRAME;
+        let active = Cr3::read().0 == self.level_4_table;
+        let mut written = 0;
+        while written < data.len() {
+            let addr = start + written as u64;
+            let entry = leaf_entry(self.level_4_table, addr).ok_or(PagingError::PageNotMapped)?;
+            if unshare(entry, zero)? && active {
+                tlb::flush(addr);
+            }
+            let phys = entry.addr() + u64::from(addr.page_offset());
+            let len =
+                (PAGE_SIZE - u64::from(addr.page_offset())).min((data.len() - written) as u64);
+            unsafe {
+                phys_to_virt(phys)
+                    .as_mut_ptr::<u8>()
+                    .copy_from_nonoverlapping(data[written..].as_ptr(), len as usize);
+            }
+            written += len as usize;
+        }
+        Ok(())
+    }
+
+    /// Maps `size` bytes of anonymous memory with `protection`, as `placement` says, and returns
+    /// where. Unlike [`AddressSpace::map`], it only adds an area: its pages are mapped when they
+    /// are first touched, see [`AddressSpace::fault_in`].
+    pub fn map_anonymous(
+        &mut self,
+        placement: Placement,
+        size: u64,
+        protection: Protection,
+    ) -> Result<VirtAddr, PagingError> {
+        self.map_lazily(placement, size, protection, AreaKind::Anonymous)
+    }
+
+    /// Like [`AddressSpace::map_anonymous`], but for an area of any `kind`, e.g. a file mapping.
+    pub fn map_lazily(
+        &mut self,
+        placement: Placement,
+        size: u64,
+        protection: Protection,
+        kind: AreaKind,
+    ) -> Result<VirtAddr, PagingError> {
+        let start = match placement {
+            Placement::Fixed(start) => {
+                self.unmap(start, size)?;
+                start
+            }
+            Placement::Hint(start)
+                if check_range(start, size).is_ok_and(|range| !self.areas.overlaps(range)) =>
+            {
+                start
+            }
+            Placement::Anywhere | Placement::Hint(_) => {
+                if size == 0 || !size.is_multiple_of(PAGE_SIZE) {
+                    return Err(PagingError::NotAligned);
+                }
+                let start = self
+                    .areas
+                    .find_free(size, MMAP_START..USER_END)
+                    .ok_or(PagingError::InvalidRange)?;
+                VirtAddr::new(start)
+            }
+        };
+        let range = check_range(start, size)?;
+        self.areas.insert(Area {
+            start: range.start,
+            end: range.end,
+            protection,
+            kind,
+        });
+        Ok(start)
+    }
+
+    /// Unmaps `start..start + size`, which must be page aligned, and frees the frames no one else
+    /// shares. Parts of the range that are not mapped are skipped.
+    pub fn unmap(&mut self, start: VirtAddr, size: u64) -> Result<(), PagingError> {
+        let range = check_range(start, size)?;
+        let zero = *ZERO_FRAME;
+        let active = Cr3::read().0 == self.level_4_table;
+        let mut unused = Vec::new();
+        for area in self.areas.remove(range) {
+            for addr in (area.start..area.end).step_by(PAGE_SIZE as usize) {
+                let addr = VirtAddr::new(addr);
+                let entry = match leaf_entry(self.level_4_table, addr) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let frame = entry.frame().ok();
+                entry.set_unused();
+                if active {
+                    tlb::flush(addr);
+                }
+                if let Some(frame) = frame.filter(|&frame| frame != zero) {
+                    if refcount::release(frame) {
+                        unused.push(frame.start_address());
+                    }
+                }
+            }
+        }
+        if let Some(frames) = FRAME_ALLOCATOR.lock().as_mut() {
+            for frame in unused {
+                unsafe { frames.deallocate(frame, 0) };
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the protection of `start..start + size`, which must be page aligned and mapped
+    /// completely, splitting and merging areas as needed.
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        protection: Protection,
+    ) -> Result<(), PagingError> {
+        let range = check_range(start, size)?;
+        if !self.areas.protect(range.clone(), protection) {
+            return Err(PagingError::PageNotMapped);
+        }
+        let zero = *ZERO_FRAME;
+        let active = Cr3::read().0 == self.level_4_table;
+        for addr in range.step_by(PAGE_SIZE as usize) {
+            let addr = VirtAddr::new(addr);
+            if let Some(entry) = leaf_entry(self.level_4_table, addr) {
+                let frame = entry.frame().expect("user pages are present");
+                let shared = frame == zero || refcount::count(frame) > 1;
+                entry.set_flags(protection.page_table_flags(shared));
+                if active {
+                    tlb::flush(addr);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps the page at `addr` when it is first touched, if an area allows the access, a write
+    /// if `write` is set or else a read: the zero frame for a read, a zeroed frame for a write, and
+    /// a copy of the file for a file mapping. Returns whether the access can be retried.
+    pub fn fault_in(&mut self, addr: VirtAddr, write: bool) -> bool {
+        let access = if write {
+            Protection::WRITE
+        } else {
+            Protection::READ
+        };
+        let area = match self.areas.find(addr.as_u64()) {
+            Some(area) if area.protection.contains(access) => area.clone(),
+            _ => return false,
+        };
+        if leaf_entry(self.level_4_table, addr).is_some() {
+            return false;
+        }
+        let page = Page::containing_address(addr);
+        let (frame, shared) = match area.kind {
+            AreaKind::File { inode, offset } => match allocate_zeroed_frame() {
+                Ok(frame) => {
+                    // Past the end of the file, the rest of the page stays zeroed.
+                    let offset = offset + (page.start_address().as_u64() - area.start);
+                    let bytes = unsafe {
+                        slice::from_raw_parts_mut(
+                            phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                            PAGE_SIZE as usize,
+                        )
+                    };
+                    crate::fs::read_at(inode, offset, bytes);
+                    (frame, false)
+                }
+                Err(_) => return false,
+            },
+            _ if write => match allocate_zeroed_frame() {
+                Ok(frame) => (frame, false),
+                Err(_) => return false,
+            },
+            _ => (*ZERO_FRAME, true),
+        };
+        match self.map_frame(page, frame, area.protection.page_table_flags(shared)) {
+            Ok(()) => true,
+            Err(_) => {
+                if !shared {
+                    unsafe { super::deallocate_frames(frame.start_address(), 0) };
+                }
+                false
+            }
+        }
+    }
+
+    /// The memory areas.
+    pub fn areas(&self) -> &Areas {
+        &self.areas
+    }
+
+    /// The physical address and the flags `addr` is mapped to, if it is mapped.
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        translate(self.level_4_table, addr)
+    }
+
+    /// Maps `page` to `frame`. The intermediate tables are always writable, so that a
+    /// copy-on-write page can become writable later.
+    fn map_frame(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let mut frames = FRAME_ALLOCATOR.lock();
+        let frames = frames.as_mut().ok_or(PagingError::FrameAllocationFailed)?;
+        let flush = unsafe {
+            self.mapper()
+                .map_to_with_table_flags(page, frame, flags, table_flags, frames)?
+        };
+        // Not active, or only a fresh mapping, so there is nothing to flush.
+        flush.ignore();
+        Ok(())
+    }
+
+    /// The mapped user pages, with their frames and flags.
+    fn user_pages(&self) -> Vec<(Page, PhysFrame, PageTableFlags)> {
+        let mut pages = Vec::new();
+        let level_4 = unsafe { table(self.level_4_table) };
+        for index_4 in USER_ENTRIES {
+            let level_3 = match level_4[index_4].frame() {
+                Ok(frame) => unsafe { table(frame) },
+                Err(_) => continue,
+            };
+            for (index_3, entry) in level_3.iter().enumerate() {
+                let level_2 = match entry.frame() {
+                    Ok(frame) => unsafe { table(frame) },
+                    Err(_) => continue,
+                };
+                for (index_2, entry) in level_2.iter().enumerate() {
+                    let level_1 = match entry.frame() {
+                        Ok(frame) => unsafe { table(frame) },
+                        Err(_) => continue,
+                    };
+                    for (index_1, entry) in level_1.iter().enumerate() {
+                        if let Ok(frame) = entry.frame() {
+                            let page = Page::from_page_table_indices(
+                                PageTableIndex::new(index_4 as u16),
+                                PageTableIndex::new(index_3 as u16),
+                                PageTableIndex::new(index_2 as u16),
+                                PageTableIndex::new(index_1 as u16),
+                            );
+                            pages.push((page, frame, entry.flags()));
+                        }
+                    }
+                }
+            }
+        }
+        pages
+    }
+
+    /// A mapper for the level 4 table of this address space.
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(table(self.level_4_table), phys_to_virt(PhysAddr::zero())) }
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees the user memory, the page tables and the level 4 table. The address space must not
+    /// be active anymore. Shared frames are only freed with their last reference.
+    fn drop(&mut self) {
+        assert!(
+            Cr3::read().0 != self.level_4_table,
+            "dropping the active address space"
+        );
+        let zero = *ZERO_FRAME;
+        let mut unused = Vec::new();
+        let level_4 = unsafe { table(self.level_4_table) };
+        for index in USER_ENTRIES {
+            let entry = &level_4[index];
+            if entry.is_unused() {
+                continue;
+            }
+            let level_3 = unsafe { table(entry.frame().unwrap()) };
+            for entry in level_3.iter().filter(|entry| !entry.is_unused()) {
+                let level_2 = unsafe { table(entry.frame().unwrap()) };
+                for entry in level_2.iter().filter(|entry| !entry.is_unused()) {
+                    let level_1 = unsafe { table(entry.frame().unwrap()) };
+                    for entry in level_1.iter().filter(|entry| !entry.is_unused()) {
+                        let frame = entry.frame().unwrap();
+                        if frame != zero && refcount::release(frame) {
+                            unused.push(frame.start_address());
+                        }
+                    }
+                    unused.push(entry.addr());
+                }
+                unused.push(entry.addr());
+            }
+            unused.push(entry.addr());
+        }
+        unused.push(self.level_4_table.start_address());
+
+        // Not locked while counting, which allocates.
+        if let Some(frames) = FRAME_ALLOCATOR.lock().as_mut() {
+            for frame in unused {
+                unsafe { frames.deallocate(frame, 0) };
+            }
+        }
+    }
+}
+
+/// Resolves a page fault at `addr` in the active address space, if it was a write to a
+/// copy-on-write page: the page gets a frame of its own, a copy of the shared one or a zeroed
+/// one instead of the zero frame, and becomes writable. Returns whether the access can be
+/// retried.
+///
+/// Called by the page fault handler with interrupts enabled, since it takes the paging locks.
+pub fn handle_fault(addr: VirtAddr, write: bool) -> bool {
+    let page_table = Cr3::read().0;
+    if !write || !is_user_range(addr, 1) || page_table == super::kernel_page_table() {
+        return false;
+    }
+    let zero = *ZERO_FRAME;
+    let entry = match leaf_entry(page_table, addr) {
+        Some(entry)
+            if entry
+                .flags()
+                .contains(PageTableFlags::PRESENT | COPY_ON_WRITE) =>
+        {
+            entry
+        }
+        _ => return false,
+    };
+    match unshare(entry, zero) {
+        Ok(_) => {
+            tlb::flush(addr);
+            true
+        }
+        // Out of memory: the access fails like any other.
+        Err(_) => false,
+    }
+}
+
+/// Gives the page of the level 1 `entry` a frame of its own, if it shares one, and makes a
+/// copy-on-write page writable. Returns whether the entry changed.
+fn unshare(entry: &mut PageTableEntry, zero: PhysFrame) -> Result<bool, PagingError> {
+    let frame = entry.frame().map_err(|_| PagingError::PageNotMapped)?;
+    let mut flags = entry.flags();
+    let own = if frame == zero {
+        allocate_zeroed_frame()?
+    } else if refcount::count(frame) > 1 {
+        let copy = allocate_frame()?;
+        unsafe {
+            phys_to_virt(copy.start_address())
+                .as_mut_ptr::<u8>()
+                .copy_from_nonoverlapping(
+                    phys_to_virt(frame.start_address()).as_ptr(),
+                    PAGE_SIZE as usize,
+                )
+        };
+        // The others may have dropped their references since.
+        if refcount::release(frame) {
+            unsafe { super::deallocate_frames(frame.start_address(), 0) };
+        }
+        copy
+    } else {
+        frame
+    };
+    if flags.contains(COPY_ON_WRITE) {
+        flags.remove(COPY_ON_WRITE);
+        flags.insert(PageTableFlags::WRITABLE);
+    }
+    let changed = own != frame || flags != entry.flags();
+    entry.set_frame(own, flags);
+    Ok(changed)
+}
+
+/// The level 1 entry of the user address `addr` in the address space with the level 4 table
+/// `level_4_table`, if the tables down to it exist. User memory has no huge pages.
+fn leaf_entry(level_4_table: PhysFrame, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let level_4 = unsafe { table(level_4_table) };
+    let level_3 = unsafe { table(level_4[addr.p4_index()].frame().ok()?) };
+    let level_2 = unsafe { table(level_3[addr.p3_index()].frame().ok()?) };
+    let level_1 = unsafe { table(level_2[addr.p2_index()].frame().ok()?) };
+    let entry = &mut level_1[addr.p1_index()];
+    if entry.is_unused() {
+        None
+    } else {
+        Some(entry)
+    }
+}
+
+/// The page range `start..start + size`, if it is page aligned and lies in the user range.
+fn check_range(start: VirtAddr, size: u64) -> Result<Range<u64>, PagingError> {
+    if !start.is_aligned(PAGE_SIZE) || !size.is_multiple_of(PAGE_SIZE) || size == 0 {
+        return Err(PagingError::NotAligned);
+    }
+    if !is_user_range(start, size) {
+        return Err(PagingError::InvalidRange);
+    }
+    Ok(start.as_u64()..start.as_u64() + size)
+}
+
+/// Switches to the address space with the level 4 table `page_table`, after copying the current
+/// kernel entries into it. Switching to the kernel's own table only reloads `CR3`.
+///
+/// Also called during context switches, so it doesn't take any locks.
+///
+/// # Safety
+///
+/// `page_table` must be the kernel's level 4 table or the one of a live [`AddressSpace`].
+pub unsafe fn activate(page_table: PhysFrame) {
+    if page_table != super::kernel_page_table() {
+        copy_kernel_entries(page_table);
+    }
+    if Cr3::read().0 != page_table {
+        Cr3::write(page_table, Cr3Flags::empty());
+    }
+}
+
+/// The physical address and the flags `addr` is mapped to in the active address space, if it is
+/// mapped.
+pub fn translate_current(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    translate(Cr3::read().0, addr)
+}
+
+fn translate(level_4_table: PhysFrame, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    let mapper =
+        unsafe { OffsetPageTable::new(table(level_4_table), phys_to_virt(PhysAddr::zero())) };
+    match mapper.translate(addr) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } => Some((frame.start_address() + offset, flags)),
+        _ => None,
+    }
+}
+
+/// Copies the kernel's level 4 entries, everything outside of the user range, to `page_table`.
+fn copy_kernel_entries(page_table: PhysFrame) {
+    let kernel = super::kernel_page_table();
+    let (kernel, target) = unsafe { (table(kernel), table(page_table)) };
+    for (index, entry) in kernel.iter().enumerate() {
+        if !USER_ENTRIES.contains(&index) {
+            target[index] = entry.clone();
+        }
+    }
+}
+
+/// The page table in `frame`, through the physical memory mapping.
+///
+/// # Safety
+///
+/// `frame` must hold a page table, and the reference must not outlive it.
+unsafe fn table(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+fn allocate_frame() -> Result<PhysFrame, PagingError> {
+    let addr = FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .and_then(|frames| frames.allocate(0, Zone::Normal))
+        .ok_or(PagingError::FrameAllocationFailed)?;
+    Ok(PhysFrame::containing_address(addr))
+}
+
+fn allocate_zeroed_frame() -> Result<PhysFrame, PagingError> {
+    let frame = allocate_frame()?;
+    unsafe {
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, PAGE_SIZE as usize)
+    };
+    Ok(frame)
+}
+
+#[test_case]
+fn test_kernel_does_not_use_the_user_range() {
+    let kernel = unsafe { table(super::kernel_page_table()) };
+    for index in USER_ENTRIES {
+        assert!(kernel[index].is_unused(), "level 4 entry {} is used", index);
+    }
+}
+
+#[test_case]
+fn test_address_space_maps_user_memory() {
+    let mut space = AddressSpace::new().unwrap();
+    let start = VirtAddr::new(USER_START);
+    let flags = PageTableFlags::WRITABLE;
+    space
+        .map(start, 2 * PAGE_SIZE, flags, AreaKind::Anonymous)
+        .unwrap();
+    space.write(start + 4090u64, b"across pages").unwrap();
+
+    let (phys, flags) = space.translate(start + 4096u64).unwrap();
+    assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE));
+    let bytes = unsafe { *phys_to_virt(phys).as_ptr::<[u8; 8]>() };
+    assert_eq!(&bytes, b" pages\0\0");
+    // Not mapped in the kernel's address space.
+    assert_eq!(translate_current(start), None);
+
+    assert_eq!(
+        space.map(start, PAGE_SIZE, flags, AreaKind::Anonymous),
+        Err(PagingError::PageAlreadyMapped)
+    );
+    assert_eq!(
+        space.map(
+            VirtAddr::new(USER_END),
+            PAGE_SIZE,
+            flags,
+            AreaKind::Anonymous
+        ),
+        Err(PagingError::InvalidRange)
+    );
+    // The kernel is mapped too.
+    let code = VirtAddr::from_ptr(is_user_range as *const ());
+    assert!(space.translate(code).is_some());
+}
+
+#[test_case]
+fn test_duplicate_copies_user_memory() {
+    let mut space = AddressSpace::new().unwrap();
+    let start = VirtAddr::new(USER_START);
+    space
+        .map(
+            start,
+            PAGE_SIZE,
+            PageTableFlags::empty(),
+            AreaKind::Anonymous,
+        )
+        .unwrap();
+    space.write(start, b"original").unwrap();
+    let mut copy = space.duplicate().unwrap();
+    copy.write(start, b"copy").unwrap();
+
+    let read = |space: &AddressSpace| {
+        let (phys, flags) = space.translate(start).unwrap();
+        assert!(!flags.contains(PageTableFlags::WRITABLE));
+        unsafe { *phys_to_virt(phys).as_ptr::<[u8; 8]>() }
+    };
+    assert_eq!(&read(&space), b"original");
+    assert_eq!(&read(&copy), b"copyinal");
+}
+
+#[test_case]
+fn test_pages_are_copied_on_write() {
+    let mut space = AddressSpace::new().unwrap();
+    let start = VirtAddr::new(USER_START);
+    space
+        .map(
+            start,
+            2 * PAGE_SIZE,
+            PageTableFlags::WRITABLE,
+            AreaKind::Anonymous,
+        )
+        .unwrap();
+    // Not written yet: the zero frame, read-only.
+    let (zero, flags) = space.translate(start).unwrap();
+    assert_eq!(zero, ZERO_FRAME.start_address());
+    assert!(flags.contains(COPY_ON_WRITE) && !flags.contains(PageTableFlags::WRITABLE));
+
+    space.write(start, b"shared").unwrap();
+    let (frame, flags) = space.translate(start).unwrap();
+    assert_ne!(frame, zero);
+    assert!(flags.contains(PageTableFlags::WRITABLE) && !flags.contains(COPY_ON_WRITE));
+
+    let mut copy = space.duplicate().unwrap();
+    for space in [&space, &copy] {
+        let (shared, flags) = space.translate(start).unwrap();
+        assert_eq!(shared, frame);
+        assert!(flags.contains(COPY_ON_WRITE) && !flags.contains(PageTableFlags::WRITABLE));
+    }
+    let frame = PhysFrame::containing_address(frame);
+    assert_eq!(refcount::count(frame), 2);
+
+    copy.write(start, b"copied").unwrap();
+    assert_ne!(copy.translate(start).unwrap().0, frame.start_address());
+    assert_eq!(refcount::count(frame), 1);
+    drop(copy);
+    let (phys, _) = space.translate(start).unwrap();
+    assert_eq!(
+        unsafe { *phys_to_virt(phys).as_ptr::<[u8; 6]>() },
+        *b"shared"
+    );
+}
+
+#[test_case]
+fn test_anonymous_memory_is_mapped_on_first_touch() {
+    let rw = Protection::READ | Protection::WRITE;
+    let mut space = AddressSpace::new().unwrap();
+    let start = space
+        .map_anonymous(Placement::Anywhere, 3 * PAGE_SIZE, rw)
+        .unwrap();
+    assert_eq!(start.as_u64(), MMAP_START);
+    assert_eq!(space.translate(start), None);
+
+    assert!(space.fault_in(start, true));
+    let (_, flags) = space.translate(start).unwrap();
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+    let middle = start + PAGE_SIZE;
+    assert!(space.fault_in(middle, false));
+    assert_eq!(
+        space.translate(middle).unwrap().0,
+        ZERO_FRAME.start_address()
+    );
+    assert!(!space.fault_in(middle, false), "already mapped");
+
+    space.protect(middle, PAGE_SIZE, Protection::NONE).unwrap();
+    let (_, flags) = space.translate(middle).unwrap();
+    assert!(!flags.intersects(PageTableFlags::USER_ACCESSIBLE | COPY_ON_WRITE));
+    assert!(!space.fault_in(middle + 2 * PAGE_SIZE, true), "not mapped");
+    assert_eq!(space.areas().iter().count(), 3);
+
+    space.unmap(start, PAGE_SIZE).unwrap();
+    assert_eq!(space.translate(start), None);
+    assert!(!space.fault_in(start, false));
+    assert_eq!(
+        space.protect(start, 2 * PAGE_SIZE, rw),
+        Err(PagingError::PageNotMapped)
+    );
+
+    // Taken, so the hint is ignored and the first gap is used.
+    let hint = Placement::Hint(middle);
+    let next = space.map_anonymous(hint, PAGE_SIZE, rw).unwrap();
+    assert_eq!(next, start);
+    let fixed = space
+        .map_anonymous(Placement::Fixed(middle), PAGE_SIZE, rw)
+        .unwrap();
+    assert_eq!(fixed, middle);
+    assert_eq!(space.translate(middle), None, "replaced");
+}
+
+#[test_case]
+fn test_file_mapping_copies_the_file_on_first_touch() {
+    use crate::fs::{self, OpenOptions};
+
+    let options = OpenOptions {
+        write: true,
+        create: true,
+        ..OpenOptions::default()
+    };
+    let file = fs::open("/mmap-test", options).unwrap();
+    let mut contents = Vec::new();
+    contents.resize(PAGE_SIZE as usize, b'a');
+    contents.extend_from_slice(b"end");
+    assert_eq!(file.write(&contents), Ok(contents.len()));
+    let inode = file.file_inode().unwrap();
+
+    let mut space = AddressSpace::new().unwrap();
+    let kind = AreaKind::File { inode, offset: 0 };
+    let start = space
+        .map_lazily(Placement::Anywhere, 2 * PAGE_SIZE, Protection::READ, kind)
+        .unwrap();
+    assert_eq!(space.translate(start), None);
+    assert!(space.fault_in(start + PAGE_SIZE, false));
+    assert!(!space.fault_in(start, true), "read-only");
+    let (phys, flags) = space.translate(start + PAGE_SIZE).unwrap();
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    let page = unsafe { &*phys_to_virt(phys).as_ptr::<[u8; PAGE_SIZE as usize]>() };
+    assert_eq!(&page[..3], b"end");
+    assert!(
+        page[3..].iter().all(|&byte| byte == 0),
+        "zeroed past the end"
+    );
+
+    assert!(space.fault_in(start, false));
+    let (phys, _) = space.translate(start).unwrap();
+    assert_ne!(phys, ZERO_FRAME.start_address());
+    assert_eq!(unsafe { *phys_to_virt(phys).as_ptr::<u8>() }, b'a');
+}
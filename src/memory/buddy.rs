@@ -0,0 +1,485 @@
+//! # buddy
+//!
+//! A binary buddy allocator for physical frames.
+//!
+//! Memory is handed out in blocks of `2^order` frames, where a block of order `n` is always
+//! aligned to its own size. That is what DMA-capable drivers need: a 16 KiB ring aligned to 16 KiB
+//! is simply an order-2 block. A free-list of single 4 KiB frames cannot give such guarantees.
+//!
+//! ## Bookkeeping
+//!
+//! - Every frame has one metadata byte. The first frame of a *free* block stores the block order,
+//!   every other frame stores [`NOT_FREE`]. Checking whether the buddy of a block is free (and
+//!   therefore can be merged) is a single array lookup.
+//! - Free blocks are linked into doubly-linked free lists per zone and per order. The list nodes
+//!   live *inside* the free frames themselves, which are reached through the complete physical
+//!   memory mapping set up by the bootloader. So the allocator needs no heap.
+//!
+//! ## Zones
+//!
+//! Some devices can only address the low part of physical memory (ISA DMA below 1 MiB, 32-bit PCI
+//! devices below 4 GiB). Each [`Zone`] has its own free lists and blocks never merge across a zone
+//! boundary, so an allocation can be restricted to memory below a given limit.
+
+use core::fmt;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// The size of a single physical frame (order 0 block).
+pub const FRAME_SIZE: u64 = 4096;
+
+/// The largest supported block order. Order 9 is a 2 MiB block, order 10 is 4 MiB.
+pub const MAX_ORDER: usize = 10;
+
+/// Number of distinct block orders (`0..=MAX_ORDER`).
+const ORDERS: usize = MAX_ORDER + 1;
+
+/// Number of zones, see [`Zone`].
+const ZONES: usize = 3;
+
+/// Metadata value of a frame that is not the first frame of a free block.
+const NOT_FREE: u8 = 0xff;
+
+/// Free list terminator. Physical address `0` is a valid frame, so we can't use it.
+const NIL: u64 = u64::MAX;
+
+/* REGION_START: ZONES */
+
+/// A physical memory zone, ordered from the most to the least constrained one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(usize)]
+pub enum Zone {
+    /// Memory below 1 MiB, e.g. for legacy ISA DMA and real mode trampolines.
+    Dma = 0,
+    /// Memory below 4 GiB, for devices with 32-bit DMA addresses.
+    Dma32 = 1,
+    /// All remaining memory.
+    Normal = 2,
+}
+
+impl Zone {
+    /// All zones, in ascending address order.
+    pub const ALL: [Zone; ZONES] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    /// The zone containing the given physical address.
+    pub fn of(addr: PhysAddr) -> Zone {
+        match addr.as_u64() {
+            a if a < Zone::Dma.end() => Zone::Dma,
+            a if a < Zone::Dma32.end() => Zone::Dma32,
+            _ => Zone::Normal,
+        }
+    }
+
+    /// The (exclusive) physical end address of the zone.
+    pub fn end(self) -> u64 {
+        match self {
+            Zone::Dma => 1 << 20,
+            Zone::Dma32 => 1 << 32,
+            Zone::Normal => u64::MAX,
+        }
+    }
+
+    /// A short name, used in the statistics output.
+    pub fn name(self) -> &'static str {
+        match self {
+            Zone::Dma => "DMA",
+            Zone::Dma32 => "DMA32",
+            Zone::Normal => "Normal",
+        }
+    }
+}
+
+/* REGION_END: ZONES */
+
+/* REGION_START: ALLOCATOR */
+
+/// The node stored in the first frame of every free block.
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+/// A buddy allocator over physical frames. See the module documentation.
+pub struct BuddyAllocator {
+    /// Virtual address at which the complete physical memory is mapped.
+    physical_memory_offset: VirtAddr,
+    /// One byte per frame, indexed by frame number. Frames beyond its length are never managed.
+    meta: &'static mut [u8],
+    /// Physical address of the first free block of each zone and order, or [`NIL`].
+    free_lists: [[u64; ORDERS]; ZONES],
+    /// Number of free blocks of each zone and order.
+    free_blocks: [[usize; ORDERS]; ZONES],
+    /// Number of frames ever added to each zone.
+    managed_frames: [usize; ZONES],
+}
+
+impl BuddyAllocator {
+    /// Creates an allocator that manages no memory yet, see [`BuddyAllocator::add_region`].
+    ///
+    /// `meta` needs one byte per frame up to the highest physical address that will be added.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the complete physical memory is mapped to virtual memory at
+    /// the passed `physical_memory_offset`.
+    pub unsafe fn new(physical_memory_offset: VirtAddr, meta: &'static mut [u8]) -> Self {
+        meta.iter_mut().for_each(|byte| *byte = NOT_FREE);
+        BuddyAllocator {
+            physical_memory_offset,
+            meta,
+            free_lists: [[NIL; ORDERS]; ZONES],
+            free_blocks: [[0; ORDERS]; ZONES],
+            managed_frames: [0; ZONES],
+        }
+    }
+
+    /// Changes the virtual address through which the free frames are accessed.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`BuddyAllocator::new`].
+    pub unsafe fn set_physical_memory_offset(&mut self, physical_memory_offset: VirtAddr) {
+        self.physical_memory_offset = physical_memory_offset;
+    }
+
+    /// Adds the physical memory range `start..end` to the allocator.
+    ///
+    /// The range is shrunk to whole frames and to the frames covered by the metadata. Adjacent
+    /// regions are merged into larger blocks.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the range is unused memory and that it was not added before.
+    pub unsafe fn add_region(&mut self, start: PhysAddr, end: PhysAddr) {
+        let first = start.as_u64().div_ceil(FRAME_SIZE);
+        let last = (end.as_u64() / FRAME_SIZE).min(self.meta.len() as u64);
+
+        for frame in first..last {
+            let zone = Zone::of(PhysAddr::new(frame * FRAME_SIZE));
+            self.managed_frames[zone as usize] += 1;
+            self.free_block(frame, 0);
+        }
+    }
+
+    /// Allocates a block of `2^order` physically contiguous frames, aligned to its size.
+    ///
+    /// Only memory below the end of `limit` is used. Higher zones are tried first, so that scarce
+    /// low memory stays available for the devices that really need it.
+    pub fn allocate(&mut self, order: usize, limit: Zone) -> Option<PhysAddr> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        for zone in Zone::ALL.iter().rev().filter(|&&zone| zone <= limit) {
+            let zone = *zone as usize;
+            let found = (order..ORDERS).find(|&o| self.free_lists[zone][o] != NIL);
+
+            if let Some(mut current) = found {
+                let frame = self.free_lists[zone][current] / FRAME_SIZE;
+                self.remove(zone, current, frame);
+
+                // Split the block, keeping the lower half and freeing the upper (buddy) half.
+                while current > order {
+                    current -= 1;
+                    self.push(zone, current, frame + (1 << current));
+                }
+
+                return Some(PhysAddr::new(frame * FRAME_SIZE));
+            }
+        }
+
+        None
+    }
+
+    /// Allocates a block of at least `size` bytes, aligned to at least `align` bytes.
+    pub fn allocate_bytes(&mut self, size: u64, align: u64, limit: Zone) -> Option<PhysAddr> {
+        self.allocate(order_for(size.max(align)), limit)
+    }
+
+    /// Returns a block previously returned by [`BuddyAllocator::allocate`] to the allocator.
+    ///
+    /// # Safety
+    ///
+    /// The block must have been allocated with the same `order` and must no longer be used.
+    pub unsafe fn deallocate(&mut self, addr: PhysAddr, order: usize) {
+        debug_assert!(addr.is_aligned(FRAME_SIZE << order));
+        self.free_block(addr.as_u64() / FRAME_SIZE, order);
+    }
+
+    /// Frees the block at `frame`, merging it with its buddy as long as the buddy is free too.
+    unsafe fn free_block(&mut self, mut frame: u64, mut order: usize) {
+        let zone = Zone::of(PhysAddr::new(frame * FRAME_SIZE)) as usize;
+
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            let buddy_zone = Zone::of(PhysAddr::new(buddy * FRAME_SIZE)) as usize;
+
+            if buddy_zone != zone || self.meta.get(buddy as usize) != Some(&(order as u8)) {
+                break;
+            }
+
+            self.remove(zone, order, buddy);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+
+        self.push(zone, order, frame);
+    }
+
+    /// Returns a pointer to the free list node stored in `frame`.
+    fn node(&self, frame: u64) -> *mut FreeBlock {
+        (self.physical_memory_offset + frame * FRAME_SIZE).as_mut_ptr()
+    }
+
+    /// Inserts the block at `frame` at the front of its free list.
+    fn push(&mut self, zone: usize, order: usize, frame: u64) {
+        let addr = frame * FRAME_SIZE;
+        let head = self.free_lists[zone][order];
+
+        // Safety: free frames are unused and mapped at the physical memory offset.
+        unsafe {
+            self.node(frame).write(FreeBlock {
+                next: head,
+                prev: NIL,
+            });
+            if head != NIL {
+                (*self.node(head / FRAME_SIZE)).prev = addr;
+            }
+        }
+
+        self.free_lists[zone][order] = addr;
+        self.free_blocks[zone][order] += 1;
+        self.meta[frame as usize] = order as u8;
+    }
+
+    /// Unlinks the free block at `frame` from its free list.
+    fn remove(&mut self, zone: usize, order: usize, frame: u64) {
+        // Safety: the block is free, so its node was written by `push`.
+        unsafe {
+            let FreeBlock { next, prev } = self.node(frame).read();
+            if prev == NIL {
+                self.free_lists[zone][order] = next;
+            } else {
+                (*self.node(prev / FRAME_SIZE)).next = next;
+            }
+            if next != NIL {
+                (*self.node(next / FRAME_SIZE)).prev = prev;
+            }
+        }
+
+        self.free_blocks[zone][order] -= 1;
+        self.meta[frame as usize] = NOT_FREE;
+    }
+
+    /// Takes a snapshot of the free block counts.
+    pub fn stats(&self) -> BuddyStats {
+        BuddyStats {
+            free_blocks: self.free_blocks,
+            managed_frames: self.managed_frames,
+        }
+    }
+}
+
+/// Returns the smallest order whose blocks hold `size` bytes.
+pub fn order_for(size: u64) -> usize {
+    let frames = size.max(1).div_ceil(FRAME_SIZE);
+    frames.next_power_of_two().trailing_zeros() as usize
+}
+
+// Allows using the buddy allocator for the page table functions of the `x86_64` crate.
+unsafe impl FrameAllocator<Size4KiB> for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate(0, Zone::Normal)
+            .map(PhysFrame::containing_address)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate(frame.start_address(), 0);
+    }
+}
+
+/* REGION_END: ALLOCATOR */
+
+/* REGION_START: STATISTICS */
+
+/// A snapshot of the allocator state, printable with `{}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuddyStats {
+    /// Number of free blocks of each zone and order.
+    pub free_blocks: [[usize; ORDERS]; ZONES],
+    /// Number of frames managed by each zone.
+    pub managed_frames: [usize; ZONES],
+}
+
+impl BuddyStats {
+    /// Number of free frames in `zone`.
+    pub fn free_frames(&self, zone: Zone) -> usize {
+        let counts = &self.free_blocks[zone as usize];
+        counts.iter().enumerate().map(|(o, &n)| n << o).sum()
+    }
+
+    /// The largest order with a free block in `zone`.
+    pub fn largest_free_order(&self, zone: Zone) -> Option<usize> {
+        let counts = &self.free_blocks[zone as usize];
+        (0..ORDERS).rev().find(|&o| counts[o] > 0)
+    }
+
+    /// The unusable free space index of `zone` for `order`, in percent.
+    ///
+    /// It is the share of free memory that sits in blocks too small to satisfy an allocation of
+    /// the given order: `0` means no fragmentation, `100` means no such allocation can succeed.
+    pub fn unusable_index(&self, zone: Zone, order: usize) -> usize {
+        let free = self.free_frames(zone);
+        if free == 0 {
+            return 100;
+        }
+        let counts = &self.free_blocks[zone as usize];
+        let usable: usize = (order..ORDERS).map(|o| counts[o] << o).sum();
+        (free - usable) * 100 / free
+    }
+}
+
+impl fmt::Display for BuddyStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<7}{:>9}{:>9}", "zone", "managed", "free")?;
+        for order in 0..ORDERS {
+            write!(f, "{:>6}", order)?;
+        }
+        writeln!(f, "  unusable(16K/2M)")?;
+
+        for &zone in Zone::ALL.iter() {
+            if self.managed_frames[zone as usize] == 0 {
+                continue;
+            }
+            write!(
+                f,
+                "{:<7}{:>9}{:>9}",
+                zone.name(),
+                self.managed_frames[zone as usize],
+                self.free_frames(zone)
+            )?;
+            for count in self.free_blocks[zone as usize].iter() {
+                write!(f, "{:>6}", count)?;
+            }
+            writeln!(
+                f,
+                "  {:>3}% / {:>3}%",
+                self.unusable_index(zone, 2),
+                self.unusable_index(zone, 9)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/* REGION_END: STATISTICS */
+
+/* REGION_START: TESTS */
+
+/// Fake physical memory for the tests, straddling the 1 MiB zone boundary.
+#[cfg(test)]
+#[repr(C, align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+#[cfg(test)]
+const ARENA_SIZE: usize = 64 * FRAME_SIZE as usize;
+
+/// The physical address the start of the arena pretends to be at.
+#[cfg(test)]
+const ARENA_BASE: u64 = (1 << 20) - 32 * FRAME_SIZE;
+
+#[cfg(test)]
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+#[cfg(test)]
+static mut ARENA_META: [u8; 512] = [0; 512];
+
+/// Creates an allocator whose "physical memory" is the arena.
+#[cfg(test)]
+fn arena_allocator() -> BuddyAllocator {
+    use core::ptr::addr_of_mut;
+
+    unsafe {
+        let arena = VirtAddr::from_ptr(addr_of_mut!(ARENA));
+        let meta = &mut *addr_of_mut!(ARENA_META);
+        let mut allocator = BuddyAllocator::new(arena - ARENA_BASE, meta);
+        let start = PhysAddr::new(ARENA_BASE);
+        allocator.add_region(start, start + ARENA_SIZE);
+        allocator
+    }
+}
+
+#[test_case]
+fn test_buddy_split_and_merge() {
+    let mut allocator = arena_allocator();
+    let before = allocator.stats();
+
+    let a = allocator.allocate(0, Zone::Normal).unwrap();
+    let b = allocator.allocate(0, Zone::Normal).unwrap();
+    assert_ne!(a, b);
+    assert_eq!(allocator.stats().free_frames(Zone::Dma32), 30);
+
+    unsafe {
+        allocator.deallocate(a, 0);
+        allocator.deallocate(b, 0);
+    }
+    assert_eq!(allocator.stats(), before);
+}
+
+#[test_case]
+fn test_buddy_alignment() {
+    let mut allocator = arena_allocator();
+    let _small = allocator.allocate(0, Zone::Normal).unwrap();
+
+    let ring = allocator
+        .allocate_bytes(16 * 1024, 16 * 1024, Zone::Normal)
+        .unwrap();
+    assert!(ring.is_aligned(16 * 1024u64));
+}
+
+#[test_case]
+fn test_buddy_zones() {
+    let mut allocator = arena_allocator();
+    let stats = allocator.stats();
+    assert_eq!(stats.managed_frames, [32, 32, 0]);
+    // Blocks never merge across the 1 MiB boundary.
+    assert_eq!(stats.largest_free_order(Zone::Dma), Some(5));
+
+    let low = allocator.allocate(5, Zone::Dma).unwrap();
+    assert!(low.as_u64() + (FRAME_SIZE << 5) <= Zone::Dma.end());
+    assert_eq!(allocator.allocate(0, Zone::Dma), None);
+
+    let high = allocator.allocate(0, Zone::Normal).unwrap();
+    assert_eq!(Zone::of(high), Zone::Dma32);
+}
+
+#[test_case]
+fn test_buddy_exhaustion() {
+    let mut allocator = arena_allocator();
+    assert_eq!(allocator.allocate(6, Zone::Normal), None);
+    assert_eq!(allocator.allocate(MAX_ORDER + 1, Zone::Normal), None);
+
+    let mut count = 0;
+    while allocator.allocate(0, Zone::Normal).is_some() {
+        count += 1;
+    }
+    assert_eq!(count, 64);
+    assert_eq!(allocator.stats().unusable_index(Zone::Dma, 0), 100);
+}
+
+#[test_case]
+fn test_order_for() {
+    assert_eq!(order_for(0), 0);
+    assert_eq!(order_for(4096), 0);
+    assert_eq!(order_for(4097), 1);
+    assert_eq!(order_for(16 * 1024), 2);
+    assert_eq!(order_for(2 * 1024 * 1024), 9);
+}
+
+/* REGION_END: TESTS */
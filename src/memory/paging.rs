@@ -0,0 +1,557 @@
+//! # paging
+//!
+//! Mapping virtual memory with 4 KiB, 2 MiB and 1 GiB pages.
+//!
+//! Every 4 KiB page needs its own page table entry and its own TLB entry. Large contiguous
+//! mappings like the physical memory map or the kernel heap are much cheaper with huge pages: a
+//! 2 MiB page is a single level 2 entry (with the `HUGE_PAGE` flag set) instead of a level 1 table
+//! with 512 entries, and a 1 GiB page is a single level 3 entry. Support for 1 GiB pages is
+//! optional and reported by CPUID.
+//!
+//! A huge page has one set of flags. When only a part of it needs different permissions, the page
+//! is split into the next smaller page size first (see [`split_huge_page`] and [`set_flags`]).
+
+use super::{
+    buddy::{BuddyAllocator, Zone},
+    FRAME_ALLOCATOR, MAPPER,
+};
+use core::fmt;
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult, UnmapError},
+        page_table::PageTableEntry,
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+        Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/* REGION_START: PAGE SIZES */
+
+/// The page sizes supported by the x86_64 4-level paging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MappingSize {
+    /// A regular page, mapped by a level 1 entry.
+    Page4KiB,
+    /// A huge page, mapped by a level 2 entry.
+    Page2MiB,
+    /// A huge page, mapped by a level 3 entry. Requires CPU support.
+    Page1GiB,
+}
+
+impl MappingSize {
+    /// The size of the page in bytes.
+    pub fn bytes(self) -> u64 {
+        match self {
+            MappingSize::Page4KiB => 4096,
+            MappingSize::Page2MiB => 2 * 1024 * 1024,
+            MappingSize::Page1GiB => 1024 * 1024 * 1024,
+        }
+    }
+
+    /// The buddy allocator order of a frame of this size.
+    pub fn order(self) -> usize {
+        (self.bytes() / 4096).trailing_zeros() as usize
+    }
+
+    /// The next smaller page size, which a huge page of this size is split into.
+    fn smaller(self) -> Option<MappingSize> {
+        match self {
+            MappingSize::Page4KiB => None,
+            MappingSize::Page2MiB => Some(MappingSize::Page4KiB),
+            MappingSize::Page1GiB => Some(MappingSize::Page2MiB),
+        }
+    }
+}
+
+/// Whether the CPU supports 1 GiB pages (CPUID.80000001H:EDX.Page1GB, bit 26).
+pub fn supports_1gib_pages() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    let max_extended = __cpuid(0x8000_0000).eax;
+    max_extended >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0
+}
+
+/* REGION_END: PAGE SIZES */
+
+/* REGION_START: ERRORS */
+
+/// The ways a paging operation can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// The frame allocator ran out of memory, or paging is not initialized yet.
+    FrameAllocationFailed,
+    /// A page in the range is already mapped.
+    PageAlreadyMapped,
+    /// A page in the range is not mapped.
+    PageNotMapped,
+    /// A page in the range is part of a larger huge page.
+    ParentEntryHugePage,
+    /// The addresses or the size are not aligned to the requested page size.
+    NotAligned,
+    /// The range lies outside of the region it must be in, e.g. user memory outside of
+    /// [`super::address_space::USER_START`]`..`[`super::address_space::USER_END`].
+    InvalidRange,
+}
+
+impl<S: x86_64::structures::paging::PageSize> From<MapToError<S>> for PagingError {
+    fn from(err: MapToError<S>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => PagingError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => PagingError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(_) => PagingError::PageAlreadyMapped,
+        }
+    }
+}
+
+impl From<UnmapError> for PagingError {
+    fn from(err: UnmapError) -> Self {
+        match err {
+            UnmapError::ParentEntryHugePage => PagingError::ParentEntryHugePage,
+            _ => PagingError::PageNotMapped,
+        }
+    }
+}
+
+/* REGION_END: ERRORS */
+
+/* REGION_START: MAPPING */
+
+/// Locks the global page table and frame allocator and passes them to `f`.
+fn with_paging<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BuddyAllocator) -> Result<R, PagingError>,
+) -> Result<R, PagingError> {
+    let mut mapper = MAPPER.lock();
+    let mut frames = FRAME_ALLOCATOR.lock();
+    match (mapper.as_mut(), frames.as_mut()) {
+        (Some(mapper), Some(frames)) => f(mapper, frames),
+        _ => Err(PagingError::FrameAllocationFailed),
+    }
+}
+
+/// Maps a single page of the given size. Page tables are allocated from `frames`.
+fn map_one(
+    mapper: &mut OffsetPageTable,
+    frames: &mut BuddyAllocator,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: MappingSize,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    unsafe {
+        match size {
+            MappingSize::Page4KiB => {
+                let page = Page::<Size4KiB>::containing_address(virt);
+                let frame = PhysFrame::containing_address(phys);
+                mapper.map_to(page, frame, flags, frames)?.flush();
+            }
+            MappingSize::Page2MiB => {
+                let page = Page::<Size2MiB>::containing_address(virt);
+                let frame = PhysFrame::containing_address(phys);
+                mapper.map_to(page, frame, flags, frames)?.flush();
+            }
+            MappingSize::Page1GiB => {
+                let page = Page::<Size1GiB>::containing_address(virt);
+                let frame = PhysFrame::containing_address(phys);
+                mapper.map_to(page, frame, flags, frames)?.flush();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The largest page size usable at `virt`/`phys` with `remaining` bytes left to map.
+fn largest_fitting(virt: VirtAddr, phys: PhysAddr, remaining: u64) -> MappingSize {
+    let sizes = [MappingSize::Page1GiB, MappingSize::Page2MiB];
+    sizes
+        .iter()
+        .copied()
+        .find(|&size| {
+            (size != MappingSize::Page1GiB || supports_1gib_pages())
+                && virt.is_aligned(size.bytes())
+                && phys.is_aligned(size.bytes())
+                && remaining >= size.bytes()
+        })
+        .unwrap_or(MappingSize::Page4KiB)
+}
+
+/// Maps the physical range `phys..phys + size` to `virt..virt + size`, always using the largest
+/// possible pages. No frames except for page tables are allocated.
+///
+/// # Safety
+///
+/// The caller must make sure that the physical memory may be accessed through the new mapping
+/// with the given flags.
+pub unsafe fn map_physical(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    if !virt.is_aligned(4096u64) || !phys.is_aligned(4096u64) || !size.is_multiple_of(4096) {
+        return Err(PagingError::NotAligned);
+    }
+
+    with_paging(|mapper, frames| {
+        let mut offset = 0;
+        while offset < size {
+            let size_here = largest_fitting(virt + offset, phys + offset, size - offset);
+            map_one(
+                mapper,
+                frames,
+                virt + offset,
+                phys + offset,
+                size_here,
+                flags,
+            )?;
+            offset += size_here.bytes();
+        }
+        Ok(())
+    })
+}
+
+/// Maps `virt..virt + size` to newly allocated frames of the given page size.
+///
+/// 1 GiB pages are not supported here, since the frame allocator can't hand out 1 GiB blocks.
+pub fn map_anonymous(
+    virt: VirtAddr,
+    size: u64,
+    page_size: MappingSize,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    let bytes = page_size.bytes();
+    if page_size == MappingSize::Page1GiB || !virt.is_aligned(bytes) || !size.is_multiple_of(bytes)
+    {
+        return Err(PagingError::NotAligned);
+    }
+
+    with_paging(|mapper, frames| {
+        for offset in (0..size).step_by(bytes as usize) {
+            let frame = frames
+                .allocate(page_size.order(), Zone::Normal)
+                .ok_or(PagingError::FrameAllocationFailed)?;
+            if let Err(err) = map_one(mapper, frames, virt + offset, frame, page_size, flags) {
+                unsafe { frames.deallocate(frame, page_size.order()) };
+                return Err(err);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Unmaps `virt..virt + size`, whatever page sizes it is mapped with, and frees the frames.
+///
+/// # Safety
+///
+/// The range must have been mapped by [`map_anonymous`] and must no longer be used.
+pub unsafe fn unmap_anonymous(virt: VirtAddr, size: u64) -> Result<(), PagingError> {
+    unmap(virt, size, true)
+}
+
+/// Unmaps `virt..virt + size`, which was mapped by [`map_physical`]. The frames are not freed.
+///
+/// # Safety
+///
+/// The range must no longer be used.
+pub unsafe fn unmap_physical(virt: VirtAddr, size: u64) -> Result<(), PagingError> {
+    unmap(virt, size, false)
+}
+
+unsafe fn unmap(virt: VirtAddr, size: u64, free_frames: bool) -> Result<(), PagingError> {
+    with_paging(|mapper, frames| {
+        let end = virt + size;
+        let mut addr = virt;
+        while addr < end {
+            let (frame, page_size) = match mapper.translate(addr) {
+                TranslateResult::Mapped { frame, .. } => match frame {
+                    MappedFrame::Size4KiB(_) => {
+                        let page = Page::<Size4KiB>::containing_address(addr);
+                        let (frame, flush) = mapper.unmap(page)?;
+                        flush.flush();
+                        (frame.start_address(), MappingSize::Page4KiB)
+                    }
+                    MappedFrame::Size2MiB(_) => {
+                        let page = Page::<Size2MiB>::containing_address(addr);
+                        let (frame, flush) = mapper.unmap(page)?;
+                        flush.flush();
+                        (frame.start_address(), MappingSize::Page2MiB)
+                    }
+                    MappedFrame::Size1GiB(_) => return Err(PagingError::ParentEntryHugePage),
+                },
+                _ => return Err(PagingError::PageNotMapped),
+            };
+            if free_frames {
+                frames.deallocate(frame, page_size.order());
+            }
+            addr = addr.align_down(page_size.bytes()) + page_size.bytes();
+        }
+        Ok(())
+    })
+}
+
+/// Returns the page size `addr` is mapped with, if it is mapped.
+pub fn mapping_size(addr: VirtAddr) -> Option<MappingSize> {
+    let mapper = MAPPER.lock();
+    match mapper.as_ref()?.translate(addr) {
+        TranslateResult::Mapped { frame, .. } => Some(match frame {
+            MappedFrame::Size4KiB(_) => MappingSize::Page4KiB,
+            MappedFrame::Size2MiB(_) => MappingSize::Page2MiB,
+            MappedFrame::Size1GiB(_) => MappingSize::Page1GiB,
+        }),
+        _ => None,
+    }
+}
+
+/* REGION_END: MAPPING */
+
+/* REGION_START: SPLITTING */
+
+/// Returns the lowest-level (leaf) entry mapping `addr` and the page size it maps.
+fn leaf_entry(
+    mapper: &mut OffsetPageTable,
+    addr: VirtAddr,
+) -> Result<(&'static mut PageTableEntry, MappingSize), PagingError> {
+    let offset = mapper.phys_offset();
+    let table = |entry: &PageTableEntry| -> Result<&'static mut PageTable, PagingError> {
+        if entry.is_unused() {
+            return Err(PagingError::PageNotMapped);
+        }
+        Ok(unsafe { &mut *(offset + entry.addr().as_u64()).as_mut_ptr() })
+    };
+
+    let p4 = mapper.level_4_table();
+    let p3 = table(&p4[addr.p4_index()])?;
+    let entry = &mut p3[addr.p3_index()];
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Ok((entry, MappingSize::Page1GiB));
+    }
+    let p2 = table(entry)?;
+    let entry = &mut p2[addr.p2_index()];
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Ok((entry, MappingSize::Page2MiB));
+    }
+    let p1 = table(entry)?;
+    let entry = &mut p1[addr.p1_index()];
+    if entry.is_unused() {
+        return Err(PagingError::PageNotMapped);
+    }
+    Ok((entry, MappingSize::Page4KiB))
+}
+
+/// Splits the huge page containing `addr` into pages of the next smaller size, with the same
+/// physical frames and flags. Does nothing if `addr` is mapped by a 4 KiB page.
+pub fn split_huge_page(addr: VirtAddr) -> Result<(), PagingError> {
+    with_paging(|mapper, frames| split(mapper, frames, addr))
+}
+
+fn split(
+    mapper: &mut OffsetPageTable,
+    frames: &mut BuddyAllocator,
+    addr: VirtAddr,
+) -> Result<(), PagingError> {
+    let offset = mapper.phys_offset();
+    let (entry, size) = leaf_entry(mapper, addr)?;
+    let smaller = match size.smaller() {
+        Some(smaller) => smaller,
+        None => return Ok(()),
+    };
+
+    let table_frame: PhysFrame = frames
+        .allocate_frame()
+        .ok_or(PagingError::FrameAllocationFailed)?;
+    let table: &mut PageTable =
+        unsafe { &mut *(offset + table_frame.start_address().as_u64()).as_mut_ptr() };
+
+    let mut flags = entry.flags();
+    if smaller == MappingSize::Page4KiB {
+        flags.remove(PageTableFlags::HUGE_PAGE);
+    }
+    for (i, child) in table.iter_mut().enumerate() {
+        child.set_addr(entry.addr() + i as u64 * smaller.bytes(), flags);
+    }
+
+    // Intermediate entries must be permissive, the restrictions are kept in the new leaf entries.
+    let mut parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    parent_flags |= entry.flags() & PageTableFlags::USER_ACCESSIBLE;
+    entry.set_addr(table_frame.start_address(), parent_flags);
+    tlb::flush_all();
+
+    Ok(())
+}
+
+/// Changes the flags of all pages in `virt..virt + size`, splitting huge pages that are only
+/// partially covered by the range.
+///
+/// # Safety
+///
+/// Changing the flags of memory in use can break memory safety, e.g. when removing `PRESENT`.
+pub unsafe fn set_flags(
+    virt: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    with_paging(|mapper, frames| {
+        let end = virt + size;
+        let mut addr = virt.align_down(4096u64);
+        while addr < end {
+            let (entry, page_size) = leaf_entry(mapper, addr)?;
+            let covered = addr.is_aligned(page_size.bytes()) && end - addr >= page_size.bytes();
+            if covered {
+                let mut flags = flags;
+                if page_size != MappingSize::Page4KiB {
+                    flags |= PageTableFlags::HUGE_PAGE;
+                }
+                entry.set_flags(flags);
+                addr += page_size.bytes();
+            } else {
+                split(mapper, frames, addr)?;
+            }
+        }
+        tlb::flush_all();
+        Ok(())
+    })
+}
+
+/* REGION_END: SPLITTING */
+
+/* REGION_START: BENCHMARK */
+
+/// Virtual address of the memory touched by [`tlb_benchmark`].
+const BENCHMARK_START: u64 = 0x_6666_0000_0000;
+
+/// Size of the memory touched by [`tlb_benchmark`], with each page size.
+const BENCHMARK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The result of [`tlb_benchmark`], in CPU cycles (TSC ticks).
+#[derive(Debug, Clone, Copy)]
+pub struct TlbBenchmark {
+    pub cycles_4kib: u64,
+    pub cycles_2mib: u64,
+}
+
+impl fmt::Display for TlbBenchmark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "tlb benchmark ({} MiB): 4 KiB pages {} cycles, 2 MiB pages {} cycles",
+            BENCHMARK_SIZE >> 20,
+            self.cycles_4kib,
+            self.cycles_2mib
+        )
+    }
+}
+
+/// Touches one byte in every 4 KiB page of a mapping in an order that defeats prefetching, once
+/// with 4 KiB pages and once with 2 MiB pages, and counts the cycles.
+///
+/// With 4 KiB pages, the touched pages exceed the TLB capacity and nearly every access needs a
+/// page walk. With 2 MiB pages, the whole region only needs four TLB entries.
+pub fn tlb_benchmark() -> Result<TlbBenchmark, PagingError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let run = |virt: VirtAddr, page_size| -> Result<u64, PagingError> {
+        map_anonymous(virt, BENCHMARK_SIZE, page_size, flags)?;
+        let cycles = touch_pages(virt);
+        unsafe { unmap_anonymous(virt, BENCHMARK_SIZE)? };
+        Ok(cycles)
+    };
+
+    Ok(TlbBenchmark {
+        cycles_4kib: run(VirtAddr::new(BENCHMARK_START), MappingSize::Page4KiB)?,
+        cycles_2mib: run(VirtAddr::new(BENCHMARK_START), MappingSize::Page2MiB)?,
+    })
+}
+
+fn touch_pages(virt: VirtAddr) -> u64 {
+    use core::arch::x86_64::_rdtsc;
+
+    const ROUNDS: u64 = 16;
+    // A stride coprime to the page count visits every page once per round.
+    const STRIDE: u64 = 97;
+    let pages = BENCHMARK_SIZE / 4096;
+
+    let start = unsafe { _rdtsc() };
+    for round in 0..ROUNDS {
+        for i in 0..pages {
+            let page = (i * STRIDE + round) % pages;
+            let ptr = (virt + page * 4096).as_mut_ptr::<u8>();
+            unsafe { ptr.write_volatile(ptr.read_volatile().wrapping_add(1)) };
+        }
+    }
+    unsafe { _rdtsc() - start }
+}
+
+/* REGION_END: BENCHMARK */
+
+#[test_case]
+fn test_map_anonymous_huge() {
+    let virt = VirtAddr::new(BENCHMARK_START);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    map_anonymous(
+        virt,
+        2 * MappingSize::Page2MiB.bytes(),
+        MappingSize::Page2MiB,
+        flags,
+    )
+    .unwrap();
+    assert_eq!(mapping_size(virt + 4096u64), Some(MappingSize::Page2MiB));
+
+    unsafe {
+        virt.as_mut_ptr::<u64>().write_volatile(42);
+        unmap_anonymous(virt, 2 * MappingSize::Page2MiB.bytes()).unwrap();
+    }
+    assert_eq!(mapping_size(virt), None);
+}
+
+#[test_case]
+fn test_split_on_partial_protection() {
+    let virt = VirtAddr::new(BENCHMARK_START);
+    let size = MappingSize::Page2MiB.bytes();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    map_anonymous(virt, size, MappingSize::Page2MiB, flags).unwrap();
+
+    let frame_of = |addr| {
+        MAPPER
+            .lock()
+            .as_ref()
+            .unwrap()
+            .translate_addr(addr)
+            .unwrap()
+    };
+    let base = frame_of(virt);
+
+    // Write-protect a single 4 KiB page in the middle of the huge page.
+    let read_only = virt + 16 * 4096u64;
+    unsafe { set_flags(read_only, 4096, PageTableFlags::PRESENT).unwrap() };
+
+    assert_eq!(mapping_size(virt), Some(MappingSize::Page4KiB));
+    assert_eq!(frame_of(read_only), base + 16 * 4096u64);
+    let flags_of = |addr| match MAPPER.lock().as_ref().unwrap().translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("not mapped"),
+    };
+    assert!(!flags_of(read_only).contains(PageTableFlags::WRITABLE));
+    assert!(flags_of(read_only + 4096u64).contains(PageTableFlags::WRITABLE));
+
+    unsafe { unmap_anonymous(virt, size).unwrap() };
+}
+
+#[test_case]
+fn test_physical_memory_map_uses_huge_pages() {
+    let two_mib = super::allocate_frames(9, Zone::Normal).expect("no 2 MiB block available");
+    let size = mapping_size(super::phys_to_virt(two_mib));
+    unsafe { super::deallocate_frames(two_mib, 9) };
+    assert!(matches!(
+        size,
+        Some(MappingSize::Page2MiB) | Some(MappingSize::Page1GiB)
+    ));
+}
+
+#[test_case]
+fn test_tlb_benchmark() {
+    use crate::serial_println;
+
+    let result = tlb_benchmark().unwrap();
+    serial_println!("{}", result);
+}
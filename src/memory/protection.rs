@@ -0,0 +1,195 @@
+//! # protection
+//!
+//! W^X for the kernel image and the memory protection features of the CPU.
+//!
+//! The linker script (`linker.ld`) places the kernel's code, read-only data and writable data in
+//! separate page-aligned parts. [`protect_kernel`] maps each part with the minimal permissions:
+//!
+//! | Section         | Writable | Executable |
+//! |-----------------|----------|------------|
+//! | `.text`         | no       | yes        |
+//! | `.rodata`       | no       | no         |
+//! | `.data`, `.bss` | yes      | no         |
+//!
+//! So no kernel memory is both writable and executable (W^X). [`enable_cpu_features`] turns on
+//! the CPU features that make these permissions effective:
+//!
+//! - `EFER.NXE` enables the `NO_EXECUTE` page table flag. Without it, the flag is a reserved bit.
+//! - `CR0.WP` makes read-only pages read-only for the kernel too, not just for user mode.
+//! - `CR4.SMEP` forbids the kernel to execute user pages.
+//! - `CR4.SMAP` forbids the kernel to access user pages, except when `RFLAGS.AC` is set.
+//!
+//! SMEP and SMAP are only enabled if CPUID reports them.
+
+use super::paging::{self, PagingError};
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::ptr::addr_of;
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::PageTableFlags,
+    VirtAddr,
+};
+
+extern "C" {
+    // Defined by `linker.ld`. Only their addresses are meaningful.
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+/// The parts of the kernel image, which are mapped with different permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    /// The code.
+    Text,
+    /// Constants and string literals.
+    Rodata,
+    /// Statics, including the zero-initialized ones in `.bss`.
+    Data,
+}
+
+impl Section {
+    pub const ALL: [Section; 3] = [Section::Text, Section::Rodata, Section::Data];
+
+    /// The page-aligned address range of the section.
+    pub fn range(self) -> (VirtAddr, VirtAddr) {
+        let (start, end) = match self {
+            Section::Text => (addr_of!(__text_start), addr_of!(__text_end)),
+            Section::Rodata => (addr_of!(__rodata_start), addr_of!(__rodata_end)),
+            Section::Data => (addr_of!(__data_start), addr_of!(__data_end)),
+        };
+        (VirtAddr::from_ptr(start), VirtAddr::from_ptr(end))
+    }
+
+    /// The page table flags the section is mapped with.
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            Section::Text => PageTableFlags::PRESENT,
+            Section::Rodata => PageTableFlags::PRESENT | no_execute(),
+            Section::Data => PageTableFlags::PRESENT | PageTableFlags::WRITABLE | no_execute(),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Section::Text => ".text",
+            Section::Rodata => ".rodata",
+            Section::Data => ".data",
+        }
+    }
+
+    /// Returns the section containing `addr`, if any.
+    pub fn containing(addr: VirtAddr) -> Option<Section> {
+        Section::ALL.iter().copied().find(|section| {
+            let (start, end) = section.range();
+            (start..end).contains(&addr)
+        })
+    }
+}
+
+/// Enables `EFER.NXE` and `CR0.WP`, and SMEP and SMAP if supported.
+///
+/// Must be called before any page is mapped with the flags returned by [`no_execute`].
+pub fn enable_cpu_features() {
+    let max_extended = __cpuid(0x8000_0000).eax;
+    let nx = max_extended >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 20) != 0;
+    let (smep, smap) = if __cpuid(0).eax >= 7 {
+        let ebx = __cpuid_count(7, 0).ebx;
+        (ebx & (1 << 7) != 0, ebx & (1 << 20) != 0)
+    } else {
+        (false, false)
+    };
+
+    unsafe {
+        if nx {
+            Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
+        }
+        Cr0::update(|flags| *flags |= Cr0Flags::WRITE_PROTECT);
+        Cr4::update(|flags| {
+            flags.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, smep);
+            flags.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, smap);
+        });
+    }
+}
+
+/// `NO_EXECUTE` if `EFER.NXE` is enabled, otherwise no flags.
+///
+/// Data mappings should include these flags.
+pub fn no_execute() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Whether SMAP is enabled, i.e. accesses to user memory must be wrapped in `stac`/`clac`.
+pub fn smap_enabled() -> bool {
+    Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION)
+}
+
+/// Remaps the sections of the kernel image with the permissions of [`Section::flags`].
+///
+/// # Safety
+///
+/// Must be called only once, after [`super::init`]. The kernel must not write to its code
+/// afterwards.
+pub unsafe fn protect_kernel() -> Result<(), PagingError> {
+    for section in Section::ALL.iter().copied() {
+        let (start, end) = section.range();
+        paging::set_flags(start, end - start, section.flags())?;
+    }
+    Ok(())
+}
+
+#[test_case]
+fn test_cpu_features_enabled() {
+    assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+    assert!(Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE));
+}
+
+#[test_case]
+fn test_sections_are_w_xor_x() {
+    use super::MAPPER;
+    use x86_64::structures::paging::{mapper::TranslateResult, Translate};
+
+    let flags_of = |addr| match MAPPER.lock().as_ref().unwrap().translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{:?} is not mapped", addr),
+    };
+
+    for section in Section::ALL.iter().copied() {
+        let (start, end) = section.range();
+        assert!(start < end && start.is_aligned(4096u64) && end.is_aligned(4096u64));
+        for addr in [start, end - 1u64].iter().copied() {
+            let flags = flags_of(addr);
+            let writable = flags.contains(PageTableFlags::WRITABLE);
+            let executable = !flags.contains(PageTableFlags::NO_EXECUTE);
+            assert!(!(writable && executable), "{} is W+X", section.name());
+            assert_eq!(writable, section == Section::Data);
+            assert_eq!(executable, section == Section::Text);
+        }
+    }
+
+    let heap = VirtAddr::new(crate::allocator::HEAP_START);
+    assert!(flags_of(heap).contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn test_section_lookup() {
+    let code = VirtAddr::from_ptr(enable_cpu_features as *const ());
+    let constant = VirtAddr::from_ptr("rodata".as_ptr());
+    static mut STATIC: u8 = 0;
+    let data = VirtAddr::from_ptr(addr_of!(STATIC));
+
+    assert_eq!(Section::containing(code), Some(Section::Text));
+    assert_eq!(Section::containing(constant), Some(Section::Rodata));
+    assert_eq!(Section::containing(data), Some(Section::Data));
+    assert_eq!(Section::containing(VirtAddr::new(0)), None);
+}
@@ -0,0 +1,64 @@
+//! # refcount
+//!
+//! Reference counts of the frames that more than one address space maps.
+//!
+//! A frame of user memory normally belongs to a single address space, which frees it when it
+//! unmaps it. After a `fork`, parent and child share their frames copy-on-write (see
+//! [`super::address_space`]) until one of them writes to a page and gets a copy of its own. Only
+//! shared frames are counted: a frame without an entry has a single owner, which keeps the table
+//! as small as the sharing.
+
+use crate::sync::IrqMutex;
+use alloc::collections::BTreeMap;
+use x86_64::structures::paging::PhysFrame;
+
+/// The number of references to each shared frame, always at least 2.
+static REFS: IrqMutex<BTreeMap<PhysFrame, usize>> = IrqMutex::named("FRAME_REFS", BTreeMap::new());
+
+/// Adds a reference to `frame`, which has at least one already.
+pub fn share(frame: PhysFrame) {
+    *REFS.lock().entry(frame).or_insert(1) += 1;
+}
+
+/// Drops a reference to `frame`. Returns whether it was the last one, so the caller must free
+/// the frame.
+pub fn release(frame: PhysFrame) -> bool {
+    let mut refs = REFS.lock();
+    match refs.get_mut(&frame) {
+        Some(count) => {
+            *count -= 1;
+            if *count == 1 {
+                refs.remove(&frame);
+            }
+            false
+        }
+        None => true,
+    }
+}
+
+/// The number of references to `frame`, which has at least one.
+pub fn count(frame: PhysFrame) -> usize {
+    REFS.lock().get(&frame).copied().unwrap_or(1)
+}
+
+/// The number of shared frames.
+pub fn shared_frames() -> usize {
+    REFS.lock().len()
+}
+
+#[cfg(test)]
+use x86_64::PhysAddr;
+
+#[test_case]
+fn test_shared_frames_are_counted() {
+    // Never allocated, only counted.
+    let frame = PhysFrame::containing_address(PhysAddr::new(0xffff_f000));
+    assert_eq!(count(frame), 1);
+    share(frame);
+    share(frame);
+    assert_eq!(count(frame), 3);
+    assert!(!release(frame));
+    assert!(!release(frame));
+    assert_eq!(count(frame), 1);
+    assert!(release(frame));
+}
@@ -0,0 +1,271 @@
+//! # stack
+//!
+//! Kernel stacks with guard pages.
+//!
+//! On x86_64 the stack grows downwards and nothing stops it from growing past its lower end. An
+//! overflow silently overwrites whatever memory lies below the stack. To catch overflows, every
+//! kernel stack is allocated with an *unmapped* guard page directly below it:
+//!
+//! ```text
+//!  guard page (unmapped) | stack pages (mapped)             |
+//!  ^                     ^ bottom (canary)                  ^ top (initial stack pointer)
+//! ```
+//!
+//! Touching the guard page causes a page fault, and the page fault handler (which runs on its own
+//! interrupt stack) can look up the faulting address with [`find_overflow`] and report which stack
+//! overflowed instead of corrupting memory.
+//!
+//! Additionally, a canary value is written to the lowest word of each stack. A large stack frame
+//! can jump over the guard page, so [`check_canaries`] can be called on demand to verify that no
+//! stack was ever used up to its bottom.
+
+use super::{
+    map_page,
+    paging::{self, PagingError},
+    protection, MAPPER,
+};
+use crate::sync::SpinMutex;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    structures::paging::{mapper::MapToError, Page, PageTableFlags, Size4KiB, Translate},
+    VirtAddr,
+};
+
+/// Start of the virtual address range used for kernel stacks.
+pub const STACK_REGION_START: u64 = 0x_5555_0000_0000;
+
+/// Size of the virtual address range used for kernel stacks (1 GiB).
+pub const STACK_REGION_SIZE: u64 = 1 << 30;
+
+/// Maximum number of kernel stacks that can be registered.
+const MAX_STACKS: usize = 64;
+
+/// Maximum number of pages searched for the end of the boot stack.
+const MAX_BOOT_STACK_PAGES: u64 = 512;
+
+/// The value written to the lowest word of every kernel stack.
+pub const STACK_CANARY: u64 = 0x_57AC_CA4A_4279_0DE5;
+
+/// The size of a page.
+const PAGE_SIZE: u64 = 4096;
+
+/// All registered kernel stacks.
+static STACKS: SpinMutex<[Option<KernelStack>; MAX_STACKS]> =
+    SpinMutex::named("STACKS", [None; MAX_STACKS]);
+
+/// The start of the next unused address range in the kernel stack region.
+static NEXT_STACK: AtomicU64 = AtomicU64::new(STACK_REGION_START);
+
+/// A kernel stack with a guard page below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    /// The name reported when the stack overflows.
+    pub name: &'static str,
+    /// The lowest address of the stack, where the canary is stored.
+    pub bottom: VirtAddr,
+    /// The address one past the highest address of the stack, i.e. the initial stack pointer.
+    pub top: VirtAddr,
+}
+
+impl KernelStack {
+    /// The unmapped page directly below the stack.
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.bottom - 1u64)
+    }
+
+    /// Whether `addr` lies in the guard page of this stack.
+    pub fn is_guard_hit(&self, addr: VirtAddr) -> bool {
+        Page::containing_address(addr) == self.guard_page()
+    }
+
+    /// Whether the canary at the bottom of the stack is still intact.
+    pub fn canary_intact(&self) -> bool {
+        unsafe { self.bottom.as_ptr::<u64>().read_volatile() == STACK_CANARY }
+    }
+
+    fn write_canary(&self) {
+        unsafe { self.bottom.as_mut_ptr::<u64>().write_volatile(STACK_CANARY) };
+    }
+}
+
+/// The ways allocating a kernel stack can fail.
+#[derive(Debug)]
+pub enum StackError {
+    /// The virtual address range for kernel stacks is used up.
+    RegionExhausted,
+    /// There are already `MAX_STACKS` registered stacks.
+    TooManyStacks,
+    /// Mapping a stack page failed.
+    Map(MapToError<Size4KiB>),
+}
+
+/// Allocates a kernel stack of `pages` pages with a guard page below it.
+pub fn allocate(name: &'static str, pages: u64) -> Result<KernelStack, StackError> {
+    // Reserve one more page for the guard page, which is simply never mapped.
+    let size = (pages + 1) * PAGE_SIZE;
+    let start = NEXT_STACK.fetch_add(size, Ordering::Relaxed);
+    if start + size > STACK_REGION_START + STACK_REGION_SIZE {
+        return Err(StackError::RegionExhausted);
+    }
+
+    let stack = KernelStack {
+        name,
+        bottom: VirtAddr::new(start + PAGE_SIZE),
+        top: VirtAddr::new(start + size),
+    };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | protection::no_execute();
+    let pages = Page::range(
+        Page::containing_address(stack.bottom),
+        Page::containing_address(stack.top),
+    );
+    for page in pages {
+        map_page(page, flags).map_err(StackError::Map)?;
+    }
+
+    stack.write_canary();
+    register(stack)?;
+
+    Ok(stack)
+}
+
+/// Unmaps a stack allocated by [`allocate`], frees its frames and unregisters it.
+///
+/// The address range of the stack is not reused, the stack region is large enough for a lot of
+/// stacks.
+///
+/// # Safety
+///
+/// The stack must no longer be used.
+pub unsafe fn free(stack: KernelStack) -> Result<(), PagingError> {
+    if let Some(slot) = STACKS.lock().iter_mut().find(|slot| **slot == Some(stack)) {
+        *slot = None;
+    }
+    // `allocate` maps single 4 KiB frames, just like `map_anonymous` with 4 KiB pages.
+    paging::unmap_anonymous(stack.bottom, stack.top - stack.bottom)
+}
+
+/// Registers the stack the bootloader set up for us, so that its overflows are diagnosed too.
+///
+/// The bootloader maps the boot stack with an unmapped guard page below it. We don't know its
+/// location, so we walk the page tables from the current stack pointer down to the first unmapped
+/// page and up to the end of the stack.
+pub fn init_boot_stack() {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+
+    let (bottom, top) = {
+        let mapper = MAPPER.lock();
+        let mapper = mapper.as_ref().expect("paging not initialized");
+        let mapped = |page: Page| mapper.translate_addr(page.start_address()).is_some();
+
+        let current = Page::containing_address(VirtAddr::new(rsp));
+        let bottom = (1..MAX_BOOT_STACK_PAGES)
+            .map(|i| current - i)
+            .find(|&page| !mapped(page))
+            .map(|guard| guard + 1);
+        let top = (1..MAX_BOOT_STACK_PAGES)
+            .map(|i| current + i)
+            .find(|&page| !mapped(page))
+            .unwrap_or(current + MAX_BOOT_STACK_PAGES);
+
+        (bottom, top)
+    };
+
+    // Without a guard page, there is nothing to detect.
+    if let Some(bottom) = bottom {
+        let stack = KernelStack {
+            name: "boot",
+            bottom: bottom.start_address(),
+            top: top.start_address(),
+        };
+        stack.write_canary();
+        register(stack).expect("failed to register the boot stack");
+    }
+}
+
+fn register(stack: KernelStack) -> Result<(), StackError> {
+    let mut stacks = STACKS.lock();
+    let slot = stacks
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(StackError::TooManyStacks)?;
+    *slot = Some(stack);
+
+    Ok(())
+}
+
+/// Returns the stack whose guard page contains `addr`, if any.
+///
+/// Called from the page fault and double fault handlers, so it must not deadlock if the fault
+/// happened while the stack list was locked.
+pub fn find_overflow(addr: VirtAddr) -> Option<KernelStack> {
+    let stacks = STACKS.try_lock()?;
+    stacks
+        .iter()
+        .flatten()
+        .find(|stack| stack.is_guard_hit(addr))
+        .copied()
+}
+
+/// Checks the canaries of all registered stacks. Returns the first stack whose canary was
+/// overwritten.
+pub fn check_canaries() -> Result<(), KernelStack> {
+    match STACKS.lock().iter().flatten().find(|s| !s.canary_intact()) {
+        Some(stack) => Err(*stack),
+        None => Ok(()),
+    }
+}
+
+#[test_case]
+fn test_stack_has_guard_page() {
+    let stack = allocate("test", 2).unwrap();
+    assert_eq!(stack.top - stack.bottom, 2 * PAGE_SIZE);
+
+    {
+        let mapper = MAPPER.lock();
+        let mapper = mapper.as_ref().unwrap();
+        assert!(mapper.translate_addr(stack.bottom).is_some());
+        assert!(mapper.translate_addr(stack.top - 1u64).is_some());
+        assert!(mapper
+            .translate_addr(stack.guard_page().start_address())
+            .is_none());
+    }
+
+    assert_eq!(find_overflow(stack.bottom - 8u64), Some(stack));
+    assert_eq!(find_overflow(stack.bottom), None);
+}
+
+#[test_case]
+fn test_free_stack() {
+    let stack = allocate("free", 1).unwrap();
+    unsafe { free(stack).unwrap() };
+
+    let mapper = MAPPER.lock();
+    assert!(mapper
+        .as_ref()
+        .unwrap()
+        .translate_addr(stack.bottom)
+        .is_none());
+    assert!(STACKS.lock().iter().flatten().all(|s| *s != stack));
+}
+
+#[test_case]
+fn test_boot_stack_registered() {
+    let rsp = VirtAddr::from_ptr(&0u8);
+    let stacks = STACKS.lock();
+    let boot = stacks.iter().flatten().find(|s| s.name == "boot").unwrap();
+    assert!(boot.bottom <= rsp && rsp < boot.top);
+}
+
+#[test_case]
+fn test_canary_detects_corruption() {
+    let stack = allocate("canary", 1).unwrap();
+    assert_eq!(check_canaries(), Ok(()));
+
+    unsafe { stack.bottom.as_mut_ptr::<u64>().write_volatile(0) };
+    assert_eq!(check_canaries(), Err(stack));
+
+    stack.write_canary();
+    assert_eq!(check_canaries(), Ok(()));
+}
@@ -0,0 +1,153 @@
+//! # user
+//!
+//! Copies between kernel memory and the memory of the current process.
+//!
+//! Pointers passed by user code can't be trusted: they may point to kernel memory, to unmapped
+//! pages or to pages that don't allow the access. [`copy_from_user`] and [`copy_to_user`] first
+//! check that the range lies in the user range (see [`address_space::is_user_range`]), so the
+//! kernel never reads or writes its own memory on behalf of a process. Whether the pages are
+//! mapped is not checked in advance: the copy simply runs, and if it faults, the page fault
+//! handler sees that the faulting instruction is the copy loop and lets it continue at a fixup
+//! path instead of panicking (see [`fixup`]), so the copy fails with [`BadAddress`]. A write to a
+//! copy-on-write page or the first access to a lazily mapped page faults as well, but the handler
+//! resolves it and the copy simply continues.
+//!
+//! Checking the page tables first would not be enough anyway, since another thread of the
+//! process could unmap the pages between the check and the copy.
+
+use super::{address_space, protection};
+use core::arch::{asm, global_asm};
+use x86_64::{structures::idt::InterruptStackFrame, VirtAddr};
+
+/// A user pointer that the kernel can't access: outside of the user range, not mapped or not
+/// writable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadAddress;
+
+extern "C" {
+    /// Copies `len` bytes from `src` to `dst`. Returns the number of bytes not copied because of a
+    /// page fault, so 0 on success.
+    fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+
+    /// The instruction of [`copy_user`] that may fault.
+    fn copy_user_copy();
+
+    /// Where [`copy_user`] continues after a fault.
+    fn copy_user_fixup();
+}
+
+global_asm!(
+    "copy_user:",
+    "mov rcx, rdx",
+    // Interrupted by a fault, `rep movsb` leaves the number of remaining bytes in `rcx`.
+    "copy_user_copy:",
+    "rep movsb",
+    "xor eax, eax",
+    "ret",
+    "copy_user_fixup:",
+    "mov rax, rcx",
+    "ret",
+);
+
+/// Copies `dst.len()` bytes from the current process's memory at `src` to `dst`.
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), BadAddress> {
+    if !address_space::is_user_range(src, dst.len() as u64) {
+        return Err(BadAddress);
+    }
+    copy(dst.as_mut_ptr(), src.as_ptr(), dst.len())
+}
+
+/// Copies `src` to the current process's memory at `dst`.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), BadAddress> {
+    if !address_space::is_user_range(dst, src.len() as u64) {
+        return Err(BadAddress);
+    }
+    copy(dst.as_mut_ptr(), src.as_ptr(), src.len())
+}
+
+fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), BadAddress> {
+    match with_user_access(|| unsafe { copy_user(dst, src, len) }) {
+        0 => Ok(()),
+        _ => Err(BadAddress),
+    }
+}
+
+/// Runs `f` with access to user memory, which SMAP otherwise forbids.
+fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = protection::smap_enabled();
+    if smap {
+        unsafe { asm!("stac", options(nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+    result
+}
+
+/// Called by the page fault handler for faults in kernel mode. If the fault at `addr` happened in
+/// a copy from or to user memory, changes `stack_frame` to continue at the copy's fixup path and
+/// returns `true`.
+pub(crate) fn fixup(stack_frame: &mut InterruptStackFrame, addr: VirtAddr) -> bool {
+    let copy = copy_user_copy as *const () as u64;
+    if stack_frame.instruction_pointer.as_u64() != copy || !address_space::is_user_range(addr, 1) {
+        return false;
+    }
+    let fixup = VirtAddr::new(copy_user_fixup as *const () as u64);
+    unsafe {
+        stack_frame
+            .as_mut()
+            .update(|frame| frame.instruction_pointer = fixup)
+    };
+    true
+}
+
+#[cfg(test)]
+use crate::{
+    memory::{address_space::AddressSpace, vma::AreaKind},
+    thread,
+};
+#[cfg(test)]
+use x86_64::structures::paging::PageTableFlags;
+
+#[test_case]
+fn test_copy_rejects_kernel_and_unmapped_memory() {
+    let mut buffer = [0u8; 8];
+    let kernel = VirtAddr::from_ptr(&buffer);
+    assert_eq!(copy_from_user(&mut [0; 8], kernel), Err(BadAddress));
+    assert_eq!(copy_to_user(kernel, b"12345678"), Err(BadAddress));
+    // The kernel's address space maps nothing in the user range, so the copy faults.
+    let user = VirtAddr::new(address_space::USER_START);
+    assert_eq!(copy_from_user(&mut buffer, user), Err(BadAddress));
+}
+
+#[test_case]
+fn test_copy_recovers_from_faults_in_user_memory() {
+    let mut space = AddressSpace::new().unwrap();
+    let start = VirtAddr::new(address_space::USER_START);
+    space
+        .map(start, 4096, PageTableFlags::WRITABLE, AreaKind::Anonymous)
+        .unwrap();
+    let read_only = start + 4096u64;
+    space
+        .map(
+            read_only,
+            4096,
+            PageTableFlags::empty(),
+            AreaKind::Anonymous,
+        )
+        .unwrap();
+
+    unsafe { thread::set_page_table(space.page_table()) };
+    let mut buffer = [0u8; 6];
+    let copied = copy_to_user(start + 10u64, b"kernel")
+        .and_then(|()| copy_from_user(&mut buffer, start + 10u64));
+    let into_unmapped = copy_from_user(&mut [0; 8], read_only + 4092u64);
+    let into_read_only = copy_to_user(read_only - 4u64, b"12345678");
+    unsafe { thread::set_page_table(super::kernel_page_table()) };
+
+    assert_eq!(copied, Ok(()));
+    assert_eq!(&buffer, b"kernel");
+    assert_eq!(into_unmapped, Err(BadAddress));
+    assert_eq!(into_read_only, Err(BadAddress));
+}
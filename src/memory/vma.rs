@@ -0,0 +1,397 @@
+//! # vma
+//!
+//! Virtual memory areas: the ranges of user memory a process has mapped, with their protection.
+//!
+//! The page tables of an address space only say which pages are present right now. Most pages
+//! of a mapping are not, until they are first touched (see [`super::address_space`]), so the
+//! address space also keeps the mappings themselves as [`Area`]s in [`Areas`], a tree ordered by
+//! start address. Changing the protection of or unmapping part of an area splits it, and
+//! neighbouring areas that became alike are merged again, so the tree stays as small as the
+//! mappings are different.
+
+use super::protection;
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{fmt, ops::Range};
+use x86_64::structures::paging::PageTableFlags;
+
+/// The access rights of an area, with the bits of the `prot` argument of `mmap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection(u8);
+
+impl Protection {
+    pub const NONE: Protection = Protection(0);
+    pub const READ: Protection = Protection(1);
+    pub const WRITE: Protection = Protection(2);
+    pub const EXEC: Protection = Protection(4);
+
+    /// The protection with the bits `bits`, if they are all known.
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        (bits & !7 == 0).then_some(Protection(bits as u8))
+    }
+
+    /// The protection of pages mapped with `flags`. Every present page can be read.
+    pub fn from_page_table_flags(flags: PageTableFlags) -> Self {
+        let mut protection = Protection::READ;
+        if flags.contains(PageTableFlags::WRITABLE) {
+            protection = protection | Protection::WRITE;
+        }
+        if !flags.contains(PageTableFlags::NO_EXECUTE) {
+            protection = protection | Protection::EXEC;
+        }
+        protection
+    }
+
+    pub fn contains(self, other: Protection) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The flags of a present page with this protection. A writable page that shares its frame
+    /// is mapped copy-on-write instead, see [`super::address_space::COPY_ON_WRITE`]. Without any
+    /// access, the page is only accessible to the kernel.
+    pub fn page_table_flags(self, shared: bool) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self != Protection::NONE {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if self.contains(Protection::WRITE) {
+            flags |= if shared {
+                super::address_space::COPY_ON_WRITE
+            } else {
+                PageTableFlags::WRITABLE
+            };
+        }
+        if !self.contains(Protection::EXEC) {
+            flags |= protection::no_execute();
+        }
+        flags
+    }
+}
+
+impl core::ops::BitOr for Protection {
+    type Output = Protection;
+
+    fn bitor(self, other: Protection) -> Protection {
+        Protection(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Protection {
+    /// Like `/proc/<pid>/maps`, e.g. `r-x`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |bit: Protection, name| if self.contains(bit) { name } else { "-" };
+        write!(
+            f,
+            "{}{}{}",
+            flag(Protection::READ, "r"),
+            flag(Protection::WRITE, "w"),
+            flag(Protection::EXEC, "x")
+        )
+    }
+}
+
+/// What an area was mapped for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaKind {
+    /// Code or data of the program.
+    Image,
+    /// The initial stack.
+    Stack,
+    /// Anonymous memory from `mmap`.
+    Anonymous,
+    /// A private mapping of the file `inode` of [`crate::fs`] from `offset` on, also from `mmap`.
+    /// Its pages are copies of the file, made when they are first touched.
+    File { inode: usize, offset: u64 },
+}
+
+impl AreaKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AreaKind::Image => "[image]",
+            AreaKind::Stack => "[stack]",
+            AreaKind::Anonymous => "[anon]",
+            AreaKind::File { .. } => "[file]",
+        }
+    }
+
+    /// The kind of the part of an area of this kind that starts `bytes` into it.
+    fn advanced(self, bytes: u64) -> Self {
+        match self {
+            AreaKind::File { inode, offset } => AreaKind::File {
+                inode,
+                offset: offset + bytes,
+            },
+            kind => kind,
+        }
+    }
+}
+
+/// A mapped range of user memory, page aligned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Area {
+    pub start: u64,
+    pub end: u64,
+    pub protection: Protection,
+    pub kind: AreaKind,
+}
+
+impl Area {
+    pub fn contains(&self, addr: u64) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    /// Whether `self`, which ends where `next` starts, can be merged with it.
+    fn mergeable(&self, next: &Area) -> bool {
+        self.end == next.start
+            && self.protection == next.protection
+            && self.kind.advanced(self.end - self.start) == next.kind
+    }
+}
+
+/// The areas of an address space, which never overlap.
+#[derive(Debug, Clone, Default)]
+pub struct Areas {
+    /// By their start addresses.
+    areas: BTreeMap<u64, Area>,
+}
+
+impl Areas {
+    pub const fn new() -> Self {
+        Areas {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// The area containing `addr`.
+    pub fn find(&self, addr: u64) -> Option<&Area> {
+        let (_, area) = self.areas.range(..=addr).next_back()?;
+        area.contains(addr).then_some(area)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Area> {
+        self.areas.values()
+    }
+
+    /// Whether any area overlaps `range`.
+    pub fn overlaps(&self, range: Range<u64>) -> bool {
+        self.find(range.start).is_some() || self.areas.range(range).next().is_some()
+    }
+
+    /// Whether areas cover all of `range`, without gaps.
+    pub fn covers(&self, range: Range<u64>) -> bool {
+        let mut covered = range.start;
+        while covered < range.end {
+            match self.find(covered) {
+                Some(area) => covered = area.end,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Whether areas cover all of `range` and allow `access` everywhere in it.
+    pub fn allow(&self, range: Range<u64>, access: Protection) -> bool {
+        let mut checked = range.start;
+        while checked < range.end {
+            match self.find(checked) {
+                Some(area) if area.protection.contains(access) => checked = area.end,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// The lowest start address of a gap of `size` bytes within `within`.
+    pub fn find_free(&self, size: u64, within: Range<u64>) -> Option<u64> {
+        let mut start = within.start;
+        for area in self.areas.values() {
+            if area.end <= start {
+                continue;
+            }
+            if area.start >= start.checked_add(size)? {
+                break;
+            }
+            start = area.end;
+        }
+        (start.checked_add(size)? <= within.end).then_some(start)
+    }
+
+    /// Adds `area`, which must not overlap any other, and merges it with its neighbours.
+    pub fn insert(&mut self, area: Area) {
+        assert!(!self.overlaps(area.start..area.end), "overlapping areas");
+        let range = area.start..area.end;
+        self.areas.insert(area.start, area);
+        self.merge(range);
+    }
+
+    /// Removes `range` from the areas, splitting the ones that only partly overlap it. Returns
+    /// the removed parts.
+    pub fn remove(&mut self, range: Range<u64>) -> Vec<Area> {
+        self.split(range.start);
+        self.split(range.end);
+        let starts: Vec<u64> = self.areas.range(range).map(|(&start, _)| start).collect();
+        starts
+            .into_iter()
+            .filter_map(|start| self.areas.remove(&start))
+            .collect()
+    }
+
+    /// Changes the protection of `range`, which areas must cover. Returns `false` otherwise,
+    /// without changing anything.
+    pub fn protect(&mut self, range: Range<u64>, protection: Protection) -> bool {
+        if !self.covers(range.clone()) {
+            return false;
+        }
+        self.split(range.start);
+        self.split(range.end);
+        for area in self.areas.range_mut(range.clone()).map(|(_, area)| area) {
+            area.protection = protection;
+        }
+        self.merge(range);
+        true
+    }
+
+    /// Splits the area containing `at` into one that ends and one that starts there.
+    fn split(&mut self, at: u64) {
+        let area = match self.areas.range_mut(..at).next_back() {
+            Some((_, area)) if area.contains(at) => area,
+            _ => return,
+        };
+        let upper = Area {
+            start: at,
+            kind: area.kind.advanced(at - area.start),
+            ..area.clone()
+        };
+        area.end = at;
+        self.areas.insert(at, upper);
+    }
+
+    /// Merges the mergeable areas overlapping `range`, and the ones adjacent to it.
+    fn merge(&mut self, range: Range<u64>) {
+        let first = match self.areas.range(..range.start).next_back() {
+            Some((&start, area)) if area.end == range.start => start,
+            _ => range.start,
+        };
+        let starts: Vec<u64> = self
+            .areas
+            .range(first..=range.end)
+            .map(|(&start, _)| start)
+            .collect();
+        let mut current = match starts.first() {
+            Some(&start) => start,
+            None => return,
+        };
+        for &next in &starts[1..] {
+            if self.areas[&current].mergeable(&self.areas[&next]) {
+                let next = self.areas.remove(&next).unwrap();
+                self.areas.get_mut(&current).unwrap().end = next.end;
+            } else {
+                current = next;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn area(range: Range<u64>, protection: Protection) -> Area {
+    Area {
+        start: range.start,
+        end: range.end,
+        protection,
+        kind: AreaKind::Anonymous,
+    }
+}
+
+#[cfg(test)]
+fn ranges(areas: &Areas) -> Vec<(Range<u64>, Protection)> {
+    areas
+        .iter()
+        .map(|area| (area.start..area.end, area.protection))
+        .collect()
+}
+
+#[test_case]
+fn test_areas_split_and_merge() {
+    let rw = Protection::READ | Protection::WRITE;
+    let mut areas = Areas::new();
+    areas.insert(area(0x1000..0x3000, rw));
+    areas.insert(area(0x3000..0x5000, rw));
+    areas.insert(area(0x8000..0x9000, Protection::READ));
+    assert_eq!(
+        ranges(&areas),
+        [(0x1000..0x5000, rw), (0x8000..0x9000, Protection::READ)]
+    );
+    assert_eq!(areas.find(0x4fff).map(|area| area.start), Some(0x1000));
+    assert!(areas.find(0x5000).is_none());
+
+    assert!(areas.protect(0x2000..0x3000, Protection::READ));
+    assert_eq!(
+        ranges(&areas),
+        [
+            (0x1000..0x2000, rw),
+            (0x2000..0x3000, Protection::READ),
+            (0x3000..0x5000, rw),
+            (0x8000..0x9000, Protection::READ)
+        ]
+    );
+    assert!(
+        !areas.protect(0x4000..0x9000, Protection::READ),
+        "not covered"
+    );
+    assert!(areas.protect(0x2000..0x3000, rw));
+    assert_eq!(areas.iter().count(), 2);
+
+    let removed = areas.remove(0x2000..0x8800);
+    assert_eq!(
+        removed,
+        [
+            area(0x2000..0x5000, rw),
+            area(0x8000..0x8800, Protection::READ)
+        ]
+    );
+    assert_eq!(
+        ranges(&areas),
+        [(0x1000..0x2000, rw), (0x8800..0x9000, Protection::READ)]
+    );
+}
+
+#[test_case]
+fn test_areas_find_free_gaps() {
+    let mut areas = Areas::new();
+    areas.insert(area(0x2000..0x3000, Protection::READ));
+    areas.insert(area(0x4000..0x6000, Protection::READ));
+    assert_eq!(areas.find_free(0x1000, 0x1000..0x10000), Some(0x1000));
+    assert_eq!(areas.find_free(0x2000, 0x1000..0x10000), Some(0x6000));
+    assert_eq!(areas.find_free(0x1000, 0x2000..0x10000), Some(0x3000));
+    assert_eq!(areas.find_free(0x1000, 0x4000..0x6000), None);
+    assert!(areas.overlaps(0x1000..0x2001));
+    assert!(!areas.overlaps(0x3000..0x4000));
+}
+
+#[test_case]
+fn test_file_areas_keep_their_offsets() {
+    let file = |range: Range<u64>, offset| Area {
+        kind: AreaKind::File { inode: 1, offset },
+        ..area(range, Protection::READ)
+    };
+    let mut areas = Areas::new();
+    areas.insert(file(0x1000..0x4000, 0x2000));
+    assert!(areas.protect(0x2000..0x3000, Protection::NONE));
+    assert_eq!(
+        areas.find(0x3000).map(|area| area.kind),
+        Some(AreaKind::File {
+            inode: 1,
+            offset: 0x4000
+        })
+    );
+    assert!(areas.protect(0x2000..0x3000, Protection::READ));
+    assert_eq!(
+        areas.iter().cloned().collect::<Vec<_>>(),
+        [file(0x1000..0x4000, 0x2000)]
+    );
+
+    // Adjacent, but not the next part of the file.
+    areas.insert(file(0x4000..0x5000, 0));
+    assert_eq!(areas.iter().count(), 2);
+    assert!(!areas.allow(0x1000..0x6000, Protection::READ));
+    assert!(areas.allow(0x1000..0x5000, Protection::READ));
+}
@@ -0,0 +1,445 @@
+//! # percpu
+//!
+//! Per-CPU data, addressed through the GS segment base.
+//!
+//! Statics declared with [`per_cpu!`] are placed in the `.percpu` section (see `linker.ld`),
+//! which is only a template: [`init`] gives every CPU its own copy of the whole section, in a
+//! per-CPU *area*, and points the GS base of the CPU to it. A [`PerCpu`] static then finds the
+//! current CPU's copy at the same offset in the area of the current CPU, without any locking.
+//!
+//! The area starts with a small header, which is read and written with `gs:`-relative
+//! instructions. It holds the preemption count: [`PerCpu::get`] disables preemption while the
+//! value is borrowed, so no other thread on the same CPU can use it at the same time. Interrupt
+//! handlers still can, so values that handlers change should be atomics or `Cell`s. The header
+//! also holds the kernel stack the `syscall` entry switches to, since that instruction doesn't
+//! switch stacks itself (see [`crate::syscall`]).
+//!
+//! In the kernel, the GS base always points to the area. When user mode code is interrupted,
+//! `swapgs` exchanges its GS base with the kernel's (kept in `IA32_KERNEL_GS_BASE` while user code
+//! runs), see [`InterruptEntry`].
+//!
+//! The header also counts the hardware interrupt handlers the CPU is running, for
+//! [`in_interrupt`]. A thread can be switched out in the timer interrupt handler, so the count is
+//! saved and restored with the rest of the thread's context (see [`crate::thread`]).
+//!
+//! The interrupts and context switches of each CPU are counted in per-CPU counters, which
+//! [`print_stats`] prints.
+
+use crate::{serial_println, smp};
+use core::{
+    alloc::Layout,
+    arch::asm,
+    marker::PhantomData,
+    mem::{offset_of, size_of},
+    ops::Deref,
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use x86_64::{
+    instructions::{interrupts, segmentation::GS},
+    registers::model_specific::{GsBase, KernelGsBase},
+    structures::idt::InterruptStackFrame,
+    VirtAddr,
+};
+
+extern "C" {
+    // Defined by `linker.ld`. Only their addresses are meaningful.
+    static __percpu_start: u8;
+    static __percpu_end: u8;
+}
+
+/// Declares statics with a separate value for each CPU, see [`PerCpu`].
+///
+/// ```ignore
+/// per_cpu! {
+///     /// The number of page faults on each CPU.
+///     pub static PAGE_FAULTS: AtomicU64 = AtomicU64::new(0);
+/// }
+/// ```
+#[macro_export]
+macro_rules! per_cpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            #[link_section = ".percpu"]
+            $vis static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new($init);
+        )*
+    };
+}
+
+per_cpu! {
+    /// The hardware interrupts handled by each CPU.
+    pub static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+    /// The context switches on each CPU.
+    pub static CONTEXT_SWITCHES: AtomicU64 = AtomicU64::new(0);
+}
+
+/// The start of the per-CPU area of each CPU, once initialized.
+static AREAS: [AtomicU64; smp::MAX_CPUS] = [const { AtomicU64::new(0) }; smp::MAX_CPUS];
+
+/// Set once the BSP's area is initialized, see [`is_initialized`].
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// The header of a per-CPU area, followed by the copy of the `.percpu` section at
+/// [`DATA_OFFSET`].
+#[repr(C)]
+struct Header {
+    /// The address of the area itself.
+    this: u64,
+    cpu: u64,
+    /// Preemption is disabled while this is not 0.
+    preempt_count: u64,
+    /// Set if the timer wanted to preempt the thread while preemption was disabled.
+    need_resched: u64,
+    /// A copy of `RSP0` of the TSS, see [`set_kernel_stack`].
+    kernel_stack: u64,
+    /// Where the `syscall` entry saves the user stack pointer until it is on the kernel stack.
+    user_stack: u64,
+    /// The number of nested hardware interrupt handlers running, see [`in_interrupt`].
+    interrupt_depth: u64,
+}
+
+/// The offset of the kernel stack in the header, for the `syscall` entry.
+pub(crate) const KERNEL_STACK_OFFSET: usize = offset_of!(Header, kernel_stack);
+/// The offset of the saved user stack pointer in the header, for the `syscall` entry.
+pub(crate) const USER_STACK_OFFSET: usize = offset_of!(Header, user_stack);
+
+/// The offset of the data in an area. The `.percpu` section is 64-byte aligned too.
+const DATA_OFFSET: usize = 64;
+const _: () = assert!(size_of::<Header>() <= DATA_OFFSET);
+
+/// Allocates the per-CPU area of the current CPU and points its GS base to it. Called on every
+/// CPU, before it enables interrupts.
+pub fn init(cpu: usize) {
+    let (start, end) = (addr_of!(__percpu_start), addr_of!(__percpu_end));
+    let len = end as usize - start as usize;
+    let layout = Layout::from_size_align(DATA_OFFSET + len, DATA_OFFSET).unwrap();
+    let area = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    assert!(!area.is_null(), "failed to allocate the per-CPU area");
+
+    unsafe {
+        area.add(DATA_OFFSET).copy_from_nonoverlapping(start, len);
+        area.cast::<Header>().write(Header {
+            this: area as u64,
+            cpu: cpu as u64,
+            preempt_count: 0,
+            need_resched: 0,
+            kernel_stack: 0,
+            user_stack: 0,
+            interrupt_depth: 0,
+        });
+    }
+    GsBase::write(VirtAddr::from_ptr(area));
+    // The GS base of user mode, swapped in on return to user mode.
+    KernelGsBase::write(VirtAddr::zero());
+    AREAS[cpu].store(area as u64, Ordering::Release);
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+/// Whether the GS base points to the current CPU's area. Only false early at boot, until [`init`]
+/// was called for the BSP: the APs call it before anything else.
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Acquire)
+}
+
+/// Reads a header field of the current CPU's area.
+macro_rules! read_header {
+    ($field:ident) => {{
+        let value: u64;
+        unsafe {
+            asm!(
+                "mov {}, gs:[{offset}]",
+                out(reg) value,
+                offset = const offset_of!(Header, $field),
+                options(nostack, readonly, preserves_flags)
+            )
+        };
+        value
+    }};
+}
+
+/// Writes a header field of the current CPU's area.
+macro_rules! write_header {
+    ($field:ident, $value:expr) => {{
+        let value: u64 = $value;
+        unsafe {
+            asm!(
+                "mov gs:[{offset}], {}",
+                in(reg) value,
+                offset = const offset_of!(Header, $field),
+                options(nostack, preserves_flags)
+            )
+        };
+    }};
+}
+
+/// The number of the current CPU, from its per-CPU area.
+pub fn cpu_id() -> usize {
+    read_header!(cpu) as usize
+}
+
+/// A value with a separate instance for each CPU, declared with [`per_cpu!`].
+///
+/// The static itself is only the template for the instances, and never changes.
+#[repr(transparent)]
+pub struct PerCpu<T> {
+    template: T,
+}
+
+// Each CPU gets its own copy of the template, so the value must be allowed to move to another
+// CPU. Through `get`, a CPU only accesses its own instance; `for_cpu` shares the instances of
+// other CPUs, and requires `T: Sync` for that.
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(value: T) -> Self {
+        PerCpu { template: value }
+    }
+
+    /// The offset of the value in the data of a per-CPU area.
+    fn offset(&'static self) -> usize {
+        self as *const Self as usize - addr_of!(__percpu_start) as usize
+    }
+
+    /// Borrows the instance of the current CPU. Preemption is disabled until it is dropped, so
+    /// the thread stays on this CPU and no other thread uses the instance in the meantime.
+    pub fn get(&'static self) -> PerCpuRef<T> {
+        let guard = PreemptGuard::new();
+        let area = read_header!(this) as usize;
+        let value = unsafe { &*((area + DATA_OFFSET + self.offset()) as *const T) };
+        PerCpuRef {
+            value,
+            _guard: guard,
+        }
+    }
+
+    /// Runs `f` with the instance of the current CPU.
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.get())
+    }
+
+    /// The instance of another CPU, or `None` if its area wasn't initialized yet.
+    pub fn for_cpu(&'static self, cpu: usize) -> Option<&'static T>
+    where
+        T: Sync,
+    {
+        let area = AREAS.get(cpu)?.load(Ordering::Acquire) as usize;
+        if area == 0 {
+            return None;
+        }
+        Some(unsafe { &*((area + DATA_OFFSET + self.offset()) as *const T) })
+    }
+}
+
+/// The borrowed instance of a [`PerCpu`] of the current CPU.
+pub struct PerCpuRef<T: 'static> {
+    value: &'static T,
+    _guard: PreemptGuard,
+}
+
+impl<T> Deref for PerCpuRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+/// Disables preemption of the current thread until dropped. Can be nested.
+pub struct PreemptGuard {
+    /// Bound to the current CPU.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl PreemptGuard {
+    pub fn new() -> Self {
+        // A single instruction, so an interrupt can't split the update.
+        unsafe {
+            asm!(
+                "inc qword ptr gs:[{offset}]",
+                offset = const offset_of!(Header, preempt_count),
+                options(nostack)
+            )
+        };
+        PreemptGuard {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for PreemptGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        unsafe {
+            asm!(
+                "dec qword ptr gs:[{offset}]",
+                offset = const offset_of!(Header, preempt_count),
+                options(nostack)
+            )
+        };
+        // Catch up on a preemption that was held back, unless in an interrupt handler or with
+        // interrupts disabled for another reason.
+        if preemptible() && read_header!(need_resched) != 0 && interrupts::are_enabled() {
+            write_header!(need_resched, 0);
+            crate::thread::yield_now();
+        }
+    }
+}
+
+/// Whether the running thread can be preempted.
+pub fn preemptible() -> bool {
+    read_header!(preempt_count) == 0
+}
+
+/// Remembers that the timer wanted to preempt the running thread, see [`PreemptGuard`].
+pub(crate) fn set_need_resched() {
+    write_header!(need_resched, 1);
+}
+
+/// Whether the current CPU is running a hardware interrupt handler. Exceptions like page faults
+/// run in the context of the code that caused them, so they don't count.
+pub fn in_interrupt() -> bool {
+    is_initialized() && read_header!(interrupt_depth) != 0
+}
+
+/// The number of nested interrupt handlers, saved by a thread that is switched out.
+pub(crate) fn interrupt_depth() -> u64 {
+    read_header!(interrupt_depth)
+}
+
+/// Restores the number of nested interrupt handlers of a thread that is switched in.
+pub(crate) fn set_interrupt_depth(depth: u64) {
+    write_header!(interrupt_depth, depth);
+}
+
+/// Sets the stack the `syscall` entry of the current CPU switches to. Called together with setting
+/// `RSP0`, see [`crate::gdt::set_kernel_stack`].
+pub(crate) fn set_kernel_stack(top: VirtAddr) {
+    write_header!(kernel_stack, top.as_u64());
+}
+
+/// Switches to the kernel's GS base when an interrupt arrives in user mode, and back when it is
+/// dropped. Created first in every interrupt handler that can interrupt user mode.
+pub struct InterruptEntry {
+    from_user: bool,
+    /// Whether [`in_interrupt`] counts this entry.
+    interrupt: bool,
+}
+
+impl InterruptEntry {
+    /// For hardware interrupts, which are also counted, and which end an idle period.
+    pub fn interrupt(stack_frame: &InterruptStackFrame) -> Self {
+        let mut entry = Self::exception(stack_frame);
+        unsafe {
+            asm!(
+                "inc qword ptr gs:[{offset}]",
+                offset = const offset_of!(Header, interrupt_depth),
+                options(nostack)
+            )
+        };
+        entry.interrupt = true;
+        INTERRUPTS.with(|count| count.fetch_add(1, Ordering::Relaxed));
+        crate::time::tick::exit_idle();
+        entry
+    }
+
+    /// For exceptions, which may happen before the per-CPU area is initialized.
+    pub fn exception(stack_frame: &InterruptStackFrame) -> Self {
+        // The requested privilege level of the interrupted code segment.
+        let from_user = stack_frame.code_segment & 3 == 3;
+        if from_user {
+            unsafe { GS::swap() };
+        }
+        InterruptEntry {
+            from_user,
+            interrupt: false,
+        }
+    }
+}
+
+impl Drop for InterruptEntry {
+    fn drop(&mut self) {
+        if self.interrupt {
+            unsafe {
+                asm!(
+                    "dec qword ptr gs:[{offset}]",
+                    offset = const offset_of!(Header, interrupt_depth),
+                    options(nostack)
+                )
+            };
+        }
+        if self.from_user {
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+/// Prints the per-CPU counters over serial.
+pub fn print_stats() {
+    serial_println!("{:>4} {:>12} {:>12}", "CPU", "INTERRUPTS", "SWITCHES");
+    for cpu in 0..smp::cpu_count() {
+        let load = |counter: &'static PerCpu<AtomicU64>| {
+            counter
+                .for_cpu(cpu)
+                .map_or(0, |count| count.load(Ordering::Relaxed))
+        };
+        serial_println!(
+            "{:>4} {:>12} {:>12}",
+            cpu,
+            load(&INTERRUPTS),
+            load(&CONTEXT_SWITCHES)
+        );
+    }
+}
+
+#[test_case]
+fn test_instances_are_separate_from_template() {
+    per_cpu! {
+        static VALUE: AtomicU64 = AtomicU64::new(7);
+    }
+
+    assert_eq!(VALUE.with(|value| value.swap(8, Ordering::Relaxed)), 7);
+    assert_eq!(VALUE.get().load(Ordering::Relaxed), 8);
+    assert_eq!(VALUE.template.load(Ordering::Relaxed), 7);
+    assert_eq!(VALUE.for_cpu(0).unwrap().load(Ordering::Relaxed), 8);
+    // The other CPUs got copies of the template before.
+    for cpu in 1..smp::cpu_count() {
+        assert_eq!(VALUE.for_cpu(cpu).unwrap().load(Ordering::Relaxed), 7);
+    }
+}
+
+#[test_case]
+fn test_borrow_disables_preemption() {
+    assert!(preemptible());
+    let count = INTERRUPTS.get();
+    assert!(!preemptible());
+    let nested = CONTEXT_SWITCHES.get();
+    drop(count);
+    assert!(!preemptible());
+    drop(nested);
+    assert!(preemptible());
+}
+
+#[test_case]
+fn test_interrupts_are_counted() {
+    let before = INTERRUPTS.with(|count| count.load(Ordering::Relaxed));
+    x86_64::instructions::hlt();
+    assert!(INTERRUPTS.with(|count| count.load(Ordering::Relaxed)) > before);
+    assert_eq!(cpu_id(), 0);
+}
+
+#[test_case]
+fn test_interrupt_handlers_are_interrupt_context() {
+    use core::time::Duration;
+
+    assert!(!in_interrupt());
+    // Switched back in by the timer interrupt handler of another thread.
+    crate::thread::sleep(Duration::from_millis(5));
+    assert!(!in_interrupt());
+}
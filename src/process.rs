@@ -0,0 +1,1268 @@
+//! # process
+//!
+//! User-mode processes.
+//!
+//! A process is a kernel thread that runs code in ring 3, in its own [`AddressSpace`].
+//! [`spawn_elf`] loads an ELF executable (see [`elf`]), maps a stack below [`STACK_TOP`] with the
+//! arguments and the environment on it (see [`stack`]) and starts the thread, which switches to
+//! the address space and enters user mode with `iretq` (see [`entry`]). [`spawn`] runs a flat
+//! binary instead, i.e. plain machine code loaded at [`CODE_START`], which is handy for tests.
+//!
+//! User code talks to the kernel with system calls (see [`crate::syscall`]). Every interrupt or
+//! exception in user mode enters the kernel on the thread's kernel stack, and the thread can be
+//! preempted there like any other thread.
+//!
+//! A process ends with the `exit` system call, or is killed by a signal, e.g. when it causes a
+//! CPU exception: the exception handler prints a report (see [`fault`]), the process's memory is
+//! freed and the kernel keeps running. Either way, [`Process::wait`] returns the [`ExitStatus`].
+//!
+//! ## The process table
+//!
+//! Every process has a [`Pid`] and an entry in the process table, which also records its parent.
+//! [`fork`] starts a child with a copy-on-write copy of the parent's memory (see
+//! [`AddressSpace::duplicate`]) and its registers, and [`exec`] replaces the program of the
+//! current process with one from [`programs`]. When a process with a parent ends, its entry stays
+//! in the table as a *zombie* with the exit status, until the parent collects it with
+//! [`wait_child`] (the `waitpid` system call). The children of an ending process are handed to
+//! the init process, PID 1, which [`spawn_init`] starts at boot and which collects them. Outside
+//! of tests, init is the `init` program of the `user` crate, which also starts the shell (see
+//! [`programs`]). Processes started by the kernel with [`spawn`] or [`spawn_elf`] have no parent: the
+//! kernel waits for them with [`Process::wait`] instead.
+//!
+//! [`print_processes`], or the serial command `ps`, prints the process table.
+//!
+//! ## Signals
+//!
+//! [`send`] (the `kill` system call) posts a [`Signal`] to a process, which handles it the next
+//! time it returns to user mode (see [`signal`]). A process blocked in a system call is woken up,
+//! and the call fails with `EINTR`. CPU exceptions raise signals too, see [`Fault::signal`], and
+//! Ctrl+C on the keyboard sends `SIGINT` to the foreground process (see [`set_foreground`]).
+//! Signals with a default action only end the process with the signal in its status; init only
+//! gets the signals it has handlers for.
+//!
+//! ## Files
+//!
+//! Every process has a table of open files (see [`crate::file`]), which starts with the console as
+//! stdin, stdout and stderr. A child gets a copy of its parent's table, sharing the open files,
+//! and [`exec`] closes the ones marked close-on-exec. The files of a process are closed when it
+//! ends, so the reader of a pipe sees the end of file once all writers ended.
+//! Every process also has a current directory in the file system (see [`crate::fs`]), which
+//! relative paths start from. It starts as `/`, and a child gets its parent's.
+//! [`print_files`], or the serial command `fds <pid>`, lists them.
+
+pub mod elf;
+mod entry;
+pub mod programs;
+pub mod signal;
+pub mod stack;
+
+use crate::{
+    file::FdTable,
+    memory::{
+        self,
+        address_space::{self, AddressSpace},
+        paging::PagingError,
+        protection,
+        stack::StackError,
+        vma::{AreaKind, Protection},
+    },
+    println, serial_println,
+    sync::{self, IrqMutex, WaitList},
+    syscall::{Registers, SYS_SLEEP, SYS_WAITPID},
+    thread::{self, JoinHandle, ThreadId},
+};
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt, mem,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use elf::ElfError;
+pub use signal::Signal;
+use signal::{Action, DefaultAction, SigSet, Signals, SA_NODEFER, SA_RESETHAND};
+use x86_64::{
+    instructions::interrupts,
+    structures::{
+        idt::{InterruptStackFrame, InterruptStackFrameValue},
+        paging::PageTableFlags,
+    },
+    VirtAddr,
+};
+
+/// Where [`spawn`] loads the code.
+pub const CODE_START: u64 = address_space::USER_START;
+
+/// The initial stack pointer of a process, at the end of user memory.
+pub const STACK_TOP: u64 = address_space::USER_END;
+
+/// The number of pages of the user stack of flat binaries.
+const STACK_PAGES: u64 = 16;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The code of the init process: it waits for any child in a loop, and sleeps for a second
+/// whenever it has none.
+#[rustfmt::skip]
+const INIT_CODE: &[u8] = &[
+    0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff,  // mov rdi, -1 (any child)
+    0x31, 0xf6,                                // xor esi, esi (no status)
+    0x31, 0xd2,                                // xor edx, edx (no options)
+    0xb8, SYS_WAITPID as u8, 0x00, 0x00, 0x00, // mov eax, SYS_WAITPID
+    0x0f, 0x05,                                // syscall
+    0x48, 0x85, 0xc0,                          // test rax, rax
+    0x79, 0xe9,                                // jns 0 (collected one)
+    0xbf, 0x00, 0xca, 0x9a, 0x3b,              // mov edi, 1000000000 (1 s)
+    0xb8, SYS_SLEEP as u8, 0x00, 0x00, 0x00,   // mov eax, SYS_SLEEP
+    0x0f, 0x05,                                // syscall
+    0xeb, 0xdb,                                // jmp 0
+];
+
+/// The process table.
+static PROCESSES: IrqMutex<Table> = IrqMutex::named(
+    "PROCESSES",
+    Table {
+        processes: BTreeMap::new(),
+        threads: BTreeMap::new(),
+        waiters: WaitList::new(),
+        foreground: None,
+    },
+);
+
+struct Table {
+    processes: BTreeMap<Pid, Entry>,
+    /// The processes of the running threads.
+    threads: BTreeMap<ThreadId, Pid>,
+    /// The threads in [`wait_child`] and of stopped processes, woken whenever a process ends or
+    /// continues.
+    waiters: WaitList,
+    /// The process Ctrl+C interrupts, see [`set_foreground`].
+    foreground: Option<Pid>,
+}
+
+struct Entry {
+    name: String,
+    parent: Option<Pid>,
+    /// Set once the thread runs.
+    thread: Option<ThreadId>,
+    state: State,
+    /// Taken out while the process's own thread works with it, see [`with_space`], and when it
+    /// ends.
+    space: Option<AddressSpace>,
+    signals: Signals,
+    files: FdTable,
+    /// The current directory, an absolute path.
+    cwd: String,
+    /// The interrupt stack frame of the user code, while an interrupt handler is diverted to the
+    /// delivery of signals, see [`entry::divert`].
+    diverted: Option<InterruptStackFrameValue>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    /// Stopped by a signal, until it gets [`Signal::SIGCONT`] or [`Signal::SIGKILL`].
+    Stopped,
+    /// Ended, but the thread still frees the process's memory.
+    Exiting(ExitStatus),
+    /// Ended, waiting for the parent to collect the status.
+    Zombie(ExitStatus),
+}
+
+impl State {
+    fn name(self) -> &'static str {
+        match self {
+            State::Running => "running",
+            State::Stopped => "stopped",
+            State::Exiting(_) => "exiting",
+            State::Zombie(_) => "zombie",
+        }
+    }
+}
+
+/// A unique process ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    /// The init process, see [`spawn_init`].
+    pub const INIT: Pid = Pid(1);
+
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(2);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// The CPU exceptions that raise a signal in a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    DivideError,
+    InvalidOpcode,
+    GeneralProtection,
+    PageFault,
+}
+
+impl Fault {
+    pub fn name(self) -> &'static str {
+        match self {
+            Fault::DivideError => "divide error",
+            Fault::InvalidOpcode => "invalid opcode",
+            Fault::GeneralProtection => "general protection fault",
+            Fault::PageFault => "page fault",
+        }
+    }
+
+    /// The signal Linux raises for this exception.
+    pub fn signal(self) -> Signal {
+        match self {
+            Fault::DivideError => Signal::SIGFPE,
+            Fault::InvalidOpcode => Signal::SIGILL,
+            Fault::GeneralProtection | Fault::PageFault => Signal::SIGSEGV,
+        }
+    }
+}
+
+/// How a process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// With the `exit` system call and this code.
+    Exited(i32),
+    /// Killed by a signal.
+    Killed(Signal),
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exited with code {}", code),
+            ExitStatus::Killed(signal) => write!(f, "killed by {}", signal),
+        }
+    }
+}
+
+/// The ways starting a process can fail.
+#[derive(Debug)]
+pub enum SpawnError {
+    /// Creating the address space or mapping its memory failed.
+    Paging(PagingError),
+    /// The executable is malformed or unsupported.
+    Elf(ElfError),
+    /// The arguments and the environment don't fit on the stack, see [`stack::MAX_SIZE`].
+    ArgumentsTooLong,
+    /// Creating the thread failed.
+    Thread(StackError),
+}
+
+impl From<PagingError> for SpawnError {
+    fn from(err: PagingError) -> Self {
+        SpawnError::Paging(err)
+    }
+}
+
+impl From<ElfError> for SpawnError {
+    fn from(err: ElfError) -> Self {
+        SpawnError::Elf(err)
+    }
+}
+
+impl From<StackError> for SpawnError {
+    fn from(err: StackError) -> Self {
+        SpawnError::Thread(err)
+    }
+}
+
+/// The ways [`wait_child`] can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    /// The process has no matching children.
+    NoChildren,
+    /// A signal arrived while it waited.
+    Interrupted,
+}
+
+/// [`send`] found no process with the PID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoSuchProcess;
+
+/// A started process.
+pub struct Process {
+    pid: Pid,
+    thread: JoinHandle<ExitStatus>,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Waits for the process to end.
+    pub fn wait(self) -> ExitStatus {
+        self.thread
+            .join()
+            .expect("process thread ended without a status")
+    }
+}
+
+/// Starts a process named `name` that runs the machine code `code`, loaded at [`CODE_START`], with
+/// an empty stack.
+pub fn spawn(name: &str, code: &[u8]) -> Result<Process, SpawnError> {
+    let (space, registers) = load_flat(code)?;
+    let pid = Pid::new();
+    let thread = start(pid, name, None, space, Inherited::fresh(), registers)?;
+    Ok(Process { pid, thread })
+}
+
+/// Starts a process named `name` that runs the ELF executable `elf`, with the arguments `args`
+/// and the environment variables `env` (like `KEY=value`) on its initial stack. By convention,
+/// `args[0]` is the name of the program.
+pub fn spawn_elf(
+    name: &str,
+    elf: &[u8],
+    args: &[&str],
+    env: &[&str],
+) -> Result<Process, SpawnError> {
+    let (space, registers) = load_elf(elf, args, env)?;
+    let pid = Pid::new();
+    let thread = start(pid, name, None, space, Inherited::fresh(), registers)?;
+    Ok(Process { pid, thread })
+}
+
+/// Starts the init process, see the [module documentation](self). Called once at boot. Init runs
+/// the ELF executable `elf`, or a minimal init that only collects orphans without one.
+pub fn spawn_init(elf: Option<&[u8]>) -> Result<(), SpawnError> {
+    let (space, registers) = match elf {
+        Some(elf) => load_elf(elf, &["init"], &[])?,
+        None => load_flat(INIT_CODE)?,
+    };
+    start(
+        Pid::INIT,
+        "init",
+        None,
+        space,
+        Inherited::fresh(),
+        registers,
+    )?;
+    Ok(())
+}
+
+/// Creates a child of the current process with a copy of its memory. The child continues with
+/// `registers`, the ones of the `fork` system call, except that the call returns 0 to it.
+pub fn fork(registers: &Registers) -> Result<Pid, SpawnError> {
+    let parent = current_pid().expect("fork outside of a process");
+    let space = with_space(|space| space.duplicate())?;
+    let (name, inherited) = {
+        let table = PROCESSES.lock();
+        let entry = &table.processes[&parent];
+        let inherited = Inherited {
+            signals: entry.signals.fork(),
+            files: entry.files.clone(),
+            cwd: entry.cwd.clone(),
+        };
+        (entry.name.clone(), inherited)
+    };
+    let mut registers = registers.clone();
+    registers.rax = 0;
+    let pid = Pid::new();
+    start(pid, &name, Some(parent), space, inherited, registers)?;
+    Ok(pid)
+}
+
+/// Replaces the program of the current process with the ELF executable `elf`, started like
+/// [`spawn_elf`] starts it. On success, the process is renamed to `name`, its signal handlers are
+/// reset, its files marked close-on-exec are closed and `registers` are set, so that the `exec`
+/// system call returns to the start of the new program. On failure, the process is unchanged.
+pub fn exec(
+    name: &str,
+    elf: &[u8],
+    args: &[&str],
+    env: &[&str],
+    registers: &mut Registers,
+) -> Result<(), SpawnError> {
+    let (space, new_registers) = load_elf(elf, args, env)?;
+    unsafe { thread::set_page_table(space.page_table()) };
+    let old = with_space(|current| mem::replace(current, space));
+    drop(old);
+
+    let pid = current_pid().expect("exec outside of a process");
+    let closed = match PROCESSES.lock().processes.get_mut(&pid) {
+        Some(entry) => {
+            entry.name = name.to_string();
+            entry.signals.exec();
+            entry.files.exec()
+        }
+        None => Vec::new(),
+    };
+    drop(closed);
+    *registers = new_registers;
+    Ok(())
+}
+
+/// Waits for a child of the current process to end, the one with `pid` or any if it is `None`,
+/// and removes it from the process table. Returns its PID and how it ended, or `None` if `block`
+/// is `false` and no child ended yet. A signal interrupts the wait.
+pub fn wait_child(pid: Option<Pid>, block: bool) -> Result<Option<(Pid, ExitStatus)>, WaitError> {
+    let parent = current_pid().expect("wait outside of a process");
+    let mut table = PROCESSES.lock();
+    loop {
+        let mut children = table
+            .processes
+            .iter()
+            .filter(|(&child, entry)| entry.parent == Some(parent) && pid.unwrap_or(child) == child)
+            .peekable();
+        if children.peek().is_none() {
+            return Err(WaitError::NoChildren);
+        }
+        let zombie = children.find_map(|(&child, entry)| match entry.state {
+            State::Zombie(status) => Some((child, status)),
+            _ => None,
+        });
+        if let Some((child, status)) = zombie {
+            table.processes.remove(&child);
+            return Ok(Some((child, status)));
+        }
+        if !block {
+            return Ok(None);
+        }
+        if table.processes[&parent].signals.has_deliverable() {
+            return Err(WaitError::Interrupted);
+        }
+        sync::sleep_interruptible(
+            &PROCESSES,
+            table,
+            |table| &mut table.waiters,
+            None,
+            signal_pending,
+        );
+        table = PROCESSES.lock();
+    }
+}
+
+/// Loads the flat binary `code` into a new address space, see [`spawn`].
+fn load_flat(code: &[u8]) -> Result<(AddressSpace, Registers), SpawnError> {
+    let mut space = AddressSpace::new()?;
+    let code_start = VirtAddr::new(CODE_START);
+    let code_size = (code.len() as u64).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    // Read-only and executable.
+    space.map(
+        code_start,
+        code_size,
+        PageTableFlags::empty(),
+        AreaKind::Image,
+    )?;
+    space.write(code_start, code)?;
+    map_stack(&mut space, STACK_PAGES * PAGE_SIZE)?;
+    Ok((space, entry::initial_registers(CODE_START, STACK_TOP)))
+}
+
+/// Loads the ELF executable `elf` into a new address space, with its initial stack, see
+/// [`spawn_elf`].
+fn load_elf(
+    elf: &[u8],
+    args: &[&str],
+    env: &[&str],
+) -> Result<(AddressSpace, Registers), SpawnError> {
+    let mut space = AddressSpace::new()?;
+    let executable = elf::load(&mut space, elf)?;
+    let mut auxv = vec![
+        (stack::AT_PHENT, elf::PROGRAM_HEADER_SIZE as u64),
+        (stack::AT_PHNUM, u64::from(executable.program_header_count)),
+        (stack::AT_PAGESZ, PAGE_SIZE),
+        (stack::AT_ENTRY, executable.entry),
+    ];
+    if let Some(program_headers) = executable.program_headers {
+        auxv.push((stack::AT_PHDR, program_headers));
+    }
+    let stack = stack::build(STACK_TOP, args, env, &auxv).ok_or(SpawnError::ArgumentsTooLong)?;
+    map_stack(&mut space, executable.stack_size)?;
+    space.write(VirtAddr::new(stack.stack_pointer), &stack.data)?;
+    let registers = entry::initial_registers(executable.entry, stack.stack_pointer);
+    Ok((space, registers))
+}
+
+/// Maps a stack of `size` bytes below [`STACK_TOP`].
+fn map_stack(space: &mut AddressSpace, size: u64) -> Result<(), PagingError> {
+    let flags = PageTableFlags::WRITABLE | protection::no_execute();
+    space.map(
+        VirtAddr::new(STACK_TOP - size),
+        size,
+        flags,
+        AreaKind::Stack,
+    )
+}
+
+/// What a child gets from its parent in [`fork`].
+struct Inherited {
+    signals: Signals,
+    files: FdTable,
+    cwd: String,
+}
+
+impl Inherited {
+    /// What processes started by the kernel get instead: the default signal actions, the console
+    /// as stdin, stdout and stderr, and `/` as the current directory.
+    fn fresh() -> Self {
+        Inherited {
+            signals: Signals::new(),
+            files: FdTable::console(),
+            cwd: "/".to_string(),
+        }
+    }
+}
+
+/// Adds a process to the table and starts its thread, which runs in `space` with `registers`.
+fn start(
+    pid: Pid,
+    name: &str,
+    parent: Option<Pid>,
+    space: AddressSpace,
+    inherited: Inherited,
+    registers: Registers,
+) -> Result<JoinHandle<ExitStatus>, SpawnError> {
+    let entry = Entry {
+        name: name.to_string(),
+        parent,
+        thread: None,
+        state: State::Running,
+        space: Some(space),
+        signals: inherited.signals,
+        files: inherited.files,
+        cwd: inherited.cwd,
+        diverted: None,
+    };
+    PROCESSES.lock().processes.insert(pid, entry);
+    match thread::spawn(name, move || run(pid, registers)) {
+        Ok(thread) => Ok(thread),
+        Err(err) => {
+            let entry = PROCESSES.lock().processes.remove(&pid);
+            drop(entry);
+            Err(err.into())
+        }
+    }
+}
+
+/// The body of a process thread.
+fn run(pid: Pid, registers: Registers) -> ExitStatus {
+    let id = thread::current_id().expect("threads not initialized");
+    let page_table = {
+        let mut table = PROCESSES.lock();
+        table.threads.insert(id, pid);
+        let entry = table.processes.get_mut(&pid).expect("process vanished");
+        entry.thread = Some(id);
+        entry.space.as_ref().expect("no address space").page_table()
+    };
+
+    unsafe {
+        thread::set_page_table(page_table);
+        entry::enter_user(&registers);
+        thread::set_page_table(memory::kernel_page_table());
+    }
+
+    let (space, files, status) = {
+        let mut table = PROCESSES.lock();
+        table.threads.remove(&id);
+        let entry = table.processes.get_mut(&pid).expect("process vanished");
+        match entry.state {
+            State::Exiting(status) => (entry.space.take(), mem::take(&mut entry.files), status),
+            state => panic!("process ended while {}", state.name()),
+        }
+    };
+    // Freeing the memory takes the paging locks, and closing a pipe wakes the other end, so not
+    // with the table locked.
+    drop(space);
+    drop(files);
+    end(&mut PROCESSES.lock(), pid, status);
+    status
+}
+
+/// Turns the ended process `pid` into a zombie if it has a parent, or removes it, and hands its
+/// children to the init process. The parent gets [`Signal::SIGCHLD`] and the foreground, if the
+/// process had it.
+fn end(table: &mut Table, pid: Pid, status: ExitStatus) {
+    let entry = table.processes.get_mut(&pid).expect("process vanished");
+    let parent = entry.parent;
+    if parent.is_some() {
+        entry.state = State::Zombie(status);
+    } else {
+        table.processes.remove(&pid);
+    }
+    if let Some(parent) = parent {
+        post(table, parent, Signal::SIGCHLD);
+    }
+    if table.foreground == Some(pid) {
+        table.foreground = parent;
+    }
+
+    let init = Some(Pid::INIT).filter(|init| table.processes.contains_key(init));
+    let mut orphans = Vec::new();
+    for (&child, entry) in table.processes.iter_mut() {
+        if entry.parent != Some(pid) {
+            continue;
+        }
+        entry.parent = init;
+        if init.is_none() && matches!(entry.state, State::Zombie(_)) {
+            orphans.push(child);
+        }
+    }
+    // Nobody will collect them anymore.
+    for orphan in orphans {
+        table.processes.remove(&orphan);
+    }
+    table.waiters.wake_all();
+}
+
+/// Runs `f` with the address space of the current process. Only the process's own thread uses
+/// it, so it is taken out of the table meanwhile instead of keeping the table locked.
+pub(crate) fn with_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> R {
+    try_with_space(f).expect("not in a process with an address space")
+}
+
+/// Like [`with_space`], but returns `None` if the current thread runs no process or its address
+/// space is already in use further up the stack.
+fn try_with_space<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    let pid = current_pid()?;
+    let mut space = PROCESSES
+        .lock()
+        .processes
+        .get_mut(&pid)
+        .and_then(|entry| entry.space.take())?;
+    let result = f(&mut space);
+    if let Some(entry) = PROCESSES.lock().processes.get_mut(&pid) {
+        entry.space = Some(space);
+    }
+    Some(result)
+}
+
+/// Maps the page at `addr` of the current process on its first access, see
+/// [`AddressSpace::fault_in`]. Returns whether the access can be retried. Called by the page
+/// fault handler.
+pub(crate) fn fault_in(addr: VirtAddr, write: bool) -> bool {
+    try_with_space(|space| space.fault_in(addr, write)).unwrap_or(false)
+}
+
+/// Whether the memory areas of the current process allow `access` to the `len` bytes at `addr`,
+/// e.g. to check a buffer before data that can't be put back is copied to it.
+pub(crate) fn user_memory_allows(addr: VirtAddr, len: u64, access: Protection) -> bool {
+    let range = match addr.as_u64().checked_add(len) {
+        Some(end) => addr.as_u64()..end,
+        None => return false,
+    };
+    try_with_space(|space| space.areas().allow(range, access)).unwrap_or(false)
+}
+
+/// Runs `f` with the file descriptor table of the current process. Files that `f` closes must be
+/// returned and dropped after the table is unlocked again, see [`FdTable::close`].
+pub(crate) fn with_files<R>(f: impl FnOnce(&mut FdTable) -> R) -> R {
+    let pid = current_pid().expect("not in a process");
+    let mut table = PROCESSES.lock();
+    let entry = table.processes.get_mut(&pid).expect("process vanished");
+    f(&mut entry.files)
+}
+
+/// The current directory of the current process, see [`crate::fs`].
+pub fn current_dir() -> String {
+    let pid = current_pid().expect("not in a process");
+    PROCESSES.lock().processes[&pid].cwd.clone()
+}
+
+/// Changes the current directory of the current process to `dir`, the absolute path of a
+/// directory.
+pub(crate) fn set_current_dir(dir: String) {
+    let pid = current_pid().expect("not in a process");
+    match PROCESSES.lock().processes.get_mut(&pid) {
+        Some(entry) => entry.cwd = dir,
+        None => panic!("process vanished"),
+    }
+}
+
+/// The process of the current thread, if it runs one.
+pub fn current_pid() -> Option<Pid> {
+    let id = thread::current_id()?;
+    PROCESSES.lock().threads.get(&id).copied()
+}
+
+/// The parent of the current process, if it runs one and the parent is still alive.
+pub fn parent_pid() -> Option<Pid> {
+    parent_of(current_pid()?)
+}
+
+/// The parent of the process `pid`, if both are still alive.
+pub fn parent_of(pid: Pid) -> Option<Pid> {
+    PROCESSES.lock().processes.get(&pid)?.parent
+}
+
+/// Whether the process `pid` exists, even if only as a zombie.
+pub fn exists(pid: Pid) -> bool {
+    PROCESSES.lock().processes.contains_key(&pid)
+}
+
+/// Ends the current process with `status`. Called by system calls and exception handlers of the
+/// process's thread.
+pub(crate) fn exit(status: ExitStatus) -> ! {
+    let pid = current_pid().expect("exit outside of a process");
+    match PROCESSES.lock().processes.get_mut(&pid) {
+        Some(entry) => entry.state = State::Exiting(status),
+        None => panic!("process vanished"),
+    }
+    entry::leave_user()
+}
+
+/// Raises the signal of `fault` in the current process, whose user code caused it. Without a
+/// handler for it, the process is killed after printing a report with the exception's `details`
+/// and stack frame. Otherwise the exception returns to the handler (see [`entry::divert`]), so
+/// interrupts must stay disabled until it does. Called by the exception handlers.
+pub(crate) fn fault(fault: Fault, details: fmt::Arguments, stack_frame: &mut InterruptStackFrame) {
+    let pid = match current_pid() {
+        Some(pid) => pid,
+        None => panic!("{} in user mode outside of a process", fault.name()),
+    };
+    let signal = fault.signal();
+    let mut table = PROCESSES.lock();
+    let entry = table.processes.get_mut(&pid).expect("process vanished");
+    if entry.signals.force(signal) {
+        entry.diverted = Some(entry::divert(stack_frame));
+        return;
+    }
+    drop(table);
+    println!(
+        "process {} ({}) killed by {}: {}{}\n  at {:#x}, stack pointer {:#x}",
+        pid,
+        thread::CurrentName,
+        signal,
+        fault.name(),
+        details,
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.stack_pointer.as_u64()
+    );
+    exit(ExitStatus::Killed(signal))
+}
+
+/// Sends `signal` to the process `pid`, see [`signal`]. Signals to processes that already ended
+/// are dropped.
+pub fn send(pid: Pid, signal: Signal) -> Result<(), NoSuchProcess> {
+    let mut table = PROCESSES.lock();
+    if !table.processes.contains_key(&pid) {
+        return Err(NoSuchProcess);
+    }
+    post(&mut table, pid, signal);
+    Ok(())
+}
+
+/// Makes `signal` pending in the process `pid`, if it is alive, and wakes its thread, so a
+/// blocking system call is interrupted. [`Signal::SIGCONT`] and [`Signal::SIGKILL`] also continue
+/// a stopped process.
+fn post(table: &mut Table, pid: Pid, signal: Signal) {
+    let entry = match table.processes.get_mut(&pid) {
+        Some(entry) => entry,
+        None => return,
+    };
+    if !matches!(entry.state, State::Running | State::Stopped) {
+        return;
+    }
+    if pid == Pid::INIT && !matches!(entry.signals.action(signal), Action::Handler { .. }) {
+        return;
+    }
+    let pending = entry.signals.post(signal);
+    let continued =
+        entry.state == State::Stopped && (signal == Signal::SIGCONT || signal == Signal::SIGKILL);
+    if continued {
+        entry.state = State::Running;
+        table.waiters.wake_all();
+    } else if pending {
+        if let Some(thread) = entry.thread {
+            thread::unblock(thread);
+        }
+    }
+}
+
+/// Whether the current process has a pending signal it doesn't block, which interrupts blocking
+/// system calls.
+pub fn signal_pending() -> bool {
+    current_pid().is_some_and(|pid| {
+        PROCESSES
+            .lock()
+            .processes
+            .get(&pid)
+            .is_some_and(|entry| entry.signals.has_deliverable())
+    })
+}
+
+/// Sets the action of `signal` in the current process to `action` if it is `Some`, and returns
+/// the previous one. `signal` must not be [`Signal::is_unstoppable`] for a new action.
+pub fn signal_action(signal: Signal, action: Option<Action>) -> Action {
+    with_signals(|signals| {
+        let old = signals.action(signal);
+        if let Some(action) = action {
+            signals.set_action(signal, action);
+        }
+        old
+    })
+}
+
+/// Replaces the blocked signals of the current process with what `f` makes of them, and returns
+/// the previous ones. [`Signal::SIGKILL`] and [`Signal::SIGSTOP`] can't be blocked.
+pub fn update_blocked(f: impl FnOnce(SigSet) -> SigSet) -> SigSet {
+    with_signals(|signals| {
+        let old = signals.blocked();
+        signals.set_blocked(f(old));
+        old
+    })
+}
+
+fn with_signals<R>(f: impl FnOnce(&mut Signals) -> R) -> R {
+    let pid = current_pid().expect("not in a process");
+    let mut table = PROCESSES.lock();
+    let entry = table.processes.get_mut(&pid).expect("process vanished");
+    f(&mut entry.signals)
+}
+
+/// Returns from a signal handler of the current process for the `sigreturn` system call: restores
+/// the registers and the mask that the handler interrupted. A broken frame kills the process.
+pub fn sigreturn(registers: &mut Registers) {
+    match signal::leave_handler(registers) {
+        Ok(blocked) => {
+            update_blocked(|_| blocked);
+        }
+        Err(_) => {
+            println!(
+                "process {} ({}) killed by SIGSEGV: bad signal frame",
+                current_pid().map_or(0, Pid::as_u64),
+                thread::CurrentName
+            );
+            exit(ExitStatus::Killed(Signal::SIGSEGV));
+        }
+    }
+}
+
+/// Handles the pending signals of the current process that it doesn't block, before it returns to
+/// user mode with `registers`: for a signal with a handler, `registers` are changed to call it,
+/// other signals take their default action. Called after system calls, and by interrupts diverted
+/// with [`entry::divert`].
+pub(crate) fn deliver_signals(registers: &mut Registers) {
+    let pid = match current_pid() {
+        Some(pid) => pid,
+        None => return,
+    };
+    loop {
+        let (signal, action, blocked) = {
+            let mut table = PROCESSES.lock();
+            let entry = table.processes.get_mut(&pid).expect("process vanished");
+            let blocked = entry.signals.blocked();
+            match entry.signals.take() {
+                Some((signal, action)) => (signal, action, blocked),
+                None => return,
+            }
+        };
+        match action {
+            Action::Ignore => {}
+            Action::Default => match signal.default_action() {
+                DefaultAction::Ignore | DefaultAction::Continue => {}
+                DefaultAction::Stop => stop(pid),
+                DefaultAction::Terminate | DefaultAction::Core => exit(ExitStatus::Killed(signal)),
+            },
+            Action::Handler {
+                entry,
+                restorer,
+                mask,
+                flags,
+            } => {
+                // Like Linux, a process without room for the frame gets SIGSEGV instead.
+                if signal::enter_handler(registers, signal, entry, restorer, blocked).is_err() {
+                    println!(
+                        "process {} ({}) killed by SIGSEGV: no stack for the {} handler",
+                        pid,
+                        thread::CurrentName,
+                        signal
+                    );
+                    exit(ExitStatus::Killed(Signal::SIGSEGV));
+                }
+                with_signals(|signals| {
+                    let mut blocked = blocked | mask;
+                    if flags & SA_NODEFER == 0 {
+                        blocked.insert(signal);
+                    }
+                    signals.set_blocked(blocked);
+                    if flags & SA_RESETHAND != 0 {
+                        signals.set_action(signal, Action::Default);
+                    }
+                });
+                // The other signals follow when the handler returns.
+                return;
+            }
+        }
+    }
+}
+
+/// Stops the process `pid`, the current one, until it is continued, see [`post`].
+fn stop(pid: Pid) {
+    let mut table = PROCESSES.lock();
+    table
+        .processes
+        .get_mut(&pid)
+        .expect("process vanished")
+        .state = State::Stopped;
+    while table.processes[&pid].state == State::Stopped {
+        sync::sleep(&PROCESSES, table, |table| &mut table.waiters, None);
+        table = PROCESSES.lock();
+    }
+}
+
+/// Called by interrupt handlers that interrupted user code, last: if the current process has a
+/// signal to handle, the handler returns to [`deliver_signals`] instead (see [`entry::divert`]).
+pub(crate) fn interrupt_return(stack_frame: &mut InterruptStackFrame) {
+    let pid = match current_pid() {
+        Some(pid) => pid,
+        None => return,
+    };
+    let mut table = PROCESSES.lock();
+    if let Some(entry) = table.processes.get_mut(&pid) {
+        if entry.signals.has_deliverable() && entry.diverted.is_none() {
+            entry.diverted = Some(entry::divert(stack_frame));
+        }
+    }
+}
+
+/// Where a diverted interrupt handler returns to, with the registers of the user code. Completes
+/// them with the interrupt stack frame saved by [`entry::divert`] and delivers the signals.
+extern "C" fn resume_diverted(registers: &mut Registers) {
+    let pid = current_pid().expect("diverted outside of a process");
+    let frame = PROCESSES
+        .lock()
+        .processes
+        .get_mut(&pid)
+        .and_then(|entry| entry.diverted.take())
+        .expect("not diverted");
+    registers.rip = frame.instruction_pointer.as_u64();
+    registers.cs = frame.code_segment;
+    registers.rflags = frame.cpu_flags;
+    registers.rsp = frame.stack_pointer.as_u64();
+    registers.ss = frame.stack_segment;
+    // Like a system call, e.g. to resolve copy-on-write faults while writing the signal frame.
+    interrupts::enable();
+    deliver_signals(registers);
+    interrupts::disable();
+}
+
+/// Makes `pid` the foreground process, which Ctrl+C interrupts. When it ends, its parent gets the
+/// foreground back.
+pub fn set_foreground(pid: Pid) -> Result<(), NoSuchProcess> {
+    let mut table = PROCESSES.lock();
+    if !table.processes.contains_key(&pid) {
+        return Err(NoSuchProcess);
+    }
+    table.foreground = Some(pid);
+    Ok(())
+}
+
+/// The foreground process, see [`set_foreground`].
+pub fn foreground() -> Option<Pid> {
+    PROCESSES.lock().foreground
+}
+
+/// Sends [`Signal::SIGINT`] to the foreground process, for Ctrl+C. Returns whether there is one.
+pub fn interrupt_foreground() -> bool {
+    let mut table = PROCESSES.lock();
+    match table.foreground {
+        Some(pid) => {
+            post(&mut table, pid, Signal::SIGINT);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Whether the exception with `stack_frame` interrupted user mode.
+pub(crate) fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Prints the process table over serial.
+pub fn print_processes() {
+    struct Line {
+        pid: Pid,
+        parent: Option<Pid>,
+        state: State,
+        thread: Option<ThreadId>,
+        name: String,
+    }
+
+    // Collect everything first, so the serial port isn't locked with interrupts disabled.
+    let lines: Vec<Line> = PROCESSES
+        .lock()
+        .processes
+        .iter()
+        .map(|(&pid, entry)| Line {
+            pid,
+            parent: entry.parent,
+            state: entry.state,
+            thread: entry.thread,
+            name: entry.name.clone(),
+        })
+        .collect();
+
+    serial_println!("{} processes", lines.len());
+    serial_println!(
+        "{:>5} {:>5} {:<8} {:>6} {}",
+        "PID",
+        "PPID",
+        "STATE",
+        "THREAD",
+        "NAME"
+    );
+    for line in lines {
+        let thread = match line.state {
+            State::Zombie(_) => None,
+            _ => line.thread,
+        };
+        serial_println!(
+            "{:>5} {:>5} {:<8} {:>6} {}",
+            line.pid,
+            line.parent.map_or(0, Pid::as_u64),
+            line.state.name(),
+            thread.map_or(String::from("-"), |id| id.to_string()),
+            line.name
+        );
+    }
+}
+
+/// Prints the memory areas of the process `pid` over serial, like `/proc/<pid>/maps`.
+pub fn print_maps(pid: Pid) {
+    let (name, areas) = {
+        let table = PROCESSES.lock();
+        let entry = match table.processes.get(&pid) {
+            Some(entry) => entry,
+            None => {
+                serial_println!("no process {}", pid);
+                return;
+            }
+        };
+        (
+            entry.name.clone(),
+            entry.space.as_ref().map(|space| space.areas().clone()),
+        )
+    };
+    let areas = match areas {
+        Some(areas) => areas,
+        None => {
+            serial_println!("process {} ({}) has no address space now", pid, name);
+            return;
+        }
+    };
+    for area in areas.iter() {
+        serial_println!(
+            "{:012x}-{:012x} {}p {:>8}K {}",
+            area.start,
+            area.end,
+            area.protection,
+            (area.end - area.start) / 1024,
+            match area.kind {
+                AreaKind::Image => name.as_str(),
+                kind => kind.name(),
+            }
+        );
+    }
+}
+
+/// Prints the open files of the process `pid` over serial.
+pub fn print_files(pid: Pid) {
+    let files: Vec<(u64, &'static str, bool)> = {
+        let table = PROCESSES.lock();
+        let entry = match table.processes.get(&pid) {
+            Some(entry) => entry,
+            None => {
+                serial_println!("no process {}", pid);
+                return;
+            }
+        };
+        entry
+            .files
+            .iter()
+            .map(|(fd, file)| {
+                let close_on_exec = entry.files.close_on_exec(fd) == Ok(true);
+                (fd, file.name(), close_on_exec)
+            })
+            .collect()
+    };
+    for (fd, name, close_on_exec) in files {
+        let flags = if close_on_exec {
+            " (close-on-exec)"
+        } else {
+            ""
+        };
+        serial_println!("{:>3} {}{}", fd, name, flags);
+    }
+}
+
+#[cfg(test)]
+use crate::syscall::{SYS_EXEC, SYS_EXIT, SYS_FORK, SYS_WRITE};
+
+/// Exits with `argc * 256 + argv[1][0]`.
+#[cfg(test)]
+#[rustfmt::skip]
+const ARGS_CODE: &[u8] = &[
+    0x48, 0x8b, 0x3c, 0x24,                  // mov rdi, [rsp] (argc)
+    0x48, 0x8b, 0x44, 0x24, 0x10,            // mov rax, [rsp + 16] (argv[1])
+    0x0f, 0xb6, 0x00,                        // movzx eax, byte ptr [rax]
+    0x48, 0xc1, 0xe7, 0x08,                  // shl rdi, 8
+    0x48, 0x01, 0xc7,                        // add rdi, rax
+    0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,  // mov eax, SYS_EXIT
+    0x0f, 0x05,                              // syscall
+];
+
+#[test_case]
+fn test_user_program_writes_to_console() {
+    const MESSAGE: &[u8] = b"hello from ring 3\n";
+    #[rustfmt::skip]
+    let code: &[u8] = &[
+        0x48, 0x8d, 0x35, 0x1d, 0x00, 0x00, 0x00,  // lea rsi, [rip + 0x1d] (the message)
+        0xbf, 0x01, 0x00, 0x00, 0x00,              // mov edi, 1 (stdout)
+        0xba, MESSAGE.len() as u8, 0x00, 0x00, 0x00,  // mov edx, len
+        0xb8, SYS_WRITE as u8, 0x00, 0x00, 0x00,   // mov eax, SYS_WRITE
+        0xcd, 0x80,                                // int 0x80
+        0x48, 0x89, 0xc7,                          // mov rdi, rax
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,    // mov eax, SYS_EXIT
+        0xcd, 0x80,                                // int 0x80
+        0x0f, 0x0b,                                // ud2
+    ];
+    let mut image = alloc::vec::Vec::from(code);
+    image.extend_from_slice(MESSAGE);
+
+    let process = spawn("hello", &image).unwrap();
+    assert_eq!(process.wait(), ExitStatus::Exited(MESSAGE.len() as i32));
+}
+
+#[test_case]
+fn test_fault_kills_only_the_process() {
+    // mov rax, [0x200000]: the kernel image, which user mode can't read.
+    let code = [0x48, 0x8b, 0x04, 0x25, 0x00, 0x00, 0x20, 0x00];
+    let process = spawn("reader", &code).unwrap();
+    assert_eq!(process.wait(), ExitStatus::Killed(Signal::SIGSEGV));
+
+    // hlt is privileged.
+    let process = spawn("halter", &[0xf4]).unwrap();
+    assert_eq!(process.wait(), ExitStatus::Killed(Signal::SIGSEGV));
+
+    // ud2 and a division by zero.
+    let process = spawn("ud2", &[0x0f, 0x0b]).unwrap();
+    assert_eq!(process.wait(), ExitStatus::Killed(Signal::SIGILL));
+    let process = spawn("divider", &[0x31, 0xc9, 0xf7, 0xf1]).unwrap(); // xor ecx, ecx; div ecx
+    assert_eq!(process.wait(), ExitStatus::Killed(Signal::SIGFPE));
+}
+
+#[test_case]
+fn test_ctrl_c_interrupts_the_foreground_process() {
+    // A busy loop, which only timer interrupts can deliver signals to.
+    let process = spawn("spinner", &[0xeb, 0xfe]).unwrap(); // jmp $
+    let pid = process.pid();
+    set_foreground(pid).unwrap();
+    assert!(interrupt_foreground());
+    assert_eq!(process.wait(), ExitStatus::Killed(Signal::SIGINT));
+    assert_eq!(foreground(), None);
+    assert_eq!(send(pid, Signal::SIGINT), Err(NoSuchProcess));
+}
+
+#[test_case]
+fn test_elf_program_gets_its_arguments() {
+    let elf = elf::build_program(ARGS_CODE);
+    let process = spawn_elf("args", &elf, &["args", "x"], &["KEY=value"]).unwrap();
+    assert_eq!(
+        process.wait(),
+        ExitStatus::Exited(2 * 256 + i32::from(b'x'))
+    );
+
+    assert!(matches!(
+        spawn_elf("broken", &elf[..10], &[], &[]),
+        Err(SpawnError::Elf(ElfError::Truncated))
+    ));
+}
+
+#[test_case]
+fn test_fork_and_wait_for_the_child() {
+    #[rustfmt::skip]
+    let code = [
+        0xb8, SYS_FORK as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_FORK
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x85, 0xc0,                           // test rax, rax
+        0x75, 0x0c,                                 // jnz parent
+        0xbf, 0x2a, 0x00, 0x00, 0x00,               // mov edi, 42
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+        // parent:
+        0x48, 0x89, 0xc3,                           // mov rbx, rax (the child)
+        0x48, 0x83, 0xec, 0x08,                     // sub rsp, 8
+        0x48, 0x89, 0xc7,                           // mov rdi, rax
+        0x48, 0x89, 0xe6,                           // mov rsi, rsp (the status)
+        0x31, 0xd2,                                 // xor edx, edx
+        0xb8, SYS_WAITPID as u8, 0x00, 0x00, 0x00,  // mov eax, SYS_WAITPID
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x29, 0xd8,                           // sub rax, rbx
+        0x49, 0x89, 0xc4,                           // mov r12, rax
+        0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff,   // mov rdi, -1 (no children left)
+        0xb8, SYS_WAITPID as u8, 0x00, 0x00, 0x00,  // mov eax, SYS_WAITPID
+        0x0f, 0x05,                                 // syscall
+        0x8b, 0x3c, 0x24,                           // mov edi, [rsp]
+        0x4c, 0x01, 0xe7,                           // add rdi, r12
+        0x48, 0x01, 0xc7,                           // add rdi, rax
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+    ];
+    let process = spawn("forker", &code).unwrap();
+    // The child's status, the child's PID from `waitpid` and then `ECHILD`.
+    assert_eq!(process.wait(), ExitStatus::Exited(42 * 256 - 10));
+}
+
+#[test_case]
+fn test_exec_runs_a_registered_program() {
+    programs::register("exec-args", elf::build_program(ARGS_CODE).leak()).unwrap();
+    // A flat binary that calls `exec(path, [path, "x"], NULL)` and exits with the result.
+    let exec = |path: &str| {
+        let path_addr = CODE_START + 64;
+        let arg_addr = path_addr + path.len() as u64 + 1;
+        let mut code = vec![0x48, 0xbf]; // mov rdi, path
+        code.extend_from_slice(&path_addr.to_le_bytes());
+        code.extend_from_slice(&[0x48, 0xbe]); // mov rsi, argv
+        code.extend_from_slice(&(CODE_START + 40).to_le_bytes());
+        #[rustfmt::skip]
+        let call = [
+            0x31, 0xd2,                              // xor edx, edx (no environment)
+            0xb8, SYS_EXEC as u8, 0x00, 0x00, 0x00,  // mov eax, SYS_EXEC
+            0x0f, 0x05,                              // syscall
+            0x48, 0x89, 0xc7,                        // mov rdi, rax
+            0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,  // mov eax, SYS_EXIT
+            0x0f, 0x05,                              // syscall
+        ];
+        code.extend_from_slice(&call);
+        code.resize(40, 0);
+        for word in [path_addr, arg_addr, 0] {
+            code.extend_from_slice(&word.to_le_bytes());
+        }
+        code.extend_from_slice(path.as_bytes());
+        code.extend_from_slice(b"\0x\0");
+        code
+    };
+
+    let process = spawn("exec", &exec("exec-args")).unwrap();
+    assert_eq!(
+        process.wait(),
+        ExitStatus::Exited(2 * 256 + i32::from(b'x'))
+    );
+    let process = spawn("exec", &exec("missing")).unwrap();
+    assert_eq!(process.wait(), ExitStatus::Exited(-2));
+}
@@ -0,0 +1,574 @@
+//! # elf
+//!
+//! Loading ELF64 executables into an address space.
+//!
+//! Only statically linked x86_64 executables are supported (`ET_EXEC`, without `PT_INTERP`).
+//! [`parse`] checks the file header and the program headers and returns the `PT_LOAD` segments,
+//! the entry point and the stack size; [`load`] then maps the segments into an [`AddressSpace`]
+//! and copies their contents from the file. A segment's memory beyond its file contents (the BSS)
+//! needs no extra work: freshly mapped user memory is always zeroed.
+//!
+//! The file comes from user space eventually, so nothing in it is trusted: every offset, size and
+//! address is checked before it is used, and a malformed file is rejected with an [`ElfError`]
+//! that says what is wrong with it.
+//!
+//! Segments get the permissions of their flags, but never both writable and executable, and the
+//! stack is never executable, matching the W^X policy of the kernel itself (see
+//! [`crate::memory::protection`]). Segments must not share pages, since a page has only one set of
+//! permissions.
+
+use crate::memory::{
+    address_space::{self, AddressSpace},
+    paging::PagingError,
+    protection,
+    vma::AreaKind,
+};
+use alloc::vec::Vec;
+use core::{convert::TryFrom, ops::Range};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+/// The size of the ELF64 file header.
+pub const HEADER_SIZE: usize = 64;
+/// The size of an ELF64 program header.
+pub const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// `e_type` of executables.
+const ET_EXEC: u16 = 2;
+/// `e_machine` of x86_64.
+const EM_X86_64: u16 = 62;
+
+/// A loadable segment.
+pub const PT_LOAD: u32 = 1;
+/// The program interpreter, for dynamically linked executables.
+pub const PT_INTERP: u32 = 3;
+/// The permissions and size of the stack.
+pub const PT_GNU_STACK: u32 = 0x6474_e551;
+
+/// Segment flag: executable.
+pub const PF_X: u32 = 1;
+/// Segment flag: writable.
+pub const PF_W: u32 = 2;
+/// Segment flag: readable.
+pub const PF_R: u32 = 4;
+
+/// The stack size, unless `PT_GNU_STACK` asks for more.
+pub const DEFAULT_STACK_SIZE: u64 = 64 * 1024;
+/// The largest stack `PT_GNU_STACK` can ask for.
+pub const MAX_STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The ways an ELF file can be malformed or unsupported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file is shorter than the file header.
+    Truncated,
+    /// The file doesn't start with `\x7fELF`.
+    BadMagic,
+    /// Not a 64-bit file, with this `EI_CLASS`.
+    NotElf64(u8),
+    /// Not little endian, with this `EI_DATA`.
+    NotLittleEndian(u8),
+    /// An ELF version other than 1.
+    BadVersion,
+    /// Not an executable, with this `e_type`. Shared objects and position independent executables
+    /// are not supported.
+    NotExecutable(u16),
+    /// Not for x86_64, with this `e_machine`.
+    WrongMachine(u16),
+    /// The program headers have this size instead of [`PROGRAM_HEADER_SIZE`].
+    BadProgramHeaderSize(u16),
+    /// The program header table extends past the end of the file.
+    ProgramHeadersOutOfBounds,
+    /// The executable needs a program interpreter, i.e. it is dynamically linked.
+    DynamicallyLinked,
+    /// There is no `PT_LOAD` segment.
+    NoLoadableSegments,
+    /// The contents of the segment with this program header index extend past the end of the
+    /// file.
+    SegmentOutOfBounds(usize),
+    /// The segment with this index has more file contents than memory.
+    FileSizeExceedsMemorySize(usize),
+    /// The segment with this index doesn't lie in user memory.
+    SegmentOutsideUserMemory(usize),
+    /// The address and the file offset of the segment with this index differ modulo the page size.
+    MisalignedSegment(usize),
+    /// The segment with this index shares a page with an earlier segment.
+    OverlappingSegments(usize),
+    /// The segment with this index is both writable and executable.
+    WritableAndExecutable(usize),
+    /// `PT_GNU_STACK` asks for an executable stack.
+    ExecutableStack,
+    /// `PT_GNU_STACK` asks for a stack larger than [`MAX_STACK_SIZE`].
+    StackTooLarge(u64),
+    /// The entry point is not in an executable segment.
+    EntryNotExecutable(u64),
+    /// Mapping the segments failed.
+    Paging(PagingError),
+}
+
+impl From<PagingError> for ElfError {
+    fn from(err: PagingError) -> Self {
+        ElfError::Paging(err)
+    }
+}
+
+/// A parsed executable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Executable {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    /// The size of the stack, from `PT_GNU_STACK` or [`DEFAULT_STACK_SIZE`], in whole pages.
+    pub stack_size: u64,
+    /// Where the program headers are in memory, if a segment contains them, for the auxiliary
+    /// vector.
+    pub program_headers: Option<u64>,
+    pub program_header_count: u16,
+}
+
+/// A `PT_LOAD` segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// The virtual address.
+    pub start: u64,
+    /// The size in memory, at least the size of `file_range`.
+    pub mem_size: u64,
+    /// The contents in the file.
+    pub file_range: Range<usize>,
+    /// [`PF_R`], [`PF_W`] and [`PF_X`].
+    pub flags: u32,
+}
+
+impl Segment {
+    /// The page aligned memory range of the segment.
+    pub fn pages(&self) -> Range<u64> {
+        align_down(self.start)..align_up(self.start + self.mem_size)
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        (self.start..self.start + self.mem_size).contains(&addr)
+    }
+
+    /// The page table flags for the segment's memory.
+    fn page_table_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= protection::no_execute();
+        }
+        flags
+    }
+}
+
+/// Checks the ELF file `data` and returns its segments and entry point.
+pub fn parse(data: &[u8]) -> Result<Executable, ElfError> {
+    if data.len() < HEADER_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if data[..4] != *b"\x7fELF" {
+        return Err(ElfError::BadMagic);
+    }
+    match data[4] {
+        2 => {}
+        class => return Err(ElfError::NotElf64(class)),
+    }
+    match data[5] {
+        1 => {}
+        encoding => return Err(ElfError::NotLittleEndian(encoding)),
+    }
+    if data[6] != 1 || read_u32(data, 20) != 1 {
+        return Err(ElfError::BadVersion);
+    }
+    match read_u16(data, 16) {
+        ET_EXEC => {}
+        kind => return Err(ElfError::NotExecutable(kind)),
+    }
+    match read_u16(data, 18) {
+        EM_X86_64 => {}
+        machine => return Err(ElfError::WrongMachine(machine)),
+    }
+    let entry = read_u64(data, 24);
+    let program_header_offset = read_u64(data, 32);
+    let program_header_size = read_u16(data, 54);
+    let program_header_count = read_u16(data, 56);
+    if usize::from(program_header_size) != PROGRAM_HEADER_SIZE && program_header_count > 0 {
+        return Err(ElfError::BadProgramHeaderSize(program_header_size));
+    }
+    let table = file_range(
+        data,
+        program_header_offset,
+        u64::from(program_header_count) * PROGRAM_HEADER_SIZE as u64,
+    )
+    .ok_or(ElfError::ProgramHeadersOutOfBounds)?;
+
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut stack_size = DEFAULT_STACK_SIZE;
+    let mut program_headers = None;
+    for (index, header) in data[table].chunks_exact(PROGRAM_HEADER_SIZE).enumerate() {
+        let flags = read_u32(header, 4);
+        match read_u32(header, 0) {
+            PT_LOAD => {}
+            PT_INTERP => return Err(ElfError::DynamicallyLinked),
+            PT_GNU_STACK => {
+                if flags & PF_X != 0 {
+                    return Err(ElfError::ExecutableStack);
+                }
+                let size = read_u64(header, 40);
+                if size > MAX_STACK_SIZE {
+                    return Err(ElfError::StackTooLarge(size));
+                }
+                stack_size = align_up(size.max(DEFAULT_STACK_SIZE));
+                continue;
+            }
+            _ => continue,
+        }
+        let offset = read_u64(header, 8);
+        let start = read_u64(header, 16);
+        let file_size = read_u64(header, 32);
+        let mem_size = read_u64(header, 40);
+
+        let file_range =
+            file_range(data, offset, file_size).ok_or(ElfError::SegmentOutOfBounds(index))?;
+        if file_size > mem_size {
+            return Err(ElfError::FileSizeExceedsMemorySize(index));
+        }
+        let in_user_memory = VirtAddr::try_new(start)
+            .is_ok_and(|start| address_space::is_user_range(start, mem_size))
+            // Leaves room to round the end up to a page.
+            && start + mem_size <= align_down(address_space::USER_END);
+        if !in_user_memory {
+            return Err(ElfError::SegmentOutsideUserMemory(index));
+        }
+        if start % PAGE_SIZE != offset % PAGE_SIZE {
+            return Err(ElfError::MisalignedSegment(index));
+        }
+        if flags & PF_W != 0 && flags & PF_X != 0 {
+            return Err(ElfError::WritableAndExecutable(index));
+        }
+        let segment = Segment {
+            start,
+            mem_size,
+            file_range,
+            flags,
+        };
+        let pages = segment.pages();
+        let overlaps = segments.iter().any(|other| {
+            let other = other.pages();
+            pages.start < other.end && other.start < pages.end
+        });
+        if overlaps {
+            return Err(ElfError::OverlappingSegments(index));
+        }
+        let headers_offset = program_header_offset.wrapping_sub(offset);
+        if program_headers.is_none() && headers_offset < file_size {
+            program_headers = Some(start + headers_offset);
+        }
+        segments.push(segment);
+    }
+
+    if segments.is_empty() {
+        return Err(ElfError::NoLoadableSegments);
+    }
+    let executable = segments
+        .iter()
+        .any(|segment| segment.flags & PF_X != 0 && segment.contains(entry));
+    if !executable {
+        return Err(ElfError::EntryNotExecutable(entry));
+    }
+    Ok(Executable {
+        entry,
+        segments,
+        stack_size,
+        program_headers,
+        program_header_count,
+    })
+}
+
+/// Parses the ELF file `data` and maps its segments into `space`, which must not have any user
+/// memory in their pages yet.
+pub fn load(space: &mut AddressSpace, data: &[u8]) -> Result<Executable, ElfError> {
+    let executable = parse(data)?;
+    for segment in &executable.segments {
+        let pages = segment.pages();
+        space.map(
+            VirtAddr::new(pages.start),
+            pages.end - pages.start,
+            segment.page_table_flags(),
+            AreaKind::Image,
+        )?;
+        space.write(
+            VirtAddr::new(segment.start),
+            &data[segment.file_range.clone()],
+        )?;
+    }
+    Ok(executable)
+}
+
+/// The range of `len` bytes at `offset` in `data`, if it lies within.
+fn file_range(data: &[u8], offset: u64, len: u64) -> Option<Range<usize>> {
+    let start = usize::try_from(offset).ok()?;
+    let end = start.checked_add(usize::try_from(len).ok()?)?;
+    (end <= data.len()).then_some(start..end)
+}
+
+fn align_down(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn align_up(addr: u64) -> u64 {
+    align_down(addr + PAGE_SIZE - 1)
+}
+
+// The callers checked that `data` is long enough.
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// A program header to build, for tests.
+#[cfg(test)]
+pub(crate) struct BuildHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub vaddr: u64,
+    /// Placed in the file after the data of earlier headers, at the same offset within a page as
+    /// `vaddr`.
+    pub data: &'static [u8],
+    pub mem_size: u64,
+}
+
+#[cfg(test)]
+impl BuildHeader {
+    pub fn load(flags: u32, vaddr: u64, data: &'static [u8], mem_size: u64) -> Self {
+        BuildHeader {
+            kind: PT_LOAD,
+            flags,
+            vaddr,
+            data,
+            mem_size,
+        }
+    }
+}
+
+/// An executable with the program `headers` and their data, for tests.
+#[cfg(test)]
+pub(crate) fn build_elf(entry: u64, headers: &[BuildHeader]) -> Vec<u8> {
+    let mut file = alloc::vec![0; HEADER_SIZE + headers.len() * PROGRAM_HEADER_SIZE];
+    file[..4].copy_from_slice(b"\x7fELF");
+    file[4..7].copy_from_slice(&[2, 1, 1]);
+    file[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    file[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+    file[20..24].copy_from_slice(&1u32.to_le_bytes());
+    file[24..32].copy_from_slice(&entry.to_le_bytes());
+    file[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    file[52..54].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    file[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    file[56..58].copy_from_slice(&(headers.len() as u16).to_le_bytes());
+
+    for (index, header) in headers.iter().enumerate() {
+        // The same offset within the page as the address.
+        let mut offset = align_up(file.len() as u64) + header.vaddr % PAGE_SIZE;
+        if header.data.is_empty() {
+            offset = 0;
+        }
+        file.resize(offset as usize, 0);
+        file.extend_from_slice(header.data);
+
+        let at = HEADER_SIZE + index * PROGRAM_HEADER_SIZE;
+        let fields: [u64; 6] = [
+            offset,
+            header.vaddr,
+            header.vaddr,
+            header.data.len() as u64,
+            header.mem_size,
+            PAGE_SIZE,
+        ];
+        file[at..at + 4].copy_from_slice(&header.kind.to_le_bytes());
+        file[at + 4..at + 8].copy_from_slice(&header.flags.to_le_bytes());
+        for (field, value) in fields.iter().enumerate() {
+            let start = at + 8 + field * 8;
+            file[start..start + 8].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+    file
+}
+
+/// An executable that runs `code`, loaded at [`crate::process::CODE_START`], for tests.
+#[cfg(test)]
+pub(crate) fn build_program(code: &'static [u8]) -> Vec<u8> {
+    let start = crate::process::CODE_START;
+    let len = code.len() as u64;
+    build_elf(start, &[BuildHeader::load(PF_R | PF_X, start, code, len)])
+}
+
+#[cfg(test)]
+use crate::{memory::phys_to_virt, process::CODE_START};
+
+#[test_case]
+fn test_parse_accepts_an_executable() {
+    const CODE: &[u8] = &[0x0f, 0x0b];
+    const DATA: &[u8] = b"data";
+    let data_start = CODE_START + 0x2010;
+    let mut stack = BuildHeader::load(PF_R | PF_W, 0, &[], 128 * 1024);
+    stack.kind = PT_GNU_STACK;
+    let file = build_elf(
+        CODE_START + 1,
+        &[
+            BuildHeader::load(PF_R | PF_X, CODE_START, CODE, 2),
+            BuildHeader::load(PF_R | PF_W, data_start, DATA, 0x2000),
+            stack,
+        ],
+    );
+
+    let executable = parse(&file).unwrap();
+    assert_eq!(executable.entry, CODE_START + 1);
+    assert_eq!(executable.stack_size, 128 * 1024);
+    assert_eq!(executable.segments.len(), 2);
+    let data = &executable.segments[1];
+    assert_eq!(data.start, data_start);
+    assert_eq!(data.mem_size, 0x2000);
+    assert_eq!(&file[data.file_range.clone()], DATA);
+    assert_eq!(data.pages(), CODE_START + 0x2000..CODE_START + 0x5000);
+}
+
+#[test_case]
+fn test_parse_rejects_malformed_headers() {
+    let valid = build_program(&[0x0f, 0x0b]);
+    let broken = |offset: usize, bytes: &[u8]| {
+        let mut file = valid.clone();
+        file[offset..offset + bytes.len()].copy_from_slice(bytes);
+        parse(&file)
+    };
+
+    assert_eq!(parse(&valid[..HEADER_SIZE - 1]), Err(ElfError::Truncated));
+    assert_eq!(broken(0, b"\x7fELL"), Err(ElfError::BadMagic));
+    assert_eq!(broken(4, &[1]), Err(ElfError::NotElf64(1)));
+    assert_eq!(broken(5, &[2]), Err(ElfError::NotLittleEndian(2)));
+    assert_eq!(broken(20, &[2]), Err(ElfError::BadVersion));
+    assert_eq!(broken(16, &[3, 0]), Err(ElfError::NotExecutable(3)));
+    assert_eq!(broken(18, &[40, 0]), Err(ElfError::WrongMachine(40)));
+    assert_eq!(
+        broken(54, &[32, 0]),
+        Err(ElfError::BadProgramHeaderSize(32))
+    );
+    assert_eq!(
+        broken(56, &[200, 0]),
+        Err(ElfError::ProgramHeadersOutOfBounds)
+    );
+    assert_eq!(
+        broken(32, &u64::MAX.to_le_bytes()),
+        Err(ElfError::ProgramHeadersOutOfBounds)
+    );
+    assert_eq!(broken(56, &[0, 0]), Err(ElfError::NoLoadableSegments));
+}
+
+#[test_case]
+fn test_parse_rejects_bad_segments() {
+    const CODE: &[u8] = &[0x0f, 0x0b];
+    let code = || BuildHeader::load(PF_R | PF_X, CODE_START, CODE, 2);
+    let parse_with = |entry: u64, extra: BuildHeader| parse(&build_elf(entry, &[code(), extra]));
+    let header = |kind: u32, flags: u32, vaddr: u64, mem_size: u64| BuildHeader {
+        kind,
+        flags,
+        vaddr,
+        data: CODE,
+        mem_size,
+    };
+    let data = CODE_START + 0x10000;
+
+    assert_eq!(
+        parse_with(CODE_START, header(PT_INTERP, PF_R, data, 2)),
+        Err(ElfError::DynamicallyLinked)
+    );
+    assert_eq!(
+        parse_with(CODE_START, header(PT_LOAD, PF_R, data, 1)),
+        Err(ElfError::FileSizeExceedsMemorySize(1))
+    );
+    assert_eq!(
+        parse_with(CODE_START, header(PT_LOAD, PF_R, 0x200000, 2)),
+        Err(ElfError::SegmentOutsideUserMemory(1))
+    );
+    assert_eq!(
+        parse_with(CODE_START, header(PT_LOAD, PF_R, data, u64::MAX)),
+        Err(ElfError::SegmentOutsideUserMemory(1))
+    );
+    assert_eq!(
+        parse_with(CODE_START, header(PT_LOAD, PF_R, CODE_START + 0x800, 2)),
+        Err(ElfError::OverlappingSegments(1))
+    );
+    assert_eq!(
+        parse_with(CODE_START, header(PT_LOAD, PF_W | PF_X, data, 2)),
+        Err(ElfError::WritableAndExecutable(1))
+    );
+    assert_eq!(
+        parse_with(CODE_START, header(PT_GNU_STACK, PF_R | PF_W | PF_X, 0, 0)),
+        Err(ElfError::ExecutableStack)
+    );
+    assert_eq!(
+        parse_with(CODE_START, header(PT_GNU_STACK, PF_R | PF_W, 0, u64::MAX)),
+        Err(ElfError::StackTooLarge(u64::MAX))
+    );
+    assert_eq!(
+        parse_with(data, header(PT_LOAD, PF_R | PF_W, data, 2)),
+        Err(ElfError::EntryNotExecutable(data))
+    );
+
+    // The contents of the second segment are at the end of the file.
+    let mut file = build_elf(CODE_START, &[code(), header(PT_LOAD, PF_R, data, 2)]);
+    file.pop();
+    assert_eq!(parse(&file), Err(ElfError::SegmentOutOfBounds(1)));
+    let mut file = build_elf(CODE_START, &[code(), header(PT_LOAD, PF_R, data, 2)]);
+    let vaddr = HEADER_SIZE + PROGRAM_HEADER_SIZE + 16;
+    file[vaddr..vaddr + 8].copy_from_slice(&(data + 1).to_le_bytes());
+    assert_eq!(parse(&file), Err(ElfError::MisalignedSegment(1)));
+}
+
+#[test_case]
+fn test_load_maps_segments_and_zeroes_the_bss() {
+    const CODE: &[u8] = &[0x0f, 0x0b];
+    const DATA: &[u8] = b"initialized";
+    let data_start = CODE_START + 0x1ff8;
+    let file = build_elf(
+        CODE_START,
+        &[
+            BuildHeader::load(PF_R | PF_X, CODE_START, CODE, 2),
+            BuildHeader::load(PF_R | PF_W, data_start, DATA, 0x2000),
+        ],
+    );
+    let mut space = AddressSpace::new().unwrap();
+    load(&mut space, &file).unwrap();
+
+    let (_, flags) = space.translate(VirtAddr::new(CODE_START)).unwrap();
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+    let read = |addr: u64| {
+        let (phys, flags) = space.translate(VirtAddr::new(addr)).unwrap();
+        // The last page was never written, so it is still the zero frame, see `address_space`.
+        assert!(flags.intersects(PageTableFlags::WRITABLE | address_space::COPY_ON_WRITE));
+        assert!(flags.contains(protection::no_execute()));
+        unsafe { *phys_to_virt(phys).as_ptr::<u8>() }
+    };
+    // Across a page boundary.
+    for (offset, byte) in DATA.iter().enumerate() {
+        assert_eq!(read(data_start + offset as u64), *byte);
+    }
+    // The BSS, up to the end of the last page.
+    for offset in (DATA.len() as u64..0x2008).step_by(0x100) {
+        assert_eq!(read(data_start + offset), 0);
+    }
+    assert_eq!(space.translate(VirtAddr::new(CODE_START + 0x4000)), None);
+}
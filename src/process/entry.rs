@@ -0,0 +1,186 @@
+//! # entry
+//!
+//! Entering user mode, and returning from it for good.
+//!
+//! [`enter_user`] saves the callee-saved registers and `RFLAGS` on the kernel stack of the thread,
+//! like a context switch (see [`crate::thread`]), and makes the stack pointer below them the kernel
+//! stack of the CPU for interrupts in user mode (`RSP0`, see [`gdt::set_kernel_stack`]). Then it
+//! copies the [`Registers`] of the user code below them, in the layout of the system call entry,
+//! and returns to the user code like a system call would, by restoring them and `iretq`. A new
+//! process starts with [`initial_registers`], a forked one with a copy of its parent's.
+//!
+//! From then on, the thread only runs kernel code in interrupt and exception handlers and system
+//! calls, all on its kernel stack below the saved registers (or on an interrupt stack). To end the
+//! user code, [`leave_user`] restores the saved registers from `RSP0`, so [`enter_user`] finally
+//! returns to its caller, and the kernel stack of whatever handler called it is simply dropped.
+//!
+//! Delivering a signal changes all registers of the user code (see [`super::signal`]), but an
+//! interrupt handler only has the interrupt stack frame. So [`divert`] makes the handler return to
+//! `signal_entry` in kernel mode instead, which saves the untouched registers of the user code as
+//! [`Registers`], like the system call entry, and delivers the signals with them.
+
+use crate::{gdt, syscall::Registers};
+use core::{arch::global_asm, mem::size_of};
+use x86_64::{
+    structures::idt::{InterruptStackFrame, InterruptStackFrameValue},
+    VirtAddr,
+};
+
+extern "C" {
+    /// Runs user code with `registers`, until [`leave_user`] is called. The current address space
+    /// must map its code and stack.
+    pub fn enter_user(registers: &Registers);
+
+    /// Continues after the [`enter_user`] call whose kernel stack is `kernel_stack`.
+    fn leave_user_at(kernel_stack: u64) -> !;
+
+    /// Where [`divert`] returns to.
+    fn signal_entry();
+}
+
+/// The `RFLAGS` of new user code: interrupts enabled, and the reserved bit 1.
+const USER_RFLAGS: u64 = 0x202;
+
+global_asm!(
+    ".global enter_user",
+    "enter_user:",
+    "pushfq",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // Not preempted between setting RSP0 and reaching user mode; `iretq` enables interrupts.
+    "cli",
+    "mov r12, rdi",
+    // With the return address and 7 registers, the stack is 16 byte aligned for the call.
+    "mov rdi, rsp",
+    "call {set_kernel_stack}",
+    // The registers and the interrupt stack frame, from the argument. The direction flag is clear.
+    "sub rsp, {registers_size}",
+    "mov rdi, rsp",
+    "mov rsi, r12",
+    "mov ecx, {registers_words}",
+    "rep movsq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // The GS base of user mode, see `crate::percpu::InterruptEntry`.
+    "swapgs",
+    "iretq",
+    "",
+    ".global leave_user_at",
+    "leave_user_at:",
+    "mov rsp, rdi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "popfq",
+    "ret",
+    "",
+    ".global signal_entry",
+    "signal_entry:",
+    // With interrupts disabled, on the empty kernel stack, and with the GS base of user mode.
+    "swapgs",
+    // The interrupt stack frame, which `resume_diverted` fills in. The stack is 16 byte aligned
+    // again after it and 15 registers.
+    "sub rsp, 40",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "call {resume_diverted}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "swapgs",
+    "iretq",
+    set_kernel_stack = sym set_kernel_stack,
+    resume_diverted = sym super::resume_diverted,
+    registers_size = const size_of::<Registers>(),
+    registers_words = const size_of::<Registers>() / 8,
+);
+
+/// The registers of new user code that starts at `entry` with the stack pointer `stack`: all
+/// others are 0, so no kernel values leak to user mode.
+pub fn initial_registers(entry: u64, stack: u64) -> Registers {
+    Registers {
+        rip: entry,
+        cs: u64::from(gdt::USER_CODE_SELECTOR.0),
+        rflags: USER_RFLAGS,
+        rsp: stack,
+        ss: u64::from(gdt::USER_DATA_SELECTOR.0),
+        ..Registers::default()
+    }
+}
+
+extern "C" fn set_kernel_stack(stack: u64) {
+    gdt::set_kernel_stack(VirtAddr::new(stack));
+}
+
+/// Returns from the [`enter_user`] call of the current thread, abandoning the handler that calls
+/// it. Must be called in kernel mode, with the kernel's GS base, on the thread that entered user
+/// mode.
+pub fn leave_user() -> ! {
+    unsafe { leave_user_at(gdt::kernel_stack().as_u64()) }
+}
+
+/// Makes the interrupt or exception handler with `stack_frame`, which interrupted user code,
+/// return to `signal_entry` in kernel mode instead, which calls [`super::resume_diverted`] with
+/// the registers of the user code. Returns the replaced stack frame, which `resume_diverted` needs
+/// to complete them. Interrupts must stay disabled until the handler returns.
+pub fn divert(stack_frame: &mut InterruptStackFrame) -> InterruptStackFrameValue {
+    let user = **stack_frame;
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(signal_entry as *const () as u64);
+            frame.code_segment = u64::from(gdt::KERNEL_CODE_SELECTOR.0);
+            // Interrupts disabled, and the reserved bit 1.
+            frame.cpu_flags = 0x2;
+            // The interrupt stack frame was the only thing on the kernel stack.
+            frame.stack_pointer = gdt::kernel_stack();
+            frame.stack_segment = u64::from(gdt::KERNEL_DATA_SELECTOR.0);
+        })
+    };
+    user
+}
@@ -0,0 +1,112 @@
+//! # programs
+//!
+//! The programs [`super::exec`] can run, by name.
+//!
+//! Programs aren't files of the file system (see [`crate::fs`]) yet, but executables kept in
+//! memory, e.g. the programs of the `user` crate, which `build.rs` builds into the kernel image
+//! (see [`register_user_programs`]). [`register`] checks that an executable can be parsed before
+//! adding it, so a registered program only fails to start if its memory or arguments don't fit.
+
+use super::elf::{self, ElfError};
+use crate::sync::IrqMutex;
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+static PROGRAMS: IrqMutex<BTreeMap<String, &'static [u8]>> =
+    IrqMutex::named("PROGRAMS", BTreeMap::new());
+
+/// The programs of the `user` crate, see `user/src/bin`.
+const USER_PROGRAMS: [(&str, &[u8]); 7] = [
+    ("cat", include_bytes!(concat!(env!("OUT_DIR"), "/user/cat"))),
+    (
+        "init",
+        include_bytes!(concat!(env!("OUT_DIR"), "/user/init")),
+    ),
+    ("ls", include_bytes!(concat!(env!("OUT_DIR"), "/user/ls"))),
+    (
+        "mkdir",
+        include_bytes!(concat!(env!("OUT_DIR"), "/user/mkdir")),
+    ),
+    ("sh", include_bytes!(concat!(env!("OUT_DIR"), "/user/sh"))),
+    (
+        "sleep",
+        include_bytes!(concat!(env!("OUT_DIR"), "/user/sleep")),
+    ),
+    ("wc", include_bytes!(concat!(env!("OUT_DIR"), "/user/wc"))),
+];
+
+/// Adds the ELF executable `elf` as the program `name`, replacing an older one with that name.
+pub fn register(name: &str, elf: &'static [u8]) -> Result<(), ElfError> {
+    elf::parse(elf)?;
+    PROGRAMS.lock().insert(name.to_string(), elf);
+    Ok(())
+}
+
+/// Registers the programs of the `user` crate under their names. Called once at boot.
+pub fn register_user_programs() {
+    for &(name, elf) in USER_PROGRAMS.iter() {
+        register(name, elf).expect("invalid user program");
+    }
+}
+
+/// The executable of the program `name`.
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS.lock().get(name).copied()
+}
+
+/// The names of all programs, sorted.
+pub fn names() -> Vec<String> {
+    PROGRAMS.lock().keys().cloned().collect()
+}
+
+#[test_case]
+fn test_programs_are_registered_by_name() {
+    let elf: &'static [u8] = elf::build_program(&[0x0f, 0x0b]).leak();
+    assert_eq!(register("broken", &elf[..10]), Err(ElfError::Truncated));
+    assert_eq!(find("broken"), None);
+
+    register("ud2", elf).unwrap();
+    assert_eq!(find("ud2"), Some(elf));
+    assert!(names().iter().any(|name| name == "ud2"));
+}
+
+/// Runs `sh -c command` and waits for it.
+#[cfg(test)]
+fn run_shell(command: &str) -> super::ExitStatus {
+    register_user_programs();
+    let sh = find("sh").unwrap();
+    super::spawn_elf("sh", sh, &["sh", "-c", command], &[])
+        .unwrap()
+        .wait()
+}
+
+#[test_case]
+fn test_shell_runs_pipelines_with_redirections() {
+    use super::ExitStatus;
+    use crate::fs::{self, OpenOptions};
+
+    assert_eq!(run_shell("mkdir /sh-test"), ExitStatus::Exited(0));
+    assert_eq!(
+        run_shell("echo 'hello  world' > /sh-test/in"),
+        ExitStatus::Exited(0)
+    );
+    assert_eq!(
+        run_shell("cat < /sh-test/in | wc > /sh-test/out"),
+        ExitStatus::Exited(0)
+    );
+    let read = OpenOptions {
+        read: true,
+        ..OpenOptions::default()
+    };
+    let mut buffer = [0; 32];
+    let out = fs::open("/sh-test/out", read).unwrap();
+    let len = out.read(&mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"1 2 13\n");
+
+    assert_eq!(run_shell("exit 3"), ExitStatus::Exited(3));
+    assert_eq!(run_shell("no-such-program"), ExitStatus::Exited(127));
+    assert_eq!(run_shell("echo |"), ExitStatus::Exited(2));
+}
@@ -0,0 +1,562 @@
+//! # signal
+//!
+//! POSIX signals, with the numbers and the user ABI of Linux on x86_64.
+//!
+//! Every process has a set of pending signals, a mask of blocked ones and an [`Action`] for each
+//! signal, all in its [`Signals`]. A signal is sent by posting it as pending (see
+//! [`super::send`]); it is delivered when the process returns to user mode next and doesn't block
+//! it: after a system call, after an interrupt that preempted its user code, or right away for
+//! the signals of CPU exceptions (see [`super::fault`]). The default action of most signals ends
+//! the process (see [`DefaultAction`]), but user code can ignore them or install a handler with
+//! `sigaction`.
+//!
+//! A handler runs on the user stack, below the red zone, on top of a [`Frame`] with the
+//! interrupted registers and signal mask. It is called with the signal number in `rdi` and
+//! returns to the *restorer* given to `sigaction`, which must make the `sigreturn` system call:
+//! that restores the registers and the mask from the frame, so the user code continues where the
+//! signal interrupted it. While the handler runs, the signal itself and the handler's mask are
+//! blocked.
+
+use crate::{
+    gdt,
+    memory::user::{copy_from_user, copy_to_user, BadAddress},
+    syscall::Registers,
+};
+use core::{fmt, mem::size_of, slice};
+use x86_64::{registers::rflags::RFlags, VirtAddr};
+
+/// A signal number, from 1 to [`Signal::MAX`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Signal(u8);
+
+impl Signal {
+    pub const SIGHUP: Signal = Signal(1);
+    pub const SIGINT: Signal = Signal(2);
+    pub const SIGQUIT: Signal = Signal(3);
+    pub const SIGILL: Signal = Signal(4);
+    pub const SIGTRAP: Signal = Signal(5);
+    pub const SIGABRT: Signal = Signal(6);
+    pub const SIGBUS: Signal = Signal(7);
+    pub const SIGFPE: Signal = Signal(8);
+    pub const SIGKILL: Signal = Signal(9);
+    pub const SIGUSR1: Signal = Signal(10);
+    pub const SIGSEGV: Signal = Signal(11);
+    pub const SIGUSR2: Signal = Signal(12);
+    pub const SIGPIPE: Signal = Signal(13);
+    pub const SIGALRM: Signal = Signal(14);
+    pub const SIGTERM: Signal = Signal(15);
+    pub const SIGSTKFLT: Signal = Signal(16);
+    pub const SIGCHLD: Signal = Signal(17);
+    pub const SIGCONT: Signal = Signal(18);
+    pub const SIGSTOP: Signal = Signal(19);
+    pub const SIGTSTP: Signal = Signal(20);
+    pub const SIGTTIN: Signal = Signal(21);
+    pub const SIGTTOU: Signal = Signal(22);
+    pub const SIGURG: Signal = Signal(23);
+    pub const SIGXCPU: Signal = Signal(24);
+    pub const SIGXFSZ: Signal = Signal(25);
+    pub const SIGVTALRM: Signal = Signal(26);
+    pub const SIGPROF: Signal = Signal(27);
+    pub const SIGWINCH: Signal = Signal(28);
+    pub const SIGIO: Signal = Signal(29);
+    pub const SIGPWR: Signal = Signal(30);
+    pub const SIGSYS: Signal = Signal(31);
+
+    /// The highest signal number.
+    pub const MAX: u8 = 31;
+
+    /// The signal `number`, if there is one.
+    pub fn new(number: u64) -> Option<Self> {
+        (1..=u64::from(Self::MAX))
+            .contains(&number)
+            .then_some(Signal(number as u8))
+    }
+
+    pub fn number(self) -> u8 {
+        self.0
+    }
+
+    pub fn name(self) -> &'static str {
+        const NAMES: [&str; Signal::MAX as usize] = [
+            "SIGHUP",
+            "SIGINT",
+            "SIGQUIT",
+            "SIGILL",
+            "SIGTRAP",
+            "SIGABRT",
+            "SIGBUS",
+            "SIGFPE",
+            "SIGKILL",
+            "SIGUSR1",
+            "SIGSEGV",
+            "SIGUSR2",
+            "SIGPIPE",
+            "SIGALRM",
+            "SIGTERM",
+            "SIGSTKFLT",
+            "SIGCHLD",
+            "SIGCONT",
+            "SIGSTOP",
+            "SIGTSTP",
+            "SIGTTIN",
+            "SIGTTOU",
+            "SIGURG",
+            "SIGXCPU",
+            "SIGXFSZ",
+            "SIGVTALRM",
+            "SIGPROF",
+            "SIGWINCH",
+            "SIGIO",
+            "SIGPWR",
+            "SIGSYS",
+        ];
+        NAMES[usize::from(self.0 - 1)]
+    }
+
+    pub fn default_action(self) -> DefaultAction {
+        match self {
+            Signal::SIGCHLD | Signal::SIGURG | Signal::SIGWINCH => DefaultAction::Ignore,
+            Signal::SIGCONT => DefaultAction::Continue,
+            Signal::SIGSTOP | Signal::SIGTSTP | Signal::SIGTTIN | Signal::SIGTTOU => {
+                DefaultAction::Stop
+            }
+            Signal::SIGQUIT
+            | Signal::SIGILL
+            | Signal::SIGTRAP
+            | Signal::SIGABRT
+            | Signal::SIGBUS
+            | Signal::SIGFPE
+            | Signal::SIGSEGV
+            | Signal::SIGXCPU
+            | Signal::SIGXFSZ
+            | Signal::SIGSYS => DefaultAction::Core,
+            _ => DefaultAction::Terminate,
+        }
+    }
+
+    /// Whether the action of the signal can't be changed and the signal can't be blocked.
+    pub fn is_unstoppable(self) -> bool {
+        self == Signal::SIGKILL || self == Signal::SIGSTOP
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What happens to a process for a signal without a handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    /// Terminate with a core dump. There are no core files, so it terminates like
+    /// [`DefaultAction::Terminate`].
+    Core,
+    Ignore,
+    /// Stop until a [`Signal::SIGCONT`].
+    Stop,
+    /// Continue if stopped, otherwise ignore.
+    Continue,
+}
+
+/// A set of signals, with bit `n - 1` for signal `n` like `sigset_t` on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SigSet(u64);
+
+impl SigSet {
+    pub const EMPTY: SigSet = SigSet(0);
+
+    /// The set with the bits `bits`, without the bits of signals that don't exist.
+    pub fn from_bits_truncate(bits: u64) -> Self {
+        SigSet(bits & ((1 << Signal::MAX) - 1))
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, signal: Signal) -> bool {
+        self.0 & bit(signal) != 0
+    }
+
+    pub fn insert(&mut self, signal: Signal) {
+        self.0 |= bit(signal);
+    }
+
+    pub fn remove(&mut self, signal: Signal) {
+        self.0 &= !bit(signal);
+    }
+
+    /// The set without [`Signal::SIGKILL`] and [`Signal::SIGSTOP`], which can't be blocked.
+    pub fn blockable(mut self) -> Self {
+        self.remove(Signal::SIGKILL);
+        self.remove(Signal::SIGSTOP);
+        self
+    }
+
+    /// The lowest signal in the set.
+    fn first(self) -> Option<Signal> {
+        (self.0 != 0).then(|| Signal(self.0.trailing_zeros() as u8 + 1))
+    }
+}
+
+impl core::ops::BitOr for SigSet {
+    type Output = SigSet;
+
+    fn bitor(self, other: SigSet) -> SigSet {
+        SigSet(self.0 | other.0)
+    }
+}
+
+impl core::ops::Sub for SigSet {
+    type Output = SigSet;
+
+    fn sub(self, other: SigSet) -> SigSet {
+        SigSet(self.0 & !other.0)
+    }
+}
+
+fn bit(signal: Signal) -> u64 {
+    1 << (signal.0 - 1)
+}
+
+/// The `sa_handler` of the default action.
+pub const SIG_DFL: u64 = 0;
+/// The `sa_handler` that ignores the signal.
+pub const SIG_IGN: u64 = 1;
+
+/// The flag of [`UserAction`] that `sa_restorer` is set, which is required for a handler.
+pub const SA_RESTORER: u64 = 0x0400_0000;
+/// The flag of [`UserAction`] to not block the signal while its handler runs.
+pub const SA_NODEFER: u64 = 0x4000_0000;
+/// The flag of [`UserAction`] to reset the action to the default when the handler is called.
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// `struct sigaction` of the `sigaction` system call, in the layout of Linux.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct UserAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: u64,
+}
+
+/// What a process does for a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Default,
+    Ignore,
+    /// Call the user code at `entry`, which returns to `restorer`.
+    Handler {
+        entry: u64,
+        restorer: u64,
+        /// The signals blocked while it runs, in addition to the current mask.
+        mask: SigSet,
+        /// The `SA_` flags.
+        flags: u64,
+    },
+}
+
+impl Action {
+    /// The action described by `action`, if it is valid.
+    pub fn from_user(action: &UserAction) -> Option<Self> {
+        let known = SA_RESTORER | SA_NODEFER | SA_RESETHAND;
+        if action.flags & !known != 0 {
+            return None;
+        }
+        match action.handler {
+            SIG_DFL => Some(Action::Default),
+            SIG_IGN => Some(Action::Ignore),
+            entry if action.flags & SA_RESTORER != 0 => Some(Action::Handler {
+                entry,
+                restorer: action.restorer,
+                mask: SigSet::from_bits_truncate(action.mask),
+                flags: action.flags,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_user(self) -> UserAction {
+        match self {
+            Action::Default => UserAction::default(),
+            Action::Ignore => UserAction {
+                handler: SIG_IGN,
+                ..UserAction::default()
+            },
+            Action::Handler {
+                entry,
+                restorer,
+                mask,
+                flags,
+            } => UserAction {
+                handler: entry,
+                flags,
+                restorer,
+                mask: mask.bits(),
+            },
+        }
+    }
+
+    /// Whether the action for `signal` is to do nothing.
+    fn ignores(self, signal: Signal) -> bool {
+        match self {
+            Action::Ignore => true,
+            Action::Default => signal.default_action() == DefaultAction::Ignore,
+            Action::Handler { .. } => false,
+        }
+    }
+}
+
+/// The signal state of a process.
+#[derive(Debug, Clone)]
+pub struct Signals {
+    pending: SigSet,
+    blocked: SigSet,
+    actions: [Action; Signal::MAX as usize],
+}
+
+impl Signals {
+    pub const fn new() -> Self {
+        Signals {
+            pending: SigSet::EMPTY,
+            blocked: SigSet::EMPTY,
+            actions: [Action::Default; Signal::MAX as usize],
+        }
+    }
+
+    /// The state of a child created by `fork`: the same actions and mask, nothing pending.
+    pub fn fork(&self) -> Self {
+        Signals {
+            pending: SigSet::EMPTY,
+            ..self.clone()
+        }
+    }
+
+    /// Resets the handlers to the default action for `exec`, since their code is gone. Ignored
+    /// signals stay ignored.
+    pub fn exec(&mut self) {
+        for action in &mut self.actions {
+            if let Action::Handler { .. } = action {
+                *action = Action::Default;
+            }
+        }
+    }
+
+    pub fn action(&self, signal: Signal) -> Action {
+        self.actions[usize::from(signal.0 - 1)]
+    }
+
+    /// Sets the action of `signal`, which must not be [`Signal::is_unstoppable`]. Ignoring a
+    /// signal discards it if it is pending.
+    pub fn set_action(&mut self, signal: Signal, action: Action) {
+        self.actions[usize::from(signal.0 - 1)] = action;
+        if action.ignores(signal) {
+            self.pending.remove(signal);
+        }
+    }
+
+    pub fn blocked(&self) -> SigSet {
+        self.blocked
+    }
+
+    pub fn set_blocked(&mut self, blocked: SigSet) {
+        self.blocked = blocked.blockable();
+    }
+
+    pub fn pending(&self) -> SigSet {
+        self.pending
+    }
+
+    /// Makes `signal` pending, unless the process ignores it and doesn't block it. Returns
+    /// whether it is pending now. Continuing discards pending stop signals and the other way
+    /// around, like on Linux.
+    pub fn post(&mut self, signal: Signal) -> bool {
+        match signal.default_action() {
+            DefaultAction::Continue => {
+                for stop in [
+                    Signal::SIGSTOP,
+                    Signal::SIGTSTP,
+                    Signal::SIGTTIN,
+                    Signal::SIGTTOU,
+                ] {
+                    self.pending.remove(stop);
+                }
+            }
+            DefaultAction::Stop => self.pending.remove(Signal::SIGCONT),
+            _ => {}
+        }
+        if !self.blocked.contains(signal) && self.action(signal).ignores(signal) {
+            return false;
+        }
+        self.pending.insert(signal);
+        true
+    }
+
+    /// Makes `signal`, raised by user code itself, pending even if it is blocked or ignored: then
+    /// its default action is forced, like on Linux. Returns whether a handler will be called.
+    pub fn force(&mut self, signal: Signal) -> bool {
+        let handled =
+            !self.blocked.contains(signal) && matches!(self.action(signal), Action::Handler { .. });
+        if !handled {
+            self.blocked.remove(signal);
+            self.set_action(signal, Action::Default);
+        }
+        self.pending.insert(signal);
+        handled
+    }
+
+    /// Whether a pending signal is not blocked.
+    pub fn has_deliverable(&self) -> bool {
+        (self.pending - self.blocked).first().is_some()
+    }
+
+    /// Takes the lowest pending signal that is not blocked, with its action.
+    pub fn take(&mut self) -> Option<(Signal, Action)> {
+        let signal = (self.pending - self.blocked).first()?;
+        self.pending.remove(signal);
+        Some((signal, self.action(signal)))
+    }
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a signal handler finds on the user stack: the return address of the handler, followed by
+/// what `sigreturn` restores.
+#[derive(Debug, Clone)]
+#[repr(C)]
+struct Frame {
+    restorer: u64,
+    blocked: u64,
+    registers: Registers,
+}
+
+/// The bytes below the user stack pointer that leaf functions may use without moving it.
+const RED_ZONE: u64 = 128;
+
+/// The flags user code can change with `sigreturn`: carry, parity, adjust, zero, sign, trap,
+/// direction, overflow and alignment check.
+const USER_FLAGS: u64 = 0x4_0dd5;
+
+/// Sets up `registers` to call the handler `entry` with `signal`, after saving them and
+/// `blocked` in a [`Frame`] on the user stack. Fails if the frame can't be written there.
+pub(super) fn enter_handler(
+    registers: &mut Registers,
+    signal: Signal,
+    entry: u64,
+    restorer: u64,
+    blocked: SigSet,
+) -> Result<(), BadAddress> {
+    let frame = Frame {
+        restorer,
+        blocked: blocked.bits(),
+        registers: registers.clone(),
+    };
+    let size = size_of::<Frame>() as u64;
+    // Aligned like after a call: the return address is at a 16 byte aligned address minus 8.
+    let stack = registers
+        .rsp
+        .checked_sub(RED_ZONE + size)
+        .ok_or(BadAddress)?;
+    let stack = (stack & !15) - 8;
+    let bytes =
+        unsafe { slice::from_raw_parts((&frame as *const Frame).cast::<u8>(), size as usize) };
+    copy_to_user(VirtAddr::try_new(stack).map_err(|_| BadAddress)?, bytes)?;
+
+    registers.rip = entry;
+    registers.rsp = stack;
+    registers.rdi = u64::from(signal.number());
+    registers.rflags &= !(RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG).bits();
+    Ok(())
+}
+
+/// Restores the registers and returns the mask saved by [`enter_handler`], for `sigreturn`. The
+/// handler returned to the restorer, so the frame starts just below the stack pointer.
+pub(super) fn leave_handler(registers: &mut Registers) -> Result<SigSet, BadAddress> {
+    let addr = registers.rsp.checked_sub(8).ok_or(BadAddress)?;
+    let mut bytes = [0; size_of::<Frame>()];
+    copy_from_user(&mut bytes, VirtAddr::try_new(addr).map_err(|_| BadAddress)?)?;
+    let frame = unsafe { bytes.as_ptr().cast::<Frame>().read_unaligned() };
+
+    // Only what user code could have set itself, so `iretq` can't fail or return to the kernel.
+    let saved = frame.registers;
+    if VirtAddr::try_new(saved.rip).is_err() || VirtAddr::try_new(saved.rsp).is_err() {
+        return Err(BadAddress);
+    }
+    let rflags = (saved.rflags & USER_FLAGS) | (registers.rflags & !USER_FLAGS);
+    *registers = Registers {
+        rflags,
+        cs: u64::from(gdt::USER_CODE_SELECTOR.0),
+        ss: u64::from(gdt::USER_DATA_SELECTOR.0),
+        ..saved
+    };
+    Ok(SigSet::from_bits_truncate(frame.blocked))
+}
+
+#[test_case]
+fn test_signals_are_pending_until_unblocked() {
+    let mut signals = Signals::new();
+    assert!(!signals.post(Signal::SIGCHLD), "ignored by default");
+    signals.set_blocked(SigSet::from_bits_truncate(u64::MAX));
+    assert_eq!(
+        signals.blocked(),
+        SigSet::from_bits_truncate(u64::MAX).blockable()
+    );
+    assert!(signals.post(Signal::SIGUSR1));
+    assert!(signals.post(Signal::SIGINT));
+    assert!(!signals.has_deliverable());
+
+    assert!(signals.post(Signal::SIGKILL));
+    assert_eq!(signals.take(), Some((Signal::SIGKILL, Action::Default)));
+    signals.set_blocked(SigSet::EMPTY);
+    assert_eq!(signals.take(), Some((Signal::SIGINT, Action::Default)));
+    signals.set_action(Signal::SIGUSR1, Action::Ignore);
+    assert_eq!(signals.take(), None, "discarded when ignored");
+}
+
+#[test_case]
+fn test_faults_force_the_default_action() {
+    let handler = Action::Handler {
+        entry: 0x1000,
+        restorer: 0x2000,
+        mask: SigSet::EMPTY,
+        flags: SA_RESTORER,
+    };
+    let mut signals = Signals::new();
+    signals.set_action(Signal::SIGSEGV, handler);
+    assert!(signals.force(Signal::SIGSEGV));
+    assert_eq!(signals.take(), Some((Signal::SIGSEGV, handler)));
+
+    let mut blocked = SigSet::EMPTY;
+    blocked.insert(Signal::SIGSEGV);
+    signals.set_blocked(blocked);
+    assert!(!signals.force(Signal::SIGSEGV));
+    assert_eq!(signals.take(), Some((Signal::SIGSEGV, Action::Default)));
+    assert_eq!(signals.fork().pending(), SigSet::EMPTY);
+}
+
+#[test_case]
+fn test_user_actions_are_checked() {
+    let user = UserAction {
+        handler: 0x1000,
+        flags: SA_RESTORER | SA_RESETHAND,
+        restorer: 0x2000,
+        mask: 1 << 40 | 1,
+    };
+    let action = Action::from_user(&user).unwrap();
+    assert_eq!(action.to_user(), UserAction { mask: 1, ..user });
+    let no_restorer = UserAction { flags: 0, ..user };
+    assert_eq!(Action::from_user(&no_restorer), None);
+    assert_eq!(
+        Action::from_user(&UserAction::default()),
+        Some(Action::Default)
+    );
+    assert_eq!(Signal::new(0), None);
+    assert_eq!(Signal::new(9).map(Signal::name), Some("SIGKILL"));
+}
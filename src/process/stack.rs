@@ -0,0 +1,112 @@
+//! # stack
+//!
+//! The initial user stack of a process, as the System V ABI for x86_64 lays it out.
+//!
+//! ```text
+//!  top             end of the stack, e.g. `STACK_TOP`
+//!                  the argument and environment strings, NUL terminated
+//!                  padding, so the stack pointer is 16 byte aligned
+//!                  the auxiliary vector: (type, value) pairs, ending with `AT_NULL`
+//!                  0
+//!                  envp[0] .. envp[envc - 1]
+//!                  0
+//!                  argv[0] .. argv[argc - 1]
+//!  stack pointer   argc
+//! ```
+//!
+//! The program finds everything from its initial stack pointer. The auxiliary vector tells it
+//! about the kernel and the loaded executable, e.g. where its program headers are.
+
+use alloc::vec::Vec;
+
+/// Ends the auxiliary vector.
+pub const AT_NULL: u64 = 0;
+/// The address of the program headers.
+pub const AT_PHDR: u64 = 3;
+/// The size of a program header.
+pub const AT_PHENT: u64 = 4;
+/// The number of program headers.
+pub const AT_PHNUM: u64 = 5;
+/// The page size.
+pub const AT_PAGESZ: u64 = 6;
+/// The entry point of the executable.
+pub const AT_ENTRY: u64 = 9;
+
+/// The most bytes the arguments, the environment and the auxiliary vector may take, so they
+/// always fit into the stack with room to spare.
+pub const MAX_SIZE: usize = 32 * 1024;
+
+/// The contents of an initial stack.
+#[derive(Debug)]
+pub struct InitialStack {
+    /// The initial stack pointer, where `argc` is.
+    pub stack_pointer: u64,
+    /// The bytes from the stack pointer to the end of the stack.
+    pub data: Vec<u8>,
+}
+
+/// Lays out the initial stack ending at `top`, which must be 16 byte aligned, with the arguments
+/// `args`, the environment `env` and the auxiliary vector `auxv` (without `AT_NULL`). Returns
+/// `None` if it would be larger than [`MAX_SIZE`].
+pub fn build(top: u64, args: &[&str], env: &[&str], auxv: &[(u64, u64)]) -> Option<InitialStack> {
+    let strings: usize = args.iter().chain(env).map(|s| s.len() + 1).sum();
+    let words = 1 + (args.len() + 1) + (env.len() + 1) + 2 * (auxv.len() + 1);
+    let size = strings.checked_add(words * 8)?;
+    if size > MAX_SIZE {
+        return None;
+    }
+    let strings_start = top - strings as u64;
+    let stack_pointer = (strings_start - words as u64 * 8) & !15;
+
+    let mut data = Vec::with_capacity((top - stack_pointer) as usize);
+    let mut string_data = Vec::with_capacity(strings);
+    let mut push_word = |word: u64| data.extend_from_slice(&word.to_le_bytes());
+    push_word(args.len() as u64);
+    for list in [args, env] {
+        for string in list {
+            push_word(strings_start + string_data.len() as u64);
+            string_data.extend_from_slice(string.as_bytes());
+            string_data.push(0);
+        }
+        push_word(0);
+    }
+    for &(kind, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        push_word(kind);
+        push_word(value);
+    }
+    data.resize((strings_start - stack_pointer) as usize, 0);
+    data.extend_from_slice(&string_data);
+    Some(InitialStack {
+        stack_pointer,
+        data,
+    })
+}
+
+#[cfg(test)]
+use core::convert::TryInto;
+
+#[test_case]
+fn test_initial_stack_layout() {
+    let top = 0x10_0000;
+    let stack = build(top, &["prog", "-v"], &["HOME=/"], &[(AT_PAGESZ, 4096)]).unwrap();
+    assert_eq!(stack.stack_pointer % 16, 0);
+    assert_eq!(stack.stack_pointer + stack.data.len() as u64, top);
+
+    let word = |index: usize| u64::from_le_bytes(stack.data[index * 8..][..8].try_into().unwrap());
+    let string = |addr: u64| {
+        let bytes = &stack.data[(addr - stack.stack_pointer) as usize..];
+        let len = bytes.iter().position(|&byte| byte == 0).unwrap();
+        core::str::from_utf8(&bytes[..len]).unwrap()
+    };
+    assert_eq!(word(0), 2);
+    assert_eq!(string(word(1)), "prog");
+    assert_eq!(string(word(2)), "-v");
+    assert_eq!(word(3), 0);
+    assert_eq!(string(word(4)), "HOME=/");
+    assert_eq!(word(5), 0);
+    assert_eq!((word(6), word(7)), (AT_PAGESZ, 4096));
+    assert_eq!((word(8), word(9)), (AT_NULL, 0));
+
+    let long = alloc::string::String::from_utf8(alloc::vec![b'a'; MAX_SIZE]).unwrap();
+    assert!(build(top, &[&long], &[], &[]).is_none());
+}
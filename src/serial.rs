@@ -3,7 +3,8 @@
 //! The `serial` module provides a way to send data from the kernel to the host system using the 16550
 //! UART interface. It allows printing to the serial interface for testing purposes, and also provides
 //! macros to print to the interface, similar to the VGA buffer.
-//! The `lazy_static` and `spin` crates are used to create a static writer instance.
+//! The `lazy_static` crate and an interrupt-safe [`IrqMutex`] are used to create a static writer
+//! instance, so interrupt handlers and the panic handler can print too.
 //! The `_print` function is used to print formatted strings to the serial port, and the
 //! `serial_print!` and `serial_println!` macros allow passing token trees as arguments to generate
 //! formatted strings.
@@ -21,17 +22,17 @@
 //! Note that the serial_println macro lives directly under the root namespace because we used the
 //! `#[macro_export]` attribute, so importing it through use crate::serial::serial_println will not work.
 
+use crate::sync::{self, IrqMutex};
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort; // The uart_16550 crate contains a SerialPort struct that represents the
                             // UART registers, but we still need to construct an instance of it ourselves.
 
 // Like with the VGA text buffer, we use lazy_static and a spinlock to create a static writer instance
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqMutex<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();// By using lazy_static we can ensure that the init method is called exactly once on its first use
-        Mutex::new(serial_port)
+        IrqMutex::named("SERIAL1", serial_port)
     };
 }
 
@@ -44,10 +45,13 @@ lazy_static! {
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    SERIAL1
-        .lock()
-        .write_fmt(args)
-        .expect("Printing to serial failed");
+    // While panicking, the lock may be held by the code that panicked.
+    let mut serial = if sync::panicking() {
+        SERIAL1.force_lock()
+    } else {
+        SERIAL1.lock()
+    };
+    serial.write_fmt(args).expect("Printing to serial failed");
 }
 
 /// Prints to the host through the serial interface.
@@ -0,0 +1,390 @@
+//! # smp
+//!
+//! Symmetric multiprocessing: starting the application processors (APs).
+//!
+//! At boot only the bootstrap processor (BSP) runs; the APs wait for an INIT IPI followed by
+//! startup IPIs (SIPIs). A SIPI starts the AP in 16-bit real mode at a page-aligned address below
+//! 1 MiB, given by the vector of the SIPI. The trampoline code at that address switches directly
+//! to long mode: it loads a temporary GDT, enables PAE, loads the kernel's page table, sets
+//! `EFER.LME`, enables paging and protection at once and far-jumps to 64-bit code. That code loads
+//! the AP's stack and calls [`ap_entry`].
+//!
+//! The trampoline is copied to a frame of the [`Zone::Dma`], which is identity-mapped while the
+//! APs start, since paging is enabled while the AP executes there. The APs are started one after
+//! another, so the data area of the trampoline can be reused for each of them. An AP that doesn't
+//! start in time is sent back to the wait-for-SIPI state, and the remaining APs are not started:
+//! it might still have been about to read the data area, and to take the next AP's stack and CPU
+//! number.
+//!
+//! Each AP gets its own stack, GDT, TSS with interrupt stacks and IDT.
+//! Hardware interrupts and threads only run on the BSP for now; the APs sit in an idle loop.
+//!
+//! CPUs are numbered in the order they started, the BSP is CPU 0. See [`cpu_id`]. With the `kasan`
+//! feature, only the BSP runs (see [`crate::kasan`]).
+
+use crate::{
+    acpi::{AcpiError, Madt},
+    apic, gdt, interrupts,
+    memory::{
+        self,
+        buddy::Zone,
+        paging::{self, PagingError},
+        protection,
+        stack::{self, StackError},
+        MAPPER,
+    },
+    percpu, serial_println, time,
+};
+use core::arch::{global_asm, x86_64::__cpuid};
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::{
+    registers::{control::Cr3, model_specific::Efer},
+    structures::paging::{PageTableFlags, Translate},
+    PhysAddr, VirtAddr,
+};
+
+/// The maximum number of CPUs.
+pub const MAX_CPUS: usize = 16;
+
+/// The number of pages of the stack of each AP.
+const AP_STACK_PAGES: u64 = 8;
+
+/// How long to wait for an AP to start.
+const AP_START_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The number of running CPUs, including the BSP.
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Set by an AP once it no longer needs the trampoline.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum SmpError {
+    Acpi(AcpiError),
+    Paging(PagingError),
+    Stack(StackError),
+    /// No free frame below 1 MiB for the trampoline.
+    NoTrampolineMemory,
+    /// The kernel's level 4 page table is above 4 GiB, out of reach of the 32-bit `CR3` load.
+    PageTableTooHigh,
+    /// The AP didn't start in time.
+    Timeout,
+}
+
+impl From<AcpiError> for SmpError {
+    fn from(err: AcpiError) -> Self {
+        SmpError::Acpi(err)
+    }
+}
+
+impl From<PagingError> for SmpError {
+    fn from(err: PagingError) -> Self {
+        SmpError::Paging(err)
+    }
+}
+
+impl From<StackError> for SmpError {
+    fn from(err: StackError) -> Self {
+        SmpError::Stack(err)
+    }
+}
+
+/* REGION_START: TRAMPOLINE */
+
+/// The data the BSP passes to a starting AP, at the start of the trampoline.
+#[repr(C)]
+struct TrampolineData {
+    /// A null, a 64-bit code and a data descriptor.
+    gdt: [u64; 3],
+    /// The limit and the (24-bit, in real mode) base of the GDT, for `lgdt`.
+    gdtr: [u16; 3],
+    /// The 32-bit offset and the selector of the far jump to 64-bit code.
+    long_mode_jump: [u16; 3],
+    cr3: u64,
+    efer: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+/// The offset of the data area in the trampoline, after the initial jump. 8 byte aligned.
+const TRAMPOLINE_DATA_OFFSET: usize = 8;
+
+global_asm!(
+    ".pushsection .rodata.ap_trampoline, \"a\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_long_mode",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    // A short jump over the data, encoded by hand to fix the size of the instruction.
+    ".byte 0xeb, {data} - 2 + {data_size}",
+    ".space {data} - 2",
+    "1:",
+    ".space {data_size}",
+    "cli",
+    "cld",
+    // The code segment starts at the trampoline, so offsets in it are addresses relative to DS.
+    "mov ax, cs",
+    "mov ds, ax",
+    "lgdt [{data} + {gdtr}]",
+    // Enable PAE and load the kernel's page table.
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, [{data} + {cr3}]",
+    "mov cr3, eax",
+    // Copy `EFER` of the BSP, which enables long mode and no-execute.
+    "mov ecx, 0xc0000080",
+    "mov eax, [{data} + {efer}]",
+    "xor edx, edx",
+    "wrmsr",
+    // Enable paging, write protection and protected mode at once.
+    "mov eax, cr0",
+    "or eax, 0x80010001",
+    "mov cr0, eax",
+    "jmp fword ptr [{data} + {jump}]",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "xor eax, eax",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov rsp, [rip + 1b + {stack_top}]",
+    "mov rdi, [rip + 1b + {cpu}]",
+    "mov rax, [rip + 1b + {entry}]",
+    "xor ebp, ebp",
+    "call rax",
+    "ud2",
+    "ap_trampoline_end:",
+    ".popsection",
+    data = const TRAMPOLINE_DATA_OFFSET,
+    gdtr = const offset_of!(TrampolineData, gdtr),
+    jump = const offset_of!(TrampolineData, long_mode_jump),
+    cr3 = const offset_of!(TrampolineData, cr3),
+    efer = const offset_of!(TrampolineData, efer),
+    stack_top = const offset_of!(TrampolineData, stack_top),
+    entry = const offset_of!(TrampolineData, entry),
+    cpu = const offset_of!(TrampolineData, cpu),
+    data_size = const core::mem::size_of::<TrampolineData>(),
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// The offset of a trampoline symbol from the start of the trampoline.
+fn trampoline_offset(symbol: *const u8) -> u64 {
+    symbol as u64 - core::ptr::addr_of!(ap_trampoline_start) as u64
+}
+
+/// A copy of the trampoline in low memory.
+struct Trampoline {
+    frame: PhysAddr,
+    /// Whether the identity mapping was created by us, and must be removed again.
+    mapped: bool,
+}
+
+impl Trampoline {
+    fn new() -> Result<Trampoline, SmpError> {
+        let frame = memory::allocate_frames(0, Zone::Dma).ok_or(SmpError::NoTrampolineMemory)?;
+        let size = trampoline_offset(core::ptr::addr_of!(ap_trampoline_end)) as usize;
+        assert!(size <= 4096, "AP trampoline larger than a page");
+        unsafe {
+            let code = core::ptr::addr_of!(ap_trampoline_start);
+            let copy = memory::phys_to_virt(frame).as_mut_ptr::<u8>();
+            core::ptr::copy_nonoverlapping(code, copy, size);
+        }
+
+        // Executable, but not writable: the AP only reads the data.
+        let virt = VirtAddr::new(frame.as_u64());
+        let flags = PageTableFlags::PRESENT;
+        let mapped = match unsafe { paging::map_physical(virt, frame, 4096, flags) } {
+            Ok(()) => true,
+            // The bootloader identity-maps some of the low memory already.
+            Err(PagingError::PageAlreadyMapped) if translate(virt) == Some(frame) => {
+                unsafe { paging::set_flags(virt, 4096, flags)? };
+                false
+            }
+            Err(err) => {
+                unsafe { memory::deallocate_frames(frame, 0) };
+                return Err(err.into());
+            }
+        };
+        Ok(Trampoline { frame, mapped })
+    }
+
+    /// The SIPI vector that starts an AP at the trampoline.
+    fn vector(&self) -> u8 {
+        (self.frame.as_u64() >> 12) as u8
+    }
+
+    /// Fills in the data for the next AP.
+    fn prepare(&self, cpu: usize, stack_top: VirtAddr) -> Result<(), SmpError> {
+        let base = self.frame.as_u64();
+        let gdt = base + TRAMPOLINE_DATA_OFFSET as u64;
+        let long_mode = base + trampoline_offset(core::ptr::addr_of!(ap_trampoline_long_mode));
+        let cr3 = Cr3::read().0.start_address().as_u64();
+        if cr3 > u64::from(u32::MAX) {
+            return Err(SmpError::PageTableTooHigh);
+        }
+        // `EFER.LMA` is set by the CPU when paging is enabled.
+        let efer = Efer::read_raw() & !(1 << 10);
+
+        let data = TrampolineData {
+            gdt: [0, 0x00af_9a00_0000_ffff, 0x00cf_9200_0000_ffff],
+            gdtr: [23, gdt as u16, (gdt >> 16) as u16],
+            long_mode_jump: [long_mode as u16, (long_mode >> 16) as u16, 0x08],
+            cr3,
+            efer,
+            stack_top: stack_top.as_u64(),
+            entry: ap_entry as *const () as u64,
+            cpu: cpu as u64,
+        };
+        let virt = memory::phys_to_virt(PhysAddr::new(gdt));
+        unsafe { virt.as_mut_ptr::<TrampolineData>().write_volatile(data) };
+        Ok(())
+    }
+}
+
+impl Drop for Trampoline {
+    fn drop(&mut self) {
+        let virt = VirtAddr::new(self.frame.as_u64());
+        if self.mapped {
+            unsafe { paging::unmap_physical(virt, 4096) }
+                .expect("failed to unmap the AP trampoline");
+            unsafe { memory::deallocate_frames(self.frame, 0) };
+        }
+        // Otherwise the frame stays mapped, so it can't be reused.
+    }
+}
+
+fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    MAPPER.lock().as_ref()?.translate_addr(addr)
+}
+
+/* REGION_END: TRAMPOLINE */
+
+/// Starts all enabled processors listed in the MADT. Must be called on the BSP after the clock was
+/// calibrated (see [`crate::time`]). An AP that fails to start is reported and skipped, and after
+/// one that timed out, the rest are not started.
+pub fn init() -> Result<(), SmpError> {
+    let madt = Madt::find()?;
+    apic::init(madt.local_apic_address)?;
+    let bsp = current_apic_id();
+    if cfg!(feature = "kasan") {
+        // KASAN's shadow memory and quarantine aren't safe to use from several CPUs.
+        serial_println!("smp: KASAN assumes a single CPU, not starting the others");
+        return Ok(());
+    }
+
+    let trampoline = Trampoline::new()?;
+    let aps = madt
+        .local_apics
+        .iter()
+        .filter(|local_apic| local_apic.enabled && u32::from(local_apic.apic_id) != bsp);
+    for local_apic in aps {
+        let cpu = CPU_COUNT.load(Ordering::Acquire);
+        if cpu == MAX_CPUS {
+            serial_println!("smp: more than {} CPUs, ignoring the rest", MAX_CPUS);
+            break;
+        }
+        match start_ap(&trampoline, cpu, local_apic.apic_id) {
+            Ok(()) => {
+                CPU_COUNT.store(cpu + 1, Ordering::Release);
+            }
+            Err(err) => {
+                serial_println!(
+                    "smp: failed to start the CPU with APIC ID {}: {:?}",
+                    local_apic.apic_id,
+                    err
+                );
+                if let SmpError::Timeout = err {
+                    serial_println!("smp: not starting the remaining CPUs");
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Starts the AP with the given APIC ID as CPU number `cpu` with INIT-SIPI-SIPI.
+fn start_ap(trampoline: &Trampoline, cpu: usize, apic_id: u8) -> Result<(), SmpError> {
+    let stack = stack::allocate("ap", AP_STACK_PAGES)?;
+    trampoline.prepare(cpu, stack.top)?;
+    AP_STARTED.store(false, Ordering::Release);
+
+    // The specification asks for 10 ms after INIT and 200 µs after each SIPI.
+    apic::send_init(apic_id);
+    time::delay(Duration::from_millis(10));
+    apic::send_startup(apic_id, trampoline.vector());
+    time::delay(Duration::from_micros(200));
+    if !AP_STARTED.load(Ordering::Acquire) {
+        apic::send_startup(apic_id, trampoline.vector());
+    }
+
+    let deadline = time::now() + AP_START_TIMEOUT;
+    while !AP_STARTED.load(Ordering::Acquire) {
+        if time::now() >= deadline {
+            // Stops the AP, in case it is only slow. It may have used the stack already, so it is
+            // not freed.
+            apic::send_init(apic_id);
+            return Err(SmpError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// The Rust entry point of the APs, called by the trampoline on the AP's own stack.
+extern "C" fn ap_entry(cpu: u64) -> ! {
+    protection::enable_cpu_features();
+    percpu::init(cpu as usize);
+    gdt::init();
+    crate::syscall::init();
+    interrupts::init_idt();
+    apic::enable();
+    AP_STARTED.store(true, Ordering::Release);
+
+    idle()
+}
+
+/// The idle loop of the APs.
+fn idle() -> ! {
+    loop {
+        x86_64::instructions::interrupts::disable();
+        time::tick::idle(false);
+    }
+}
+
+/// The initial APIC ID of the current CPU, from CPUID.
+fn current_apic_id() -> u32 {
+    __cpuid(1).ebx >> 24
+}
+
+/// The number of the current CPU, from its per-CPU area (see [`percpu::cpu_id`]). The BSP is
+/// CPU 0, which is also returned before the per-CPU areas are initialized.
+pub fn cpu_id() -> usize {
+    if percpu::is_initialized() {
+        percpu::cpu_id()
+    } else {
+        0
+    }
+}
+
+/// The number of running CPUs.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+#[test_case]
+fn test_bsp_is_cpu_0() {
+    assert_eq!(cpu_id(), 0);
+}
@@ -0,0 +1,370 @@
+//! # sync
+//!
+//! Synchronization primitives: spinlocks that can be shared between normal code and interrupt
+//! handlers, and sleeping primitives for threads.
+//!
+//! ## Spinlocks
+//!
+//! A plain [`spin::Mutex`] deadlocks as soon as an interrupt handler tries to lock it while the
+//! interrupted code holds it: the handler spins forever, because the holder only continues after
+//! the handler returns. An [`IrqMutex`] disables interrupts on the current CPU for as long as it is
+//! locked and restores the previous RFLAGS.IF when the guard is dropped, so the holder can't be
+//! interrupted (or preempted) on its own CPU. Other CPUs simply spin until it is unlocked again.
+//!
+//! A [`SpinMutex`] leaves interrupts enabled, for state that interrupt handlers never touch but
+//! that may be locked for a while, like the page tables. It disables preemption instead, so a
+//! thread never spins on a holder that was preempted on the same CPU.
+//!
+//! The panic handlers call [`start_panic`] first. From then on, the consoles use
+//! [`IrqMutex::force_lock`], which takes the lock even if it was held when the panic happened, so
+//! the panic message is always printed.
+//!
+//! With the `lockdep` feature, the order in which named locks (and all [`SpinMutex`]es) are
+//! acquired is validated at runtime, see [`lockdep`].
+//!
+//! ## Sleeping primitives
+//!
+//! A spinlock burns CPU time while it waits. [`Mutex`], [`RwLock`], [`Semaphore`], [`Condvar`]
+//! and [`Once`] (with [`Lazy`], a replacement for `lazy_static!`) block the waiting thread instead
+//! (see [`crate::thread::block`]), until the thread releasing them wakes it up. They are built on
+//! [`WaitList`]s of blocked threads, which are protected by an [`IrqMutex`] together with the
+//! state of the primitive. Waiting can time out, see
+//! [`crate::time`], and waits on behalf of a process can be interrupted, see
+//! [`sleep_interruptible`].
+//!
+//! They can only block after the threads are initialized, and never in interrupt handlers.
+
+use crate::percpu::{self, PreemptGuard};
+use core::{
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::instructions::interrupts;
+
+mod condvar;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+mod mutex;
+mod once;
+mod rwlock;
+mod semaphore;
+mod wait_queue;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use once::{Lazy, Once};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use wait_queue::{sleep, sleep_interruptible, WaitList, WaitQueue, Waiter};
+
+/// How often [`IrqMutex::force_lock`] tries to lock before breaking the lock. Long enough for
+/// another CPU to finish printing a line.
+const FORCE_LOCK_ATTEMPTS: usize = 1_000_000;
+
+/// Whether the kernel is panicking.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Disables interrupts on the current CPU and switches the consoles to
+/// [`IrqMutex::force_lock`]. Called at the start of every panic handler.
+pub fn start_panic() {
+    interrupts::disable();
+    PANICKING.store(true, Ordering::SeqCst);
+}
+
+/// Whether a panic handler is running.
+pub fn panicking() -> bool {
+    PANICKING.load(Ordering::SeqCst)
+}
+
+/// A spinlock that disables interrupts on the current CPU while it is locked.
+pub struct IrqMutex<T> {
+    inner: spin::Mutex<T>,
+    /// The lock class for [`lockdep`]; unnamed locks aren't tracked.
+    name: Option<&'static str>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqMutex {
+            inner: spin::Mutex::new(value),
+            name: None,
+        }
+    }
+
+    /// A lock whose acquisitions are validated by `lockdep`, if enabled. All locks with the same
+    /// name are treated as one lock.
+    pub const fn named(name: &'static str, value: T) -> Self {
+        IrqMutex {
+            inner: spin::Mutex::new(value),
+            name: Some(name),
+        }
+    }
+
+    pub fn name(&self) -> Option<&'static str> {
+        self.name
+    }
+
+    /// Disables interrupts and spins until the lock is acquired. Interrupts are enabled again when
+    /// the guard is dropped, if they were enabled before.
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        // Interrupts are always disabled while an `IrqMutex` is held.
+        #[cfg(feature = "lockdep")]
+        if let Some(name) = self.name {
+            lockdep::acquire(name, lockdep::Context::current(false));
+        }
+        self.guard(self.inner.lock(), were_enabled)
+    }
+
+    /// Tries to acquire the lock once, without spinning.
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(feature = "lockdep")]
+                if let Some(name) = self.name {
+                    lockdep::acquire_try(name, lockdep::Context::current(false));
+                }
+                Some(self.guard(guard, were_enabled))
+            }
+            None => {
+                if were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Acquires the lock even if it is never released, e.g. because its holder panicked or was
+    /// interrupted by the panic. Waits a bounded time first, in case another CPU holds it.
+    ///
+    /// Only meant for the panic path: breaking the lock lets two users access the value at once.
+    pub fn force_lock(&self) -> IrqMutexGuard<'_, T> {
+        for _ in 0..FORCE_LOCK_ATTEMPTS {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            core::hint::spin_loop();
+        }
+        // Safety: the lock is abandoned, see above.
+        unsafe { self.inner.force_unlock() };
+        self.lock()
+    }
+
+    fn guard<'a>(
+        &'a self,
+        guard: spin::MutexGuard<'a, T>,
+        were_enabled: bool,
+    ) -> IrqMutexGuard<'a, T> {
+        IrqMutexGuard {
+            guard: Some(guard),
+            were_enabled,
+            #[cfg(feature = "lockdep")]
+            name: self.name,
+        }
+    }
+
+    /// Whether the lock is currently held. Only a snapshot, for assertions and diagnostics.
+    pub fn is_locked(&self) -> bool {
+        self.inner.try_lock().is_none()
+    }
+}
+
+/// Releases the lock and restores the previous interrupt state when dropped.
+pub struct IrqMutexGuard<'a, T> {
+    /// Always `Some` until dropped; taken first so the lock is released before interrupts are
+    /// enabled again.
+    guard: Option<spin::MutexGuard<'a, T>>,
+    were_enabled: bool,
+    #[cfg(feature = "lockdep")]
+    name: Option<&'static str>,
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.guard = None;
+        #[cfg(feature = "lockdep")]
+        if let Some(name) = self.name {
+            lockdep::release(name);
+        }
+        if self.were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// A spinlock that leaves interrupts enabled and disables preemption while it is locked. Must not
+/// be used by interrupt handlers, which lockdep checks.
+pub struct SpinMutex<T> {
+    inner: spin::Mutex<T>,
+    /// The lock class for [`lockdep`].
+    name: &'static str,
+}
+
+impl<T> SpinMutex<T> {
+    pub const fn named(name: &'static str, value: T) -> Self {
+        SpinMutex {
+            inner: spin::Mutex::new(value),
+            name,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Spins until the lock is acquired. Preemption is enabled again when the guard is dropped.
+    pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        let preempt = Self::disable_preemption();
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(
+            self.name,
+            lockdep::Context::current(interrupts::are_enabled()),
+        );
+        self.guard(self.inner.lock(), preempt)
+    }
+
+    /// Tries to acquire the lock once, without spinning.
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T>> {
+        let preempt = Self::disable_preemption();
+        let guard = self.inner.try_lock()?;
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire_try(
+            self.name,
+            lockdep::Context::current(interrupts::are_enabled()),
+        );
+        Some(self.guard(guard, preempt))
+    }
+
+    /// Early at boot, before the per-CPU areas exist, there are no threads to preempt yet.
+    fn disable_preemption() -> Option<PreemptGuard> {
+        percpu::is_initialized().then(PreemptGuard::new)
+    }
+
+    fn guard<'a>(
+        &'a self,
+        guard: spin::MutexGuard<'a, T>,
+        preempt: Option<PreemptGuard>,
+    ) -> SpinMutexGuard<'a, T> {
+        SpinMutexGuard {
+            guard: Some(guard),
+            _preempt: preempt,
+            #[cfg(feature = "lockdep")]
+            name: self.name,
+        }
+    }
+}
+
+/// Releases the lock and enables preemption again when dropped.
+pub struct SpinMutexGuard<'a, T> {
+    /// Always `Some` until dropped; taken first so the lock is released before the thread can be
+    /// preempted.
+    guard: Option<spin::MutexGuard<'a, T>>,
+    _preempt: Option<PreemptGuard>,
+    #[cfg(feature = "lockdep")]
+    name: &'static str,
+}
+
+impl<T> Deref for SpinMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for SpinMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for SpinMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.guard = None;
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.name);
+    }
+}
+
+#[test_case]
+fn test_lock_disables_interrupts() {
+    let mutex = IrqMutex::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = mutex.lock();
+        assert!(!interrupts::are_enabled());
+        *guard += 1;
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*mutex.lock(), 1);
+}
+
+#[test_case]
+fn test_nested_locks_restore_interrupts() {
+    let a = IrqMutex::new(());
+    let b = IrqMutex::new(());
+    let guard_a = a.lock();
+    let guard_b = b.lock();
+    drop(guard_b);
+    // The outer guard still holds a lock.
+    assert!(!interrupts::are_enabled());
+    drop(guard_a);
+    assert!(interrupts::are_enabled());
+}
+
+#[test_case]
+fn test_try_lock_fails_while_locked() {
+    let mutex = IrqMutex::new(());
+    let guard = mutex.lock();
+    assert!(mutex.is_locked());
+    assert!(mutex.try_lock().is_none());
+    // A failed attempt must not enable interrupts while the guard is held.
+    assert!(!interrupts::are_enabled());
+    drop(guard);
+    assert!(mutex.try_lock().is_some());
+}
+
+#[test_case]
+fn test_force_lock_breaks_abandoned_lock() {
+    let mutex = IrqMutex::new(7);
+    let guard = mutex.lock();
+    // Like a holder that never runs again.
+    core::mem::forget(guard);
+    assert_eq!(*mutex.force_lock(), 7);
+    // Interrupts stay disabled, the forgotten guard never restores them.
+    interrupts::enable();
+    assert!(!mutex.is_locked());
+}
+
+#[test_case]
+fn test_spin_mutex_disables_preemption() {
+    let mutex = SpinMutex::named("TEST SPIN", 0);
+    assert!(percpu::preemptible());
+    {
+        let mut guard = mutex.lock();
+        assert!(interrupts::are_enabled());
+        assert!(!percpu::preemptible());
+        *guard += 1;
+        assert!(mutex.try_lock().is_none());
+    }
+    assert!(percpu::preemptible());
+    assert_eq!(*mutex.lock(), 1);
+}
@@ -0,0 +1,124 @@
+use super::{
+    mutex::MutexGuard,
+    wait_queue::{self, WaitList},
+    IrqMutex,
+};
+use crate::time::Instant;
+use core::time::Duration;
+
+/// A condition variable: threads wait on it with a locked [`super::Mutex`] until another thread
+/// changes the protected state and notifies them.
+pub struct Condvar {
+    waiters: IrqMutex<WaitList>,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            waiters: IrqMutex::new(WaitList::new()),
+        }
+    }
+
+    /// Releases the mutex of `guard`, blocks until notified and locks the mutex again.
+    ///
+    /// May wake up spuriously, so the condition has to be checked in a loop, see
+    /// [`Condvar::wait_while`].
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_until(guard, None).0
+    }
+
+    /// Like [`Condvar::wait`], but gives up after `timeout`. Also returns whether it timed out.
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, bool) {
+        self.wait_until(guard, wait_queue::deadline_after(timeout))
+    }
+
+    /// Blocks while `condition` returns `true` for the value protected by the mutex.
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    fn wait_until<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        deadline: Option<Instant>,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex;
+        // Queued before the mutex is released, so a notification right after it isn't missed.
+        let waiter = self.waiters.lock().push_current();
+        drop(guard);
+        let timed_out = !waiter.wait(deadline) && self.waiters.lock().remove(&waiter);
+        (mutex.lock(), timed_out)
+    }
+
+    /// Wakes the thread that waits the longest. Returns `false` if no thread waits.
+    pub fn notify_one(&self) -> bool {
+        self.waiters.lock().wake_one()
+    }
+
+    /// Wakes all waiting threads and returns their number.
+    pub fn notify_all(&self) -> usize {
+        self.waiters.lock().wake_all()
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+use super::Mutex;
+#[cfg(test)]
+use crate::thread;
+#[cfg(test)]
+use alloc::{collections::VecDeque, sync::Arc};
+
+#[test_case]
+fn test_producer_consumer() {
+    let shared = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
+    let consumer_shared = shared.clone();
+    let consumer = thread::spawn("consumer", move || {
+        let (queue, condvar) = &*consumer_shared;
+        let mut sum = 0;
+        for _ in 0..10 {
+            let mut queue = condvar.wait_while(queue.lock(), |queue| queue.is_empty());
+            sum += queue.pop_front().unwrap();
+        }
+        sum
+    })
+    .unwrap();
+
+    let (queue, condvar) = &*shared;
+    for i in 1..=10 {
+        queue.lock().push_back(i);
+        condvar.notify_one();
+        if i % 3 == 0 {
+            thread::yield_now();
+        }
+    }
+    assert_eq!(consumer.join(), Some(55));
+}
+
+#[test_case]
+fn test_condvar_wait_timeout() {
+    let mutex = Mutex::new(());
+    let condvar = Condvar::new();
+    let (guard, timed_out) = condvar.wait_timeout(mutex.lock(), Duration::from_millis(2));
+    assert!(timed_out);
+    // The mutex is locked again.
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert!(!condvar.notify_one());
+}
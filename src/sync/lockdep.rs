@@ -0,0 +1,495 @@
+//! # lockdep
+//!
+//! A lock dependency validator, enabled by the `lockdep` cargo feature.
+//!
+//! Every tracked lock belongs to a *class*, identified by its name (see [`super::IrqMutex::named`]);
+//! all locks with the same name share a class. For each CPU, lockdep keeps the stack of classes it
+//! currently holds, and records that class `B` *depends on* class `A` whenever `B` is acquired
+//! while `A` is held. Before a lock is acquired, it checks for:
+//!
+//! - recursive acquisition: the CPU already holds a lock of the same class, so it would spin
+//!   forever.
+//! - circular dependencies: the new dependency closes a cycle, e.g. `A -> B` was seen before and
+//!   now `A` is acquired while holding `B`. Two CPUs (or a thread and an interrupt handler) taking
+//!   the locks in those orders at the same time deadlock, even if it never happened so far.
+//! - inconsistent interrupt state: a class is acquired in an interrupt handler (see
+//!   [`crate::percpu::in_interrupt`]) and held with interrupts enabled elsewhere. The handler
+//!   deadlocks if it interrupts such a holder on the same CPU. An [`super::IrqMutex`] is never held
+//!   with interrupts enabled, so only locks that leave interrupts enabled can do this.
+//!
+//! The first violation is fatal: lockdep turns itself off, prints the offending chain over serial
+//! and panics. While the kernel is panicking, nothing is tracked.
+
+use crate::{serial_println, smp, sync};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::instructions::interrupts;
+
+/// The maximum number of lock classes; the dependencies of a class are a bitmap in a `u64`.
+const MAX_CLASSES: usize = 64;
+/// The maximum number of locks a CPU can hold at once.
+const MAX_HELD: usize = 16;
+
+static STATE: spin::Mutex<State> = spin::Mutex::new(State::new());
+
+/// Set after the first report, so the report itself (and everything after it) isn't checked.
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// The circumstances of an acquisition, for the interrupt state check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Context {
+    /// Whether interrupts will be enabled while the lock is held.
+    pub irqs_enabled: bool,
+    /// Whether the lock is acquired in an interrupt handler.
+    pub in_interrupt: bool,
+}
+
+impl Context {
+    /// The context of the current CPU, for a lock that is held with interrupts enabled or not.
+    pub fn current(irqs_enabled: bool) -> Self {
+        Context {
+            irqs_enabled,
+            in_interrupt: crate::percpu::in_interrupt(),
+        }
+    }
+}
+
+/// Checks and records that the current CPU is about to acquire a lock of class `name`.
+pub fn acquire(name: &'static str, context: Context) {
+    track(|state, cpu| state.acquire(cpu, name, context));
+}
+
+/// Records that the current CPU acquired a lock of class `name` with a `try_lock`. Trying can't
+/// deadlock, so only the interrupt state is checked and no dependencies are recorded.
+pub fn acquire_try(name: &'static str, context: Context) {
+    track(|state, cpu| state.acquire_try(cpu, name, context));
+}
+
+/// Records that the current CPU released a lock of class `name`. Locks may be released in any
+/// order.
+pub fn release(name: &'static str) {
+    track(|state, cpu| {
+        state.release(cpu, name);
+        Ok(())
+    });
+}
+
+fn track(f: impl FnOnce(&mut State, usize) -> Result<(), Report>) {
+    if DISABLED.load(Ordering::Acquire) || sync::panicking() {
+        return;
+    }
+    let cpu = smp::cpu_id();
+    let failure = interrupts::without_interrupts(|| {
+        let mut state = STATE.lock();
+        let report = f(&mut state, cpu).err()?;
+        DISABLED.store(true, Ordering::Release);
+        // Copied, so the state isn't locked while printing.
+        Some((report, state.names, state.held[cpu]))
+    });
+    if let Some((report, names, held)) = failure {
+        // The current CPU may hold the serial lock.
+        sync::start_panic();
+        serial_println!("\n==================================================================");
+        serial_println!("BUG: lockdep: {} on cpu{}", report.kind(), cpu);
+        serial_println!("{}", report.describe(&names));
+        serial_println!("Held locks: {}", Chain(&names, held.as_slice()));
+        serial_println!("==================================================================");
+        panic!("lockdep: {}", report.kind());
+    }
+}
+
+/// A set of lock classes, as a list of indices into [`State::names`].
+#[derive(Debug, Clone, Copy)]
+struct Classes {
+    classes: [u8; MAX_CLASSES],
+    len: usize,
+}
+
+impl Classes {
+    const fn new() -> Self {
+        Classes {
+            classes: [0; MAX_CLASSES],
+            len: 0,
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.classes[..self.len]
+    }
+
+    fn push(&mut self, class: u8) {
+        self.classes[self.len] = class;
+        self.len += 1;
+    }
+
+    fn contains(&self, class: u8) -> bool {
+        self.as_slice().contains(&class)
+    }
+}
+
+/// The dependency graph and the locks held by each CPU.
+struct State {
+    names: [&'static str; MAX_CLASSES],
+    class_count: usize,
+    /// Bit `b` of `after[a]` is set if class `b` was acquired while class `a` was held.
+    after: [u64; MAX_CLASSES],
+    /// The classes that were held with interrupts enabled.
+    held_irqs_on: u64,
+    /// The classes that were acquired in interrupt handlers.
+    used_in_irq: u64,
+    /// The classes held by each CPU, in acquisition order.
+    held: [Classes; smp::MAX_CPUS],
+}
+
+impl State {
+    const fn new() -> Self {
+        State {
+            names: [""; MAX_CLASSES],
+            class_count: 0,
+            after: [0; MAX_CLASSES],
+            held_irqs_on: 0,
+            used_in_irq: 0,
+            held: [Classes::new(); smp::MAX_CPUS],
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<u8> {
+        self.names[..self.class_count]
+            .iter()
+            .position(|&n| n == name)
+            .map(|class| class as u8)
+    }
+
+    fn class(&mut self, name: &'static str) -> Result<u8, Report> {
+        if let Some(class) = self.find(name) {
+            return Ok(class);
+        }
+        if self.class_count == MAX_CLASSES {
+            return Err(Report::TooManyClasses { name });
+        }
+        self.names[self.class_count] = name;
+        self.class_count += 1;
+        Ok(self.class_count as u8 - 1)
+    }
+
+    fn acquire(&mut self, cpu: usize, name: &'static str, context: Context) -> Result<(), Report> {
+        let class = self.class(name)?;
+        let held = self.held[cpu];
+        if held.contains(class) {
+            return Err(Report::Recursive { class });
+        }
+        for &holding in held.as_slice() {
+            if self.after[usize::from(holding)] & 1 << class != 0 {
+                continue;
+            }
+            // The new dependency `holding -> class` closes a cycle if `class -> ... -> holding`
+            // was seen before.
+            if let Some(chain) = self.path(class, holding) {
+                return Err(Report::Circular {
+                    class,
+                    holding,
+                    chain,
+                });
+            }
+            self.after[usize::from(holding)] |= 1 << class;
+        }
+        self.acquire_try(cpu, name, context)
+    }
+
+    fn acquire_try(
+        &mut self,
+        cpu: usize,
+        name: &'static str,
+        context: Context,
+    ) -> Result<(), Report> {
+        let class = self.class(name)?;
+        let inconsistent = Err(Report::InconsistentInterrupts {
+            class,
+            in_interrupt: context.in_interrupt,
+        });
+        if context.irqs_enabled {
+            if self.used_in_irq & 1 << class != 0 {
+                return inconsistent;
+            }
+            self.held_irqs_on |= 1 << class;
+        }
+        if context.in_interrupt {
+            if self.held_irqs_on & 1 << class != 0 {
+                return inconsistent;
+            }
+            self.used_in_irq |= 1 << class;
+        }
+
+        if self.held[cpu].len == MAX_HELD {
+            return Err(Report::TooManyHeld);
+        }
+        self.held[cpu].push(class);
+        Ok(())
+    }
+
+    fn release(&mut self, cpu: usize, name: &'static str) {
+        let Some(class) = self.find(name) else {
+            return;
+        };
+        let held = &mut self.held[cpu];
+        if let Some(i) = held.as_slice().iter().rposition(|&c| c == class) {
+            held.classes.copy_within(i + 1..held.len, i);
+            held.len -= 1;
+        }
+    }
+
+    /// The shortest chain of dependencies from class `from` to class `to`, including both.
+    fn path(&self, from: u8, to: u8) -> Option<Classes> {
+        // A breadth-first search, remembering where each class was reached from.
+        let mut parent = [u8::MAX; MAX_CLASSES];
+        let mut queue = Classes::new();
+        let mut visited = 1u64 << from;
+        queue.push(from);
+        let mut next = 0;
+        while next < queue.len {
+            let class = queue.classes[next];
+            next += 1;
+            if class == to {
+                let mut reversed = Classes::new();
+                let mut c = to;
+                while c != from {
+                    reversed.push(c);
+                    c = parent[usize::from(c)];
+                }
+                reversed.push(from);
+                let mut chain = Classes::new();
+                for &c in reversed.as_slice().iter().rev() {
+                    chain.push(c);
+                }
+                return Some(chain);
+            }
+            for dependent in 0..self.class_count as u8 {
+                if self.after[usize::from(class)] & 1 << dependent != 0
+                    && visited & 1 << dependent == 0
+                {
+                    visited |= 1 << dependent;
+                    parent[usize::from(dependent)] = class;
+                    queue.push(dependent);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Lock classes, displayed as a chain of their names.
+struct Chain<'a>(&'a [&'static str], &'a [u8]);
+
+impl fmt::Display for Chain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Chain(names, classes) = self;
+        if classes.is_empty() {
+            return write!(f, "(none)");
+        }
+        for (i, &class) in classes.iter().enumerate() {
+            if i > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{}", names[usize::from(class)])?;
+        }
+        Ok(())
+    }
+}
+
+/// A lock usage that can deadlock. Classes are indices into [`State::names`].
+#[derive(Debug, Clone, Copy)]
+enum Report {
+    Recursive {
+        class: u8,
+    },
+    Circular {
+        class: u8,
+        holding: u8,
+        /// The earlier dependencies from `class` to `holding`.
+        chain: Classes,
+    },
+    InconsistentInterrupts {
+        class: u8,
+        /// Whether the offending acquisition is the one in the interrupt handler.
+        in_interrupt: bool,
+    },
+    TooManyClasses {
+        name: &'static str,
+    },
+    TooManyHeld,
+}
+
+impl Report {
+    /// A short description, used as the panic message.
+    fn kind(&self) -> &'static str {
+        match self {
+            Report::Recursive { .. } => "recursive locking",
+            Report::Circular { .. } => "possible circular locking dependency",
+            Report::InconsistentInterrupts { .. } => "inconsistent interrupt state",
+            Report::TooManyClasses { .. } => "too many lock classes",
+            Report::TooManyHeld => "too many held locks",
+        }
+    }
+
+    fn describe<'a>(&'a self, names: &'a [&'static str]) -> impl fmt::Display + 'a {
+        Describe(self, names)
+    }
+}
+
+struct Describe<'a>(&'a Report, &'a [&'static str]);
+
+impl fmt::Display for Describe<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Describe(report, names) = *self;
+        let name = |class: u8| names[usize::from(class)];
+        match *report {
+            Report::Recursive { class } => {
+                write!(f, "Acquiring {}, which is already held.", name(class))
+            }
+            Report::Circular {
+                class,
+                holding,
+                ref chain,
+            } => {
+                writeln!(
+                    f,
+                    "Acquiring {} while holding {},",
+                    name(class),
+                    name(holding)
+                )?;
+                write!(
+                    f,
+                    "but this order was seen before: {}",
+                    Chain(names, chain.as_slice())
+                )
+            }
+            Report::InconsistentInterrupts {
+                class,
+                in_interrupt: true,
+            } => {
+                writeln!(f, "Acquiring {} in an interrupt handler,", name(class))?;
+                write!(f, "but it was held with interrupts enabled before.")
+            }
+            Report::InconsistentInterrupts {
+                class,
+                in_interrupt: false,
+            } => {
+                writeln!(f, "Holding {} with interrupts enabled,", name(class))?;
+                write!(f, "but it was acquired in an interrupt handler before.")
+            }
+            Report::TooManyClasses { name } => write!(
+                f,
+                "Can't track {}, the limit is {} classes.",
+                name, MAX_CLASSES
+            ),
+            Report::TooManyHeld => write!(f, "The limit is {} held locks.", MAX_HELD),
+        }
+    }
+}
+
+/// Neither in an interrupt handler nor with interrupts enabled, like an `IrqMutex` outside of one.
+#[cfg(test)]
+const OFF: Context = Context {
+    irqs_enabled: false,
+    in_interrupt: false,
+};
+
+#[test_case]
+fn test_nested_locks_in_order() {
+    let mut state = State::new();
+    state.acquire(0, "a", OFF).unwrap();
+    state.acquire(0, "b", OFF).unwrap();
+    state.release(0, "b");
+    state.release(0, "a");
+    // The same order again, and on another CPU.
+    state.acquire(1, "a", OFF).unwrap();
+    state.acquire(1, "b", OFF).unwrap();
+    assert_eq!(state.held[0].len, 0);
+    assert_eq!(state.held[1].len, 2);
+}
+
+#[test_case]
+fn test_recursive_locking() {
+    let mut state = State::new();
+    state.acquire(0, "a", OFF).unwrap();
+    // Another CPU waiting for it is fine.
+    state.acquire(1, "a", OFF).unwrap();
+    let report = state.acquire(0, "a", OFF).unwrap_err();
+    assert!(matches!(report, Report::Recursive { class: 0 }));
+}
+
+#[test_case]
+fn test_circular_dependency() {
+    let mut state = State::new();
+    for (first, second) in [("a", "b"), ("b", "c")] {
+        state.acquire(0, first, OFF).unwrap();
+        state.acquire(0, second, OFF).unwrap();
+        state.release(0, first);
+        state.release(0, second);
+    }
+    state.acquire(1, "c", OFF).unwrap();
+    match state.acquire(1, "a", OFF).unwrap_err() {
+        Report::Circular {
+            class,
+            holding,
+            chain,
+        } => {
+            assert_eq!((class, holding), (0, 2));
+            assert_eq!(chain.as_slice(), [0, 1, 2]);
+        }
+        report => panic!("unexpected report {:?}", report),
+    }
+}
+
+#[test_case]
+fn test_out_of_order_release() {
+    let mut state = State::new();
+    state.acquire(0, "a", OFF).unwrap();
+    state.acquire(0, "b", OFF).unwrap();
+    state.release(0, "a");
+    state.acquire(0, "c", OFF).unwrap();
+    assert_eq!(state.held[0].as_slice(), [1, 2]);
+    // Only `b` was held when `c` was acquired.
+    assert_eq!(state.after[0], 1 << 1);
+    assert_eq!(state.after[1], 1 << 2);
+}
+
+#[test_case]
+fn test_inconsistent_interrupt_state() {
+    let in_interrupt = Context {
+        irqs_enabled: false,
+        in_interrupt: true,
+    };
+    let irqs_on = Context {
+        irqs_enabled: true,
+        in_interrupt: false,
+    };
+    let mut state = State::new();
+    // Held with interrupts disabled, by handlers and others.
+    state.acquire(0, "a", in_interrupt).unwrap();
+    state.release(0, "a");
+    state.acquire(0, "a", OFF).unwrap();
+    state.release(0, "a");
+    let report = state.acquire_try(0, "a", irqs_on).unwrap_err();
+    assert!(matches!(
+        report,
+        Report::InconsistentInterrupts {
+            in_interrupt: false,
+            ..
+        }
+    ));
+
+    let mut state = State::new();
+    state.acquire(0, "b", irqs_on).unwrap();
+    state.release(0, "b");
+    let report = state.acquire(1, "b", in_interrupt).unwrap_err();
+    assert!(matches!(
+        report,
+        Report::InconsistentInterrupts {
+            in_interrupt: true,
+            ..
+        }
+    ));
+}
@@ -0,0 +1,174 @@
+use super::{
+    wait_queue::{self, WaitList},
+    IrqMutex,
+};
+use crate::time::Instant;
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    time::Duration,
+};
+
+/// A mutual exclusion lock that puts waiting threads to sleep.
+///
+/// The lock is fair: when it is released while threads wait, it is handed to the one that waits
+/// the longest, so later threads can't overtake it.
+pub struct Mutex<T> {
+    state: IrqMutex<State>,
+    value: UnsafeCell<T>,
+}
+
+struct State {
+    locked: bool,
+    waiters: WaitList,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            state: IrqMutex::new(State {
+                locked: false,
+                waiters: WaitList::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Blocks until the lock is acquired.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.lock_until(None).expect("lock without timeout failed")
+    }
+
+    /// Acquires the lock if it is free.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        Some(MutexGuard { mutex: self })
+    }
+
+    /// Like [`Mutex::lock`], but gives up after `timeout`.
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+        self.lock_until(wait_queue::deadline_after(timeout))
+    }
+
+    fn lock_until(&self, deadline: Option<Instant>) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if !state.locked {
+            state.locked = true;
+            return Some(MutexGuard { mutex: self });
+        }
+        // A woken thread owns the lock, see `unlock`.
+        if wait_queue::sleep(&self.state, state, |s| &mut s.waiters, deadline) {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    fn unlock(&self) {
+        let mut state = self.state.lock();
+        // Hand the lock over, or release it if nobody waits.
+        if !state.waiters.wake_one() {
+            state.locked = false;
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+/// Releases the [`Mutex`] when dropped.
+pub struct MutexGuard<'a, T> {
+    pub(super) mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+use crate::thread;
+#[cfg(test)]
+use alloc::{sync::Arc, vec::Vec};
+
+#[test_case]
+fn test_mutex_excludes_threads() {
+    let counter = Arc::new(Mutex::new(0));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            thread::spawn("counter", move || {
+                for _ in 0..50 {
+                    let mut count = counter.lock();
+                    let read = *count;
+                    // Give the others a chance to interfere.
+                    thread::yield_now();
+                    *count = read + 1;
+                }
+            })
+            .unwrap()
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(*counter.lock(), 200);
+}
+
+#[test_case]
+fn test_mutex_is_fair() {
+    let mutex = Arc::new(Mutex::new(Vec::new()));
+    let guard = mutex.lock();
+    let handles: Vec<_> = (0..3)
+        .map(|i| {
+            let waiting = mutex.clone();
+            let handle = thread::spawn("waiter", move || waiting.lock().push(i)).unwrap();
+            // Queue them in order.
+            while mutex.state.lock().waiters.len() <= i {
+                thread::yield_now();
+            }
+            handle
+        })
+        .collect();
+    drop(guard);
+    // Would overtake the waiters if the lock were released instead of handed over.
+    mutex.lock().push(3);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(*mutex.lock(), [0, 1, 2, 3]);
+}
+
+#[test_case]
+fn test_try_lock_for_times_out() {
+    let mutex = Mutex::new(());
+    let guard = mutex.lock();
+    assert!(mutex.try_lock().is_none());
+    assert!(mutex.try_lock_for(Duration::from_millis(2)).is_none());
+    assert!(mutex.state.lock().waiters.is_empty());
+    drop(guard);
+    assert!(mutex.try_lock_for(Duration::from_millis(2)).is_some());
+}
@@ -0,0 +1,152 @@
+use super::WaitQueue;
+use core::{
+    cell::{Cell, UnsafeCell},
+    mem::MaybeUninit,
+    ops::Deref,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// Runs an initialization exactly once. Threads that need it while it runs sleep until it is
+/// complete.
+pub struct Once {
+    state: AtomicU8,
+    waiters: WaitQueue,
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU8::new(INCOMPLETE),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Runs `f` if this is the first call. Returns after `f` completed, in any case.
+    pub fn call_once(&self, f: impl FnOnce()) {
+        if self.is_completed() {
+            return;
+        }
+        match self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                f();
+                self.state.store(COMPLETE, Ordering::Release);
+                self.waiters.notify_all();
+            }
+            Err(_) => self.waiters.wait_until(|| self.is_completed()),
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl Default for Once {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A value that is initialized on first access. A replacement for `lazy_static!` that can be
+/// used in plain statics.
+///
+/// ```ignore
+/// static TABLE: Lazy<Vec<u64>> = Lazy::new(|| (0..16).collect());
+/// ```
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once,
+    init: Cell<Option<F>>,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Lazy {
+            once: Once::new(),
+            init: Cell::new(Some(init)),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Initializes the value if that didn't happen yet.
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            let init = this.init.take().expect("Lazy initialized twice");
+            unsafe { (*this.value.get()).write(init()) };
+        });
+        unsafe { (*this.value.get()).assume_init_ref() }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+impl<T, F> Drop for Lazy<T, F> {
+    fn drop(&mut self) {
+        if self.once.is_completed() {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::thread;
+#[cfg(test)]
+use alloc::vec::Vec;
+#[cfg(test)]
+use core::sync::atomic::AtomicUsize;
+
+#[test_case]
+fn test_once_runs_once() {
+    static ONCE: Once = Once::new();
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    let handles: Vec<_> = (0..3)
+        .map(|_| {
+            thread::spawn("once", || {
+                ONCE.call_once(|| {
+                    CALLS.fetch_add(1, Ordering::SeqCst);
+                    // The others have to wait for it.
+                    for _ in 0..3 {
+                        thread::yield_now();
+                    }
+                });
+                CALLS.load(Ordering::SeqCst)
+            })
+            .unwrap()
+        })
+        .collect();
+    for handle in handles {
+        // Nobody returned before the initialization completed.
+        assert_eq!(handle.join(), Some(1));
+    }
+    assert!(ONCE.is_completed());
+}
+
+#[test_case]
+fn test_lazy_initializes_on_first_access() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    static VALUE: Lazy<u64> = Lazy::new(|| {
+        CALLS.fetch_add(1, Ordering::SeqCst);
+        42
+    });
+
+    assert_eq!(CALLS.load(Ordering::SeqCst), 0);
+    assert_eq!(*VALUE, 42);
+    assert_eq!(*VALUE, 42);
+    assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+}
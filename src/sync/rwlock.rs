@@ -0,0 +1,202 @@
+use super::{
+    wait_queue::{self, WaitList},
+    IrqMutex,
+};
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+};
+
+/// A reader-writer lock that puts waiting threads to sleep.
+///
+/// Writers are preferred: while a writer waits, new readers wait too, so a steady stream of
+/// readers can't starve it. When a writer releases the lock, all readers that arrived in the
+/// meantime get it next, so writers can't starve readers either.
+pub struct RwLock<T> {
+    state: IrqMutex<State>,
+    value: UnsafeCell<T>,
+}
+
+struct State {
+    readers: usize,
+    writer: bool,
+    waiting_readers: WaitList,
+    waiting_writers: WaitList,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: IrqMutex::new(State {
+                readers: 0,
+                writer: false,
+                waiting_readers: WaitList::new(),
+                waiting_writers: WaitList::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Blocks until the lock can be shared with the other readers.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let mut state = self.state.lock();
+        if !state.writer && state.waiting_writers.is_empty() {
+            state.readers += 1;
+        } else {
+            // A woken reader was counted, see `unlock_write`.
+            wait_queue::sleep(&self.state, state, |s| &mut s.waiting_readers, None);
+        }
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.writer || !state.waiting_writers.is_empty() {
+            return None;
+        }
+        state.readers += 1;
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    /// Blocks until the lock is exclusively acquired.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let mut state = self.state.lock();
+        if !state.writer && state.readers == 0 {
+            state.writer = true;
+        } else {
+            // A woken writer owns the lock, see `unlock_read` and `unlock_write`.
+            wait_queue::sleep(&self.state, state, |s| &mut s.waiting_writers, None);
+        }
+        RwLockWriteGuard { lock: self }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.writer || state.readers > 0 {
+            return None;
+        }
+        state.writer = true;
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    fn unlock_read(&self) {
+        let mut state = self.state.lock();
+        state.readers -= 1;
+        if state.readers == 0 && state.waiting_writers.wake_one() {
+            state.writer = true;
+        }
+    }
+
+    fn unlock_write(&self) {
+        let mut state = self.state.lock();
+        if !state.waiting_readers.is_empty() {
+            state.writer = false;
+            state.readers = state.waiting_readers.wake_all();
+        } else if !state.waiting_writers.wake_one() {
+            state.writer = false;
+        }
+    }
+}
+
+/// Releases the shared access to a [`RwLock`] when dropped.
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_read();
+    }
+}
+
+/// Releases the exclusive access to a [`RwLock`] when dropped.
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock_write();
+    }
+}
+
+#[cfg(test)]
+use crate::thread;
+#[cfg(test)]
+use alloc::sync::Arc;
+
+#[test_case]
+fn test_readers_share_the_lock() {
+    let lock = RwLock::new(5);
+    let a = lock.read();
+    let b = lock.read();
+    assert_eq!(*a + *b, 10);
+    assert!(lock.try_write().is_none());
+    drop((a, b));
+    *lock.write() += 1;
+    assert_eq!(*lock.read(), 6);
+}
+
+#[test_case]
+fn test_waiting_writer_blocks_new_readers() {
+    let lock = Arc::new(RwLock::new(0));
+    let reader = lock.read();
+    let writing = lock.clone();
+    let writer = thread::spawn("writer", move || *writing.write() = 1).unwrap();
+    while lock.state.lock().waiting_writers.is_empty() {
+        thread::yield_now();
+    }
+    // A new reader would starve the writer.
+    assert!(lock.try_read().is_none());
+    drop(reader);
+    // The writer got the lock before this reader.
+    assert_eq!(*lock.read(), 1);
+    writer.join().unwrap();
+}
+
+#[test_case]
+fn test_writer_wakes_waiting_readers() {
+    use alloc::vec::Vec;
+
+    let lock = Arc::new(RwLock::new(0));
+    let writer = lock.write();
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            let lock = lock.clone();
+            thread::spawn("reader", move || *lock.read()).unwrap()
+        })
+        .collect();
+    while lock.state.lock().waiting_readers.len() < 3 {
+        thread::yield_now();
+    }
+    drop(writer);
+    assert_eq!(lock.state.lock().readers, 3);
+    for reader in readers {
+        assert_eq!(reader.join(), Some(0));
+    }
+}
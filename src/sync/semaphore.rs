@@ -0,0 +1,146 @@
+use super::{
+    wait_queue::{self, WaitList},
+    IrqMutex,
+};
+use crate::time::Instant;
+use core::time::Duration;
+
+/// A counting semaphore that puts waiting threads to sleep.
+///
+/// Like [`super::Mutex`], it is fair: a released permit goes to the thread that waits the
+/// longest.
+pub struct Semaphore {
+    state: IrqMutex<State>,
+}
+
+struct State {
+    permits: usize,
+    waiters: WaitList,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            state: IrqMutex::new(State {
+                permits,
+                waiters: WaitList::new(),
+            }),
+        }
+    }
+
+    /// Blocks until a permit is available and takes it.
+    pub fn acquire(&self) {
+        self.acquire_until(None);
+    }
+
+    /// Takes a permit if one is available.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.permits == 0 {
+            return false;
+        }
+        state.permits -= 1;
+        true
+    }
+
+    /// Like [`Semaphore::acquire`], but gives up after `timeout`. Returns whether a permit was
+    /// taken.
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        self.acquire_until(wait_queue::deadline_after(timeout))
+    }
+
+    fn acquire_until(&self, deadline: Option<Instant>) -> bool {
+        let mut state = self.state.lock();
+        if state.permits > 0 {
+            state.permits -= 1;
+            return true;
+        }
+        // A woken thread got the permit, see `release`.
+        wait_queue::sleep(&self.state, state, |s| &mut s.waiters, deadline)
+    }
+
+    /// Returns a permit, or hands it to a waiting thread.
+    pub fn release(&self) {
+        let mut state = self.state.lock();
+        if !state.waiters.wake_one() {
+            state.permits += 1;
+        }
+    }
+
+    /// The number of available permits.
+    pub fn permits(&self) -> usize {
+        self.state.lock().permits
+    }
+}
+
+#[cfg(test)]
+use crate::thread;
+#[cfg(test)]
+use alloc::{sync::Arc, vec::Vec};
+#[cfg(test)]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[test_case]
+fn test_semaphore_limits_concurrency() {
+    static RUNNING: AtomicUsize = AtomicUsize::new(0);
+    static MAX_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+    let semaphore = Arc::new(Semaphore::new(2));
+    let handles: Vec<_> = (0..5)
+        .map(|_| {
+            let semaphore = semaphore.clone();
+            thread::spawn("limited", move || {
+                semaphore.acquire();
+                let running = RUNNING.fetch_add(1, Ordering::SeqCst) + 1;
+                MAX_RUNNING.fetch_max(running, Ordering::SeqCst);
+                for _ in 0..3 {
+                    thread::yield_now();
+                }
+                RUNNING.fetch_sub(1, Ordering::SeqCst);
+                semaphore.release();
+            })
+            .unwrap()
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(MAX_RUNNING.load(Ordering::SeqCst), 2);
+    assert_eq!(semaphore.permits(), 2);
+}
+
+#[test_case]
+fn test_semaphore_is_fair() {
+    let semaphore = Arc::new(Semaphore::new(0));
+    let order = Arc::new(super::Mutex::new(Vec::new()));
+    let handles: Vec<_> = (0..3)
+        .map(|i| {
+            let (waiting, order) = (semaphore.clone(), order.clone());
+            let handle = thread::spawn("waiter", move || {
+                waiting.acquire();
+                order.lock().push(i);
+                // Pass the permit on only after recording.
+                waiting.release();
+            })
+            .unwrap();
+            while semaphore.state.lock().waiters.len() <= i {
+                thread::yield_now();
+            }
+            handle
+        })
+        .collect();
+    semaphore.release();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(*order.lock(), [0, 1, 2]);
+}
+
+#[test_case]
+fn test_acquire_timeout_expires() {
+    let semaphore = Semaphore::new(1);
+    assert!(semaphore.try_acquire());
+    assert!(!semaphore.acquire_timeout(Duration::from_millis(2)));
+    semaphore.release();
+    assert!(semaphore.acquire_timeout(Duration::from_millis(2)));
+}
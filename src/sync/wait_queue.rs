@@ -0,0 +1,273 @@
+use super::{IrqMutex, IrqMutexGuard};
+use crate::thread::{self, ThreadId};
+use crate::time::{self, Instant};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+/// A thread in a [`WaitList`].
+pub struct Waiter {
+    thread: ThreadId,
+    woken: AtomicBool,
+}
+
+impl Waiter {
+    /// Blocks until the waiter is woken, or until the monotonic clock reaches `deadline`.
+    /// Returns whether it was woken.
+    pub fn wait(&self, deadline: Option<Instant>) -> bool {
+        self.wait_interruptible(deadline, || false)
+    }
+
+    /// Like [`Waiter::wait`], but also gives up once `interrupted` returns `true`. It is checked
+    /// before blocking and whenever the thread is unblocked, so whoever makes it return `true`
+    /// must [`thread::unblock`] the thread.
+    pub fn wait_interruptible(
+        &self,
+        deadline: Option<Instant>,
+        interrupted: impl Fn() -> bool,
+    ) -> bool {
+        loop {
+            if self.woken.load(Ordering::Acquire) {
+                return true;
+            }
+            if deadline.is_some_and(|deadline| time::now() >= deadline) || interrupted() {
+                return self.woken.load(Ordering::Acquire);
+            }
+            thread::block(deadline);
+        }
+    }
+}
+
+/// Blocked threads in FIFO order.
+///
+/// A list isn't locked itself: it is part of the state of a primitive, protected by the same
+/// [`IrqMutex`] as the condition the threads wait for. So checking the condition and starting to
+/// wait is atomic, and no wakeup gets lost.
+pub struct WaitList {
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+impl WaitList {
+    pub const fn new() -> Self {
+        WaitList {
+            waiters: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+
+    /// Adds the current thread to the end of the list. It still has to [`Waiter::wait`] after the
+    /// lock of the list is released.
+    pub fn push_current(&mut self) -> Arc<Waiter> {
+        let waiter = Arc::new(Waiter {
+            thread: thread::current_id().expect("can't block before threads are initialized"),
+            woken: AtomicBool::new(false),
+        });
+        self.waiters.push_back(waiter.clone());
+        waiter
+    }
+
+    /// Removes a waiter whose timeout expired. Returns `false` if it was woken in the meantime.
+    pub fn remove(&mut self, waiter: &Arc<Waiter>) -> bool {
+        match self.waiters.iter().position(|w| Arc::ptr_eq(w, waiter)) {
+            Some(index) => {
+                self.waiters.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes the thread that waits the longest. Returns `false` if no thread waits.
+    pub fn wake_one(&mut self) -> bool {
+        match self.waiters.pop_front() {
+            Some(waiter) => {
+                waiter.woken.store(true, Ordering::Release);
+                thread::unblock(waiter.thread);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes all threads and returns their number.
+    pub fn wake_all(&mut self) -> usize {
+        let mut count = 0;
+        while self.wake_one() {
+            count += 1;
+        }
+        count
+    }
+}
+
+impl Default for WaitList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Adds the current thread to the [`WaitList`] selected by `list` in the state locked by `guard`,
+/// releases the lock and blocks until the thread is woken or until the monotonic clock reaches
+/// `deadline`. Returns `false` on timeout, after removing the thread from the list again.
+pub fn sleep<'a, S>(
+    mutex: &'a IrqMutex<S>,
+    guard: IrqMutexGuard<'a, S>,
+    list: impl Fn(&mut S) -> &mut WaitList,
+    deadline: Option<Instant>,
+) -> bool {
+    sleep_interruptible(mutex, guard, list, deadline, || false)
+}
+
+/// Like [`sleep`], but also returns `false` once `interrupted` returns `true`, see
+/// [`Waiter::wait_interruptible`]. Used for waits that a signal to a process ends early.
+pub fn sleep_interruptible<'a, S>(
+    mutex: &'a IrqMutex<S>,
+    mut guard: IrqMutexGuard<'a, S>,
+    list: impl Fn(&mut S) -> &mut WaitList,
+    deadline: Option<Instant>,
+    interrupted: impl Fn() -> bool,
+) -> bool {
+    let waiter = list(&mut *guard).push_current();
+    drop(guard);
+    if waiter.wait_interruptible(deadline, interrupted) {
+        return true;
+    }
+    // If it was woken just now, it's not in the list anymore.
+    !list(&mut *mutex.lock()).remove(&waiter)
+}
+
+/// The time at which a timeout of `timeout` from now expires.
+pub(super) fn deadline_after(timeout: Duration) -> Option<Instant> {
+    Some(time::now() + timeout)
+}
+
+/// A queue of threads waiting for an event.
+pub struct WaitQueue {
+    waiters: IrqMutex<WaitList>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqMutex::new(WaitList::new()),
+        }
+    }
+
+    /// Blocks until woken by [`WaitQueue::notify_one`] or [`WaitQueue::notify_all`].
+    pub fn wait(&self) {
+        sleep(&self.waiters, self.waiters.lock(), |list| list, None);
+    }
+
+    /// Like [`WaitQueue::wait`], but gives up after `timeout`. Returns whether it was woken.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = deadline_after(timeout);
+        sleep(&self.waiters, self.waiters.lock(), |list| list, deadline)
+    }
+
+    /// Blocks until `condition` returns `true`. It is checked whenever the queue is notified, with
+    /// the queue locked, so a notification after making the condition true is never missed.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            let waiters = self.waiters.lock();
+            if condition() {
+                return;
+            }
+            sleep(&self.waiters, waiters, |list| list, None);
+        }
+    }
+
+    /// Wakes the thread that waits the longest. Returns `false` if no thread waits.
+    pub fn notify_one(&self) -> bool {
+        self.waiters.lock().wake_one()
+    }
+
+    /// Wakes all waiting threads and returns their number.
+    pub fn notify_all(&self) -> usize {
+        self.waiters.lock().wake_all()
+    }
+
+    /// The number of waiting threads.
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_notify_wakes_waiter() {
+    let queue = Arc::new(WaitQueue::new());
+    let waiting = queue.clone();
+    let handle = thread::spawn("waiter", move || waiting.wait()).unwrap();
+    while queue.is_empty() {
+        thread::yield_now();
+    }
+    assert!(queue.notify_one());
+    handle.join().unwrap();
+    assert!(!queue.notify_one());
+}
+
+#[test_case]
+fn test_notify_all_wakes_every_waiter() {
+    use alloc::vec::Vec;
+
+    let queue = Arc::new(WaitQueue::new());
+    let handles: Vec<_> = (0..3)
+        .map(|_| {
+            let queue = queue.clone();
+            thread::spawn("waiter", move || queue.wait()).unwrap()
+        })
+        .collect();
+    while queue.len() < 3 {
+        thread::yield_now();
+    }
+    assert_eq!(queue.notify_all(), 3);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test_case]
+fn test_wait_timeout_expires() {
+    let queue = WaitQueue::new();
+    let start = time::now();
+    assert!(!queue.wait_timeout(Duration::from_millis(3)));
+    assert!(start.elapsed() >= Duration::from_millis(3));
+    // The timed out waiter was removed.
+    assert!(queue.is_empty());
+}
+
+#[test_case]
+fn test_wait_until_sees_condition() {
+    let queue = Arc::new(WaitQueue::new());
+    let flag = Arc::new(AtomicBool::new(false));
+    let (waiting, waited_flag) = (queue.clone(), flag.clone());
+    let handle = thread::spawn("waiter", move || {
+        waiting.wait_until(|| waited_flag.load(Ordering::SeqCst));
+    })
+    .unwrap();
+    // Wakeups without the condition put the thread back to sleep.
+    for _ in 0..3 {
+        while queue.is_empty() {
+            thread::yield_now();
+        }
+        queue.notify_all();
+    }
+    flag.store(true, Ordering::SeqCst);
+    queue.notify_all();
+    handle.join().unwrap();
+}
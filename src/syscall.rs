@@ -0,0 +1,1608 @@
+//! # syscall
+//!
+//! System calls of user processes.
+//!
+//! User code calls the kernel with the `syscall` instruction. Like on Linux, the number of the
+//! system call is passed in `rax` and up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and
+//! `r9`. The result is returned in `rax`: a negative value is an [`Error`], negated. The
+//! instruction itself overwrites `rcx` and `r11` with the return address and `RFLAGS`; all other
+//! registers are preserved. The numbers never change, new system calls get new numbers.
+//!
+//! | Number           | Arguments             | Result                                            |
+//! |------------------|-----------------------|---------------------------------------------------|
+//! | [`SYS_EXIT`]     | code                  | doesn't return                                    |
+//! | [`SYS_WRITE`]    | fd, buffer, length    | the number of bytes written to the file `fd`,     |
+//! |                  |                       | e.g. 1 (stdout) for the console                   |
+//! | [`SYS_READ`]     | fd, buffer, length    | the number of bytes read from the file `fd`, or 0 |
+//! |                  |                       | at the end of file; blocks until there is input   |
+//! | [`SYS_YIELD`]    |                       | 0, after other threads had the chance to run      |
+//! | [`SYS_SLEEP`]    | nanoseconds           | 0, after at least that long                       |
+//! | [`SYS_TIME`]     |                       | the monotonic clock, in nanoseconds since boot    |
+//! | [`SYS_FORK`]     |                       | the child's PID, or 0 in the child                |
+//! | [`SYS_EXEC`]     | path, argv, envp      | doesn't return on success, but runs the program   |
+//! |                  |                       | `path` (see [`process::programs`]) with the       |
+//! |                  |                       | NULL-terminated string arrays `argv` and `envp`   |
+//! | [`SYS_WAITPID`]  | pid, status, options  | the PID of the ended child `pid`, or of any child |
+//! |                  |                       | for -1; stores its [`wait_status`] at `status`    |
+//! |                  |                       | unless it is NULL; with [`WNOHANG`] in            |
+//! |                  |                       | `options`, 0 if none ended yet                    |
+//! | [`SYS_GETPID`]   |                       | the PID of the process                            |
+//! | [`SYS_GETPPID`]  |                       | the PID of the parent, or 0 without one           |
+//! | [`SYS_MMAP`]     | address, length,      | the address of `length` bytes of new memory with  |
+//! |                  | prot, flags, fd,      | `prot` (see [`Protection`]): a private copy of    |
+//! |                  | offset                | the file `fd` from `offset` on, or zeroed memory  |
+//! |                  |                       | with [`MAP_ANONYMOUS`]                            |
+//! | [`SYS_MUNMAP`]   | address, length       | 0, after unmapping the range                      |
+//! | [`SYS_MPROTECT`] | address, length, prot | 0, after changing the protection of the range     |
+//! | [`SYS_KILL`]     | pid, signal           | 0, after sending the signal (see [`Signal`]) to   |
+//! |                  |                       | the process `pid`, or only checking that it       |
+//! |                  |                       | exists for signal 0                               |
+//! | [`SYS_SIGACTION`]| signal, action,       | 0, after storing the [`UserAction`] of `signal`   |
+//! |                  | old action            | at `old action` and setting it to `action`,       |
+//! |                  |                       | unless they are NULL                              |
+//! | [`SYS_SIGPROCMASK`]| how, set, old set   | 0, after storing the blocked signals at `old set` |
+//! |                  |                       | and changing them as `how` says with `set`,       |
+//! |                  |                       | unless they are NULL                              |
+//! | [`SYS_SIGRETURN`]|                       | doesn't return, but continues where a signal      |
+//! |                  |                       | handler interrupted the process                   |
+//! | [`SYS_SETFG`]    | pid                   | 0, after making the process itself (for 0) or its |
+//! |                  |                       | child `pid` the foreground process                |
+//! | [`SYS_CLOSE`]    | fd                    | 0, after closing the file descriptor              |
+//! | [`SYS_DUP`]      | fd                    | the lowest free file descriptor, now for the file |
+//! |                  |                       | of `fd` too                                       |
+//! | [`SYS_DUP2`]     | fd, new fd            | `new fd`, closed first if open, now for the file  |
+//! |                  |                       | of `fd` too                                       |
+//! | [`SYS_PIPE`]     | fds, flags            | 0, after storing the reading and the writing end  |
+//! |                  |                       | of a new pipe at `fds`, as two 32-bit file        |
+//! |                  |                       | descriptors; see [`O_CLOEXEC`]                    |
+//! | [`SYS_FCNTL`]    | fd, command, argument | for [`F_GETFD`], the flags of the file descriptor |
+//! |                  |                       | (see [`FD_CLOEXEC`]); 0 after setting them to     |
+//! |                  |                       | `argument` for [`F_SETFD`]                        |
+//! | [`SYS_OPEN`]     | path, flags           | a new file descriptor for the file or directory   |
+//! |                  |                       | `path`, opened as [`O_RDONLY`], [`O_WRONLY`] or   |
+//! |                  |                       | [`O_RDWR`] in `flags`; see [`O_CREAT`],           |
+//! |                  |                       | [`O_TRUNC`], [`O_APPEND`], [`O_DIRECTORY`] and    |
+//! |                  |                       | [`O_CLOEXEC`]                                     |
+//! | [`SYS_CHDIR`]    | path                  | 0, after making the directory `path` the current  |
+//! |                  |                       | directory                                         |
+//! | [`SYS_GETCWD`]   | buffer, length        | the length of the current directory with its NUL, |
+//! |                  |                       | after storing it at `buffer`                      |
+//! | [`SYS_MKDIR`]    | path                  | 0, after creating the directory `path`            |
+//! | [`SYS_SETECHO`]  | fd, on                | whether typed keys were echoed, after turning it  |
+//! |                  |                       | on or off for the console `fd`                    |
+//!
+//! Pointers are only accessed with [`copy_from_user`] and [`copy_to_user`], so a bad pointer
+//! fails with [`Error::BadAddress`] instead of crashing the kernel or the process.
+//!
+//! Files are accessed by file descriptors, see [`crate::file`]. Processes start with the console as
+//! stdin, stdout and stderr. Writing to a pipe without readers fails with [`Error::BrokenPipe`]
+//! and sends [`Signal::SIGPIPE`] to the process, which ends it unless it handles or ignores it.
+//! [`SYS_OPEN`] opens the files of the file system (see [`crate::fs`]). Paths are relative to the
+//! current directory of the process, unless they start with `/`.
+//!
+//! The keyboard task echoes typed keys to the console, unless a program turns it off with
+//! [`SYS_SETECHO`] to echo them itself, e.g. a shell that edits the line being typed.
+//!
+//! Blocking system calls fail with [`Error::Interrupted`] when a signal arrives, and signals are
+//! delivered when a system call returns (see [`crate::process::signal`]).
+//!
+//! Memory from [`SYS_MMAP`] is only recorded as an area of the address space at first, and gets
+//! its pages when they are touched (see [`crate::memory::vma`]). Lengths are rounded up to whole
+//! pages, addresses and file offsets must be page aligned. Only regular files opened for reading
+//! can be mapped, and only privately: writes to the memory never reach the file, and the part of
+//! the last page past the end of the file reads as zeros.
+//!
+//! The `syscall` instruction neither switches stacks nor saves anything on the stack. [`init`]
+//! sets it up on each CPU: `IA32_LSTAR` points to the entry code, `IA32_STAR` holds the segments of
+//! both sides (see [`crate::gdt`]), and `IA32_FMASK` disables interrupts on entry. The entry code
+//! switches to the kernel's GS base and to the kernel stack of the thread from the per-CPU area
+//! (see [`crate::percpu`]), and saves the same [`Registers`] an interrupt from user mode would, so
+//! the rest of the kernel can't tell the difference. It returns with `sysretq`, unless the
+//! registers were changed in a way only `iretq` can return to.
+//!
+//! The older `int 0x80` gate (see [`VECTOR`]) stays available with the same ABI, except that it
+//! preserves `rcx` and `r11` too.
+//!
+//! The system calls run on the kernel stack of the process's thread with interrupts enabled, so
+//! they can be preempted like any other kernel code. With [`set_tracing`], or the serial command
+//! `strace on`, every system call is printed over serial with its arguments and result, like
+//! `strace` does.
+
+use crate::{
+    file::{self, FdError, File, IoError},
+    fs::{self, FsError},
+    gdt,
+    memory::{
+        address_space::{self, Placement},
+        paging::PagingError,
+        user::{copy_from_user, copy_to_user, BadAddress},
+        vma::{AreaKind, Protection},
+    },
+    percpu,
+    process::{
+        self, programs,
+        signal::{Action, SigSet, Signal, UserAction},
+        ExitStatus, NoSuchProcess, Pid, SpawnError, WaitError,
+    },
+    serial_println,
+    task::keyboard,
+    thread, time,
+};
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::{
+    arch::global_asm,
+    convert::TryFrom,
+    fmt,
+    mem::size_of,
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::idt::InterruptDescriptorTable,
+    PrivilegeLevel, VirtAddr,
+};
+
+/// The interrupt vector of system calls.
+pub const VECTOR: u8 = 0x80;
+
+/// Ends the process.
+pub const SYS_EXIT: u64 = 0;
+/// Writes to a file descriptor.
+pub const SYS_WRITE: u64 = 1;
+/// Reads from a file descriptor.
+pub const SYS_READ: u64 = 2;
+/// Lets other threads run.
+pub const SYS_YIELD: u64 = 3;
+/// Sleeps for a number of nanoseconds.
+pub const SYS_SLEEP: u64 = 4;
+/// Reads the monotonic clock.
+pub const SYS_TIME: u64 = 5;
+/// Creates a child process.
+pub const SYS_FORK: u64 = 6;
+/// Runs another program in the process.
+pub const SYS_EXEC: u64 = 7;
+/// Waits for a child process to end.
+pub const SYS_WAITPID: u64 = 8;
+/// Returns the process's PID.
+pub const SYS_GETPID: u64 = 9;
+/// Returns the parent's PID.
+pub const SYS_GETPPID: u64 = 10;
+/// Maps memory.
+pub const SYS_MMAP: u64 = 11;
+/// Unmaps memory.
+pub const SYS_MUNMAP: u64 = 12;
+/// Changes the protection of memory.
+pub const SYS_MPROTECT: u64 = 13;
+/// Sends a signal to a process.
+pub const SYS_KILL: u64 = 14;
+/// Examines and changes the action of a signal.
+pub const SYS_SIGACTION: u64 = 15;
+/// Examines and changes the blocked signals.
+pub const SYS_SIGPROCMASK: u64 = 16;
+/// Returns from a signal handler.
+pub const SYS_SIGRETURN: u64 = 17;
+/// Makes a process the foreground process.
+pub const SYS_SETFG: u64 = 18;
+/// Closes a file descriptor.
+pub const SYS_CLOSE: u64 = 19;
+/// Duplicates a file descriptor.
+pub const SYS_DUP: u64 = 20;
+/// Duplicates a file descriptor to a given one.
+pub const SYS_DUP2: u64 = 21;
+/// Creates a pipe.
+pub const SYS_PIPE: u64 = 22;
+/// Examines and changes the flags of a file descriptor.
+pub const SYS_FCNTL: u64 = 23;
+/// Opens a file of the file system.
+pub const SYS_OPEN: u64 = 24;
+/// Changes the current directory.
+pub const SYS_CHDIR: u64 = 25;
+/// Returns the current directory.
+pub const SYS_GETCWD: u64 = 26;
+/// Creates a directory.
+pub const SYS_MKDIR: u64 = 27;
+/// Turns the echo of typed keys on the console on or off.
+pub const SYS_SETECHO: u64 = 28;
+
+/// The option of [`SYS_WAITPID`] to return 0 instead of blocking.
+pub const WNOHANG: u64 = 1;
+
+/// The `how` of [`SYS_SIGPROCMASK`] to block the signals in the set as well.
+pub const SIG_BLOCK: u64 = 0;
+/// The `how` of [`SYS_SIGPROCMASK`] to unblock the signals in the set.
+pub const SIG_UNBLOCK: u64 = 1;
+/// The `how` of [`SYS_SIGPROCMASK`] to block exactly the signals in the set.
+pub const SIG_SETMASK: u64 = 2;
+
+/// The access mode of [`SYS_OPEN`] for reading.
+pub const O_RDONLY: u64 = 0;
+/// The access mode of [`SYS_OPEN`] for writing.
+pub const O_WRONLY: u64 = 1;
+/// The access mode of [`SYS_OPEN`] for reading and writing.
+pub const O_RDWR: u64 = 2;
+/// The bits of the access mode in the flags of [`SYS_OPEN`].
+const O_ACCMODE: u64 = 3;
+/// The flag of [`SYS_OPEN`] to create the file if it doesn't exist.
+pub const O_CREAT: u64 = 0x40;
+/// The flag of [`SYS_OPEN`] to empty the file, if it is opened for writing.
+pub const O_TRUNC: u64 = 0x200;
+/// The flag of [`SYS_OPEN`] to write at the end of the file.
+pub const O_APPEND: u64 = 0x400;
+/// The flag of [`SYS_OPEN`] to fail unless the path is a directory.
+pub const O_DIRECTORY: u64 = 0x10000;
+/// The flag of [`SYS_PIPE`] and [`SYS_OPEN`] to mark the new file descriptors close-on-exec.
+pub const O_CLOEXEC: u64 = 0x80000;
+
+/// The command of [`SYS_FCNTL`] to get the flags of a file descriptor.
+pub const F_GETFD: u64 = 1;
+/// The command of [`SYS_FCNTL`] to set the flags of a file descriptor.
+pub const F_SETFD: u64 = 2;
+/// The flag of a file descriptor that `exec` closes it.
+pub const FD_CLOEXEC: u64 = 1;
+
+/// The flag of [`SYS_MMAP`] to share the memory with forked children. Not supported yet.
+pub const MAP_SHARED: u64 = 0x01;
+/// The flag of [`SYS_MMAP`] to give forked children copies of the memory.
+pub const MAP_PRIVATE: u64 = 0x02;
+/// The flag of [`SYS_MMAP`] to map exactly at the address, replacing what is mapped there.
+/// Otherwise the address is only a hint.
+pub const MAP_FIXED: u64 = 0x10;
+/// The flag of [`SYS_MMAP`] to map zeroed memory instead of a file, ignoring `fd` and `offset`.
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// The most bytes a single `read` or `write` transfers.
+const MAX_IO: usize = 4096;
+
+/// The most strings in the `argv` or `envp` of [`SYS_EXEC`].
+const MAX_ARGS: usize = 1024;
+
+/// Whether system calls are printed, see [`set_tracing`].
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+/// The errors of system calls, with the numbers Linux uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Error {
+    NoSuchFile = 2,
+    NoSuchProcess = 3,
+    Interrupted = 4,
+    ArgumentListTooLong = 7,
+    ExecFormat = 8,
+    BadFileDescriptor = 9,
+    NoChildProcesses = 10,
+    TryAgain = 11,
+    OutOfMemory = 12,
+    PermissionDenied = 13,
+    BadAddress = 14,
+    FileExists = 17,
+    NoSuchDevice = 19,
+    NotADirectory = 20,
+    IsADirectory = 21,
+    InvalidArgument = 22,
+    TooManyOpenFiles = 24,
+    NotATerminal = 25,
+    NoSpaceLeft = 28,
+    BrokenPipe = 32,
+    OutOfRange = 34,
+    NoSuchSystemCall = 38,
+}
+
+impl From<BadAddress> for Error {
+    fn from(_: BadAddress) -> Self {
+        Error::BadAddress
+    }
+}
+
+impl From<SpawnError> for Error {
+    fn from(err: SpawnError) -> Self {
+        match err {
+            SpawnError::Paging(_) => Error::OutOfMemory,
+            SpawnError::Elf(_) => Error::ExecFormat,
+            SpawnError::ArgumentsTooLong => Error::ArgumentListTooLong,
+            SpawnError::Thread(_) => Error::TryAgain,
+        }
+    }
+}
+
+impl From<PagingError> for Error {
+    fn from(err: PagingError) -> Self {
+        match err {
+            PagingError::NotAligned => Error::InvalidArgument,
+            _ => Error::OutOfMemory,
+        }
+    }
+}
+
+impl From<WaitError> for Error {
+    fn from(err: WaitError) -> Self {
+        match err {
+            WaitError::NoChildren => Error::NoChildProcesses,
+            WaitError::Interrupted => Error::Interrupted,
+        }
+    }
+}
+
+impl From<FdError> for Error {
+    fn from(err: FdError) -> Self {
+        match err {
+            FdError::BadDescriptor => Error::BadFileDescriptor,
+            FdError::TooManyOpen => Error::TooManyOpenFiles,
+        }
+    }
+}
+
+impl From<IoError> for Error {
+    fn from(err: IoError) -> Self {
+        match err {
+            IoError::WrongDirection => Error::BadFileDescriptor,
+            IoError::BrokenPipe => Error::BrokenPipe,
+            IoError::Interrupted => Error::Interrupted,
+            IoError::NoSpace => Error::NoSpaceLeft,
+        }
+    }
+}
+
+impl From<FsError> for Error {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound => Error::NoSuchFile,
+            FsError::NotADirectory => Error::NotADirectory,
+            FsError::IsADirectory => Error::IsADirectory,
+            FsError::AlreadyExists => Error::FileExists,
+            FsError::InvalidPath => Error::InvalidArgument,
+        }
+    }
+}
+
+impl From<NoSuchProcess> for Error {
+    fn from(_: NoSuchProcess) -> Self {
+        Error::NoSuchProcess
+    }
+}
+
+/// The registers of the calling user code, as saved by the entry code, followed by the interrupt
+/// stack frame. Changes are visible to the user code when the system call returns.
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl Registers {
+    /// The six arguments of the system call.
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    /// Whether `sysretq` can return to these registers: it always returns to the user segments,
+    /// faults in kernel mode on a non-canonical return address, and takes the return address and
+    /// `RFLAGS` from `rcx` and `r11`, e.g. not after a signal handler or `sigreturn`.
+    fn can_sysret(&self) -> bool {
+        self.rip < address_space::USER_END
+            && self.cs == u64::from(gdt::USER_CODE_SELECTOR.0)
+            && self.ss == u64::from(gdt::USER_DATA_SELECTOR.0)
+            && self.rcx == self.rip
+            && self.r11 == self.rflags
+    }
+}
+
+/// An entry of [`TABLE`].
+struct Syscall {
+    name: &'static str,
+    /// The number of arguments, for tracing.
+    args: usize,
+    /// Whether it returns, for tracing.
+    returns: bool,
+    handler: fn(&mut Registers) -> Result<u64, Error>,
+}
+
+/// The system calls, indexed by their numbers.
+static TABLE: [Syscall; 29] = [
+    Syscall {
+        name: "exit",
+        args: 1,
+        returns: false,
+        handler: sys_exit,
+    },
+    Syscall {
+        name: "write",
+        args: 3,
+        returns: true,
+        handler: sys_write,
+    },
+    Syscall {
+        name: "read",
+        args: 3,
+        returns: true,
+        handler: sys_read,
+    },
+    Syscall {
+        name: "yield",
+        args: 0,
+        returns: true,
+        handler: sys_yield,
+    },
+    Syscall {
+        name: "sleep",
+        args: 1,
+        returns: true,
+        handler: sys_sleep,
+    },
+    Syscall {
+        name: "time",
+        args: 0,
+        returns: true,
+        handler: sys_time,
+    },
+    Syscall {
+        name: "fork",
+        args: 0,
+        returns: true,
+        handler: sys_fork,
+    },
+    Syscall {
+        name: "exec",
+        args: 3,
+        returns: true,
+        handler: sys_exec,
+    },
+    Syscall {
+        name: "waitpid",
+        args: 3,
+        returns: true,
+        handler: sys_waitpid,
+    },
+    Syscall {
+        name: "getpid",
+        args: 0,
+        returns: true,
+        handler: sys_getpid,
+    },
+    Syscall {
+        name: "getppid",
+        args: 0,
+        returns: true,
+        handler: sys_getppid,
+    },
+    Syscall {
+        name: "mmap",
+        args: 6,
+        returns: true,
+        handler: sys_mmap,
+    },
+    Syscall {
+        name: "munmap",
+        args: 2,
+        returns: true,
+        handler: sys_munmap,
+    },
+    Syscall {
+        name: "mprotect",
+        args: 3,
+        returns: true,
+        handler: sys_mprotect,
+    },
+    Syscall {
+        name: "kill",
+        args: 2,
+        returns: true,
+        handler: sys_kill,
+    },
+    Syscall {
+        name: "sigaction",
+        args: 3,
+        returns: true,
+        handler: sys_sigaction,
+    },
+    Syscall {
+        name: "sigprocmask",
+        args: 3,
+        returns: true,
+        handler: sys_sigprocmask,
+    },
+    Syscall {
+        name: "sigreturn",
+        args: 0,
+        returns: true,
+        handler: sys_sigreturn,
+    },
+    Syscall {
+        name: "setfg",
+        args: 1,
+        returns: true,
+        handler: sys_setfg,
+    },
+    Syscall {
+        name: "close",
+        args: 1,
+        returns: true,
+        handler: sys_close,
+    },
+    Syscall {
+        name: "dup",
+        args: 1,
+        returns: true,
+        handler: sys_dup,
+    },
+    Syscall {
+        name: "dup2",
+        args: 2,
+        returns: true,
+        handler: sys_dup2,
+    },
+    Syscall {
+        name: "pipe",
+        args: 2,
+        returns: true,
+        handler: sys_pipe,
+    },
+    Syscall {
+        name: "fcntl",
+        args: 3,
+        returns: true,
+        handler: sys_fcntl,
+    },
+    Syscall {
+        name: "open",
+        args: 2,
+        returns: true,
+        handler: sys_open,
+    },
+    Syscall {
+        name: "chdir",
+        args: 1,
+        returns: true,
+        handler: sys_chdir,
+    },
+    Syscall {
+        name: "getcwd",
+        args: 2,
+        returns: true,
+        handler: sys_getcwd,
+    },
+    Syscall {
+        name: "mkdir",
+        args: 1,
+        returns: true,
+        handler: sys_mkdir,
+    },
+    Syscall {
+        name: "setecho",
+        args: 2,
+        returns: true,
+        handler: sys_setecho,
+    },
+];
+
+extern "C" {
+    fn syscall_interrupt();
+    fn syscall_entry();
+}
+
+global_asm!(
+    ".macro PUSH_REGISTERS",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    ".endm",
+    "",
+    ".macro POP_REGISTERS",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    ".endm",
+    "",
+    "syscall_interrupt:",
+    // The gate is meant for user mode, but switches the GS base only if it came from there.
+    "test qword ptr [rsp + 8], 3",
+    "jz 1f",
+    "swapgs",
+    "1:",
+    "PUSH_REGISTERS",
+    // The CPU aligned the stack before pushing the 5 words of the interrupt stack frame, so it is
+    // 16 byte aligned again after 15 registers.
+    "mov rdi, rsp",
+    "call {dispatch}",
+    "POP_REGISTERS",
+    "test qword ptr [rsp + 8], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    "iretq",
+    "",
+    "syscall_entry:",
+    // Interrupts are disabled, see `init`, until `dispatch` enables them on the kernel stack.
+    "swapgs",
+    "mov gs:[{user_stack}], rsp",
+    "mov rsp, gs:[{kernel_stack}]",
+    // The interrupt stack frame `iretq` would return to. The kernel stack is 16 byte aligned.
+    "push {user_data}",
+    "push qword ptr gs:[{user_stack}]",
+    "push r11",
+    "push {user_code}",
+    "push rcx",
+    "PUSH_REGISTERS",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    "test al, al",
+    "jz 3f",
+    "POP_REGISTERS",
+    // `sysretq` takes the return address from `rcx` and `RFLAGS` from `r11`.
+    "mov rcx, [rsp]",
+    "mov r11, [rsp + 16]",
+    "mov rsp, [rsp + 24]",
+    "swapgs",
+    "sysretq",
+    "3:",
+    "POP_REGISTERS",
+    "swapgs",
+    "iretq",
+    dispatch = sym dispatch,
+    user_stack = const percpu::USER_STACK_OFFSET,
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+    user_data = const gdt::USER_DATA_SELECTOR.0,
+    user_code = const gdt::USER_CODE_SELECTOR.0,
+);
+
+/// Installs the system call gate, callable from ring 3, in `idt`.
+pub(crate) fn install(idt: &mut InterruptDescriptorTable) {
+    let entry = VirtAddr::new(syscall_interrupt as *const () as u64);
+    unsafe {
+        idt[usize::from(VECTOR)]
+            .set_handler_addr(entry)
+            .set_privilege_level(PrivilegeLevel::Ring3);
+    }
+}
+
+/// Enables the `syscall` instruction on the current CPU. Called on every CPU, after its GDT was
+/// loaded.
+pub fn init() {
+    Star::write(
+        gdt::USER_CODE_SELECTOR,
+        gdt::USER_DATA_SELECTOR,
+        gdt::KERNEL_CODE_SELECTOR,
+        gdt::KERNEL_DATA_SELECTOR,
+    )
+    .expect("the GDT is not in the order syscall and sysret expect");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // Cleared on entry. A set direction or alignment check flag must not leak into the kernel.
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS) };
+}
+
+/// Switches printing every system call over serial on or off. It is off by default.
+pub fn set_tracing(enabled: bool) {
+    TRACING.store(enabled, Ordering::Relaxed);
+}
+
+/// Called by both entries with interrupts disabled. Returns whether the `syscall` entry can return
+/// with `sysretq`.
+extern "C" fn dispatch(registers: &mut Registers) -> bool {
+    interrupts::enable();
+    let number = registers.rax;
+    let syscall = usize::try_from(number)
+        .ok()
+        .and_then(|number| TABLE.get(number));
+    let tracing = TRACING.load(Ordering::Relaxed);
+    if tracing && syscall.is_some_and(|syscall| !syscall.returns) {
+        trace(number, syscall, registers, None);
+    }
+
+    let result = match syscall {
+        Some(syscall) => (syscall.handler)(registers),
+        None => Err(Error::NoSuchSystemCall),
+    };
+    if tracing {
+        trace(number, syscall, registers, Some(result));
+    }
+    registers.rax = match result {
+        Ok(value) => value,
+        Err(err) => (-(err as i64)) as u64,
+    };
+    if registers.cs & 3 == 3 {
+        process::deliver_signals(registers);
+    }
+    interrupts::disable();
+    registers.can_sysret()
+}
+
+/// Prints a system call and its result, or only the call if it doesn't return.
+fn trace(
+    number: u64,
+    syscall: Option<&Syscall>,
+    registers: &Registers,
+    result: Option<Result<u64, Error>>,
+) {
+    let pid = process::current_pid().map_or(0, |pid| pid.as_u64());
+    let args = registers.args();
+    match syscall {
+        Some(syscall) => {
+            serial_println!(
+                "[pid {} {}] {}({}){}",
+                pid,
+                thread::CurrentName,
+                syscall.name,
+                Args(&args[..syscall.args]),
+                Outcome(result)
+            );
+        }
+        None => {
+            serial_println!(
+                "[pid {} {}] syscall_{}({}){}",
+                pid,
+                thread::CurrentName,
+                number,
+                Args(&args),
+                Outcome(result)
+            );
+        }
+    }
+}
+
+/// Arguments in hex, separated by commas.
+struct Args<'a>(&'a [u64]);
+
+impl fmt::Display for Args<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, arg) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{:#x}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of a system call, if it returned.
+struct Outcome(Option<Result<u64, Error>>);
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(Ok(value)) => write!(f, " = {}", value),
+            Some(Err(err)) => write!(f, " = -{} ({:?})", err as i64, err),
+            None => Ok(()),
+        }
+    }
+}
+
+fn sys_exit(registers: &mut Registers) -> Result<u64, Error> {
+    process::exit(ExitStatus::Exited(registers.rdi as i32))
+}
+
+fn sys_write(registers: &mut Registers) -> Result<u64, Error> {
+    let [fd, buffer, len, ..] = registers.args();
+    let file = process::with_files(|files| files.get(fd))?;
+    let buffer = VirtAddr::try_new(buffer).map_err(|_| BadAddress)?;
+    let mut bytes = vec![0; (len as usize).min(MAX_IO)];
+    copy_from_user(&mut bytes, buffer)?;
+    match file.write(&bytes, process::signal_pending) {
+        Ok(written) => Ok(written as u64),
+        Err(IoError::BrokenPipe) => {
+            if let Some(pid) = process::current_pid() {
+                let _ = process::send(pid, Signal::SIGPIPE);
+            }
+            Err(Error::BrokenPipe)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn sys_read(registers: &mut Registers) -> Result<u64, Error> {
+    let [fd, buffer, len, ..] = registers.args();
+    let file = process::with_files(|files| files.get(fd))?;
+    let buffer = VirtAddr::try_new(buffer).map_err(|_| BadAddress)?;
+    let mut bytes = vec![0; (len as usize).min(MAX_IO)];
+    // What was read from a pipe or the keyboard can't be put back if the copy fails, so the
+    // buffer is checked first. The process has no other thread that could unmap it meanwhile.
+    if !process::user_memory_allows(buffer, bytes.len() as u64, Protection::WRITE) {
+        return Err(Error::BadAddress);
+    }
+    let read = file.read(&mut bytes, process::signal_pending)?;
+    copy_to_user(buffer, &bytes[..read])?;
+    Ok(read as u64)
+}
+
+fn sys_yield(_: &mut Registers) -> Result<u64, Error> {
+    thread::yield_now();
+    Ok(0)
+}
+
+fn sys_sleep(registers: &mut Registers) -> Result<u64, Error> {
+    let deadline = time::now() + Duration::from_nanos(registers.rdi);
+    while time::now() < deadline {
+        if process::signal_pending() {
+            return Err(Error::Interrupted);
+        }
+        thread::block(Some(deadline));
+    }
+    Ok(0)
+}
+
+fn sys_time(_: &mut Registers) -> Result<u64, Error> {
+    Ok(time::now().as_nanos())
+}
+
+fn sys_fork(registers: &mut Registers) -> Result<u64, Error> {
+    Ok(process::fork(registers)?.as_u64())
+}
+
+fn sys_exec(registers: &mut Registers) -> Result<u64, Error> {
+    let [path, argv, envp, ..] = registers.args();
+    let path = read_string(path)?;
+    let args = read_strings(argv)?;
+    let env = read_strings(envp)?;
+    let elf = programs::find(&path).ok_or(Error::NoSuchFile)?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let env: Vec<&str> = env.iter().map(String::as_str).collect();
+    process::exec(&path, elf, &args, &env, registers)?;
+    // The registers are the ones of the new program now, which starts with `rax` 0 as well.
+    Ok(0)
+}
+
+fn sys_waitpid(registers: &mut Registers) -> Result<u64, Error> {
+    let [pid, status_ptr, options, ..] = registers.args();
+    let pid = match pid as i64 {
+        -1 => None,
+        pid if pid > 0 => Some(Pid::from_u64(pid as u64)),
+        _ => return Err(Error::InvalidArgument),
+    };
+    if options & !WNOHANG != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let status_ptr = VirtAddr::try_new(status_ptr).map_err(|_| BadAddress)?;
+    match process::wait_child(pid, options & WNOHANG == 0)? {
+        Some((child, status)) => {
+            if !status_ptr.is_null() {
+                copy_to_user(status_ptr, &wait_status(status).to_le_bytes())?;
+            }
+            Ok(child.as_u64())
+        }
+        None => Ok(0),
+    }
+}
+
+fn sys_getpid(_: &mut Registers) -> Result<u64, Error> {
+    Ok(process::current_pid().map_or(0, Pid::as_u64))
+}
+
+fn sys_getppid(_: &mut Registers) -> Result<u64, Error> {
+    Ok(process::parent_pid().map_or(0, Pid::as_u64))
+}
+
+fn sys_mmap(registers: &mut Registers) -> Result<u64, Error> {
+    let [addr, len, prot, flags, fd, offset] = registers.args();
+    let protection = Protection::from_bits(prot).ok_or(Error::InvalidArgument)?;
+    if flags & !(MAP_SHARED | MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS) != 0
+        || flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE
+    {
+        return Err(Error::InvalidArgument);
+    }
+    let kind = if flags & MAP_ANONYMOUS != 0 {
+        AreaKind::Anonymous
+    } else {
+        file_mapping(fd, offset)?
+    };
+    let len = page_align(len)?;
+    let addr = VirtAddr::try_new(addr).map_err(|_| Error::InvalidArgument)?;
+    let placement = if flags & MAP_FIXED != 0 {
+        Placement::Fixed(addr)
+    } else if addr.is_null() {
+        Placement::Anywhere
+    } else {
+        Placement::Hint(addr.align_down(address_space::PAGE_SIZE))
+    };
+    let start = process::with_space(|space| space.map_lazily(placement, len, protection, kind))?;
+    Ok(start.as_u64())
+}
+
+/// The area of a mapping of the file `fd` from `offset` on.
+fn file_mapping(fd: u64, offset: u64) -> Result<AreaKind, Error> {
+    if !offset.is_multiple_of(address_space::PAGE_SIZE) {
+        return Err(Error::InvalidArgument);
+    }
+    let file = process::with_files(|files| files.get(fd))?;
+    match &*file {
+        File::Regular(file) if !file.readable() => Err(Error::PermissionDenied),
+        File::Regular(file) => {
+            let inode = file.file_inode().ok_or(Error::NoSuchDevice)?;
+            Ok(AreaKind::File { inode, offset })
+        }
+        // Pipes and the console have no contents to map.
+        _ => Err(Error::NoSuchDevice),
+    }
+}
+
+fn sys_munmap(registers: &mut Registers) -> Result<u64, Error> {
+    let [addr, len, ..] = registers.args();
+    let addr = VirtAddr::try_new(addr).map_err(|_| Error::InvalidArgument)?;
+    let len = page_align(len)?;
+    process::with_space(|space| space.unmap(addr, len))?;
+    Ok(0)
+}
+
+fn sys_mprotect(registers: &mut Registers) -> Result<u64, Error> {
+    let [addr, len, prot, ..] = registers.args();
+    let protection = Protection::from_bits(prot).ok_or(Error::InvalidArgument)?;
+    let addr = VirtAddr::try_new(addr).map_err(|_| Error::InvalidArgument)?;
+    let len = page_align(len)?;
+    process::with_space(|space| space.protect(addr, len, protection))?;
+    Ok(0)
+}
+
+fn sys_kill(registers: &mut Registers) -> Result<u64, Error> {
+    let [pid, signal, ..] = registers.args();
+    let pid = match pid as i64 {
+        pid if pid > 0 => Pid::from_u64(pid as u64),
+        _ => return Err(Error::InvalidArgument),
+    };
+    match signal {
+        0 if !process::exists(pid) => return Err(Error::NoSuchProcess),
+        0 => {}
+        signal => process::send(pid, Signal::new(signal).ok_or(Error::InvalidArgument)?)?,
+    }
+    Ok(0)
+}
+
+fn sys_sigaction(registers: &mut Registers) -> Result<u64, Error> {
+    let [signal, action, old_action, ..] = registers.args();
+    let signal = Signal::new(signal).ok_or(Error::InvalidArgument)?;
+    let action = match action {
+        0 => None,
+        addr => {
+            if signal.is_unstoppable() {
+                return Err(Error::InvalidArgument);
+            }
+            let mut user = UserAction::default();
+            copy_from_user(as_bytes_mut(&mut user), user_addr(addr)?)?;
+            Some(Action::from_user(&user).ok_or(Error::InvalidArgument)?)
+        }
+    };
+    // Checked before anything changes.
+    let old_action = match old_action {
+        0 => None,
+        addr => Some(user_addr(addr)?),
+    };
+    let old = process::signal_action(signal, action).to_user();
+    if let Some(addr) = old_action {
+        copy_to_user(addr, as_bytes(&old))?;
+    }
+    Ok(0)
+}
+
+fn sys_sigprocmask(registers: &mut Registers) -> Result<u64, Error> {
+    let [how, set, old_set, ..] = registers.args();
+    let set = match set {
+        0 => None,
+        addr => {
+            let mut bits = [0; 8];
+            copy_from_user(&mut bits, user_addr(addr)?)?;
+            Some(SigSet::from_bits_truncate(u64::from_le_bytes(bits)))
+        }
+    };
+    if set.is_some() && ![SIG_BLOCK, SIG_UNBLOCK, SIG_SETMASK].contains(&how) {
+        return Err(Error::InvalidArgument);
+    }
+    let old = process::update_blocked(|blocked| match (how, set) {
+        (SIG_BLOCK, Some(set)) => blocked | set,
+        (SIG_UNBLOCK, Some(set)) => blocked - set,
+        (SIG_SETMASK, Some(set)) => set,
+        _ => blocked,
+    });
+    if old_set != 0 {
+        copy_to_user(user_addr(old_set)?, &old.bits().to_le_bytes())?;
+    }
+    Ok(0)
+}
+
+fn sys_sigreturn(registers: &mut Registers) -> Result<u64, Error> {
+    process::sigreturn(registers);
+    // Returned in `rax`, which is restored like all others.
+    Ok(registers.rax)
+}
+
+fn sys_setfg(registers: &mut Registers) -> Result<u64, Error> {
+    let current = process::current_pid().ok_or(Error::NoSuchProcess)?;
+    let pid = match registers.rdi {
+        0 => current,
+        pid => Pid::from_u64(pid),
+    };
+    if pid != current && process::parent_of(pid) != Some(current) {
+        return Err(Error::NoSuchProcess);
+    }
+    process::set_foreground(pid)?;
+    Ok(0)
+}
+
+fn sys_close(registers: &mut Registers) -> Result<u64, Error> {
+    let file = process::with_files(|files| files.close(registers.rdi))?;
+    // Not with the process table locked, see `FdTable::close`.
+    drop(file);
+    Ok(0)
+}
+
+fn sys_dup(registers: &mut Registers) -> Result<u64, Error> {
+    Ok(process::with_files(|files| files.dup(registers.rdi))?)
+}
+
+fn sys_dup2(registers: &mut Registers) -> Result<u64, Error> {
+    let [fd, new_fd, ..] = registers.args();
+    let replaced = process::with_files(|files| files.dup2(fd, new_fd))?;
+    drop(replaced);
+    Ok(new_fd)
+}
+
+fn sys_pipe(registers: &mut Registers) -> Result<u64, Error> {
+    let [fds_ptr, flags, ..] = registers.args();
+    if flags & !O_CLOEXEC != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let fds_ptr = user_addr(fds_ptr)?;
+    let close_on_exec = flags & O_CLOEXEC != 0;
+    let (reader, writer) = file::pipe();
+    let (reader, writer) = (Arc::new(reader), Arc::new(writer));
+    let (read_fd, write_fd) = process::with_files(|files| {
+        let read_fd = files.insert(reader, close_on_exec)?;
+        match files.insert(writer, close_on_exec) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(err) => {
+                // Nobody can wait for the new pipe yet, so it can be closed right here.
+                let _ = files.close(read_fd);
+                Err(err)
+            }
+        }
+    })?;
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&(read_fd as u32).to_le_bytes());
+    bytes[4..].copy_from_slice(&(write_fd as u32).to_le_bytes());
+    if let Err(err) = copy_to_user(fds_ptr, &bytes) {
+        let closed = process::with_files(|files| (files.close(read_fd), files.close(write_fd)));
+        drop(closed);
+        return Err(err.into());
+    }
+    Ok(0)
+}
+
+fn sys_fcntl(registers: &mut Registers) -> Result<u64, Error> {
+    let [fd, command, argument, ..] = registers.args();
+    process::with_files(|files| match command {
+        F_GETFD => Ok(if files.close_on_exec(fd)? {
+            FD_CLOEXEC
+        } else {
+            0
+        }),
+        F_SETFD => {
+            files.set_close_on_exec(fd, argument & FD_CLOEXEC != 0)?;
+            Ok(0)
+        }
+        _ => Err(Error::InvalidArgument),
+    })
+}
+
+fn sys_open(registers: &mut Registers) -> Result<u64, Error> {
+    let [path, flags, ..] = registers.args();
+    if flags & !(O_ACCMODE | O_CREAT | O_TRUNC | O_APPEND | O_DIRECTORY | O_CLOEXEC) != 0
+        || flags & O_ACCMODE == O_ACCMODE
+    {
+        return Err(Error::InvalidArgument);
+    }
+    let options = fs::OpenOptions {
+        read: flags & O_ACCMODE != O_WRONLY,
+        write: flags & O_ACCMODE != O_RDONLY,
+        create: flags & O_CREAT != 0,
+        truncate: flags & O_TRUNC != 0,
+        append: flags & O_APPEND != 0,
+        directory: flags & O_DIRECTORY != 0,
+    };
+    let path = user_path(path)?;
+    let file = Arc::new(File::Regular(fs::open(&path, options)?));
+    Ok(process::with_files(|files| {
+        files.insert(file, flags & O_CLOEXEC != 0)
+    })?)
+}
+
+fn sys_chdir(registers: &mut Registers) -> Result<u64, Error> {
+    let path = user_path(registers.rdi)?;
+    if !fs::is_dir(&path)? {
+        return Err(Error::NotADirectory);
+    }
+    process::set_current_dir(path);
+    Ok(0)
+}
+
+fn sys_getcwd(registers: &mut Registers) -> Result<u64, Error> {
+    let [buffer, len, ..] = registers.args();
+    let mut cwd = process::current_dir().into_bytes();
+    cwd.push(0);
+    if cwd.len() as u64 > len {
+        return Err(Error::OutOfRange);
+    }
+    copy_to_user(user_addr(buffer)?, &cwd)?;
+    Ok(cwd.len() as u64)
+}
+
+fn sys_mkdir(registers: &mut Registers) -> Result<u64, Error> {
+    fs::create_dir(&user_path(registers.rdi)?)?;
+    Ok(0)
+}
+
+fn sys_setecho(registers: &mut Registers) -> Result<u64, Error> {
+    let [fd, on, ..] = registers.args();
+    let file = process::with_files(|files| files.get(fd))?;
+    if !matches!(*file, File::Console) {
+        return Err(Error::NotATerminal);
+    }
+    Ok(u64::from(keyboard::set_echo(on != 0)))
+}
+
+/// The user address `addr`, which must be canonical.
+fn user_addr(addr: u64) -> Result<VirtAddr, BadAddress> {
+    VirtAddr::try_new(addr).map_err(|_| BadAddress)
+}
+
+/// The bytes of `value`, a plain `#[repr(C)]` struct of integers, for [`copy_to_user`].
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts((value as *const T).cast(), size_of::<T>()) }
+}
+
+/// The bytes of `value`, a plain `#[repr(C)]` struct of integers, for [`copy_from_user`].
+fn as_bytes_mut<T: Copy>(value: &mut T) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut((value as *mut T).cast(), size_of::<T>()) }
+}
+
+/// `len` rounded up to whole pages. It must not be 0.
+fn page_align(len: u64) -> Result<u64, Error> {
+    match len.checked_next_multiple_of(address_space::PAGE_SIZE) {
+        Some(0) | None => Err(Error::InvalidArgument),
+        Some(len) => Ok(len),
+    }
+}
+
+/// How `waitpid` reports how a child ended, like on Linux: the exit code times 256, or the number
+/// of the signal that killed it.
+pub fn wait_status(status: ExitStatus) -> u32 {
+    match status {
+        ExitStatus::Exited(code) => (code as u32 & 0xff) << 8,
+        ExitStatus::Killed(signal) => u32::from(signal.number()),
+    }
+}
+
+/// Reads the NUL-terminated string at `addr`, which must fit on the initial stack of a process.
+fn read_string(addr: u64) -> Result<String, Error> {
+    let mut addr = VirtAddr::try_new(addr).map_err(|_| BadAddress)?;
+    let mut bytes = Vec::new();
+    loop {
+        // Up to the end of the page, since the next one may not be mapped.
+        let mut chunk = [0; 4096];
+        let chunk = &mut chunk[..4096 - usize::from(addr.page_offset())];
+        copy_from_user(chunk, addr)?;
+        match chunk.iter().position(|&byte| byte == 0) {
+            Some(len) => {
+                bytes.extend_from_slice(&chunk[..len]);
+                break;
+            }
+            None => bytes.extend_from_slice(chunk),
+        }
+        if bytes.len() > process::stack::MAX_SIZE {
+            return Err(Error::ArgumentListTooLong);
+        }
+        addr += chunk.len() as u64;
+    }
+    String::from_utf8(bytes).map_err(|_| Error::InvalidArgument)
+}
+
+/// Reads the NUL-terminated path at `addr`, and makes it absolute with the current directory.
+fn user_path(addr: u64) -> Result<String, Error> {
+    Ok(fs::absolute(&process::current_dir(), &read_string(addr)?)?)
+}
+
+/// Reads the NULL-terminated array of strings at `addr`. NULL itself is an empty array.
+fn read_strings(addr: u64) -> Result<Vec<String>, Error> {
+    let mut strings = Vec::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+    let addr = VirtAddr::try_new(addr).map_err(|_| BadAddress)?;
+    for index in 0..=MAX_ARGS as u64 {
+        let mut pointer = [0; 8];
+        copy_from_user(&mut pointer, addr + index * 8)?;
+        match u64::from_le_bytes(pointer) {
+            0 => return Ok(strings),
+            pointer => strings.push(read_string(pointer)?),
+        }
+    }
+    Err(Error::ArgumentListTooLong)
+}
+
+#[cfg(test)]
+use crate::process::CODE_START;
+
+#[test_case]
+fn test_syscall_sleeps_and_reads_the_clock() {
+    #[rustfmt::skip]
+    let code = [
+        0xb8, SYS_TIME as u8, 0x00, 0x00, 0x00,   // mov eax, SYS_TIME
+        0x0f, 0x05,                               // syscall
+        0x48, 0x89, 0xc3,                         // mov rbx, rax
+        0xbf, 0x40, 0x42, 0x0f, 0x00,             // mov edi, 1000000 (1 ms)
+        0xb8, SYS_SLEEP as u8, 0x00, 0x00, 0x00,  // mov eax, SYS_SLEEP
+        0x0f, 0x05,                               // syscall
+        0xb8, SYS_YIELD as u8, 0x00, 0x00, 0x00,  // mov eax, SYS_YIELD
+        0x0f, 0x05,                               // syscall
+        0xb8, SYS_TIME as u8, 0x00, 0x00, 0x00,   // mov eax, SYS_TIME
+        0x0f, 0x05,                               // syscall
+        0x48, 0x29, 0xd8,                         // sub rax, rbx
+        0x48, 0x89, 0xc7,                         // mov rdi, rax
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,   // mov eax, SYS_EXIT
+        0x0f, 0x05,                               // syscall
+        0x0f, 0x0b,                               // ud2
+    ];
+    let process = process::spawn("sleeper", &code).unwrap();
+    match process.wait() {
+        ExitStatus::Exited(nanos) => assert!(nanos >= 1_000_000, "slept for {} ns", nanos),
+        status => panic!("sleeper {}", status),
+    }
+}
+
+#[test_case]
+fn test_syscall_errors_are_returned() {
+    let unmapped = (CODE_START + 0x10000).to_le_bytes();
+    #[rustfmt::skip]
+    let code = [
+        0xbf, 0x01, 0x00, 0x00, 0x00,              // mov edi, 1 (stdout)
+        0xbe, 0x00, 0x00, 0x20, 0x00,              // mov esi, 0x200000 (the kernel image)
+        0xba, 0x04, 0x00, 0x00, 0x00,              // mov edx, 4
+        0xb8, SYS_WRITE as u8, 0x00, 0x00, 0x00,   // mov eax, SYS_WRITE
+        0x0f, 0x05,                                // syscall
+        0x48, 0x89, 0xc3,                          // mov rbx, rax
+        0x48, 0xbe, unmapped[0], unmapped[1], unmapped[2], unmapped[3],
+        unmapped[4], unmapped[5], unmapped[6], unmapped[7],  // mov rsi, unmapped
+        0xb8, SYS_WRITE as u8, 0x00, 0x00, 0x00,   // mov eax, SYS_WRITE
+        0x0f, 0x05,                                // syscall
+        0x48, 0x01, 0xc3,                          // add rbx, rax
+        0xb8, 0xff, 0x00, 0x00, 0x00,              // mov eax, 0xff (no such call)
+        0x0f, 0x05,                                // syscall
+        0x48, 0x01, 0xc3,                          // add rbx, rax
+        0x48, 0x89, 0xdf,                          // mov rdi, rbx
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,    // mov eax, SYS_EXIT
+        0x0f, 0x05,                                // syscall
+        0x0f, 0x0b,                                // ud2
+    ];
+    let process = process::spawn("errors", &code).unwrap();
+    let expected = -(2 * Error::BadAddress as i32 + Error::NoSuchSystemCall as i32);
+    assert_eq!(process.wait(), ExitStatus::Exited(expected));
+}
+
+/// Maps `len` bytes of private anonymous read-write memory and keeps the address in `rbx`.
+#[cfg(test)]
+fn mmap_code(len: u32) -> Vec<u8> {
+    let len = len.to_le_bytes();
+    #[rustfmt::skip]
+    let code = vec![
+        0x31, 0xff,                                 // xor edi, edi (anywhere)
+        0xbe, len[0], len[1], len[2], len[3],       // mov esi, len
+        0xba, 0x03, 0x00, 0x00, 0x00,               // mov edx, PROT_READ | PROT_WRITE
+        0x41, 0xba, 0x22, 0x00, 0x00, 0x00,         // mov r10d, MAP_PRIVATE | MAP_ANONYMOUS
+        0x49, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff,   // mov r8, -1 (no file)
+        0x45, 0x31, 0xc9,                           // xor r9d, r9d
+        0xb8, SYS_MMAP as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_MMAP
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x89, 0xc3,                           // mov rbx, rax
+    ];
+    code
+}
+
+#[test_case]
+fn test_mmap_and_munmap_anonymous_memory() {
+    let mut code = mmap_code(0x2000);
+    #[rustfmt::skip]
+    code.extend_from_slice(&[
+        0x48, 0xc7, 0x83, 0x00, 0x10, 0x00, 0x00,
+        0x07, 0x00, 0x00, 0x00,                     // mov qword ptr [rbx + 0x1000], 7
+        0x48, 0x8b, 0xab, 0x00, 0x10, 0x00, 0x00,   // mov rbp, [rbx + 0x1000]
+        0x48, 0x8d, 0xbb, 0x00, 0x10, 0x00, 0x00,   // lea rdi, [rbx + 0x1000]
+        0xbe, 0x00, 0x10, 0x00, 0x00,               // mov esi, 0x1000
+        0xb8, SYS_MUNMAP as u8, 0x00, 0x00, 0x00,   // mov eax, SYS_MUNMAP
+        0x0f, 0x05,                                 // syscall
+        0xbf, 0x01, 0x00, 0x00, 0x00,               // mov edi, 1 (stdout)
+        0x48, 0x8d, 0xb3, 0x00, 0x10, 0x00, 0x00,   // lea rsi, [rbx + 0x1000]
+        0xba, 0x01, 0x00, 0x00, 0x00,               // mov edx, 1
+        0xb8, SYS_WRITE as u8, 0x00, 0x00, 0x00,    // mov eax, SYS_WRITE
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x8d, 0x3c, 0x28,                     // lea rdi, [rax + rbp]
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+    ]);
+    let process = process::spawn("mmap", &code).unwrap();
+    let expected = 7 - Error::BadAddress as i32;
+    assert_eq!(process.wait(), ExitStatus::Exited(expected));
+}
+
+#[test_case]
+fn test_mprotect_makes_memory_read_only() {
+    let mut code = mmap_code(0x1000);
+    #[rustfmt::skip]
+    code.extend_from_slice(&[
+        0xc6, 0x03, 0x01,                           // mov byte ptr [rbx], 1
+        0x48, 0x89, 0xdf,                           // mov rdi, rbx
+        0xbe, 0x00, 0x10, 0x00, 0x00,               // mov esi, 0x1000
+        0xba, 0x01, 0x00, 0x00, 0x00,               // mov edx, PROT_READ
+        0xb8, SYS_MPROTECT as u8, 0x00, 0x00, 0x00, // mov eax, SYS_MPROTECT
+        0x0f, 0x05,                                 // syscall
+        0xc6, 0x03, 0x02,                           // mov byte ptr [rbx], 2
+        0x31, 0xff,                                 // xor edi, edi
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+    ]);
+    let process = process::spawn("mprotect", &code).unwrap();
+    assert_eq!(process.wait(), ExitStatus::Killed(Signal::SIGSEGV));
+}
+
+#[test_case]
+fn test_signal_handler_runs_and_returns() {
+    // The handler stores its signal number at [rbp] and returns to the restorer.
+    const HANDLER: u64 = 0x6e;
+    const RESTORER: u64 = HANDLER + 5;
+    let handler = (CODE_START + HANDLER).to_le_bytes();
+    let restorer = (CODE_START + RESTORER).to_le_bytes();
+    #[rustfmt::skip]
+    let mut code = vec![
+        0x48, 0x83, 0xec, 0x20,                     // sub rsp, 32 (a struct sigaction)
+        0x48, 0x89, 0xe5,                           // mov rbp, rsp
+        0x48, 0xb8, handler[0], handler[1], handler[2], handler[3],
+        handler[4], handler[5], handler[6], handler[7],  // mov rax, handler
+        0x48, 0x89, 0x04, 0x24,                     // mov [rsp], rax
+        0x48, 0xc7, 0x44, 0x24, 0x08,
+        0x00, 0x00, 0x00, 0x04,                     // mov qword ptr [rsp + 8], SA_RESTORER
+        0x48, 0xb8, restorer[0], restorer[1], restorer[2], restorer[3],
+        restorer[4], restorer[5], restorer[6], restorer[7],  // mov rax, restorer
+        0x48, 0x89, 0x44, 0x24, 0x10,               // mov [rsp + 16], rax
+        0x48, 0xc7, 0x44, 0x24, 0x18,
+        0x00, 0x00, 0x00, 0x00,                     // mov qword ptr [rsp + 24], 0 (mask)
+        0xbf, 0x0a, 0x00, 0x00, 0x00,               // mov edi, SIGUSR1
+        0x48, 0x89, 0xe6,                           // mov rsi, rsp
+        0x31, 0xd2,                                 // xor edx, edx
+        0xb8, SYS_SIGACTION as u8, 0x00, 0x00, 0x00,  // mov eax, SYS_SIGACTION
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x89, 0xc3,                           // mov rbx, rax
+        0xb8, SYS_GETPID as u8, 0x00, 0x00, 0x00,   // mov eax, SYS_GETPID
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x89, 0xc7,                           // mov rdi, rax
+        0xbe, 0x0a, 0x00, 0x00, 0x00,               // mov esi, SIGUSR1
+        0xb8, SYS_KILL as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_KILL
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x8b, 0x7d, 0x00,                     // mov rdi, [rbp]
+        0x48, 0x01, 0xdf,                           // add rdi, rbx
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+    ];
+    assert_eq!(code.len() as u64, HANDLER);
+    #[rustfmt::skip]
+    code.extend_from_slice(&[
+        0x48, 0x89, 0x7d, 0x00,                     // handler: mov [rbp], rdi
+        0xc3,                                       // ret
+        0xb8, SYS_SIGRETURN as u8, 0x00, 0x00, 0x00,  // restorer: mov eax, SYS_SIGRETURN
+        0x0f, 0x05,                                 // syscall
+    ]);
+    let process = process::spawn("handler", &code).unwrap();
+    assert_eq!(process.wait(), ExitStatus::Exited(10));
+}
+
+#[test_case]
+fn test_blocked_signal_is_delivered_when_unblocked() {
+    #[rustfmt::skip]
+    let code = [
+        0x68, 0x00, 0x40, 0x00, 0x00,               // push 1 << (SIGTERM - 1)
+        0x31, 0xff,                                 // xor edi, edi (SIG_BLOCK)
+        0x48, 0x89, 0xe6,                           // mov rsi, rsp
+        0x31, 0xd2,                                 // xor edx, edx
+        0xb8, SYS_SIGPROCMASK as u8, 0x00, 0x00, 0x00,  // mov eax, SYS_SIGPROCMASK
+        0x0f, 0x05,                                 // syscall
+        0xb8, SYS_GETPID as u8, 0x00, 0x00, 0x00,   // mov eax, SYS_GETPID
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x89, 0xc7,                           // mov rdi, rax
+        0xbe, 0x0f, 0x00, 0x00, 0x00,               // mov esi, SIGTERM
+        0xb8, SYS_KILL as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_KILL
+        0x0f, 0x05,                                 // syscall
+        0xbf, 0x01, 0x00, 0x00, 0x00,               // mov edi, 1 (SIG_UNBLOCK)
+        0x48, 0x89, 0xe6,                           // mov rsi, rsp
+        0x31, 0xd2,                                 // xor edx, edx
+        0xb8, SYS_SIGPROCMASK as u8, 0x00, 0x00, 0x00,  // mov eax, SYS_SIGPROCMASK
+        0x0f, 0x05,                                 // syscall
+        0x31, 0xff,                                 // xor edi, edi
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+    ];
+    let process = process::spawn("blocker", &code).unwrap();
+    assert_eq!(process.wait(), ExitStatus::Killed(Signal::SIGTERM));
+}
+
+#[test_case]
+fn test_pipe_between_parent_and_child() {
+    #[rustfmt::skip]
+    let code = [
+        0x48, 0x83, 0xec, 0x10,                     // sub rsp, 16
+        0x48, 0x89, 0xe7,                           // mov rdi, rsp (fds)
+        0x31, 0xf6,                                 // xor esi, esi
+        0xb8, SYS_PIPE as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_PIPE
+        0x0f, 0x05,                                 // syscall
+        0xb8, SYS_FORK as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_FORK
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x85, 0xc0,                           // test rax, rax
+        0x75, 0x1d,                                 // jnz parent
+        0x8b, 0x7c, 0x24, 0x04,                     // mov edi, [rsp + 4] (writing end)
+        0x48, 0x89, 0xe6,                           // mov rsi, rsp
+        0xba, 0x03, 0x00, 0x00, 0x00,               // mov edx, 3
+        0xb8, SYS_WRITE as u8, 0x00, 0x00, 0x00,    // mov eax, SYS_WRITE
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x89, 0xc7,                           // mov rdi, rax
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+        0x8b, 0x7c, 0x24, 0x04,                     // parent: mov edi, [rsp + 4]
+        0xb8, SYS_CLOSE as u8, 0x00, 0x00, 0x00,    // mov eax, SYS_CLOSE
+        0x0f, 0x05,                                 // syscall
+        0x31, 0xdb,                                 // xor ebx, ebx
+        0x8b, 0x3c, 0x24,                           // read: mov edi, [rsp] (reading end)
+        0x48, 0x8d, 0x74, 0x24, 0x08,               // lea rsi, [rsp + 8]
+        0xba, 0x08, 0x00, 0x00, 0x00,               // mov edx, 8
+        0xb8, SYS_READ as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_READ
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x01, 0xc3,                           // add rbx, rax
+        0x48, 0x85, 0xc0,                           // test rax, rax
+        0x7f, 0xe4,                                 // jg read (until the end of file)
+        0x48, 0x89, 0xdf,                           // mov rdi, rbx
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+    ];
+    let process = process::spawn("pipe", &code).unwrap();
+    assert_eq!(process.wait(), ExitStatus::Exited(3));
+}
+
+#[test_case]
+fn test_write_without_reader_sends_sigpipe() {
+    #[rustfmt::skip]
+    let code = [
+        0x48, 0x83, 0xec, 0x10,                     // sub rsp, 16
+        0x48, 0x89, 0xe7,                           // mov rdi, rsp (fds)
+        0x31, 0xf6,                                 // xor esi, esi
+        0xb8, SYS_PIPE as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_PIPE
+        0x0f, 0x05,                                 // syscall
+        0x8b, 0x3c, 0x24,                           // mov edi, [rsp] (reading end)
+        0xb8, SYS_CLOSE as u8, 0x00, 0x00, 0x00,    // mov eax, SYS_CLOSE
+        0x0f, 0x05,                                 // syscall
+        0x8b, 0x7c, 0x24, 0x04,                     // mov edi, [rsp + 4] (writing end)
+        0x48, 0x89, 0xe6,                           // mov rsi, rsp
+        0xba, 0x01, 0x00, 0x00, 0x00,               // mov edx, 1
+        0xb8, SYS_WRITE as u8, 0x00, 0x00, 0x00,    // mov eax, SYS_WRITE
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x89, 0xc7,                           // mov rdi, rax
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+    ];
+    let process = process::spawn("sigpipe", &code).unwrap();
+    assert_eq!(process.wait(), ExitStatus::Killed(Signal::SIGPIPE));
+}
+
+#[test_case]
+fn test_dup2_and_close_file_descriptors() {
+    #[rustfmt::skip]
+    let code = [
+        0xbf, 0x01, 0x00, 0x00, 0x00,               // mov edi, 1 (stdout)
+        0xbe, 0x05, 0x00, 0x00, 0x00,               // mov esi, 5
+        0xb8, SYS_DUP2 as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_DUP2
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x89, 0xc3,                           // mov rbx, rax
+        0xbf, 0x05, 0x00, 0x00, 0x00,               // mov edi, 5
+        0xb8, SYS_CLOSE as u8, 0x00, 0x00, 0x00,    // mov eax, SYS_CLOSE
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x01, 0xc3,                           // add rbx, rax
+        0xbf, 0x05, 0x00, 0x00, 0x00,               // mov edi, 5
+        0xb8, SYS_CLOSE as u8, 0x00, 0x00, 0x00,    // mov eax, SYS_CLOSE
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x01, 0xc3,                           // add rbx, rax
+        0x48, 0x89, 0xdf,                           // mov rdi, rbx
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+    ];
+    let process = process::spawn("dup2", &code).unwrap();
+    let expected = 5 - Error::BadFileDescriptor as i32;
+    assert_eq!(process.wait(), ExitStatus::Exited(expected));
+}
+
+#[test_case]
+fn test_read_into_a_bad_buffer_keeps_the_data() {
+    #[rustfmt::skip]
+    let code = [
+        0x48, 0x83, 0xec, 0x10,                     // sub rsp, 16
+        0x48, 0x89, 0xe7,                           // mov rdi, rsp (fds)
+        0x31, 0xf6,                                 // xor esi, esi
+        0xb8, SYS_PIPE as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_PIPE
+        0x0f, 0x05,                                 // syscall
+        0xc7, 0x44, 0x24, 0x08, 0x61, 0x62, 0x63,   // mov dword [rsp + 8], "abc"
+        0x00,
+        0xc7, 0x44, 0x24, 0x0c, 0x00, 0x00, 0x00,   // mov dword [rsp + 12], 0
+        0x00,
+        0x8b, 0x7c, 0x24, 0x04,                     // mov edi, [rsp + 4] (writing end)
+        0x48, 0x8d, 0x74, 0x24, 0x08,               // lea rsi, [rsp + 8]
+        0xba, 0x03, 0x00, 0x00, 0x00,               // mov edx, 3
+        0xb8, SYS_WRITE as u8, 0x00, 0x00, 0x00,    // mov eax, SYS_WRITE
+        0x0f, 0x05,                                 // syscall
+        0x8b, 0x7c, 0x24, 0x04,                     // mov edi, [rsp + 4]
+        0xb8, SYS_CLOSE as u8, 0x00, 0x00, 0x00,    // mov eax, SYS_CLOSE
+        0x0f, 0x05,                                 // syscall
+        0x8b, 0x3c, 0x24,                           // mov edi, [rsp] (reading end)
+        0x48, 0xbe, 0x00, 0x00, 0x00, 0x00, 0x00,   // mov rsi, 1 << 40 (unmapped)
+        0x01, 0x00, 0x00,
+        0xba, 0x04, 0x00, 0x00, 0x00,               // mov edx, 4
+        0xb8, SYS_READ as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_READ
+        0x0f, 0x05,                                 // syscall
+        0x48, 0x89, 0xc3,                           // mov rbx, rax
+        0x8b, 0x3c, 0x24,                           // mov edi, [rsp]
+        0x48, 0x8d, 0x74, 0x24, 0x0c,               // lea rsi, [rsp + 12]
+        0xba, 0x04, 0x00, 0x00, 0x00,               // mov edx, 4
+        0xb8, SYS_READ as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_READ
+        0x0f, 0x05,                                 // syscall
+        0x8b, 0x7c, 0x24, 0x0c,                     // mov edi, [rsp + 12] (what was read)
+        0x48, 0x01, 0xdf,                           // add rdi, rbx
+        0xb8, SYS_EXIT as u8, 0x00, 0x00, 0x00,     // mov eax, SYS_EXIT
+        0x0f, 0x05,                                 // syscall
+    ];
+    let process = process::spawn("bad read", &code).unwrap();
+    let expected = i32::from_le_bytes(*b"abc\0") - Error::BadAddress as i32;
+    assert_eq!(process.wait(), ExitStatus::Exited(expected));
+}
@@ -0,0 +1,52 @@
+//! # task
+//!
+//! Cooperative multitasking with `async`/`await`.
+//!
+//! A [`Task`] wraps a pinned, heap-allocated future. The [`executor::Executor`] polls the tasks
+//! whose [`Waker`](core::task::Waker) was called and halts the CPU when no task is ready, until
+//! the next interrupt arrives.
+//!
+//! Tasks never run in interrupt context. Interrupt handlers hand data to tasks through
+//! [`input::InputQueue`]s and wake them, like the [`keyboard`] and [`serial`] input streams do.
+
+pub mod executor;
+pub mod input;
+pub mod keyboard;
+pub mod serial;
+
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+/// A unit of cooperative work: a future that runs until it completes.
+pub struct Task {
+    id: TaskId,
+    /// The future is pinned, since `async` blocks may contain references to themselves.
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+/// A unique task ID, which the wakers use to refer to their task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
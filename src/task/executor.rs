@@ -0,0 +1,224 @@
+//! # executor
+//!
+//! An executor that only polls tasks that were woken.
+//!
+//! Each task has a [`Waker`] that pushes the task's ID to the wake queue. Wakers can be called
+//! from interrupt handlers, so the wake queue is a fixed-size lock-free [`ArrayQueue`]: pushing to
+//! it neither locks nor allocates.
+//!
+//! A waker only pushes the ID if the task isn't queued already, and there are never more tasks
+//! than the wake queue holds, so waking a task never fails.
+
+use super::{Task, TaskId};
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+
+/// The maximum number of tasks, and so of woken tasks waiting to be polled.
+pub const MAX_TASKS: usize = 100;
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    /// The IDs of the woken tasks. Shared with the wakers.
+    wake_queue: Arc<ArrayQueue<TaskId>>,
+    /// One waker per task, created on its first poll.
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            wake_queue: Arc::new(ArrayQueue::new(MAX_TASKS)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a task. It is polled for the first time on the next run of the executor.
+    ///
+    /// Panics if there are [`MAX_TASKS`] tasks already.
+    pub fn spawn(&mut self, task: Task) {
+        assert!(self.tasks.len() < MAX_TASKS, "too many tasks");
+        let id = task.id;
+        if self.tasks.insert(id, task).is_some() {
+            panic!("task with same ID already spawned");
+        }
+        // Each task is queued at most once, so this can't fail.
+        self.wake_queue.push(id).expect("wake queue full");
+    }
+
+    /// Runs the tasks forever, halting the CPU whenever no task is ready.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Polls all woken tasks, until the wake queue is empty.
+    pub fn run_ready_tasks(&mut self) {
+        // Destructure `self` to borrow the fields independently in the closure.
+        let Self {
+            tasks,
+            wake_queue,
+            waker_cache,
+        } = self;
+
+        while let Some(id) = wake_queue.pop() {
+            let task = match tasks.get_mut(&id) {
+                Some(task) => task,
+                // The task completed already, e.g. it was woken twice.
+                None => continue,
+            };
+            let task_waker = waker_cache
+                .entry(id)
+                .or_insert_with(|| TaskWaker::new(id, wake_queue.clone()));
+            // Cleared before polling, so a wakeup during the poll queues the task again.
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            if let Poll::Ready(()) = task.poll(&mut context) {
+                // Wakers that are still around must not queue the completed task again.
+                task_waker.queued.store(true, Ordering::Release);
+                tasks.remove(&id);
+                waker_cache.remove(&id);
+            }
+        }
+    }
+
+    /// The number of tasks that have not completed yet.
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Halts the CPU until the next interrupt if no task is ready.
+    fn sleep_if_idle(&self) {
+        // An interrupt between the check and `hlt` could wake a task and we would still sleep
+        // until the next interrupt. So we check with interrupts disabled, and `tick::idle`
+        // enables them and halts atomically.
+        interrupts::disable();
+        if self.wake_queue.is_empty() {
+            crate::time::tick::idle(false);
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Wakes a task by pushing its ID to the wake queue, unless it is queued already.
+struct TaskWaker {
+    id: TaskId,
+    /// Set while the ID is in the wake queue, and after the task completed.
+    queued: AtomicBool,
+    wake_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn new(id: TaskId, wake_queue: Arc<ArrayQueue<TaskId>>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
+            id,
+            queued: AtomicBool::new(false),
+            wake_queue,
+        })
+    }
+
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            // At most one entry per task, and never more tasks than the queue holds.
+            self.wake_queue.push(self.id).expect("wake queue full");
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+/// Returns `Pending` once after waking itself, like a task yielding to the others.
+#[cfg(test)]
+struct YieldOnce(bool);
+
+#[cfg(test)]
+impl core::future::Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: core::pin::Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn test_spawned_tasks_run() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        executor.spawn(Task::new(async {
+            COMPLETED.fetch_add(1, Ordering::Relaxed);
+        }));
+    }
+    executor.run_ready_tasks();
+    assert_eq!(COMPLETED.load(Ordering::Relaxed), 3);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn test_woken_task_is_polled_again() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        YieldOnce(false).await;
+        YieldOnce(false).await;
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn test_pending_task_without_wakeup_stays() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(core::future::pending()));
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 1);
+    assert!(executor.wake_queue.is_empty());
+}
+
+#[test_case]
+fn test_repeated_wakes_queue_task_once() {
+    let mut executor = Executor::new();
+    let mut polls = 0;
+    executor.spawn(Task::new(core::future::poll_fn(move |context| {
+        polls += 1;
+        if polls > 1 {
+            return Poll::Ready(());
+        }
+        // More wakeups than the wake queue holds.
+        for _ in 0..2 * MAX_TASKS {
+            context.waker().wake_by_ref();
+        }
+        Poll::Pending
+    })));
+    executor.run_ready_tasks();
+    assert_eq!(executor.task_count(), 0);
+    assert!(executor.wake_queue.is_empty());
+}
@@ -0,0 +1,120 @@
+//! # input
+//!
+//! Byte queues filled by interrupt handlers and consumed by async tasks.
+//!
+//! An [`InputQueue`] is a static that an interrupt handler pushes the received bytes to. Its
+//! single consumer is an [`InputStream`], which implements [`Stream`] and is woken when new bytes
+//! arrive. Pushing never blocks or allocates: the queue is allocated when the stream is created,
+//! and bytes received before that or while the queue is full are dropped.
+
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+/// The number of bytes an [`InputQueue`] can hold.
+const INPUT_QUEUE_SIZE: usize = 100;
+
+pub struct InputQueue {
+    queue: OnceCell<ArrayQueue<u8>>,
+    /// The waker of the task reading the [`InputStream`].
+    waker: AtomicWaker,
+    /// The number of bytes dropped because the queue was full or not yet created.
+    dropped: AtomicU64,
+}
+
+impl InputQueue {
+    pub const fn new() -> Self {
+        InputQueue {
+            queue: OnceCell::uninit(),
+            waker: AtomicWaker::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds a byte and wakes the reading task. Called by interrupt handlers.
+    pub fn push(&self, byte: u8) {
+        // No printing here: the interrupted code may hold the lock of the output.
+        match self.queue.try_get() {
+            Ok(queue) if queue.push(byte).is_ok() => self.waker.wake(),
+            _ => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Creates the stream of bytes pushed from now on.
+    ///
+    /// Panics if called more than once, since each byte can only be consumed once.
+    pub fn stream(&'static self) -> InputStream {
+        self.queue
+            .try_init_once(|| ArrayQueue::new(INPUT_QUEUE_SIZE))
+            .expect("input stream should only be created once");
+        InputStream { input: self }
+    }
+
+    /// The number of bytes dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for InputQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The bytes pushed to an [`InputQueue`]. Never ends.
+pub struct InputStream {
+    input: &'static InputQueue,
+}
+
+impl Stream for InputStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        let queue = self.input.queue.try_get().expect("input queue not created");
+
+        // Fast path, without registering the waker.
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        // A byte pushed between the check above and registering the waker would not wake us, so
+        // we check again after registering.
+        self.input.waker.register(context.waker());
+        match queue.pop() {
+            Some(byte) => {
+                self.input.waker.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[test_case]
+fn test_input_stream_receives_pushed_bytes() {
+    use core::task::Waker;
+    use futures_util::task::noop_waker;
+
+    static INPUT: InputQueue = InputQueue::new();
+
+    INPUT.push(1);
+    assert_eq!(INPUT.dropped(), 1);
+
+    let mut stream = INPUT.stream();
+    let waker: Waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let mut poll = || Pin::new(&mut stream).poll_next(&mut context);
+
+    assert_eq!(poll(), Poll::Pending);
+    INPUT.push(2);
+    INPUT.push(3);
+    assert_eq!(poll(), Poll::Ready(Some(2)));
+    assert_eq!(poll(), Poll::Ready(Some(3)));
+    assert_eq!(poll(), Poll::Pending);
+}
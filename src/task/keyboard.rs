@@ -0,0 +1,175 @@
+//! # keyboard
+//!
+//! The scancodes of the PS/2 keyboard as an async stream.
+//!
+//! The keyboard interrupt handler pushes each scancode with [`add_scancode`]. The
+//! [`print_keypresses`] task decodes them with the `pc-keyboard` crate and prints the keys.
+//!
+//! The typed characters are also buffered for threads, which block in [`read`] until there is
+//! input, e.g. for the `read` system call of processes. Characters typed while the buffer is full
+//! are dropped. Ctrl+D ends the input like the end of a file. Ctrl+C is not buffered, but sends
+//! `SIGINT` to the foreground process (see [`crate::process::interrupt_foreground`]).
+//!
+//! The keys are echoed to the screen, unless [`set_echo`] turned it off for a program that
+//! echoes them itself, e.g. a shell that edits the line being typed.
+
+use super::input::{InputQueue, InputStream};
+use crate::{
+    print, process,
+    sync::{self, IrqMutex, WaitList},
+};
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, Ordering};
+use futures_util::stream::StreamExt;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+
+static SCANCODES: InputQueue = InputQueue::new();
+
+/// The most bytes [`KEYS`] buffers.
+const MAX_KEYS: usize = 256;
+
+/// The typed characters, UTF-8 encoded, until they are [`read`].
+static KEYS: IrqMutex<Keys> = IrqMutex::named(
+    "KEYS",
+    Keys {
+        bytes: VecDeque::new(),
+        readers: WaitList::new(),
+    },
+);
+
+struct Keys {
+    bytes: VecDeque<u8>,
+    /// The threads blocked in [`read`].
+    readers: WaitList,
+}
+
+/// Called by the keyboard interrupt handler.
+pub(crate) fn add_scancode(scancode: u8) {
+    SCANCODES.push(scancode);
+}
+
+/// Returns the stream of scancodes. Can only be called once.
+pub fn scancodes() -> InputStream {
+    SCANCODES.stream()
+}
+
+/// Whether [`print_keypresses`] prints the typed keys.
+static ECHO: AtomicBool = AtomicBool::new(true);
+
+/// The ETX character that Ctrl+C produces.
+const CTRL_C: char = '\u{3}';
+
+/// The EOT character that Ctrl+D produces.
+const CTRL_D: char = '\u{4}';
+
+/// Blocks until characters were typed, and moves as many as fit to `buffer`. Returns the number of
+/// bytes read, which is 0 for an empty `buffer`, and for a Ctrl+D: the characters before it are
+/// read first, and the read that reaches it returns 0 like at the end of a file.
+pub fn read(buffer: &mut [u8]) -> usize {
+    read_interruptible(buffer, || false).unwrap()
+}
+
+/// Like [`read`], but gives up and returns `None` once `interrupted` returns `true` while it
+/// waits, e.g. when a signal arrives.
+pub fn read_interruptible(buffer: &mut [u8], interrupted: impl Fn() -> bool) -> Option<usize> {
+    if buffer.is_empty() {
+        return Some(0);
+    }
+    let mut keys = KEYS.lock();
+    while keys.bytes.is_empty() {
+        sync::sleep_interruptible(&KEYS, keys, |keys| &mut keys.readers, None, &interrupted);
+        if interrupted() {
+            return None;
+        }
+        keys = KEYS.lock();
+    }
+    let end = CTRL_D as u8;
+    if keys.bytes.front() == Some(&end) {
+        keys.bytes.pop_front();
+        return Some(0);
+    }
+    let available = keys.bytes.iter().position(|&byte| byte == end);
+    let len = buffer.len().min(available.unwrap_or(keys.bytes.len()));
+    for (byte, key) in buffer.iter_mut().zip(keys.bytes.drain(..len)) {
+        *byte = key;
+    }
+    Some(len)
+}
+
+/// Turns the echo of typed keys on or off. Returns whether it was on.
+pub fn set_echo(on: bool) -> bool {
+    ECHO.swap(on, Ordering::Relaxed)
+}
+
+/// Buffers a typed character for [`read`].
+fn push_key(character: char) {
+    let mut utf8 = [0; 4];
+    let bytes = character.encode_utf8(&mut utf8).as_bytes();
+    let mut keys = KEYS.lock();
+    if keys.bytes.len() + bytes.len() > MAX_KEYS {
+        return;
+    }
+    keys.bytes.extend(bytes);
+    keys.readers.wake_all();
+}
+
+/// Prints the pressed keys to the VGA text buffer, and buffers the typed characters for [`read`].
+/// Control keys with a letter are decoded to control characters, of which Ctrl+C interrupts the
+/// foreground process instead.
+pub async fn print_keypresses() {
+    let mut scancodes = scancodes();
+    let mut keyboard = Keyboard::new(
+        ScancodeSet1::new(),
+        layouts::Us104Key,
+        HandleControl::MapLettersToUnicode,
+    );
+
+    while let Some(scancode) = scancodes.next().await {
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            match keyboard.process_keyevent(key_event) {
+                Some(DecodedKey::Unicode(CTRL_C)) => {
+                    print!("^C");
+                    process::interrupt_foreground();
+                }
+                Some(DecodedKey::Unicode(CTRL_D)) => push_key(CTRL_D),
+                Some(DecodedKey::Unicode(character)) => {
+                    if ECHO.load(Ordering::Relaxed) {
+                        print!("{}", character);
+                    }
+                    push_key(character);
+                }
+                Some(DecodedKey::RawKey(key)) if ECHO.load(Ordering::Relaxed) => {
+                    print!("{:?}", key)
+                }
+                Some(DecodedKey::RawKey(_)) => {}
+                None => {}
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_read_returns_buffered_keys() {
+    push_key('h');
+    push_key('\u{e9}');
+    let mut buffer = [0; 2];
+    assert_eq!(read(&mut buffer), 2);
+    assert_eq!(&buffer, b"h\xc3");
+    assert_eq!(read(&mut buffer), 1);
+    assert_eq!(buffer[0], 0xa9);
+    assert_eq!(read(&mut []), 0);
+    assert_eq!(read_interruptible(&mut buffer, || true), None);
+}
+
+#[test_case]
+fn test_ctrl_d_ends_the_input() {
+    push_key('a');
+    push_key(CTRL_D);
+    push_key('b');
+    let mut buffer = [0; 4];
+    assert_eq!(read(&mut buffer), 1);
+    assert_eq!(buffer[0], b'a');
+    assert_eq!(read(&mut buffer), 0);
+    assert_eq!(read(&mut buffer), 1);
+    assert_eq!(buffer[0], b'b');
+}
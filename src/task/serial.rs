@@ -0,0 +1,106 @@
+//! # serial
+//!
+//! Input from the first serial port as an async stream of lines.
+//!
+//! The serial interrupt handler pushes each received byte with [`add_byte`]. A [`LineReader`]
+//! collects them into lines, echoing the typed characters back, and the [`print_lines`] task
+//! prints each line. The line `top` prints the thread list instead, see [`thread::print_top`],
+//! `cpus` the per-CPU counters, see [`percpu::print_stats`], `timers` the pending timers, see
+//! [`timer::print_stats`], and `ticks` the ticks and idle time, see [`tick::print_stats`].
+//! `ps` prints the process table, see [`process::print_processes`], `maps <pid>` the memory areas
+//! of a process, see [`process::print_maps`], and `fds <pid>` its open files, see
+//! [`process::print_files`]. `tickless on` and `tickless off` switch tickless idle on and off, and
+//! `strace on` and `strace off` the tracing of system calls, see [`syscall::set_tracing`].
+
+use super::input::{InputQueue, InputStream};
+use crate::{
+    percpu, println, process,
+    serial::SERIAL1,
+    serial_print, syscall, thread,
+    time::{tick, timer},
+};
+use alloc::string::String;
+use futures_util::stream::StreamExt;
+
+static BYTES: InputQueue = InputQueue::new();
+
+/// Called by the serial interrupt handler.
+pub(crate) fn add_byte(byte: u8) {
+    BYTES.push(byte);
+}
+
+/// Reads lines from the serial port. Only one can exist.
+pub struct LineReader {
+    bytes: InputStream,
+    line: String,
+}
+
+impl LineReader {
+    pub fn new() -> Self {
+        // Initializing the port enables its receive interrupt.
+        lazy_static::initialize(&SERIAL1);
+        LineReader {
+            bytes: BYTES.stream(),
+            line: String::new(),
+        }
+    }
+
+    /// Returns the next line, without the line ending.
+    pub async fn next_line(&mut self) -> String {
+        while let Some(byte) = self.bytes.next().await {
+            match byte {
+                b'\r' | b'\n' => {
+                    serial_print!("\n");
+                    return core::mem::take(&mut self.line);
+                }
+                // Backspace and delete.
+                0x08 | 0x7f => {
+                    if self.line.pop().is_some() {
+                        serial_print!("\x08 \x08");
+                    }
+                }
+                // Multi-byte UTF-8 sequences and control characters are ignored.
+                byte if byte.is_ascii() && !byte.is_ascii_control() => {
+                    self.line.push(char::from(byte));
+                    serial_print!("{}", char::from(byte));
+                }
+                _ => {}
+            }
+        }
+        unreachable!("serial input never ends")
+    }
+}
+
+impl Default for LineReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Prints each line received over serial to the VGA text buffer.
+pub async fn print_lines() {
+    let mut reader = LineReader::new();
+    loop {
+        let line = reader.next_line().await;
+        match line.as_str() {
+            "top" => thread::print_top(),
+            "cpus" => percpu::print_stats(),
+            "timers" => timer::print_stats(),
+            "ticks" => tick::print_stats(),
+            "ps" => process::print_processes(),
+            "tickless on" => tick::set_tickless(true),
+            "tickless off" => tick::set_tickless(false),
+            "strace on" => syscall::set_tracing(true),
+            "strace off" => syscall::set_tracing(false),
+            line if line.starts_with("maps ") => match line[5..].trim().parse() {
+                Ok(pid) => process::print_maps(process::Pid::from_u64(pid)),
+                Err(_) => println!("serial: maps <pid>"),
+            },
+            line if line.starts_with("fds ") => match line[4..].trim().parse() {
+                Ok(pid) => process::print_files(process::Pid::from_u64(pid)),
+                Err(_) => println!("serial: fds <pid>"),
+            },
+            _ => println!("serial: {}", line),
+        }
+    }
+}
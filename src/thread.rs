@@ -0,0 +1,863 @@
+//! # thread
+//!
+//! Preemptive kernel threads.
+//!
+//! Every thread has its own kernel stack (with a guard page, see [`crate::memory::stack`]) and a
+//! saved context (see [`switch`]). On every timer tick, the [`scheduler`] policy decides whether
+//! the running thread is preempted, and which ready thread runs next. A thread can also give up
+//! the CPU voluntarily with [`yield_now`], or block until another thread (or a timeout) wakes it
+//! up again with [`block`] and [`unblock`], or [`sleep`] for a while; the sleeping primitives in
+//! [`crate::sync`] are built on these.
+//!
+//! The code that runs when [`init`] is called becomes the `main` thread, on the boot stack. An
+//! `idle` thread runs whenever no other thread is ready; it halts the CPU and frees the stacks of
+//! exited threads.
+//!
+//! A thread can run user code (see [`crate::process`]). Then its address space (see
+//! [`set_page_table`]) and the kernel stack the CPU switches to when an interrupt arrives in user
+//! mode (see [`crate::gdt::set_kernel_stack`]) are switched along with it.
+//!
+//! The CPU time of each thread is measured with the time stamp counter and can be compared with
+//! [`print_top`].
+//!
+//! ## Locking
+//!
+//! The thread state is an [`IrqMutex`], since the timer interrupt handler accesses it too.
+//! Allocating with interrupts disabled is fine, because the heap disables interrupts while it is
+//! locked (see [`crate::allocator`]).
+//!
+//! The kernel's spinlocks either disable interrupts like [`IrqMutex`] or preemption like
+//! [`crate::sync::SpinMutex`], so a thread is never preempted while it holds one. A plain
+//! `spin::Mutex` can still be held while its holder is preempted; a thread waiting for it keeps
+//! spinning until it is preempted itself and the holder can run again.
+
+pub mod scheduler;
+mod switch;
+
+use crate::gdt;
+use crate::memory::{
+    self, address_space,
+    stack::{self, KernelStack, StackError},
+};
+use crate::percpu;
+use crate::serial_println;
+use crate::sync::{IrqMutex, WaitQueue};
+use crate::time::{self, timer, Instant};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use scheduler::{Entity, Policy, Priority, Scheduler};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+/// The number of pages of the stack of each thread.
+const THREAD_STACK_PAGES: u64 = 8;
+
+/// All threads. It is `None` until [`init`] was called.
+static THREADS: IrqMutex<Option<Threads>> = IrqMutex::named("THREADS", None);
+
+/// A unique thread ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// Waiting in the run queue.
+    Ready,
+    Running,
+    /// Waiting to be woken up by [`unblock`] or a timeout.
+    Blocked,
+    /// Finished; the stack is freed by the idle thread.
+    Exited,
+}
+
+impl ThreadState {
+    pub fn name(self) -> &'static str {
+        match self {
+            ThreadState::Ready => "ready",
+            ThreadState::Running => "running",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Exited => "exited",
+        }
+    }
+}
+
+/// The CPU usage of a thread.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuStats {
+    /// The time the thread ran, in TSC cycles.
+    pub cycles: u64,
+    /// How often the thread was switched to.
+    pub switches: u64,
+    /// The value of `cycles` at the previous [`print_top`].
+    cycles_at_top: u64,
+}
+
+struct Thread {
+    name: String,
+    state: ThreadState,
+    sched: Entity,
+    stats: CpuStats,
+    /// The saved stack pointer, while the thread is not running.
+    rsp: u64,
+    /// `None` for the `main` thread, which runs on the boot stack.
+    stack: Option<KernelStack>,
+    /// The level 4 page table that is active while the thread runs.
+    page_table: PhysFrame,
+    /// The kernel stack for interrupts in user mode (`RSP0` of the TSS), while the thread is not
+    /// running.
+    kernel_stack: VirtAddr,
+    /// Set when the thread exits, shared with its [`JoinHandle`].
+    finished: Arc<Finished>,
+    /// Set by [`unblock`] if the thread wasn't blocked yet, so its next [`block`] returns
+    /// immediately instead of missing the wakeup.
+    wakeup_pending: bool,
+}
+
+impl Thread {
+    fn new(name: String, priority: Priority, stack: Option<KernelStack>) -> Box<Thread> {
+        Box::new(Thread {
+            name,
+            state: ThreadState::Ready,
+            sched: Entity::new(ThreadId::new(), priority),
+            stats: CpuStats::default(),
+            rsp: 0,
+            stack,
+            page_table: memory::kernel_page_table(),
+            kernel_stack: stack.map_or(VirtAddr::zero(), |stack| stack.top),
+            finished: Arc::new(Finished::default()),
+            wakeup_pending: false,
+        })
+    }
+
+    fn id(&self) -> ThreadId {
+        self.sched.id
+    }
+}
+
+/// Why the running thread gives up the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reason {
+    Tick,
+    Yield,
+    /// Blocks until [`unblock`].
+    Block,
+    Exit,
+}
+
+struct Threads {
+    /// All threads that were not freed yet. Boxed, so that the saved stack pointers don't move.
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    policy: Policy,
+    scheduler: Box<dyn Scheduler>,
+    /// The exited threads, whose stacks were not freed yet.
+    exited: Vec<ThreadId>,
+    current: ThreadId,
+    idle: ThreadId,
+    /// The number of timer ticks.
+    ticks: u64,
+    /// The time stamp counter when the running thread was last charged.
+    last_charged: u64,
+    /// The time stamp counter at the previous [`print_top`].
+    last_top: u64,
+}
+
+impl Threads {
+    fn thread(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("no such thread")
+    }
+
+    /// Adds a new thread to the ready queue.
+    fn add(&mut self, mut thread: Box<Thread>) {
+        self.scheduler.enqueue(&mut thread.sched);
+        self.threads.insert(thread.id(), thread);
+    }
+
+    /// Charges the running thread for the cycles since it was last charged.
+    fn charge(&mut self) {
+        let now = unsafe { _rdtsc() };
+        let cycles = now - self.last_charged;
+        self.last_charged = now;
+
+        let thread = self.threads.get_mut(&self.current).expect("no such thread");
+        thread.stats.cycles += cycles;
+        if self.current != self.idle {
+            self.scheduler.charge(&mut thread.sched, cycles);
+        }
+    }
+
+    /// Makes a blocked thread ready again. Returns `false` if it wasn't blocked.
+    fn wake(&mut self, id: ThreadId) -> bool {
+        let thread = match self.threads.get_mut(&id) {
+            Some(thread) if thread.state == ThreadState::Blocked => thread,
+            _ => return false,
+        };
+        thread.state = ThreadState::Ready;
+        self.scheduler.enqueue(&mut thread.sched);
+        true
+    }
+
+    /// Picks the next thread and updates the states. Returns the location to save the current
+    /// stack pointer to and the stack pointer to switch to, or `None` to continue the current
+    /// thread.
+    fn switch(&mut self, reason: Reason) -> Option<(*mut u64, u64)> {
+        let current = self.current;
+        self.charge();
+
+        if reason == Reason::Tick {
+            self.ticks += 1;
+            let preempt = if current == self.idle {
+                self.scheduler.ready_count() > 0
+            } else {
+                let sched = self.thread(current).sched;
+                self.scheduler.tick(&sched)
+            };
+            if !preempt {
+                return None;
+            }
+        }
+
+        let thread = self.threads.get_mut(&current).expect("no such thread");
+        if reason == Reason::Block {
+            assert!(current != self.idle, "the idle thread must not block");
+            if thread.wakeup_pending {
+                thread.wakeup_pending = false;
+                return None;
+            }
+            thread.state = ThreadState::Blocked;
+        } else if reason == Reason::Exit {
+            thread.state = ThreadState::Exited;
+            self.exited.push(current);
+        } else if current != self.idle {
+            thread.state = ThreadState::Ready;
+            self.scheduler.enqueue(&mut thread.sched);
+        }
+
+        let next = self.scheduler.pick_next().unwrap_or(self.idle);
+        if next == current {
+            self.thread(current).state = ThreadState::Running;
+            return None;
+        }
+
+        self.thread(current).kernel_stack = gdt::kernel_stack();
+        self.current = next;
+        let next = self.thread(next);
+        next.state = ThreadState::Running;
+        next.stats.switches += 1;
+        unsafe { address_space::activate(next.page_table) };
+        gdt::set_kernel_stack(next.kernel_stack);
+        let new_rsp = next.rsp;
+        let old_rsp = &mut self.thread(current).rsp as *mut u64;
+        Some((old_rsp, new_rsp))
+    }
+}
+
+/// Turns the running code into the `main` thread and creates the `idle` thread, scheduled with
+/// `policy`. Preemption starts with the next timer interrupt.
+pub fn init(policy: Policy) {
+    let mut main = Thread::new(String::from("main"), Priority::NORMAL, None);
+    main.state = ThreadState::Running;
+    let idle = new_thread(String::from("idle"), Priority::LOWEST, Box::new(idle))
+        .expect("failed to create the idle thread");
+
+    let now = unsafe { _rdtsc() };
+    let threads = Threads {
+        current: main.id(),
+        idle: idle.id(),
+        threads: BTreeMap::new(),
+        policy,
+        scheduler: policy.scheduler(),
+        exited: Vec::new(),
+        ticks: 0,
+        last_charged: now,
+        last_top: now,
+    };
+    interrupts::without_interrupts(|| {
+        let mut guard = THREADS.lock();
+        let threads = guard.insert(threads);
+        threads.threads.insert(main.id(), main);
+        threads.threads.insert(idle.id(), idle);
+    });
+}
+
+/// Creates a thread that runs `main` on a new stack.
+fn new_thread(
+    name: String,
+    priority: Priority,
+    main: Box<dyn FnOnce() + Send>,
+) -> Result<Box<Thread>, StackError> {
+    let stack = stack::allocate("thread", THREAD_STACK_PAGES)?;
+    let mut thread = Thread::new(name, priority, Some(stack));
+    // A `Box<dyn FnOnce()>` is a fat pointer, so it is boxed again to pass it in a register.
+    let arg = Box::into_raw(Box::new(main));
+    thread.rsp = unsafe { switch::init_stack(stack.top.as_u64(), arg as u64) };
+    Ok(thread)
+}
+
+/// Called by `thread_trampoline` with the argument prepared by [`new_thread`].
+extern "C" fn thread_entry(main: *mut Box<dyn FnOnce() + Send>) -> ! {
+    let main = unsafe { Box::from_raw(main) };
+    // It may have been switched to from an interrupt handler of another thread.
+    percpu::set_interrupt_depth(0);
+    interrupts::enable();
+    main();
+    exit();
+}
+
+/// Switches to the next thread if the scheduler decides so. Must be called with interrupts
+/// disabled.
+fn schedule(reason: Reason) {
+    let switch = match THREADS.try_lock() {
+        Some(mut threads) => match threads.as_mut() {
+            Some(threads) => threads.switch(reason),
+            None => None,
+        },
+        // Only possible if we interrupted ourselves, i.e. a bug.
+        None => panic!("thread lock held during a switch"),
+    };
+
+    if let Some((old_rsp, new_rsp)) = switch {
+        percpu::CONTEXT_SWITCHES.with(|count| count.fetch_add(1, Ordering::Relaxed));
+        // The depth belongs to the thread, which may be switched out in the timer interrupt handler.
+        let interrupt_depth = percpu::interrupt_depth();
+        unsafe { switch::switch_context(old_rsp, new_rsp) };
+        percpu::set_interrupt_depth(interrupt_depth);
+    }
+}
+
+/// Called by the timer interrupt handler, after the end of interrupt was signaled. While
+/// preemption is disabled (see [`crate::percpu::PreemptGuard`]), the switch is only remembered.
+pub(crate) fn preempt() {
+    if !percpu::preemptible() {
+        percpu::set_need_resched();
+        return;
+    }
+    schedule(Reason::Tick);
+}
+
+/// Gives up the CPU to the next ready thread. Returns immediately if the scheduler picks the
+/// current thread again.
+pub fn yield_now() {
+    interrupts::without_interrupts(|| schedule(Reason::Yield));
+}
+
+/// Blocks the current thread until [`unblock`] is called for it or the monotonic clock reaches
+/// `deadline`.
+///
+/// May also return early, e.g. for a wakeup that arrived before the thread blocked, so callers
+/// must check what they are waiting for in a loop. Panics before [`init`].
+pub fn block(deadline: Option<Instant>) {
+    if deadline.is_some_and(|deadline| deadline <= time::now()) {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let current = THREADS
+            .lock()
+            .as_ref()
+            .map(|threads| threads.current)
+            .expect("can't block before threads are initialized");
+        // With interrupts disabled, the timer can't expire before the thread blocked, and it is
+        // cancelled before it could wake the thread again later.
+        let timer = deadline.map(|deadline| timer::unblock_at(deadline, current));
+        schedule(Reason::Block);
+        if let Some(timer) = timer {
+            timer.cancel();
+        }
+    });
+}
+
+/// Blocks the current thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    sleep_until(time::now() + duration);
+}
+
+/// Blocks the current thread until the monotonic clock reaches `deadline`.
+pub fn sleep_until(deadline: Instant) {
+    while time::now() < deadline {
+        block(Some(deadline));
+    }
+}
+
+/// Wakes up the thread `id` if it is blocked. Otherwise, its next [`block`] returns immediately.
+pub fn unblock(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut guard = THREADS.lock();
+        let threads = guard.as_mut().expect("threads not initialized");
+        if !threads.wake(id) {
+            if let Some(thread) = threads.threads.get_mut(&id) {
+                thread.wakeup_pending = true;
+            }
+        }
+    });
+}
+
+/// Switches the current thread to the address space with the level 4 table `page_table`, which
+/// stays active whenever the thread runs.
+///
+/// # Safety
+///
+/// `page_table` must be the kernel's (see [`memory::kernel_page_table`]), or the one of an
+/// [`AddressSpace`](address_space::AddressSpace) that lives until the thread switched to another
+/// one. The code and the stack of the thread must be mapped in it.
+pub unsafe fn set_page_table(page_table: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        let mut guard = THREADS.lock();
+        let threads = guard.as_mut().expect("threads not initialized");
+        let current = threads.current;
+        threads.thread(current).page_table = page_table;
+        address_space::activate(page_table);
+    });
+}
+
+/// Ends the current thread.
+pub fn exit() -> ! {
+    let finished = {
+        let mut guard = THREADS.lock();
+        let threads = guard.as_mut().expect("threads not initialized");
+        let current = threads.current;
+        threads.thread(current).finished.clone()
+    };
+    // Not with the thread state locked, since waking the joining threads locks it.
+    finished.set();
+    drop(finished);
+    interrupts::disable();
+    schedule(Reason::Exit);
+    unreachable!("exited thread was scheduled again");
+}
+
+/// The body of the `idle` thread.
+fn idle() {
+    loop {
+        free_exited_threads();
+        // Like the executor: check with interrupts disabled, then enable them and halt atomically.
+        interrupts::disable();
+        let ready = THREADS
+            .lock()
+            .as_ref()
+            .is_some_and(|t| t.scheduler.ready_count() > 0);
+        if ready {
+            schedule(Reason::Yield);
+            interrupts::enable();
+        } else {
+            // Nothing runs until the next interrupt, so the tick can stop.
+            time::tick::idle(true);
+        }
+    }
+}
+
+/// Frees the stacks of exited threads. Must be called with interrupts enabled, since it takes
+/// the paging locks, which interrupt handlers must not take (see [`crate::sync::SpinMutex`]).
+fn free_exited_threads() {
+    loop {
+        let thread = interrupts::without_interrupts(|| {
+            let mut guard = THREADS.lock();
+            let threads = guard.as_mut()?;
+            let current = threads.current;
+            let index = threads.exited.iter().position(|&id| id != current)?;
+            let id = threads.exited.swap_remove(index);
+            threads.threads.remove(&id)
+        });
+        match thread {
+            Some(thread) => {
+                if let Some(stack) = thread.stack {
+                    unsafe { stack::free(stack) }.expect("failed to free a thread stack");
+                }
+            }
+            None => break,
+        }
+    }
+}
+
+/// Whether a thread finished, and the threads that wait for it.
+#[derive(Default)]
+struct Finished {
+    done: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl Finished {
+    fn is_set(&self) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+
+    fn set(&self) {
+        self.done.store(true, Ordering::Release);
+        self.waiters.notify_all();
+    }
+}
+
+/// The owned permission to wait for a thread to finish and get its result.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    finished: Arc<Finished>,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.is_set()
+    }
+
+    /// Waits for the thread to finish. Returns `None` if it ended with [`exit`] instead of
+    /// returning. Blocks until the thread calls [`exit`], which it also does after returning.
+    pub fn join(self) -> Option<T> {
+        self.finished.waiters.wait_until(|| self.finished.is_set());
+        self.result.lock().take()
+    }
+}
+
+/// Configures a new thread.
+pub struct Builder {
+    name: String,
+    priority: Priority,
+}
+
+impl Builder {
+    pub fn new(name: &str) -> Self {
+        Builder {
+            name: String::from(name),
+            priority: Priority::NORMAL,
+        }
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Spawns the thread, which runs `f`.
+    pub fn spawn<F, T>(self, f: F) -> Result<JoinHandle<T>, StackError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        free_exited_threads();
+
+        let result = Arc::new(Mutex::new(None));
+        let thread_result = result.clone();
+        let main = Box::new(move || {
+            let value = f();
+            *thread_result.lock() = Some(value);
+        });
+        let thread = new_thread(self.name, self.priority, main)?;
+
+        let handle = JoinHandle {
+            id: thread.id(),
+            finished: thread.finished.clone(),
+            result,
+        };
+        interrupts::without_interrupts(|| {
+            THREADS
+                .lock()
+                .as_mut()
+                .expect("threads not initialized")
+                .add(thread);
+        });
+        Ok(handle)
+    }
+}
+
+/// Spawns a new thread named `name` with normal priority that runs `f`.
+pub fn spawn<F, T>(name: &str, f: F) -> Result<JoinHandle<T>, StackError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new(name).spawn(f)
+}
+
+/// Returns the ID of the running thread, or `None` before [`init`].
+pub fn current_id() -> Option<ThreadId> {
+    interrupts::without_interrupts(|| THREADS.lock().as_ref().map(|t| t.current))
+}
+
+/// Returns the scheduling policy, or `None` before [`init`].
+pub fn policy() -> Option<Policy> {
+    interrupts::without_interrupts(|| THREADS.lock().as_ref().map(|t| t.policy))
+}
+
+/// Returns the CPU usage of a thread, or `None` if it doesn't exist (anymore).
+pub fn cpu_stats(id: ThreadId) -> Option<CpuStats> {
+    interrupts::without_interrupts(|| {
+        let mut guard = THREADS.lock();
+        let threads = guard.as_mut()?;
+        threads.charge();
+        threads.threads.get(&id).map(|thread| thread.stats)
+    })
+}
+
+/// A line of the [`print_top`] output.
+struct TopLine {
+    id: ThreadId,
+    name: String,
+    state: ThreadState,
+    nice: i8,
+    stats: CpuStats,
+}
+
+/// Prints the threads and their CPU usage over serial, like `top`. The CPU percentages are
+/// relative to the previous call.
+pub fn print_top() {
+    // Collect everything first, so the serial port isn't locked with interrupts disabled.
+    let snapshot = interrupts::without_interrupts(|| {
+        let mut guard = THREADS.lock();
+        let threads = guard.as_mut()?;
+        threads.charge();
+        let now = threads.last_charged;
+        let elapsed = now - threads.last_top;
+        threads.last_top = now;
+
+        let lines: Vec<TopLine> = threads
+            .threads
+            .iter_mut()
+            .map(|(&id, thread)| {
+                let line = TopLine {
+                    id,
+                    name: thread.name.clone(),
+                    state: thread.state,
+                    nice: thread.sched.priority.nice(),
+                    stats: thread.stats,
+                };
+                thread.stats.cycles_at_top = thread.stats.cycles;
+                line
+            })
+            .collect();
+        Some((threads.scheduler.name(), threads.ticks, elapsed, lines))
+    });
+    let (policy, ticks, elapsed, lines) = match snapshot {
+        Some(snapshot) => snapshot,
+        None => return,
+    };
+
+    serial_println!(
+        "scheduler: {}, {} threads, {} ticks",
+        policy,
+        lines.len(),
+        ticks
+    );
+    serial_println!(
+        "{:>4} {:<16} {:<8} {:>4} {:>6} {:>12} {:>8}",
+        "ID",
+        "NAME",
+        "STATE",
+        "NICE",
+        "CPU%",
+        "MCYCLES",
+        "SWITCHES"
+    );
+    for line in lines {
+        let recent = line.stats.cycles - line.stats.cycles_at_top;
+        let permille = (u128::from(recent) * 1000 / u128::from(elapsed.max(1))) as u64;
+        serial_println!(
+            "{:>4} {:<16} {:<8} {:>4} {:>4}.{} {:>12} {:>8}",
+            line.id,
+            line.name,
+            line.state.name(),
+            line.nice,
+            permille / 10,
+            permille % 10,
+            line.stats.cycles / 1_000_000,
+            line.stats.switches
+        );
+    }
+}
+
+/// Displays the name of the running thread. Used in panic messages, so it never blocks.
+pub struct CurrentName;
+
+impl fmt::Display for CurrentName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let threads = THREADS.try_lock();
+        match threads.as_ref().and_then(|t| t.as_ref()) {
+            Some(t) => match t.threads.get(&t.current) {
+                Some(thread) => f.write_str(&thread.name),
+                None => f.write_str("<unknown>"),
+            },
+            None => f.write_str("<unknown>"),
+        }
+    }
+}
+
+#[test_case]
+fn test_join_returns_result() {
+    let handle = spawn("answer", || 6 * 7).unwrap();
+    assert_eq!(handle.join(), Some(42));
+}
+
+#[test_case]
+fn test_exit_ends_thread() {
+    let handle = spawn("exit", || {
+        exit();
+    })
+    .unwrap();
+    let result: Option<()> = handle.join();
+    assert_eq!(result, None);
+}
+
+#[test_case]
+fn test_join_blocks_until_exit() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    let worker = spawn("worker", || {
+        while !STOP.load(Ordering::SeqCst) {
+            yield_now();
+        }
+    })
+    .unwrap();
+    let finished = worker.finished.clone();
+    let joiner = spawn("joiner", move || worker.join()).unwrap();
+    // The joining thread sleeps on the queue instead of polling.
+    while finished.waiters.is_empty() {
+        yield_now();
+    }
+    assert!(!finished.is_set());
+    STOP.store(true, Ordering::SeqCst);
+    assert_eq!(joiner.join(), Some(Some(())));
+    assert!(finished.waiters.is_empty());
+}
+
+#[test_case]
+fn test_yield_interleaves_threads() {
+    use alloc::vec;
+
+    let log = Arc::new(Mutex::new(vec![]));
+    let handles: Vec<_> = (0..2)
+        .map(|i| {
+            let log = log.clone();
+            spawn("yield", move || {
+                for _ in 0..3 {
+                    log.lock().push(i);
+                    yield_now();
+                }
+            })
+            .unwrap()
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(log.lock().len(), 6);
+    // The other policies may pick the yielding thread again.
+    if policy() == Some(Policy::RoundRobin) {
+        assert_ne!(*log.lock(), [0, 0, 0, 1, 1, 1]);
+    }
+}
+
+#[test_case]
+fn test_timer_preempts_busy_thread() {
+    static STARTED: AtomicBool = AtomicBool::new(false);
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    let handle = spawn("busy", || {
+        STARTED.store(true, Ordering::SeqCst);
+        // Never yields, so `main` only runs again if the timer preempts this thread.
+        while !STOP.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    })
+    .unwrap();
+
+    // Never yields either, so the busy thread only starts if the timer preempts `main`.
+    while !STARTED.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    STOP.store(true, Ordering::SeqCst);
+    handle.join();
+}
+
+#[test_case]
+fn test_unblock_wakes_blocked_thread() {
+    static BLOCKED: AtomicBool = AtomicBool::new(false);
+
+    let handle = spawn("blocked", || {
+        BLOCKED.store(true, Ordering::SeqCst);
+        block(None);
+    })
+    .unwrap();
+    while !BLOCKED.load(Ordering::SeqCst) {
+        yield_now();
+    }
+    // Before or after it actually blocked, the wakeup must not get lost.
+    unblock(handle.id());
+    handle.join();
+}
+
+#[test_case]
+fn test_block_times_out() {
+    let deadline = time::now() + Duration::from_millis(3);
+    block(Some(deadline));
+    assert!(time::now() >= deadline);
+}
+
+#[test_case]
+fn test_sleep() {
+    let start = time::now();
+    sleep(Duration::from_millis(5));
+    assert!(start.elapsed() >= Duration::from_millis(5));
+}
+
+#[test_case]
+fn test_thread_names() {
+    use core::fmt::Write;
+
+    struct Name([u8; 16], usize);
+    impl Write for Name {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0[self.1..self.1 + s.len()].copy_from_slice(s.as_bytes());
+            self.1 += s.len();
+            Ok(())
+        }
+    }
+
+    let handle = spawn("worker", || {
+        let mut name = Name([0; 16], 0);
+        write!(name, "{}", CurrentName).unwrap();
+        name.0[..name.1] == *b"worker"
+    })
+    .unwrap();
+    assert_eq!(handle.join(), Some(true));
+}
+
+#[test_case]
+fn test_cpu_accounting() {
+    let handle = Builder::new("accounted")
+        .priority(Priority::new(5))
+        .spawn(|| {
+            let id = current_id().unwrap();
+            let before = cpu_stats(id).unwrap();
+            for _ in 0..10_000 {
+                core::hint::spin_loop();
+            }
+            let after = cpu_stats(id).unwrap();
+            (before, after)
+        })
+        .unwrap();
+    let (before, after) = handle.join().unwrap();
+    assert!(before.switches >= 1);
+    assert!(after.cycles > before.cycles);
+}
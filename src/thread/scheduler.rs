@@ -0,0 +1,511 @@
+//! # scheduler
+//!
+//! The scheduling policies: which ready thread runs next, and when the running thread is
+//! preempted.
+//!
+//! The [thread](super) module owns the threads and switches between them. A [`Scheduler`] only
+//! keeps the queue of ready threads and sees each thread as an [`Entity`]. The running thread is
+//! not in the queue; it is passed to [`Scheduler::charge`] and [`Scheduler::tick`] instead. The
+//! idle thread is never queued.
+//!
+//! There are three policies, selected at boot with a [`Policy`]:
+//!
+//! - [`RoundRobin`] runs the ready threads in turn, for a time slice each, and ignores priorities.
+//! - [`FixedPriority`] always runs the ready thread with the highest priority. Waiting threads age:
+//!   their priority rises the longer they wait, so low priority threads don't starve.
+//! - [`Fair`] gives each thread a share of the CPU proportional to its weight, like Linux's CFS.
+//!   Every thread has a virtual runtime, its CPU time divided by its weight, and the thread with
+//!   the lowest virtual runtime runs next.
+//!
+//! All methods are called with interrupts disabled and the thread lock held.
+
+use super::ThreadId;
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+
+/// The number of timer ticks a thread may run before another thread of the same priority gets
+/// the CPU.
+pub const TIME_SLICE_TICKS: u64 = 10;
+
+/// The number of ticks after which the priority of a waiting thread rises by one level.
+pub const AGING_TICKS: u64 = 10;
+
+/// A scheduling policy.
+pub trait Scheduler: Send {
+    /// The name shown in the `top` dump.
+    fn name(&self) -> &'static str;
+
+    /// Adds a thread that became ready: a new thread, or one that gave up the CPU.
+    fn enqueue(&mut self, thread: &mut Entity);
+
+    /// Removes the thread that runs next from the ready queue.
+    fn pick_next(&mut self) -> Option<ThreadId>;
+
+    /// The number of ready threads.
+    fn ready_count(&self) -> usize;
+
+    /// Charges the running thread for `cycles` of CPU time. Called on every tick and switch.
+    fn charge(&mut self, current: &mut Entity, cycles: u64) {
+        let _ = (current, cycles);
+    }
+
+    /// Called on every timer tick while `current` runs. Returns whether it should be preempted.
+    fn tick(&mut self, current: &Entity) -> bool;
+}
+
+/// The per-thread state the schedulers work with.
+#[derive(Debug, Clone, Copy)]
+pub struct Entity {
+    pub id: ThreadId,
+    pub priority: Priority,
+    /// The CPU time in cycles, scaled by the weight of the priority. Only used by [`Fair`].
+    pub vruntime: u64,
+}
+
+impl Entity {
+    pub fn new(id: ThreadId, priority: Priority) -> Self {
+        Entity {
+            id,
+            priority,
+            vruntime: 0,
+        }
+    }
+}
+
+/// The priority of a thread, like a Unix nice value: from -20 (highest) to 19 (lowest).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Priority(i8);
+
+impl Priority {
+    pub const HIGHEST: Priority = Priority(-20);
+    pub const NORMAL: Priority = Priority(0);
+    pub const LOWEST: Priority = Priority(19);
+
+    /// Creates a priority from a nice value, clamped to the valid range.
+    pub fn new(nice: i8) -> Self {
+        Priority(nice.clamp(Self::HIGHEST.0, Self::LOWEST.0))
+    }
+
+    pub fn nice(self) -> i8 {
+        self.0
+    }
+
+    /// The priority as a level from 0 (lowest) to [`MAX_LEVEL`] (highest).
+    fn level(self) -> u64 {
+        (Self::LOWEST.0 - self.0) as u64
+    }
+
+    /// The weight for [`Fair`]. Each nice step changes the CPU share by about 10%, like in Linux,
+    /// from which the table is taken.
+    fn weight(self) -> u64 {
+        const WEIGHTS: [u64; 40] = [
+            88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100,
+            4904, 3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172,
+            137, 110, 87, 70, 56, 45, 36, 29, 23, 18, 15,
+        ];
+        WEIGHTS[(self.0 - Self::HIGHEST.0) as usize]
+    }
+}
+
+/// The level of [`Priority::HIGHEST`].
+const MAX_LEVEL: u64 = 39;
+
+/// The weight of [`Priority::NORMAL`]. A normal thread's virtual runtime is its CPU time.
+const NORMAL_WEIGHT: u64 = 1024;
+
+/// The scheduling policy, selected at boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    RoundRobin,
+    FixedPriority,
+    Fair,
+}
+
+impl Policy {
+    pub const ALL: [Policy; 3] = [Policy::RoundRobin, Policy::FixedPriority, Policy::Fair];
+
+    /// The name used to select the policy.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Policy::RoundRobin => "round-robin",
+            Policy::FixedPriority => "priority",
+            Policy::Fair => "fair",
+        }
+    }
+
+    /// A `const fn`, so that [`Policy::BOOT`] is checked at build time.
+    pub const fn from_name(name: &str) -> Option<Policy> {
+        let mut i = 0;
+        while i < Self::ALL.len() {
+            if str_eq(Self::ALL[i].name(), name) {
+                return Some(Self::ALL[i]);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// The policy named by the `OS_SCHEDULER` environment variable when the kernel is built, e.g.
+    /// `OS_SCHEDULER=fair cargo run`. Round-robin by default. An unknown name fails the build.
+    pub const BOOT: Policy = match option_env!("OS_SCHEDULER") {
+        Some(name) => match Self::from_name(name) {
+            Some(policy) => policy,
+            None => panic!("unknown OS_SCHEDULER policy"),
+        },
+        None => Policy::RoundRobin,
+    };
+
+    /// The policy the kernel boots with. It is selected at build time, see [`Policy::BOOT`].
+    pub const fn boot() -> Policy {
+        Self::BOOT
+    }
+
+    pub fn scheduler(self) -> Box<dyn Scheduler> {
+        match self {
+            Policy::RoundRobin => Box::new(RoundRobin::new()),
+            Policy::FixedPriority => Box::new(FixedPriority::new()),
+            Policy::Fair => Box::new(Fair::new()),
+        }
+    }
+}
+
+/// `str` equality, which isn't usable in a `const fn` yet.
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/* REGION_START: ROUND_ROBIN */
+
+/// Runs the ready threads in FIFO order, for [`TIME_SLICE_TICKS`] each.
+pub struct RoundRobin {
+    ready: VecDeque<ThreadId>,
+    /// The ticks the running thread has used of its time slice.
+    used: u64,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        RoundRobin {
+            ready: VecDeque::new(),
+            used: 0,
+        }
+    }
+}
+
+impl Default for RoundRobin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn name(&self) -> &'static str {
+        Policy::RoundRobin.name()
+    }
+
+    fn enqueue(&mut self, thread: &mut Entity) {
+        self.ready.push_back(thread.id);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.used = 0;
+        self.ready.pop_front()
+    }
+
+    fn ready_count(&self) -> usize {
+        self.ready.len()
+    }
+
+    fn tick(&mut self, _current: &Entity) -> bool {
+        self.used += 1;
+        self.used >= TIME_SLICE_TICKS && !self.ready.is_empty()
+    }
+}
+
+/* REGION_END: ROUND_ROBIN */
+
+/* REGION_START: FIXED_PRIORITY */
+
+/// Runs the ready thread with the highest priority, preempting lower priority threads at the
+/// next tick. Threads of the same priority share the CPU round-robin.
+///
+/// A waiting thread rises by one priority level every [`AGING_TICKS`], up to the highest level.
+/// Its priority drops back when it is scheduled.
+pub struct FixedPriority {
+    /// The ready threads, in the order they were enqueued.
+    ready: Vec<Waiting>,
+    /// The number of ticks so far, the clock for aging.
+    ticks: u64,
+    used: u64,
+}
+
+struct Waiting {
+    id: ThreadId,
+    level: u64,
+    /// The tick at which the thread was enqueued.
+    since: u64,
+}
+
+impl FixedPriority {
+    pub fn new() -> Self {
+        FixedPriority {
+            ready: Vec::new(),
+            ticks: 0,
+            used: 0,
+        }
+    }
+
+    /// The level of a waiting thread including aging.
+    fn effective_level(&self, thread: &Waiting) -> u64 {
+        let aging = (self.ticks - thread.since) / AGING_TICKS;
+        (thread.level + aging).min(MAX_LEVEL)
+    }
+
+    /// The index of the waiting thread with the highest effective level, the first one on ties.
+    fn best(&self) -> Option<(usize, u64)> {
+        let mut best: Option<(usize, u64)> = None;
+        for (index, thread) in self.ready.iter().enumerate() {
+            let level = self.effective_level(thread);
+            if best.is_none_or(|(_, best_level)| level > best_level) {
+                best = Some((index, level));
+            }
+        }
+        best
+    }
+}
+
+impl Default for FixedPriority {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler for FixedPriority {
+    fn name(&self) -> &'static str {
+        Policy::FixedPriority.name()
+    }
+
+    fn enqueue(&mut self, thread: &mut Entity) {
+        self.ready.push(Waiting {
+            id: thread.id,
+            level: thread.priority.level(),
+            since: self.ticks,
+        });
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.used = 0;
+        let (index, _) = self.best()?;
+        Some(self.ready.remove(index).id)
+    }
+
+    fn ready_count(&self) -> usize {
+        self.ready.len()
+    }
+
+    fn tick(&mut self, current: &Entity) -> bool {
+        self.ticks += 1;
+        self.used += 1;
+        let level = current.priority.level();
+        match self.best() {
+            Some((_, best)) => best > level || (self.used >= TIME_SLICE_TICKS && best == level),
+            None => false,
+        }
+    }
+}
+
+/* REGION_END: FIXED_PRIORITY */
+
+/* REGION_START: FAIR */
+
+/// Runs the ready thread with the lowest virtual runtime. The running thread is preempted as soon
+/// as another thread has a lower virtual runtime, after at least one tick.
+pub struct Fair {
+    /// The ready threads with their virtual runtimes, in the order they were enqueued.
+    ready: Vec<(ThreadId, u64)>,
+    /// A lower bound of the virtual runtimes of all runnable threads, which only grows. New
+    /// threads start with it, so they don't get the CPU until they caught up with the others.
+    min_vruntime: u64,
+}
+
+impl Fair {
+    pub fn new() -> Self {
+        Fair {
+            ready: Vec::new(),
+            min_vruntime: 0,
+        }
+    }
+
+    /// The index and virtual runtime of the waiting thread with the lowest virtual runtime.
+    fn best(&self) -> Option<(usize, u64)> {
+        let mut best: Option<(usize, u64)> = None;
+        for (index, &(_, vruntime)) in self.ready.iter().enumerate() {
+            if best.is_none_or(|(_, best_vruntime)| vruntime < best_vruntime) {
+                best = Some((index, vruntime));
+            }
+        }
+        best
+    }
+}
+
+impl Default for Fair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler for Fair {
+    fn name(&self) -> &'static str {
+        Policy::Fair.name()
+    }
+
+    fn enqueue(&mut self, thread: &mut Entity) {
+        thread.vruntime = thread.vruntime.max(self.min_vruntime);
+        self.ready.push((thread.id, thread.vruntime));
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let (index, _) = self.best()?;
+        Some(self.ready.remove(index).0)
+    }
+
+    fn ready_count(&self) -> usize {
+        self.ready.len()
+    }
+
+    fn charge(&mut self, current: &mut Entity, cycles: u64) {
+        let weighted =
+            u128::from(cycles) * u128::from(NORMAL_WEIGHT) / u128::from(current.priority.weight());
+        current.vruntime = current.vruntime.saturating_add(weighted as u64);
+
+        let min = match self.best() {
+            Some((_, vruntime)) => vruntime.min(current.vruntime),
+            None => current.vruntime,
+        };
+        self.min_vruntime = self.min_vruntime.max(min);
+    }
+
+    fn tick(&mut self, current: &Entity) -> bool {
+        self.best()
+            .is_some_and(|(_, vruntime)| vruntime < current.vruntime)
+    }
+}
+
+/* REGION_END: FAIR */
+
+#[cfg(test)]
+fn entities(priorities: &[i8]) -> Vec<Entity> {
+    priorities
+        .iter()
+        .map(|&nice| Entity::new(ThreadId::new(), Priority::new(nice)))
+        .collect()
+}
+
+#[test_case]
+fn test_round_robin_order() {
+    let mut threads = entities(&[0, -20, 19]);
+    let mut scheduler = RoundRobin::new();
+    for thread in threads.iter_mut() {
+        scheduler.enqueue(thread);
+    }
+    for thread in &threads {
+        assert_eq!(scheduler.pick_next(), Some(thread.id));
+    }
+    assert_eq!(scheduler.pick_next(), None);
+}
+
+#[test_case]
+fn test_round_robin_time_slice() {
+    let mut threads = entities(&[0, 0]);
+    let mut scheduler = RoundRobin::new();
+    scheduler.enqueue(&mut threads[1]);
+    for _ in 1..TIME_SLICE_TICKS {
+        assert!(!scheduler.tick(&threads[0]));
+    }
+    assert!(scheduler.tick(&threads[0]));
+}
+
+#[test_case]
+fn test_priority_picks_highest() {
+    let mut threads = entities(&[5, -10, 0]);
+    let mut scheduler = FixedPriority::new();
+    for thread in threads.iter_mut() {
+        scheduler.enqueue(thread);
+    }
+    assert_eq!(scheduler.pick_next(), Some(threads[1].id));
+    assert_eq!(scheduler.pick_next(), Some(threads[2].id));
+    assert_eq!(scheduler.pick_next(), Some(threads[0].id));
+}
+
+#[test_case]
+fn test_priority_preempts_lower() {
+    let mut threads = entities(&[0, -1]);
+    let mut scheduler = FixedPriority::new();
+    scheduler.enqueue(&mut threads[1]);
+    assert!(scheduler.tick(&threads[0]));
+}
+
+#[test_case]
+fn test_priority_aging() {
+    let mut threads = entities(&[-20, 19]);
+    let mut scheduler = FixedPriority::new();
+    scheduler.enqueue(&mut threads[1]);
+
+    // The lowest priority thread needs `MAX_LEVEL` steps to reach the highest priority.
+    let mut ticks = 0;
+    while !scheduler.tick(&threads[0]) {
+        ticks += 1;
+        assert!(
+            ticks <= (MAX_LEVEL + 1) * AGING_TICKS,
+            "waiting thread starved"
+        );
+    }
+    assert_eq!(scheduler.pick_next(), Some(threads[1].id));
+}
+
+#[test_case]
+fn test_fair_picks_lowest_vruntime() {
+    let mut threads = entities(&[0, 0]);
+    let mut scheduler = Fair::new();
+    scheduler.charge(&mut threads[0], 1000);
+    scheduler.enqueue(&mut threads[0]);
+    scheduler.enqueue(&mut threads[1]);
+    // The new thread starts at the minimum virtual runtime, not at 0.
+    assert_eq!(threads[1].vruntime, 1000);
+
+    assert_eq!(scheduler.pick_next(), Some(threads[0].id));
+    assert!(!scheduler.tick(&threads[0]));
+    scheduler.charge(&mut threads[0], 1);
+    assert!(scheduler.tick(&threads[0]));
+}
+
+#[test_case]
+fn test_fair_weights() {
+    let mut threads = entities(&[0, -5, 5]);
+    let mut scheduler = Fair::new();
+    for thread in threads.iter_mut() {
+        scheduler.charge(thread, 1_000_000);
+    }
+    assert_eq!(threads[0].vruntime, 1_000_000);
+    assert!(threads[1].vruntime < threads[0].vruntime);
+    assert!(threads[2].vruntime > threads[0].vruntime);
+}
+
+#[test_case]
+fn test_policy_names() {
+    for policy in Policy::ALL.iter().copied() {
+        assert_eq!(Policy::from_name(policy.name()), Some(policy));
+        assert_eq!(policy.scheduler().name(), policy.name());
+    }
+    assert_eq!(Policy::from_name("lottery"), None);
+}
@@ -0,0 +1,83 @@
+//! # switch
+//!
+//! The context switch between kernel threads.
+//!
+//! A thread that is not running is described by a single value: its saved stack pointer. The
+//! context switch pushes the callee-saved registers and `RFLAGS` onto the stack of the old thread,
+//! saves its stack pointer, loads the stack pointer of the new thread and pops its registers. All
+//! other registers are saved by the compiler around the call of [`switch_context`], like around any
+//! other function call.
+//!
+//! Saving `RFLAGS` gives each thread its own interrupt flag: a thread that is switched away with
+//! interrupts disabled continues with interrupts disabled.
+
+use core::arch::global_asm;
+
+extern "C" {
+    /// Saves the context of the current thread to `*old_rsp` and continues the thread whose saved
+    /// stack pointer is `new_rsp`. Returns when another thread switches back to this one.
+    pub fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+
+    /// The first code a new thread executes: calls `thread_entry` with the argument in `r12`.
+    fn thread_trampoline();
+}
+
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    "thread_trampoline:",
+    "mov rdi, r12",
+    // The end of the call chain, for backtraces.
+    "xor rbp, rbp",
+    // The ABI requires a 16 byte aligned stack at function calls.
+    "and rsp, -16",
+    "call {entry}",
+    "ud2",
+    entry = sym super::thread_entry,
+);
+
+/// The value of `RFLAGS` a new thread starts with: interrupts disabled, only the reserved bit 1
+/// set. `thread_entry` enables interrupts.
+const INITIAL_RFLAGS: u64 = 0x2;
+
+/// Prepares the stack of a new thread, so that switching to it calls `thread_entry(arg)`. Returns
+/// the stack pointer to pass to [`switch_context`].
+///
+/// # Safety
+///
+/// `top` must be the 16 byte aligned top of an unused, mapped stack.
+pub unsafe fn init_stack(top: u64, arg: u64) -> u64 {
+    // The registers in the order `switch_context` pops them, followed by its return address.
+    let frame: [u64; 9] = [
+        INITIAL_RFLAGS,
+        0,   // r15
+        0,   // r14
+        0,   // r13
+        arg, // r12
+        0,   // rbx
+        0,   // rbp
+        thread_trampoline as *const () as u64,
+        0, // A fake return address of `thread_trampoline`.
+    ];
+    let rsp = top - core::mem::size_of_val(&frame) as u64;
+    core::ptr::write(rsp as *mut [u64; 9], frame);
+    rsp
+}
@@ -0,0 +1,227 @@
+//! # time
+//!
+//! The kernel's monotonic clock and the tick that drives [`timer`]s.
+//!
+//! The clock counts nanoseconds since boot. It is read from the time stamp counter (TSC), whose
+//! frequency is measured once at boot against channel 2 of the programmable interval timer (PIT),
+//! which runs at a known frequency. The TSC is assumed to be invariant and synchronized between
+//! the CPUs, as it is on current hardware and in QEMU.
+//!
+//! The timer interrupt, the *tick*, arrives [`TICK_HZ`] times per second while a CPU is busy. Each
+//! tick advances the timer wheel to the current time (see [`timer`]) and lets the scheduler preempt
+//! the running thread. At boot, channel 0 of the PIT raises it periodically; once the local APIC
+//! is available, its timer takes over, and the tick stops while the CPU is idle (see [`tick`]).
+
+pub mod tick;
+pub mod timer;
+mod wheel;
+
+use core::{
+    arch::x86_64::_rdtsc,
+    convert::TryFrom,
+    fmt,
+    ops::{Add, Sub},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::instructions::port::Port;
+
+/// The frequency of the timer interrupt.
+pub const TICK_HZ: u64 = 1000;
+
+/// The time between two ticks.
+pub const TICK_PERIOD: Duration = Duration::from_nanos(1_000_000_000 / TICK_HZ);
+
+/// The input frequency of the PIT.
+const PIT_HZ: u64 = 1_193_182;
+
+/// How long the TSC is measured against the PIT at boot.
+const CALIBRATION_MS: u64 = 10;
+
+/// The TSC frequency, or 0 before [`init`].
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// The TSC at the start of the clock.
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+/// A point in time of the monotonic clock, in nanoseconds since boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The start of the clock.
+    pub const ZERO: Instant = Instant(0);
+
+    /// A point in time that is never reached, e.g. for a timeout that never expires.
+    pub const MAX: Instant = Instant(u64::MAX);
+
+    /// The current time. Always [`Instant::ZERO`] before [`init`].
+    pub fn now() -> Self {
+        now()
+    }
+
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Instant(nanos)
+    }
+
+    pub const fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// The time from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// The time since `self`.
+    pub fn elapsed(self) -> Duration {
+        now().duration_since(self)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Instant)
+    }
+}
+
+/// Saturates at [`Instant::MAX`].
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).unwrap_or(Instant::MAX)
+    }
+}
+
+/// Saturates at zero, like [`Instant::duration_since`].
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl fmt::Display for Instant {
+    /// Seconds with millisecond precision, like `12.345s`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let millis = self.0 / 1_000_000;
+        write!(f, "{}.{:03}s", millis / 1000, millis % 1000)
+    }
+}
+
+/// Calibrates the clock, starts the timer interrupt at [`TICK_HZ`] and initializes the timer
+/// wheel. Called once on the BSP, before interrupts are enabled.
+pub fn init() {
+    let tsc_hz = calibrate_tsc();
+    TSC_START.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+    TSC_HZ.store(tsc_hz, Ordering::Release);
+    start_tick();
+    timer::init();
+}
+
+/// The current time of the monotonic clock.
+pub fn now() -> Instant {
+    let tsc_hz = TSC_HZ.load(Ordering::Acquire);
+    if tsc_hz == 0 {
+        return Instant::ZERO;
+    }
+    // Another CPU's TSC may lag a little behind.
+    let cycles = unsafe { _rdtsc() }.saturating_sub(TSC_START.load(Ordering::Relaxed));
+    Instant((u128::from(cycles) * 1_000_000_000 / u128::from(tsc_hz)) as u64)
+}
+
+/// The TSC value at `instant`, for hardware that compares against the TSC.
+pub fn tsc_at(instant: Instant) -> u64 {
+    let cycles = u128::from(instant.as_nanos()) * u128::from(tsc_hz()) / 1_000_000_000;
+    TSC_START
+        .load(Ordering::Relaxed)
+        .saturating_add(u64::try_from(cycles).unwrap_or(u64::MAX))
+}
+
+/// The measured TSC frequency in Hz, or 0 before [`init`].
+pub fn tsc_hz() -> u64 {
+    TSC_HZ.load(Ordering::Acquire)
+}
+
+/// Spins for `duration`, e.g. for hardware that needs a short delay. Works with interrupts
+/// disabled, but not before [`init`]; threads should use [`crate::thread::sleep`] instead.
+pub fn delay(duration: Duration) {
+    assert!(tsc_hz() != 0, "the clock is not calibrated yet");
+    let deadline = now() + duration;
+    while now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Counts TSC cycles until channel 2 of the PIT counted down [`CALIBRATION_MS`], and returns the
+/// TSC frequency.
+fn calibrate_tsc() -> u64 {
+    let mut control = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_2 = Port::<u8>::new(0x42);
+    let count = PIT_HZ * CALIBRATION_MS / 1000;
+
+    let (start, end) = unsafe {
+        // Bit 0 enables the counter's gate, bit 1 would connect it to the speaker.
+        let value = control.read();
+        control.write((value & !0x02) | 0x01);
+        // Channel 2, low and high byte, mode 0 (the output goes high when the count reaches 0).
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        let start = _rdtsc();
+        // Bit 5 is the output of channel 2.
+        while control.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        (start, _rdtsc())
+    };
+    (end - start) * 1000 / CALIBRATION_MS
+}
+
+/// Programs channel 0 of the PIT, which raises the timer interrupt, to [`TICK_HZ`].
+fn start_tick() {
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_0 = Port::<u8>::new(0x40);
+    let divisor = (PIT_HZ + TICK_HZ / 2) / TICK_HZ;
+    unsafe {
+        // Channel 0, low and high byte, mode 3 (square wave).
+        command.write(0b0011_0110);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
+
+#[test_case]
+fn test_clock_is_monotonic() {
+    let start = now();
+    delay(Duration::from_micros(100));
+    let end = now();
+    assert!(end - start >= Duration::from_micros(100));
+    assert_eq!(start - end, Duration::ZERO);
+}
+
+#[test_case]
+fn test_clock_agrees_with_ticks() {
+    // The PIT and the TSC are independent clocks, but they should roughly agree.
+    let (start, ticks) = (now(), crate::interrupts::ticks());
+    while crate::interrupts::ticks() < ticks + 20 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(10), "{:?}", elapsed);
+    assert!(elapsed <= Duration::from_millis(500), "{:?}", elapsed);
+}
+
+#[test_case]
+fn test_instant_arithmetic() {
+    let instant = Instant::from_nanos(1_500_000_000);
+    assert_eq!(
+        instant + Duration::from_millis(500),
+        Instant::from_nanos(2_000_000_000)
+    );
+    assert_eq!(Instant::MAX + Duration::from_nanos(1), Instant::MAX);
+    assert_eq!(instant.checked_add(Duration::MAX), None);
+}
@@ -0,0 +1,271 @@
+//! # tick
+//!
+//! The timer interrupt, tickless idle and idle time accounting.
+//!
+//! At boot, the PIT ticks periodically (see [`super::init`]). [`init_lapic`] hands the tick over
+//! to the local APIC timer of the BSP, in TSC-deadline mode if the CPU supports it and in one-shot
+//! mode otherwise, and masks the PIT. Then every tick programs the next one, one
+//! [`TICK_PERIOD`] later.
+//!
+//! When the idle thread halts the CPU, the tick is stopped: the timer is only programmed for the
+//! next [timer](super::timer) that expires, or not at all. The next interrupt, whatever its
+//! source, ends the idle period (see [`exit_idle`], which every interrupt handler calls through
+//! [`InterruptEntry`](crate::percpu::InterruptEntry)) and restarts the tick. Tickless idle can be
+//! switched off with [`set_tickless`], to compare.
+//!
+//! The time each CPU spends halted is accounted as idle time, and [`print_stats`] shows it next to
+//! the number of ticks.
+
+use super::{timer, Instant, TICK_PERIOD};
+use crate::{
+    apic::{self, TimerMode},
+    interrupts, per_cpu, percpu, serial_println, smp,
+};
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+use x86_64::instructions::interrupts as cpu_interrupts;
+
+/// How long the local APIC timer is measured in one-shot mode.
+const CALIBRATION: Duration = Duration::from_millis(10);
+
+/// The device that raises the tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Device {
+    /// The PIT, periodically. The tick never stops.
+    Pit,
+    /// The local APIC timer in one-shot mode.
+    LapicOneShot,
+    /// The local APIC timer in TSC-deadline mode.
+    LapicTscDeadline,
+}
+
+impl Device {
+    pub fn name(self) -> &'static str {
+        match self {
+            Device::Pit => "pit",
+            Device::LapicOneShot => "lapic one-shot",
+            Device::LapicTscDeadline => "lapic tsc-deadline",
+        }
+    }
+}
+
+static DEVICE: AtomicU8 = AtomicU8::new(Device::Pit as u8);
+
+/// The count rate of the local APIC timer in one-shot mode, in Hz.
+static LAPIC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the tick stops in idle.
+static TICKLESS: AtomicBool = AtomicBool::new(true);
+
+per_cpu! {
+    /// The ticks of each CPU.
+    pub static TICKS: AtomicU64 = AtomicU64::new(0);
+    /// The time each CPU spent halted, in nanoseconds.
+    pub static IDLE_NANOS: AtomicU64 = AtomicU64::new(0);
+    /// When the current idle period started, in nanoseconds, or 0 while not idle.
+    static IDLE_SINCE: AtomicU64 = AtomicU64::new(0);
+    /// Whether the tick is stopped until the idle period ends.
+    static STOPPED: AtomicBool = AtomicBool::new(false);
+}
+
+/// The device that raises the tick.
+pub fn device() -> Device {
+    match DEVICE.load(Ordering::Acquire) {
+        1 => Device::LapicOneShot,
+        2 => Device::LapicTscDeadline,
+        _ => Device::Pit,
+    }
+}
+
+/// Moves the tick from the PIT to the local APIC timer of the current CPU, which must be the BSP.
+/// Does nothing if the local APIC isn't mapped (see [`crate::smp::init`]).
+pub fn init_lapic() {
+    if !apic::is_mapped() {
+        return;
+    }
+    let device = if apic::tsc_deadline_supported() {
+        apic::timer_init(TimerMode::TscDeadline);
+        Device::LapicTscDeadline
+    } else {
+        apic::timer_init(TimerMode::OneShot);
+        LAPIC_HZ.store(calibrate_lapic(), Ordering::Release);
+        Device::LapicOneShot
+    };
+    cpu_interrupts::without_interrupts(|| {
+        DEVICE.store(device as u8, Ordering::Release);
+        program(super::now() + TICK_PERIOD);
+        interrupts::disable_pit();
+    });
+}
+
+/// Measures the count rate of the local APIC timer in one-shot mode against the TSC.
+fn calibrate_lapic() -> u64 {
+    apic::timer_start(u32::MAX);
+    super::delay(CALIBRATION);
+    let counted = u64::from(u32::MAX - apic::timer_count());
+    apic::timer_start(0);
+    counted * 1_000_000_000 / CALIBRATION.as_nanos() as u64
+}
+
+/// Arms the local APIC timer for `deadline`.
+fn program(deadline: Instant) {
+    match device() {
+        Device::Pit => {}
+        Device::LapicTscDeadline => apic::timer_set_deadline(super::tsc_at(deadline)),
+        Device::LapicOneShot => {
+            let nanos = deadline.duration_since(super::now()).as_nanos();
+            let count = nanos * u128::from(LAPIC_HZ.load(Ordering::Acquire)) / 1_000_000_000;
+            // A count of 0 would disarm it.
+            apic::timer_start(count.clamp(1, u128::from(u32::MAX)) as u32);
+        }
+    }
+}
+
+/// Disarms the local APIC timer.
+fn disarm() {
+    match device() {
+        Device::Pit => {}
+        Device::LapicTscDeadline => apic::timer_set_deadline(0),
+        Device::LapicOneShot => apic::timer_start(0),
+    }
+}
+
+/// Whether the tick stops in idle.
+pub fn tickless() -> bool {
+    TICKLESS.load(Ordering::Relaxed) && device() != Device::Pit
+}
+
+/// Switches tickless idle on or off. It is on by default, but only works with the local APIC
+/// timer.
+pub fn set_tickless(enabled: bool) {
+    TICKLESS.store(enabled, Ordering::Relaxed);
+}
+
+/// Counts the tick and programs the next one. Called by the timer interrupt handler.
+pub(crate) fn on_tick() {
+    TICKS.with(|ticks| ticks.fetch_add(1, Ordering::Relaxed));
+    program(super::now() + TICK_PERIOD);
+}
+
+/// Halts the CPU until the next interrupt and accounts the time as idle. Must be called with
+/// interrupts disabled, which are enabled atomically with halting, so no wakeup gets lost.
+///
+/// With `stop_tick`, the tick stops while halted, if [`tickless`]. Only the idle thread should do
+/// that: other code still needs the tick to be preempted.
+pub fn idle(stop_tick: bool) {
+    let now = super::now();
+    IDLE_SINCE.with(|since| since.store(now.as_nanos().max(1), Ordering::Relaxed));
+    // Only the BSP ticks.
+    if stop_tick && tickless() && percpu::cpu_id() == 0 {
+        STOPPED.with(|stopped| stopped.store(true, Ordering::Relaxed));
+        match timer::next_deadline() {
+            Some(deadline) => program(deadline),
+            None => disarm(),
+        }
+    }
+    cpu_interrupts::enable_and_hlt();
+    // In case the CPU woke up without an interrupt handler that did it.
+    cpu_interrupts::without_interrupts(exit_idle);
+}
+
+/// Ends the idle period of the current CPU, if it is in one, and restarts the tick if it was
+/// stopped. Called with interrupts disabled, at the start of every hardware interrupt.
+pub(crate) fn exit_idle() {
+    let since = IDLE_SINCE.with(|since| since.swap(0, Ordering::Relaxed));
+    if since == 0 {
+        return;
+    }
+    let now = super::now();
+    IDLE_NANOS.with(|idle| idle.fetch_add(now.as_nanos().saturating_sub(since), Ordering::Relaxed));
+    if STOPPED.with(|stopped| stopped.swap(false, Ordering::Relaxed)) {
+        program(now + TICK_PERIOD);
+    }
+}
+
+/// The idle time of `cpu` so far, including the current idle period.
+fn idle_time(cpu: usize, now: Instant) -> Duration {
+    let load = |counter: &'static percpu::PerCpu<AtomicU64>| {
+        counter
+            .for_cpu(cpu)
+            .map_or(0, |value| value.load(Ordering::Relaxed))
+    };
+    let since = load(&IDLE_SINCE);
+    let current = if since == 0 {
+        0
+    } else {
+        now.as_nanos().saturating_sub(since)
+    };
+    Duration::from_nanos(load(&IDLE_NANOS) + current)
+}
+
+/// Prints the tick device, and the ticks and idle time of each CPU over serial.
+pub fn print_stats() {
+    let now = super::now();
+    let uptime = now.as_nanos().max(1);
+    serial_println!(
+        "tick: {} at {} Hz, tickless idle {}, uptime {}",
+        device().name(),
+        super::TICK_HZ,
+        if tickless() { "on" } else { "off" },
+        now
+    );
+    serial_println!(
+        "{:>4} {:>12} {:>8} {:>7}",
+        "CPU",
+        "TICKS",
+        "TICKS/S",
+        "IDLE"
+    );
+    for cpu in 0..smp::cpu_count() {
+        let ticks = TICKS
+            .for_cpu(cpu)
+            .map_or(0, |ticks| ticks.load(Ordering::Relaxed));
+        let idle = idle_time(cpu, now).as_nanos() as u64;
+        // In tenths of a percent.
+        let permille = idle.min(uptime) * 1000 / uptime;
+        serial_println!(
+            "{:>4} {:>12} {:>8} {:>5}.{}%",
+            cpu,
+            ticks,
+            u128::from(ticks) * 1_000_000_000 / u128::from(uptime),
+            permille / 10,
+            permille % 10
+        );
+    }
+}
+
+#[test_case]
+fn test_idle_time_is_accounted() {
+    let before = IDLE_NANOS.with(|idle| idle.load(Ordering::Relaxed));
+    // Woken by the next tick at the latest.
+    cpu_interrupts::disable();
+    idle(false);
+    assert!(IDLE_NANOS.with(|idle| idle.load(Ordering::Relaxed)) > before);
+    assert_eq!(IDLE_SINCE.with(|since| since.load(Ordering::Relaxed)), 0);
+}
+
+#[test_case]
+fn test_tick_stops_in_idle() {
+    if !tickless() {
+        return;
+    }
+    let ticks = || TICKS.with(|ticks| ticks.load(Ordering::Relaxed));
+    let wakeup = timer::after(TICK_PERIOD * 5, || {});
+    let before = ticks();
+    // Other interrupts may end the idle period early.
+    while wakeup.is_pending() {
+        cpu_interrupts::disable();
+        idle(true);
+    }
+    // With the tick running, there would have been 5 ticks.
+    assert!(ticks() - before < 4, "{} ticks in idle", ticks() - before);
+    assert!(!STOPPED.with(|stopped| stopped.load(Ordering::Relaxed)));
+
+    // The tick runs again.
+    let before = ticks();
+    super::delay(TICK_PERIOD * 3);
+    assert!(ticks() > before);
+}
@@ -0,0 +1,338 @@
+//! # timer
+//!
+//! One-shot and periodic timers on the monotonic clock, for callbacks, async tasks and threads.
+//!
+//! All timers live in one [timer wheel](super::wheel) with a resolution of one [`JIFFY`]. The
+//! timer interrupt advances the wheel to the current time (see [`on_tick`]), so a timer expires
+//! within a jiffy after its deadline, and never before it.
+//!
+//! - [`after`], [`at`] and [`every`] run a callback. Callbacks run in the timer interrupt handler,
+//!   so like interrupt handlers they must be short and must not block.
+//! - [`delay`], [`delay_until`] and [`Interval`] are futures for async tasks, see
+//!   [`crate::task`].
+//! - Threads sleep with [`crate::thread::sleep`], and the timeouts of the sleeping primitives in
+//!   [`crate::sync`] are timers too.
+//!
+//! A [`Timer`] handle cancels its timer. Dropping the handle doesn't, except for the futures.
+
+use super::{
+    wheel::{Key, Wheel},
+    Instant,
+};
+use crate::{
+    serial_println,
+    sync::IrqMutex,
+    thread::{self, ThreadId},
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use futures_util::task::AtomicWaker;
+
+/// The resolution of timers.
+pub const JIFFY: Duration = Duration::from_millis(1);
+
+const JIFFY_NANOS: u64 = JIFFY.as_nanos() as u64;
+
+/// The timer wheel. It is `None` until [`init`] was called.
+static WHEEL: IrqMutex<Option<Wheel<Action>>> = IrqMutex::named("TIMERS", None);
+
+/// What happens when a timer expires.
+#[derive(Clone)]
+enum Action {
+    Callback(Arc<dyn Fn() + Send + Sync>),
+    Wake(Arc<AtomicWaker>),
+    Unblock(ThreadId),
+}
+
+impl Action {
+    fn run(self) {
+        match self {
+            Action::Callback(callback) => callback(),
+            Action::Wake(waker) => waker.wake(),
+            Action::Unblock(thread) => thread::unblock(thread),
+        }
+    }
+}
+
+/// The last jiffy that started at or before `instant`.
+fn jiffy(instant: Instant) -> u64 {
+    instant.as_nanos() / JIFFY_NANOS
+}
+
+/// The first jiffy that starts at or after `instant`, so a timer never expires early.
+fn deadline_jiffy(instant: Instant) -> u64 {
+    instant.as_nanos().div_ceil(JIFFY_NANOS)
+}
+
+/// A period of at least one jiffy.
+fn period_jiffies(period: Duration) -> u64 {
+    (period.as_nanos() as u64).div_ceil(JIFFY_NANOS).max(1)
+}
+
+/// Creates the timer wheel, starting at the current time.
+pub(super) fn init() {
+    *WHEEL.lock() = Some(Wheel::new(jiffy(super::now())));
+}
+
+fn insert(deadline: Instant, period: u64, action: Action) -> Timer {
+    let mut wheel = WHEEL.lock();
+    let wheel = wheel.as_mut().expect("timers not initialized");
+    Timer {
+        key: wheel.insert(deadline_jiffy(deadline), period, action),
+    }
+}
+
+/// Runs `callback` once at `deadline`, in interrupt context.
+pub fn at(deadline: Instant, callback: impl Fn() + Send + Sync + 'static) -> Timer {
+    insert(deadline, 0, Action::Callback(Arc::new(callback)))
+}
+
+/// Runs `callback` once after `delay`, in interrupt context.
+pub fn after(delay: Duration, callback: impl Fn() + Send + Sync + 'static) -> Timer {
+    at(super::now() + delay, callback)
+}
+
+/// Runs `callback` every `period`, starting one period from now, in interrupt context. Periods
+/// are rounded up to whole jiffies.
+pub fn every(period: Duration, callback: impl Fn() + Send + Sync + 'static) -> Timer {
+    insert(
+        super::now() + period,
+        period_jiffies(period),
+        Action::Callback(Arc::new(callback)),
+    )
+}
+
+/// Unblocks `thread` at `deadline`, see [`crate::thread::block`].
+pub(crate) fn unblock_at(deadline: Instant, thread: ThreadId) -> Timer {
+    insert(deadline, 0, Action::Unblock(thread))
+}
+
+/// A handle to a pending timer.
+#[derive(Debug)]
+pub struct Timer {
+    key: Key,
+}
+
+impl Timer {
+    /// Stops the timer. Returns `false` if it already expired or was cancelled before. A callback
+    /// that already started still completes.
+    pub fn cancel(&self) -> bool {
+        WHEEL
+            .lock()
+            .as_mut()
+            .is_some_and(|wheel| wheel.cancel(self.key))
+    }
+
+    /// Whether the timer will still expire. Periodic timers stay pending until cancelled.
+    pub fn is_pending(&self) -> bool {
+        WHEEL
+            .lock()
+            .as_ref()
+            .is_some_and(|wheel| wheel.is_pending(self.key))
+    }
+}
+
+/// Advances the timer wheel to the current time and runs the actions of the expired timers.
+/// Called by the timer interrupt handler.
+pub(crate) fn on_tick() {
+    let mut expired = Vec::new();
+    if let Some(wheel) = WHEEL.lock().as_mut() {
+        wheel.advance(jiffy(super::now()), &mut expired);
+    }
+    // Unlocked, so that callbacks can start and cancel timers.
+    for action in expired {
+        action.run();
+    }
+}
+
+/// When the next timer may expire, or `None` without timers. It is never later than the deadline
+/// of the next timer, but may be earlier, see [`Wheel::next_expiry`].
+pub(super) fn next_deadline() -> Option<Instant> {
+    let wheel = WHEEL.lock();
+    let jiffy = wheel.as_ref()?.next_expiry()?;
+    Some(Instant::from_nanos(jiffy.saturating_mul(JIFFY_NANOS)))
+}
+
+/// Prints the number of pending timers over serial.
+pub fn print_stats() {
+    let (jiffies, pending) = WHEEL
+        .lock()
+        .as_ref()
+        .map_or((0, 0), |wheel| (wheel.now(), wheel.len()));
+    serial_println!("timers: {} pending, wheel at jiffy {}", pending, jiffies);
+}
+
+/// A future that completes at a deadline, see [`delay`].
+pub struct Sleep {
+    deadline: Instant,
+    /// The timer and its waker, once the future was polled before the deadline.
+    timer: Option<(Timer, Arc<AtomicWaker>)>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if let Some((_, waker)) = &self.timer {
+            // Registered before checking the time, so the expiry can't slip in between.
+            waker.register(context.waker());
+        }
+        if super::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        if self.timer.is_none() {
+            let waker = Arc::new(AtomicWaker::new());
+            waker.register(context.waker());
+            let timer = insert(self.deadline, 0, Action::Wake(waker.clone()));
+            self.timer = Some((timer, waker));
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some((timer, _)) = &self.timer {
+            timer.cancel();
+        }
+    }
+}
+
+/// Completes after `duration`.
+pub fn delay(duration: Duration) -> Sleep {
+    delay_until(super::now() + duration)
+}
+
+/// Completes at `deadline`.
+pub fn delay_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+/// Ticks every period, for periodic work in async tasks:
+///
+/// ```ignore
+/// let mut interval = Interval::new(Duration::from_millis(500));
+/// loop {
+///     interval.tick().await;
+///     // ...
+/// }
+/// ```
+///
+/// Ticks that were missed because the task ran late are skipped.
+pub struct Interval {
+    next: Instant,
+    period: Duration,
+}
+
+impl Interval {
+    /// The first tick is one period from now.
+    pub fn new(period: Duration) -> Self {
+        assert!(period > Duration::ZERO, "the period must not be zero");
+        Interval {
+            next: super::now() + period,
+            period,
+        }
+    }
+
+    /// Completes at the next tick.
+    pub fn tick(&mut self) -> Sleep {
+        let deadline = self.next;
+        self.next = self.next + self.period;
+        let now = super::now();
+        if self.next <= now {
+            self.next = now + self.period;
+        }
+        delay_until(deadline)
+    }
+}
+
+#[cfg(test)]
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Halts until `condition` holds, or panics after `timeout`.
+#[cfg(test)]
+fn wait_for(timeout: Duration, condition: impl Fn() -> bool) {
+    let deadline = super::now() + timeout;
+    while !condition() {
+        assert!(super::now() < deadline, "timed out");
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_one_shot_fires_once() {
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+    let start = super::now();
+    let timer = after(Duration::from_millis(5), || {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    });
+    wait_for(Duration::from_secs(1), || FIRED.load(Ordering::SeqCst) == 1);
+    assert!(start.elapsed() >= Duration::from_millis(5));
+    assert!(!timer.is_pending());
+    assert!(!timer.cancel());
+    super::delay(Duration::from_millis(5));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+}
+
+#[test_case]
+fn test_cancelled_timer_does_not_fire() {
+    static FIRED: AtomicBool = AtomicBool::new(false);
+
+    let timer = after(Duration::from_millis(2), || {
+        FIRED.store(true, Ordering::SeqCst)
+    });
+    assert!(timer.cancel());
+    super::delay(Duration::from_millis(10));
+    assert!(!FIRED.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn test_periodic_timer() {
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+    let timer = every(Duration::from_millis(2), || {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+    });
+    wait_for(Duration::from_secs(1), || FIRED.load(Ordering::SeqCst) >= 3);
+    assert!(timer.is_pending());
+    assert!(timer.cancel());
+    let fired = FIRED.load(Ordering::SeqCst);
+    super::delay(Duration::from_millis(10));
+    assert_eq!(FIRED.load(Ordering::SeqCst), fired);
+}
+
+#[test_case]
+fn test_sleep_future_wakes_task() {
+    use futures_util::task::{waker, ArcWake};
+
+    struct Flag(AtomicBool);
+    impl ArcWake for Flag {
+        fn wake_by_ref(flag: &Arc<Self>) {
+            flag.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = waker(flag.clone());
+    let mut context = Context::from_waker(&waker);
+    let mut sleep = delay(Duration::from_millis(3));
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Pending);
+    wait_for(Duration::from_secs(1), || flag.0.load(Ordering::SeqCst));
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Ready(()));
+    assert!(super::now() >= sleep.deadline());
+}
@@ -0,0 +1,294 @@
+//! # wheel
+//!
+//! A hierarchical timer wheel, like the classic one of Linux.
+//!
+//! Time is counted in *jiffies*, the resolution of the wheel. Each of the [`LEVELS`] levels has
+//! [`SLOTS`] slots: a slot of level 0 holds the timers that expire in one jiffy, a slot of level
+//! `n` the timers of `SLOTS^n` consecutive jiffies. A timer is inserted into the lowest level
+//! whose range covers its deadline, so inserting is O(1). Whenever the index of a level wraps
+//! around, the next slot of the level above is *cascaded*: its timers are inserted again, now
+//! into lower levels. A timer is cascaded at most once per level before it expires.
+//!
+//! Cancelling is O(1) as well: the entry of a timer is freed immediately, and the stale key in
+//! its slot is skipped when the slot is processed. Keys carry a generation, so a stale key never
+//! matches a reused entry.
+
+use alloc::vec::Vec;
+use core::mem;
+
+/// Each level indexes this many bits of the deadline.
+const BITS: u32 = 6;
+
+/// The number of slots of each level.
+pub const SLOTS: usize = 1 << BITS;
+
+/// The number of levels. Timers further in the future than `SLOTS^LEVELS` jiffies (more than
+/// two years at 1 ms per jiffy) are kept in the last level until they come into range.
+pub const LEVELS: usize = 6;
+
+const MASK: u64 = SLOTS as u64 - 1;
+
+/// Identifies an inserted timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    index: u32,
+    generation: u32,
+}
+
+struct Entry<T> {
+    generation: u32,
+    deadline: u64,
+    /// Re-inserted with this interval after it expired, unless it is 0.
+    period: u64,
+    /// `None` while the entry is free.
+    value: Option<T>,
+}
+
+pub struct Wheel<T> {
+    /// The last jiffy that was processed.
+    now: u64,
+    /// `LEVELS * SLOTS` lists of keys, level by level.
+    slots: Vec<Vec<Key>>,
+    entries: Vec<Entry<T>>,
+    free: Vec<u32>,
+}
+
+impl<T: Clone> Wheel<T> {
+    /// Creates a wheel whose time starts at `now`.
+    pub fn new(now: u64) -> Self {
+        Wheel {
+            now,
+            slots: (0..LEVELS * SLOTS).map(|_| Vec::new()).collect(),
+            entries: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    /// The last jiffy that was processed by [`Wheel::advance`].
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// The number of pending timers.
+    pub fn len(&self) -> usize {
+        self.entries.len() - self.free.len()
+    }
+
+    /// Adds a timer that expires at `deadline`, and then every `period` jiffies if that is not 0.
+    /// A deadline that already passed expires on the next jiffy.
+    pub fn insert(&mut self, deadline: u64, period: u64, value: T) -> Key {
+        let deadline = deadline.max(self.now + 1);
+        let entry = Entry {
+            generation: 0,
+            deadline,
+            period,
+            value: Some(value),
+        };
+        let key = match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.entries[index as usize];
+                let generation = slot.generation;
+                *slot = Entry {
+                    generation,
+                    ..entry
+                };
+                Key { index, generation }
+            }
+            None => {
+                self.entries.push(entry);
+                Key {
+                    index: (self.entries.len() - 1) as u32,
+                    generation: 0,
+                }
+            }
+        };
+        self.place(key, deadline);
+        key
+    }
+
+    /// Removes a pending timer. Returns `false` if it already expired (for good) or was cancelled.
+    pub fn cancel(&mut self, key: Key) -> bool {
+        if !self.is_pending(key) {
+            return false;
+        }
+        self.release(key.index);
+        true
+    }
+
+    pub fn is_pending(&self, key: Key) -> bool {
+        self.entries
+            .get(key.index as usize)
+            .is_some_and(|entry| entry.generation == key.generation && entry.value.is_some())
+    }
+
+    /// Processes all jiffies up to and including `to`, and pushes the values of the expired
+    /// timers to `expired`, in the order of their deadlines. Periodic timers are inserted again.
+    pub fn advance(&mut self, to: u64, expired: &mut Vec<T>) {
+        while self.now < to {
+            self.now += 1;
+            // Cascade the levels whose index wrapped around, from the lowest one up.
+            let mut level = 1;
+            while level < LEVELS && self.now & ((1 << (BITS * level as u32)) - 1) == 0 {
+                let index = self.slot_index(level, self.now);
+                for key in mem::take(&mut self.slots[index]) {
+                    if self.is_pending(key) {
+                        self.place(key, self.entries[key.index as usize].deadline);
+                    }
+                }
+                level += 1;
+            }
+
+            let index = self.slot_index(0, self.now);
+            for key in mem::take(&mut self.slots[index]) {
+                if self.is_pending(key) {
+                    self.expire(key, expired);
+                }
+            }
+        }
+    }
+
+    /// The next jiffy at which [`Wheel::advance`] has work to do, or `None` without timers. It is
+    /// the deadline of the next timer in level 0; timers in the other levels are only known to
+    /// expire after their slot is cascaded, so that jiffy counts instead.
+    pub fn next_expiry(&self) -> Option<u64> {
+        (0..LEVELS)
+            .filter_map(|level| {
+                let shift = BITS * level as u32;
+                let current = self.now >> shift;
+                // The slot of the current block was processed already, so it comes up last.
+                (current + 1..=current + SLOTS as u64)
+                    .find(|&block| {
+                        let index = self.slot_index(level, block << shift);
+                        self.slots[index].iter().any(|&key| self.is_pending(key))
+                    })
+                    .map(|block| block << shift)
+            })
+            .min()
+    }
+
+    fn expire(&mut self, key: Key, expired: &mut Vec<T>) {
+        let entry = &mut self.entries[key.index as usize];
+        if entry.period == 0 {
+            expired.extend(entry.value.take());
+            self.release(key.index);
+            return;
+        }
+        expired.extend(entry.value.clone());
+        entry.deadline += entry.period;
+        let deadline = entry.deadline;
+        self.place(key, deadline);
+    }
+
+    /// Puts a key into the slot for `deadline`, which is not before the current jiffy.
+    fn place(&mut self, key: Key, deadline: u64) {
+        let delta = deadline - self.now;
+        let (level, deadline) =
+            match (0..LEVELS).find(|&level| delta < 1 << (BITS * (level as u32 + 1))) {
+                Some(level) => (level, deadline),
+                // Too far ahead: kept in the last slot in range, and placed again when cascaded.
+                None => (LEVELS - 1, self.now + (1 << (BITS * LEVELS as u32)) - 1),
+            };
+        let index = self.slot_index(level, deadline);
+        self.slots[index].push(key);
+    }
+
+    /// The index into `slots` of the slot of `level` that covers the jiffy `at`.
+    fn slot_index(&self, level: usize, at: u64) -> usize {
+        level * SLOTS + ((at >> (BITS * level as u32)) & MASK) as usize
+    }
+
+    fn release(&mut self, index: u32) {
+        let entry = &mut self.entries[index as usize];
+        entry.value = None;
+        entry.generation = entry.generation.wrapping_add(1);
+        self.free.push(index);
+    }
+}
+
+#[cfg(test)]
+fn advance(wheel: &mut Wheel<u32>, to: u64) -> Vec<u32> {
+    let mut expired = Vec::new();
+    wheel.advance(to, &mut expired);
+    expired
+}
+
+#[test_case]
+fn test_wheel_expires_at_deadline() {
+    let mut wheel = Wheel::new(0);
+    wheel.insert(5, 0, 1);
+    assert!(advance(&mut wheel, 4).is_empty());
+    assert_eq!(advance(&mut wheel, 5), [1]);
+    assert!(advance(&mut wheel, 100).is_empty());
+    assert_eq!(wheel.len(), 0);
+}
+
+#[test_case]
+fn test_wheel_cascades_every_level() {
+    // Deadlines in the first levels, including the exact level boundaries.
+    let deadlines = [63, 64, 65, 4095, 4096, 4097, 262_143, 262_144, 300_001];
+    // Not starting at 0, so the deadlines aren't aligned with the levels.
+    let start = 1000;
+    let mut wheel = Wheel::new(start);
+    for (value, &deadline) in deadlines.iter().enumerate() {
+        wheel.insert(start + deadline, 0, value as u32);
+    }
+    for (value, &deadline) in deadlines.iter().enumerate() {
+        assert!(advance(&mut wheel, start + deadline - 1).is_empty());
+        assert_eq!(advance(&mut wheel, start + deadline), [value as u32]);
+    }
+}
+
+#[test_case]
+fn test_wheel_next_expiry() {
+    let mut wheel = Wheel::new(10);
+    assert_eq!(wheel.next_expiry(), None);
+    let far = wheel.insert(5000, 0, 1);
+    // Not exact: the timer is cascaded at the start of its level 1 slot first.
+    assert_eq!(wheel.next_expiry(), Some(4096));
+    let near = wheel.insert(20, 0, 2);
+    assert_eq!(wheel.next_expiry(), Some(20));
+    wheel.cancel(near);
+    assert_eq!(wheel.next_expiry(), Some(4096));
+    // Cascaded into level 1, and then into level 0.
+    advance(&mut wheel, 4096);
+    assert_eq!(wheel.next_expiry(), Some(4992));
+    advance(&mut wheel, 4992);
+    assert_eq!(wheel.next_expiry(), Some(5000));
+    wheel.cancel(far);
+    assert_eq!(wheel.next_expiry(), None);
+}
+
+#[test_case]
+fn test_wheel_cancel() {
+    let mut wheel = Wheel::new(0);
+    let key = wheel.insert(10, 0, 1);
+    assert!(wheel.cancel(key));
+    assert!(!wheel.cancel(key));
+    // The freed entry is reused, but the stale key doesn't match it.
+    let reused = wheel.insert(10, 0, 2);
+    assert_eq!(reused.index, key.index);
+    assert!(!wheel.is_pending(key));
+    assert!(!wheel.cancel(key));
+    assert_eq!(advance(&mut wheel, 20), [2]);
+}
+
+#[test_case]
+fn test_wheel_periodic() {
+    let mut wheel = Wheel::new(0);
+    let key = wheel.insert(3, 3, 7);
+    let mut fired = 0;
+    for jiffy in 1..=12 {
+        fired += advance(&mut wheel, jiffy).len();
+    }
+    assert_eq!(fired, 4);
+    assert!(wheel.is_pending(key));
+    assert!(wheel.cancel(key));
+    assert!(advance(&mut wheel, 200).is_empty());
+}
+
+#[test_case]
+fn test_wheel_past_deadline() {
+    let mut wheel = Wheel::new(50);
+    wheel.insert(10, 0, 1);
+    assert_eq!(advance(&mut wheel, 51), [1]);
+}
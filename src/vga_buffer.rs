@@ -1,6 +1,8 @@
+use crate::sync::{self, IrqMutex};
+use crate::time::timer::{self, Timer};
 use core::fmt;
+use core::time::Duration;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
 
 /* REGION_START: LAZY STATICS */
@@ -20,11 +22,13 @@ lazy_static! {
     ///
     /// - `statics` are initialized at compile time, while normal `variables` are at run time.
     ///   The `const evaluator` Rust compiler component evaluates these initialization expressions.
-    /// - Use the spinning mutex to add safe interior mutability to our static [`WRITER`]:
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    /// - Use the spinning mutex to add safe interior mutability to our static [`WRITER`]. It
+    ///   disables interrupts while locked, so an interrupt handler that prints can't deadlock on it.
+    pub static ref WRITER: IrqMutex<Writer> = IrqMutex::named("WRITER", Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        cursor_visible: false,
     });
 }
 
@@ -76,6 +80,11 @@ impl ColorCode {
     fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    /// Swaps the foreground and background colors.
+    fn inverted(self) -> ColorCode {
+        ColorCode(self.0.rotate_left(4))
+    }
 }
 
 /* REGION_END: COLORS */
@@ -120,6 +129,8 @@ pub struct Writer {
     column_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
+    /// Whether the cell at the current position is shown inverted, as the cursor.
+    cursor_visible: bool,
 }
 
 /* REGION_END: TEXT BUFFER */
@@ -130,10 +141,14 @@ pub struct Writer {
 impl Writer {
     /// Writes an ASCII byte to the buffer.
     ///
-    /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character.
+    /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character, and the `\x08`
+    /// backspace character, which moves back one column without erasing anything.
     pub fn write_byte(&mut self, byte: u8) {
+        // The cursor is drawn again by the next blink.
+        self.set_cursor(false);
         match byte {
             b'\n' => self.new_line(),
+            0x08 => self.column_position = self.column_position.saturating_sub(1),
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
@@ -161,8 +176,8 @@ impl Writer {
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
             match byte {
-                // Printable ASCII byte or newline.
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                // Printable ASCII byte, newline or backspace.
+                0x20..=0x7e | b'\n' | 0x08 => self.write_byte(byte),
                 // Not part of printable ASCII range.
                 _ => self.write_byte(0xfe),
             }
@@ -185,6 +200,21 @@ impl Writer {
         self.column_position = 0;
     }
 
+    /// Shows or hides the cursor after the last character, by inverting the colors of its cell.
+    fn set_cursor(&mut self, visible: bool) {
+        if visible == self.cursor_visible {
+            return;
+        }
+        // At the end of a full line, the cursor is off screen until the next line starts.
+        if self.column_position < BUFFER_WIDTH {
+            let cell = &mut self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position];
+            let mut character = cell.read();
+            character.color_code = character.color_code.inverted();
+            cell.write(character);
+        }
+        self.cursor_visible = visible;
+    }
+
     /// Clears a row by overwriting all of its characters with a space character.
     fn clear_row(&mut self, row: usize) {
         let blank = ScreenChar {
@@ -205,6 +235,25 @@ impl Writer {
 
 /* REGION_END: PRINTING */
 
+/* REGION_START: CURSOR */
+
+/// How long the cursor is shown and hidden.
+pub const CURSOR_BLINK_PERIOD: Duration = Duration::from_millis(500);
+
+/// Starts blinking the cursor of the VGA text buffer, until the returned timer is cancelled.
+pub fn blink_cursor() -> Timer {
+    timer::every(CURSOR_BLINK_PERIOD, || {
+        // Runs in the timer interrupt, so it must not wait for a writer on another CPU. It just
+        // skips a blink.
+        if let Some(mut writer) = WRITER.try_lock() {
+            let visible = !writer.cursor_visible;
+            writer.set_cursor(visible);
+        }
+    })
+}
+
+/* REGION_END: CURSOR */
+
 /* REGION_START: FORMATTING MACROS */
 
 impl fmt::Write for Writer {
@@ -225,18 +274,25 @@ macro_rules! print {
     ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
 }
 
-/// Like the `println!` macro in the standard library, but prints to the VGA text buffer.
+/// Like the `println!` macro in the standard library, but prints to the VGA text buffer. Each line
+/// is prefixed with the number of the CPU that prints it.
 #[macro_export]
 macro_rules! println {
-    () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+    () => ($crate::print!("[cpu{}]\n", $crate::smp::cpu_id()));
+    ($($arg:tt)*) => ($crate::print!("[cpu{}] {}\n", $crate::smp::cpu_id(), format_args!($($arg)*)));
 }
 
 /// Prints the given formatted string to the VGA text buffer through the global `WRITER` instance.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
+    // While panicking, the lock may be held by the code that panicked.
+    let mut writer = if sync::panicking() {
+        WRITER.force_lock()
+    } else {
+        WRITER.lock()
+    };
+    writer.write_fmt(args).unwrap();
 }
 
 // `write_fmt` - Glue for usage of the [`write`](https://doc.rust-lang.org/nightly/core/macros/
@@ -0,0 +1,35 @@
+//! Jumps to code on the heap and checks that the instruction fetch faults, since the heap is
+//! mapped non-executable.
+//!
+//! Execution can't continue after the fault, so this test uses `harness = false` (see
+//! `Cargo.toml`) and decides about success in its panic handler.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::serial_print;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("execute_heap::execute_heap...\t");
+
+    os::init(boot_info);
+
+    // A `ret` instruction.
+    let code = Box::new([0xc3u8]);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+
+    panic!("Execution continued after executing heap memory");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::expect_panic(info, "protection violation: execute")
+}
@@ -0,0 +1,42 @@
+//! Reads a heap value after it was freed and checks that KASAN reports a use-after-free.
+//!
+//! Only built with the `kasan` feature. Without the compiler flags of `cargo kasan-test` (see
+//! `src/kasan.rs`), nothing is instrumented, so the test is skipped. Execution can't continue after
+//! the report, so this test uses `harness = false` (see `Cargo.toml`) and decides about success in
+//! its panic handler.
+
+#![no_std]
+#![no_main]
+#![feature(cfg_sanitize)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::{exit_qemu, hlt_loop, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("kasan::use_after_free...\t");
+    if !cfg!(sanitize = "address") {
+        serial_println!("[ignored: built without -Zsanitizer=kernel-address]");
+        exit_qemu(QemuExitCode::Success);
+        hlt_loop();
+    }
+
+    os::init(boot_info);
+
+    let value = Box::new(42u64);
+    let ptr = &*value as *const u64;
+    drop(value);
+    let _ = unsafe { ptr.read_volatile() };
+
+    panic!("use-after-free not detected");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::expect_panic(info, "KASAN: use-after-free")
+}
@@ -0,0 +1,39 @@
+//! Takes two locks in both orders and checks that lockdep reports a possible deadlock, although
+//! none happens on a single CPU.
+//!
+//! Only built with the `lockdep` feature. Execution can't continue after the report, so this test
+//! uses `harness = false` (see `Cargo.toml`) and decides about success in its panic handler.
+
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::{serial_print, sync::IrqMutex};
+
+entry_point!(main);
+
+static A: IrqMutex<()> = IrqMutex::named("A", ());
+static B: IrqMutex<()> = IrqMutex::named("B", ());
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("lockdep::ab_ba...\t");
+
+    os::init(boot_info);
+
+    {
+        let _a = A.lock();
+        let _b = B.lock();
+    }
+    {
+        let _b = B.lock();
+        let _a = A.lock();
+    }
+
+    panic!("inversion not detected");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::expect_panic(info, "lockdep: possible circular locking dependency")
+}
@@ -0,0 +1,29 @@
+//! Boots with `-smp 4` (see `Cargo.toml`) and checks that all application processors start, or
+//! none with the `kasan` feature, which supports a single CPU only.
+
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::{exit_qemu, hlt_loop, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("smp::all_cpus_started...\t");
+
+    os::init(boot_info);
+    let expected = if cfg!(feature = "kasan") { 1 } else { 4 };
+    assert_eq!(os::smp::cpu_count(), expected);
+    assert_eq!(os::smp::cpu_id(), 0);
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::test_panic_handler(info)
+}
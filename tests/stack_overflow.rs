@@ -0,0 +1,35 @@
+//! Overflows the boot stack and checks that the double fault handler diagnoses the overflow.
+//!
+//! A test runner is useless here: execution can't continue after the overflow, so this test uses
+//! `harness = false` (see `Cargo.toml`) and decides about success in its panic handler.
+
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::serial_print;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_overflow::stack_overflow...\t");
+
+    os::init(boot_info);
+
+    // Trigger a stack overflow.
+    stack_overflow();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow(); // For each recursion, the return address is pushed.
+    volatile::Volatile::new(0).read(); // Prevent tail recursion optimizations.
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::expect_panic(info, "stack overflow in boot")
+}
@@ -0,0 +1,29 @@
+//! Writes to the kernel's code and checks that the write faults, since `.text` is read-only.
+//!
+//! Execution can't continue after the fault, so this test uses `harness = false` (see
+//! `Cargo.toml`) and decides about success in its panic handler.
+
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use os::serial_print;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("write_to_text::write_to_text...\t");
+
+    os::init(boot_info);
+
+    let code = main as *const () as *mut u8;
+    unsafe { code.write_volatile(0xc3) };
+
+    panic!("Execution continued after writing to .text");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    os::expect_panic(info, "protection violation: write")
+}
@@ -0,0 +1,7 @@
+// Links user programs with `linker.ld`. The path is absolute, since the linker runs in the
+// workspace root, not in this directory.
+fn main() {
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rustc-link-arg=--script={}/linker.ld", dir);
+    println!("cargo:rerun-if-changed=linker.ld");
+}
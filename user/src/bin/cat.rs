@@ -0,0 +1,54 @@
+//! # cat
+//!
+//! `cat [file]...` writes the files one after the other to stdout, or stdin without any.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use user::{
+    entry, env, eprintln, fs,
+    io::{self, STDIN, STDOUT},
+    syscall::{Error, O_RDONLY},
+};
+
+entry!(main);
+
+fn main() -> i32 {
+    let paths: Vec<&str> = env::args().skip(1).collect();
+    if paths.is_empty() {
+        return match copy(STDIN) {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("cat: {}", err.description());
+                1
+            }
+        };
+    }
+    let mut code = 0;
+    for path in paths {
+        let result = fs::open(path, O_RDONLY).and_then(|fd| {
+            let copied = copy(fd);
+            let _ = io::close(fd);
+            copied
+        });
+        if let Err(err) = result {
+            eprintln!("cat: {}: {}", path, err.description());
+            code = 1;
+        }
+    }
+    code
+}
+
+/// Writes what `fd` has to stdout, up to its end.
+fn copy(fd: u64) -> Result<(), Error> {
+    let mut buffer = [0; 4096];
+    loop {
+        match io::read(fd, &mut buffer)? {
+            0 => return Ok(()),
+            read => io::write_all(STDOUT, &buffer[..read])?,
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! # init
+//!
+//! The first process, which the kernel starts at boot.
+//!
+//! `init [program]` runs the shell `sh`, or `program`, and starts it again whenever it ends, so
+//! that there always is a shell to type into. It also waits for every other process that ends
+//! without a parent, since the kernel makes init their parent.
+
+#![no_std]
+#![no_main]
+
+use core::time::Duration;
+use user::{
+    entry, env, eprintln,
+    process::{self, Pid},
+    syscall::Error,
+    time,
+};
+
+entry!(main);
+
+/// The environment of the shell.
+const ENV: [&str; 1] = ["HOME=/"];
+
+/// The exit code of a child that failed to start the program, like the shell's for a command that
+/// is not found.
+const EXEC_FAILED: i32 = 127;
+
+fn main() -> i32 {
+    let program = env::args().nth(1).unwrap_or("sh");
+    loop {
+        let shell = match spawn(program) {
+            Ok(pid) => pid,
+            Err(err) => {
+                eprintln!("init: failed to fork: {}", err.description());
+                let _ = time::sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+        loop {
+            match process::wait(None, true) {
+                Ok(Some((pid, status))) if pid == shell => {
+                    if !status.success() {
+                        eprintln!("init: {} ended with {}", program, status);
+                        // Don't restart a shell that fails at once as fast as possible.
+                        let _ = time::sleep(Duration::from_secs(1));
+                    }
+                    break;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    eprintln!("init: failed to wait: {}", err.description());
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Runs `program` in a child.
+fn spawn(program: &str) -> Result<Pid, Error> {
+    let pid = process::fork()?;
+    if pid == 0 {
+        let err = process::exec(program, &[program], &ENV);
+        eprintln!("init: {}: {}", program, err.description());
+        process::exit(EXEC_FAILED);
+    }
+    Ok(pid)
+}
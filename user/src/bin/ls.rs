@@ -0,0 +1,60 @@
+//! # ls
+//!
+//! `ls [dir]...` lists the entries of the directories, or of the current directory without any.
+//! The names of directories end with a `/`.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use user::{
+    entry, env, eprintln, fs,
+    io::{self, STDOUT},
+    println,
+    syscall::{Error, O_DIRECTORY, O_RDONLY},
+};
+
+entry!(main);
+
+fn main() -> i32 {
+    let mut paths: Vec<&str> = env::args().skip(1).collect();
+    if paths.is_empty() {
+        paths.push(".");
+    }
+    let mut code = 0;
+    for (index, &path) in paths.iter().enumerate() {
+        if paths.len() > 1 {
+            if index > 0 {
+                println!();
+            }
+            println!("{}:", path);
+        }
+        if let Err(err) = list(path) {
+            eprintln!("ls: {}: {}", path, err.description());
+            code = 1;
+        }
+    }
+    code
+}
+
+/// Writes the entries of the directory `path` to stdout. Reading the directory returns them
+/// already listed.
+fn list(path: &str) -> Result<(), Error> {
+    let fd = fs::open(path, O_RDONLY | O_DIRECTORY)?;
+    let mut buffer = [0; 4096];
+    let result = loop {
+        match io::read(fd, &mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(read) => {
+                if let Err(err) = io::write_all(STDOUT, &buffer[..read]) {
+                    break Err(err);
+                }
+            }
+            Err(err) => break Err(err),
+        }
+    };
+    let _ = io::close(fd);
+    result
+}
@@ -0,0 +1,25 @@
+//! # mkdir
+//!
+//! `mkdir dir...` creates the directories.
+
+#![no_std]
+#![no_main]
+
+use user::{entry, env, eprintln, fs};
+
+entry!(main);
+
+fn main() -> i32 {
+    if env::args().count() < 2 {
+        eprintln!("usage: mkdir dir...");
+        return 2;
+    }
+    let mut code = 0;
+    for path in env::args().skip(1) {
+        if let Err(err) = fs::create_dir(path) {
+            eprintln!("mkdir: {}: {}", path, err.description());
+            code = 1;
+        }
+    }
+    code
+}
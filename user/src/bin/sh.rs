@@ -0,0 +1,667 @@
+//! # sh
+//!
+//! The shell, which `init` runs on the console.
+//!
+//! `sh` reads command lines from stdin, with a prompt and line editing when stdin is the console,
+//! and `sh -c line` runs a single line. A line is a pipeline of commands separated by `|`, each a
+//! program with its arguments, e.g. `cat < in | wc > out &`:
+//!
+//! - words are separated by spaces, unless they are quoted with `'…'` or `"…"`, or the space is
+//!   escaped with `\`; `$?` is the exit code of the last pipeline, except in `'…'`;
+//! - `< path` reads the input of a command from a file, `> path` writes its output to a file, and
+//!   `>> path` appends it;
+//! - a `&` at the end runs the pipeline in the background: the shell prints its job number and
+//!   reports when it is done before a later prompt, instead of waiting for it.
+//!
+//! The builtins `cd`, `echo`, `exit` and `help` run in the shell, other commands start the
+//! program of that name. Each command of a pipeline runs in a child process, builtins too, except
+//! that `cd` and `exit` on their own change the shell itself. The last command decides the exit
+//! code of a pipeline: its exit code, or 128 plus the signal that killed it. The shell reports the
+//! ones that are not 0 on the console.
+//!
+//! Ctrl+C interrupts the foreground pipeline, or discards the line being typed. Backspace erases
+//! a character, Ctrl+W a word and Ctrl+U the line, and Ctrl+D on an empty line exits.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{iter::Peekable, ops::ControlFlow, str::Chars};
+use user::{
+    entry, env, eprintln, fs,
+    io::{self, STDIN, STDOUT},
+    print, println,
+    process::{self, ExitStatus, Pid},
+    signal::{self, SIGINT},
+    syscall::{Error, O_APPEND, O_CREAT, O_RDONLY, O_TRUNC, O_WRONLY},
+};
+
+entry!(main);
+
+/// The exit code of a syntax error or of wrong arguments to a builtin, like in `bash`.
+const USAGE: i32 = 2;
+
+/// The exit code of a command whose program can't be run.
+const NOT_EXECUTABLE: i32 = 126;
+
+/// The exit code of a command whose program doesn't exist.
+const NOT_FOUND: i32 = 127;
+
+/// The characters that end a word, besides spaces.
+const OPERATORS: [char; 4] = ['|', '<', '>', '&'];
+
+const HELP: &str = "\
+Builtins:
+  cd [dir]     change the current directory, to $HOME without dir
+  echo [arg]   print the arguments
+  exit [code]  exit the shell, with the exit code of the last command without code
+  help         print this help
+Other commands run the program of that name, e.g. `ls`, `cat` or `wc`.
+Commands can be piped with `|`, read a file with `< path`, write one with `> path` or
+`>> path`, and run in the background with a `&` at the end.";
+
+fn main() -> i32 {
+    let args: Vec<&str> = env::args().collect();
+    match args.as_slice() {
+        [_] => {
+            // Only the console turns off its echo, so this also tells whether stdin is the console.
+            let interactive = io::set_echo(STDIN, true).is_ok();
+            Shell::new(interactive).run_lines()
+        }
+        [_, "-c", line] => {
+            let mut shell = Shell::new(false);
+            match shell.run(line) {
+                ControlFlow::Continue(()) => shell.status,
+                ControlFlow::Break(code) => code,
+            }
+        }
+        _ => {
+            eprintln!("usage: sh [-c command]");
+            USAGE
+        }
+    }
+}
+
+/// Called for `SIGINT`, only to interrupt the `read` of a line.
+extern "C" fn on_interrupt(_: u64) {}
+
+struct Shell {
+    /// Whether stdin is the console, which the shell edits lines on.
+    interactive: bool,
+    /// The exit code of the last pipeline, which is `$?`.
+    status: i32,
+    jobs: Vec<Job>,
+}
+
+/// A pipeline that runs in the background.
+struct Job {
+    /// The number the shell reports it with, the lowest one that was free.
+    id: usize,
+    /// The processes that didn't end yet.
+    pids: Vec<Pid>,
+    /// The process of the last command.
+    last: Pid,
+    /// How the last command ended, once it did.
+    status: Option<ExitStatus>,
+    line: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Pipe,
+    Input,
+    Output,
+    Append,
+    Background,
+}
+
+#[derive(Debug, Default)]
+struct Command {
+    args: Vec<String>,
+    input: Option<String>,
+    /// The path, and whether to append to the file.
+    output: Option<(String, bool)>,
+}
+
+struct Pipeline {
+    commands: Vec<Command>,
+    background: bool,
+}
+
+impl Shell {
+    fn new(interactive: bool) -> Self {
+        Shell {
+            interactive,
+            status: 0,
+            jobs: Vec::new(),
+        }
+    }
+
+    /// Runs the lines of stdin until its end or `exit`. Returns the exit code of the shell.
+    fn run_lines(&mut self) -> i32 {
+        if self.interactive {
+            // Ctrl+C interrupts the shell while it reads, and its children while it waits.
+            let _ = signal::set_handler(SIGINT, on_interrupt);
+            let _ = process::set_foreground(0);
+        }
+        loop {
+            self.reap_jobs();
+            match self.read_line() {
+                Ok(Some(line)) => {
+                    if let ControlFlow::Break(code) = self.run(&line) {
+                        return code;
+                    }
+                }
+                Ok(None) => {
+                    if self.interactive {
+                        println!("exit");
+                    }
+                    return self.status;
+                }
+                Err(err) => {
+                    eprintln!("sh: failed to read: {}", err.description());
+                    return 1;
+                }
+            }
+        }
+    }
+
+    /// Reads a line from stdin, without the newline. Returns `None` at the end of the input.
+    fn read_line(&self) -> Result<Option<String>, Error> {
+        if !self.interactive {
+            return read_plain_line();
+        }
+        let cwd = fs::current_dir().unwrap_or_else(|_| "?".to_string());
+        print!("{} $ ", cwd);
+        // The shell echoes the line itself, to edit it.
+        let _ = io::set_echo(STDIN, false);
+        let line = edit_line();
+        let _ = io::set_echo(STDIN, true);
+        line
+    }
+
+    /// Runs the command line `line`, and sets `$?`. Breaks with the exit code for `exit`.
+    fn run(&mut self, line: &str) -> ControlFlow<i32> {
+        let pipeline = match tokenize(line, self.status).and_then(parse) {
+            Ok(Some(pipeline)) => pipeline,
+            Ok(None) => return ControlFlow::Continue(()),
+            Err(message) => {
+                eprintln!("sh: {}", message);
+                self.status = USAGE;
+                return ControlFlow::Continue(());
+            }
+        };
+
+        if let [command] = pipeline.commands.as_slice() {
+            let args: Vec<&str> = command.args.iter().map(String::as_str).collect();
+            match args.first() {
+                Some(&"cd") if !pipeline.background => {
+                    self.status = cd(&args);
+                    return ControlFlow::Continue(());
+                }
+                Some(&"exit") if !pipeline.background => {
+                    return ControlFlow::Break(exit_code(&args, self.status));
+                }
+                _ => {}
+            }
+        }
+
+        let pids = self.spawn(&pipeline);
+        let last = match pids.last() {
+            Some(&last) => last,
+            None => {
+                self.status = 1;
+                return ControlFlow::Continue(());
+            }
+        };
+        if pipeline.background {
+            let id = (1..)
+                .find(|id| self.jobs.iter().all(|job| job.id != *id))
+                .unwrap();
+            if self.interactive {
+                eprintln!("[{}] {}", id, last);
+            }
+            let line = line.trim().trim_end_matches('&').trim_end().to_string();
+            self.jobs.push(Job {
+                id,
+                pids,
+                last,
+                status: None,
+                line,
+            });
+            self.status = 0;
+        } else {
+            let status = self.wait_foreground(&pids);
+            self.status = exit_code_of(status);
+            if self.interactive && !status.success() {
+                if status == ExitStatus::Killed(SIGINT) {
+                    // After the `^C`.
+                    println!();
+                }
+                eprintln!("[{}]", status);
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Starts the commands of `pipeline`, connected by pipes. Returns their PIDs, fewer if one
+    /// couldn't be started.
+    fn spawn(&self, pipeline: &Pipeline) -> Vec<Pid> {
+        let mut pids = Vec::new();
+        // The read end of the pipe from the previous command.
+        let mut input = None;
+        for (index, command) in pipeline.commands.iter().enumerate() {
+            let pipe = if index + 1 < pipeline.commands.len() {
+                match io::pipe() {
+                    Ok(pipe) => Some(pipe),
+                    Err(err) => {
+                        eprintln!("sh: failed to create a pipe: {}", err.description());
+                        break;
+                    }
+                }
+            } else {
+                None
+            };
+            match process::fork() {
+                Ok(0) => self.run_child(command, input, pipe),
+                Ok(pid) => pids.push(pid),
+                Err(err) => eprintln!("sh: failed to fork: {}", err.description()),
+            }
+            // The children have their copies of the pipes now.
+            if let Some(fd) = input.take() {
+                let _ = io::close(fd);
+            }
+            if let Some((read, write)) = pipe {
+                let _ = io::close(write);
+                input = Some(read);
+            }
+        }
+        if let Some(fd) = input {
+            let _ = io::close(fd);
+        }
+        pids
+    }
+
+    /// Runs `command` in the child of a pipeline, with stdin from the pipe `input` and stdout to
+    /// the pipe `output`, unless the command redirects them to files.
+    fn run_child(&self, command: &Command, input: Option<u64>, output: Option<(u64, u64)>) -> ! {
+        if self.interactive {
+            let _ = signal::set_default(SIGINT);
+        }
+        if let Some(fd) = input {
+            move_fd(fd, STDIN);
+        }
+        if let Some((read, write)) = output {
+            let _ = io::close(read);
+            move_fd(write, STDOUT);
+        }
+        if let Err(code) = redirect(command) {
+            process::exit(code);
+        }
+
+        let args: Vec<&str> = command.args.iter().map(String::as_str).collect();
+        let name = match args.first() {
+            Some(&name) => name,
+            // Only redirections, which are done.
+            None => process::exit(0),
+        };
+        if let Some(code) = builtin(&args, self.status) {
+            process::exit(code);
+        }
+        let env: Vec<&str> = env::vars().collect();
+        match process::exec(name, &args, &env) {
+            Error::NoSuchFile => {
+                eprintln!("sh: {}: command not found", name);
+                process::exit(NOT_FOUND);
+            }
+            err => {
+                eprintln!("sh: {}: {}", name, err.description());
+                process::exit(NOT_EXECUTABLE);
+            }
+        }
+    }
+
+    /// Waits for the processes of a foreground pipeline, which Ctrl+C interrupts meanwhile.
+    /// Returns how the last one ended.
+    fn wait_foreground(&self, pids: &[Pid]) -> ExitStatus {
+        let (&last, others) = pids.split_last().unwrap();
+        if self.interactive {
+            let _ = process::set_foreground(last);
+        }
+        let status = wait_for(last);
+        if status == ExitStatus::Killed(SIGINT) {
+            // Ctrl+C only reached the foreground process, but is meant for the whole pipeline.
+            for &pid in others {
+                let _ = signal::kill(pid, SIGINT);
+            }
+        }
+        for &pid in others {
+            wait_for(pid);
+        }
+        if self.interactive {
+            let _ = process::set_foreground(0);
+        }
+        status
+    }
+
+    /// Waits for the background jobs that ended, and reports the ones that are done.
+    fn reap_jobs(&mut self) {
+        while let Ok(Some((pid, status))) = process::wait(None, false) {
+            if let Some(job) = self.jobs.iter_mut().find(|job| job.pids.contains(&pid)) {
+                job.pids.retain(|&other| other != pid);
+                if pid == job.last {
+                    job.status = Some(status);
+                }
+            }
+        }
+        let interactive = self.interactive;
+        self.jobs.retain(|job| {
+            if !job.pids.is_empty() {
+                return true;
+            }
+            if interactive {
+                match job.status {
+                    Some(status) if !status.success() => {
+                        eprintln!("[{}] {}  {}", job.id, status, job.line)
+                    }
+                    _ => eprintln!("[{}] Done  {}", job.id, job.line),
+                }
+            }
+            false
+        });
+    }
+}
+
+/// Reads a line from stdin as it is, for a script or a pipe.
+fn read_plain_line() -> Result<Option<String>, Error> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    loop {
+        match io::read(STDIN, &mut byte)? {
+            0 if line.is_empty() => return Ok(None),
+            0 => break,
+            _ if byte[0] == b'\n' => break,
+            _ => line.push(byte[0]),
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(&line).to_string()))
+}
+
+/// Reads a line from the console, whose echo is off, and echoes the editing. Ctrl+C discards the
+/// line, which reads as an empty one.
+fn edit_line() -> Result<Option<String>, Error> {
+    let mut line = String::new();
+    let mut byte = [0];
+    loop {
+        match io::read(STDIN, &mut byte) {
+            Ok(0) if line.is_empty() => return Ok(None),
+            // Ctrl+D only ends an empty line.
+            Ok(0) => {}
+            Ok(_) => match byte[0] {
+                b'\n' => {
+                    println!();
+                    return Ok(Some(line));
+                }
+                // Backspace and delete.
+                0x08 | 0x7f => erase(&mut line, 1),
+                // Ctrl+U.
+                0x15 => {
+                    let len = line.len();
+                    erase(&mut line, len);
+                }
+                // Ctrl+W.
+                0x17 => {
+                    let trimmed = line.trim_end();
+                    let word = trimmed.len() - trimmed.rfind(' ').map_or(0, |space| space + 1);
+                    let len = line.len() - trimmed.len() + word;
+                    erase(&mut line, len);
+                }
+                byte @ b' '..=b'~' => {
+                    line.push(char::from(byte));
+                    print!("{}", char::from(byte));
+                }
+                _ => {}
+            },
+            Err(Error::Interrupted) => {
+                println!();
+                return Ok(Some(String::new()));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Removes the last `count` characters of `line`, and from the screen.
+fn erase(line: &mut String, count: usize) {
+    let count = count.min(line.len());
+    line.truncate(line.len() - count);
+    for _ in 0..count {
+        print!("\x08 \x08");
+    }
+}
+
+/// Splits `line` into words and operators. `status` is the value of `$?`.
+fn tokenize(line: &str, status: i32) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+        let token = match chars.peek() {
+            None => return Ok(tokens),
+            Some('|') => Token::Pipe,
+            Some('<') => Token::Input,
+            Some('>') => {
+                chars.next();
+                if chars.next_if_eq(&'>').is_some() {
+                    tokens.push(Token::Append);
+                } else {
+                    tokens.push(Token::Output);
+                }
+                continue;
+            }
+            Some('&') => Token::Background,
+            Some(_) => {
+                tokens.push(Token::Word(word(&mut chars, status)?));
+                continue;
+            }
+        };
+        chars.next();
+        tokens.push(token);
+    }
+}
+
+/// Reads the word that `chars` starts with, with its quotes and escapes removed.
+fn word(chars: &mut Peekable<Chars>, status: i32) -> Result<String, String> {
+    let mut word = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_ascii_whitespace() || OPERATORS.contains(&c) {
+            break;
+        }
+        chars.next();
+        match c {
+            '\'' => loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some(c) => word.push(c),
+                    None => return Err("unterminated quote".to_string()),
+                }
+            },
+            '"' => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') if matches!(chars.peek(), Some('"' | '\\')) => {
+                        word.push(chars.next().unwrap())
+                    }
+                    Some('$') if chars.next_if_eq(&'?').is_some() => {
+                        word.push_str(&status.to_string())
+                    }
+                    Some(c) => word.push(c),
+                    None => return Err("unterminated quote".to_string()),
+                }
+            },
+            '\\' => word.push(chars.next().unwrap_or('\\')),
+            '$' if chars.next_if_eq(&'?').is_some() => word.push_str(&status.to_string()),
+            c => word.push(c),
+        }
+    }
+    Ok(word)
+}
+
+/// Groups `tokens` into a pipeline. Returns `None` for an empty line.
+fn parse(tokens: Vec<Token>) -> Result<Option<Pipeline>, String> {
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+    let mut commands = vec![Command::default()];
+    let mut background = false;
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        let command = commands.last_mut().unwrap();
+        match token {
+            Token::Word(word) => command.args.push(word),
+            Token::Pipe if command.is_empty() => return Err("syntax error near `|`".to_string()),
+            Token::Pipe => commands.push(Command::default()),
+            Token::Input => command.input = Some(file_name(tokens.next(), "<")?),
+            Token::Output => command.output = Some((file_name(tokens.next(), ">")?, false)),
+            Token::Append => command.output = Some((file_name(tokens.next(), ">>")?, true)),
+            Token::Background if tokens.peek().is_none() => background = true,
+            Token::Background => return Err("syntax error near `&`".to_string()),
+        }
+    }
+    if commands.last().unwrap().is_empty() {
+        let operator = if background { "&" } else { "|" };
+        return Err(format!(
+            "syntax error: missing command before `{}`",
+            operator
+        ));
+    }
+    Ok(Some(Pipeline {
+        commands,
+        background,
+    }))
+}
+
+/// The path after the redirection `operator`.
+fn file_name(token: Option<Token>, operator: &str) -> Result<String, String> {
+    match token {
+        Some(Token::Word(path)) => Ok(path),
+        _ => Err(format!("syntax error: missing path after `{}`", operator)),
+    }
+}
+
+impl Command {
+    fn is_empty(&self) -> bool {
+        self.args.is_empty() && self.input.is_none() && self.output.is_none()
+    }
+}
+
+/// Makes `fd` the file descriptor `target` instead.
+fn move_fd(fd: u64, target: u64) {
+    if fd != target {
+        let _ = io::dup2(fd, target);
+        let _ = io::close(fd);
+    }
+}
+
+/// Opens the files `command` redirects stdin and stdout to, in a child. Returns the exit code if
+/// one can't be opened.
+fn redirect(command: &Command) -> Result<(), i32> {
+    let open = |path: &str, flags| {
+        fs::open(path, flags).map_err(|err| {
+            eprintln!("sh: {}: {}", path, err.description());
+            1
+        })
+    };
+    if let Some(path) = &command.input {
+        move_fd(open(path, O_RDONLY)?, STDIN);
+    }
+    if let Some((path, append)) = &command.output {
+        let mode = if *append { O_APPEND } else { O_TRUNC };
+        move_fd(open(path, O_WRONLY | O_CREAT | mode)?, STDOUT);
+    }
+    Ok(())
+}
+
+/// Waits for the child `pid` to end.
+fn wait_for(pid: Pid) -> ExitStatus {
+    loop {
+        match process::wait(Some(pid), true) {
+            Ok(Some((_, status))) => return status,
+            // A signal arrived, e.g. a Ctrl+C for the shell itself.
+            Ok(None) | Err(Error::Interrupted) => {}
+            Err(err) => {
+                eprintln!("sh: failed to wait for {}: {}", pid, err.description());
+                return ExitStatus::Exited(1);
+            }
+        }
+    }
+}
+
+/// The exit code of a command that ended with `status`, for `$?`.
+fn exit_code_of(status: ExitStatus) -> i32 {
+    match status {
+        ExitStatus::Exited(code) => code,
+        ExitStatus::Killed(signal) => 128 + i32::from(signal),
+    }
+}
+
+/// Runs the builtin `args[0]`. Returns its exit code, or `None` if there is no such builtin.
+/// `status` is the value of `$?`.
+fn builtin(args: &[&str], status: i32) -> Option<i32> {
+    let code = match args[0] {
+        "cd" => cd(args),
+        "echo" => {
+            println!("{}", args[1..].join(" "));
+            0
+        }
+        "exit" => exit_code(args, status),
+        "help" => {
+            println!("{}", HELP);
+            0
+        }
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// `cd [dir]`: changes the current directory.
+fn cd(args: &[&str]) -> i32 {
+    let dir = match args {
+        [_] => env::var("HOME").unwrap_or("/"),
+        [_, dir] => dir,
+        _ => {
+            eprintln!("sh: cd: too many arguments");
+            return USAGE;
+        }
+    };
+    match fs::set_current_dir(dir) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("sh: cd: {}: {}", dir, err.description());
+            1
+        }
+    }
+}
+
+/// The exit code of `exit [code]`, which is `status` without `code`.
+fn exit_code(args: &[&str], status: i32) -> i32 {
+    match args {
+        [_] => status,
+        [_, code] => code.parse().unwrap_or_else(|_| {
+            eprintln!("sh: exit: {}: numeric argument required", code);
+            USAGE
+        }),
+        _ => {
+            eprintln!("sh: exit: too many arguments");
+            USAGE
+        }
+    }
+}
@@ -0,0 +1,27 @@
+//! # sleep
+//!
+//! `sleep seconds` waits that many seconds, e.g. to try background jobs with `sleep 5 &`.
+
+#![no_std]
+#![no_main]
+
+use core::time::Duration;
+use user::{entry, env, eprintln, time};
+
+entry!(main);
+
+fn main() -> i32 {
+    match env::args().nth(1).map(str::parse) {
+        Some(Ok(seconds)) => match time::sleep(Duration::from_secs(seconds)) {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("sleep: {}", err.description());
+                1
+            }
+        },
+        _ => {
+            eprintln!("usage: sleep seconds");
+            2
+        }
+    }
+}
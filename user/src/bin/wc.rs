@@ -0,0 +1,85 @@
+//! # wc
+//!
+//! `wc [file]...` counts the lines, words and bytes of the files, or of stdin without any.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use user::{
+    entry, env, eprintln, fs,
+    io::{self, STDIN},
+    println,
+    syscall::{Error, O_RDONLY},
+};
+
+entry!(main);
+
+#[derive(Default)]
+struct Counts {
+    lines: usize,
+    words: usize,
+    bytes: usize,
+}
+
+fn main() -> i32 {
+    let paths: Vec<&str> = env::args().skip(1).collect();
+    if paths.is_empty() {
+        return match count(STDIN) {
+            Ok(counts) => {
+                println!("{} {} {}", counts.lines, counts.words, counts.bytes);
+                0
+            }
+            Err(err) => {
+                eprintln!("wc: {}", err.description());
+                1
+            }
+        };
+    }
+    let mut code = 0;
+    for path in paths {
+        let result = fs::open(path, O_RDONLY).and_then(|fd| {
+            let counts = count(fd);
+            let _ = io::close(fd);
+            counts
+        });
+        match result {
+            Ok(counts) => println!(
+                "{} {} {} {}",
+                counts.lines, counts.words, counts.bytes, path
+            ),
+            Err(err) => {
+                eprintln!("wc: {}: {}", path, err.description());
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+/// Counts what `fd` has, up to its end.
+fn count(fd: u64) -> Result<Counts, Error> {
+    let mut counts = Counts::default();
+    let mut in_word = false;
+    let mut buffer = [0; 4096];
+    loop {
+        let read = io::read(fd, &mut buffer)?;
+        if read == 0 {
+            return Ok(counts);
+        }
+        counts.bytes += read;
+        for &byte in &buffer[..read] {
+            if byte == b'\n' {
+                counts.lines += 1;
+            }
+            if byte.is_ascii_whitespace() {
+                in_word = false;
+            } else if !in_word {
+                in_word = true;
+                counts.words += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! # env
+//!
+//! The arguments and the environment variables of the process.
+//!
+//! The kernel puts them on the initial stack, as arrays of pointers to NUL-terminated strings
+//! after the argument count (see the kernel's `src/process/stack.rs`). [`crate::start`] records
+//! where they are before the program's `main` runs. Strings that are not UTF-8 are skipped.
+
+use core::{ffi::CStr, slice};
+
+static mut ARGS: &[*const u8] = &[];
+static mut VARS: &[*const u8] = &[];
+
+/// Records the arguments and the environment from the initial stack pointer `stack`.
+///
+/// # Safety
+///
+/// `stack` must point to the argument count the kernel put there, and this must only be called
+/// once, before any other function of this module.
+pub(crate) unsafe fn init(stack: *const u64) {
+    let argc = *stack as usize;
+    let argv = stack.add(1) as *const *const u8;
+    let envp = argv.add(argc + 1);
+    let mut envc = 0;
+    while !(*envp.add(envc)).is_null() {
+        envc += 1;
+    }
+    ARGS = slice::from_raw_parts(argv, argc);
+    VARS = slice::from_raw_parts(envp, envc);
+}
+
+/// The arguments of the process, starting with the name of the program by convention.
+pub fn args() -> impl Iterator<Item = &'static str> + Clone {
+    strings(unsafe { ARGS })
+}
+
+/// The environment variables, like `KEY=value`.
+pub fn vars() -> impl Iterator<Item = &'static str> + Clone {
+    strings(unsafe { VARS })
+}
+
+/// The value of the environment variable `key`.
+pub fn var(key: &str) -> Option<&'static str> {
+    vars().find_map(|var| {
+        let (name, value) = var.split_once('=')?;
+        (name == key).then_some(value)
+    })
+}
+
+fn strings(pointers: &'static [*const u8]) -> impl Iterator<Item = &'static str> + Clone {
+    pointers
+        .iter()
+        .filter_map(|&pointer| unsafe { CStr::from_ptr(pointer.cast()) }.to_str().ok())
+}
@@ -0,0 +1,54 @@
+//! # fs
+//!
+//! Files and directories of the kernel's in-memory file system.
+//!
+//! Paths are relative to the current directory, unless they start with `/`. An open file is a
+//! file descriptor like any other, so it is read, written and closed with the functions of
+//! [`crate::io`]. Reading a directory returns the names of its entries, one per line, with a `/`
+//! after the ones of directories.
+
+use crate::syscall::{self, Error};
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+/// Opens the file or directory `path` with the `O_*` flags of [`syscall`], e.g.
+/// `O_WRONLY | O_CREAT | O_TRUNC` to write a new file. Returns its file descriptor.
+pub fn open(path: &str, flags: u64) -> Result<u64, Error> {
+    let path = c_string(path);
+    unsafe { syscall::call(syscall::SYS_OPEN, &[path.as_ptr() as u64, flags]) }
+}
+
+pub fn create_dir(path: &str) -> Result<(), Error> {
+    let path = c_string(path);
+    unsafe { syscall::call(syscall::SYS_MKDIR, &[path.as_ptr() as u64]) }.map(drop)
+}
+
+/// The absolute path of the current directory.
+pub fn current_dir() -> Result<String, Error> {
+    let mut buffer = vec![0u8; 256];
+    loop {
+        let args = [buffer.as_mut_ptr() as u64, buffer.len() as u64];
+        match unsafe { syscall::call(syscall::SYS_GETCWD, &args) } {
+            Ok(len) => {
+                // Without the NUL.
+                let path = &buffer[..len as usize - 1];
+                return Ok(String::from_utf8_lossy(path).to_string());
+            }
+            Err(Error::OutOfRange) => buffer.resize(buffer.len() * 2, 0),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+pub fn set_current_dir(path: &str) -> Result<(), Error> {
+    let path = c_string(path);
+    unsafe { syscall::call(syscall::SYS_CHDIR, &[path.as_ptr() as u64]) }.map(drop)
+}
+
+/// The NUL-terminated copy of `string` that the kernel expects.
+fn c_string(string: &str) -> Vec<u8> {
+    string.bytes().chain(Some(0)).collect()
+}
@@ -0,0 +1,80 @@
+//! # heap
+//!
+//! The heap of user programs, which makes the `alloc` crate usable.
+//!
+//! Like the kernel heap, it is managed by the `linked_list_allocator` crate. Its memory comes from
+//! the `mmap` system call: the first allocation maps [`CHUNK_SIZE`] bytes, and whenever the heap
+//! runs out, more memory is mapped right after it. The kernel only gives the pages frames when
+//! they are touched, so the heap costs little memory until it is used.
+
+use crate::syscall::{self, Error};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+};
+use linked_list_allocator::{Heap, LockedHeap};
+
+/// The least memory the heap maps at a time.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator(LockedHeap::empty());
+
+/// A heap that grows with `mmap`.
+struct Allocator(LockedHeap);
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        if let Ok(allocation) = heap.allocate_first_fit(layout) {
+            return allocation.as_ptr();
+        }
+        // Room for the alignment and the allocator's bookkeeping too.
+        let needed = layout.size() + layout.align() + 64;
+        if grow(&mut heap, needed).is_err() {
+            return ptr::null_mut();
+        }
+        heap.allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0
+            .lock()
+            .deallocate(ptr::NonNull::new_unchecked(ptr), layout);
+    }
+}
+
+/// Maps at least `needed` more bytes for `heap`, right after its end.
+fn grow(heap: &mut Heap, needed: usize) -> Result<(), Error> {
+    let size = needed.max(CHUNK_SIZE).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+    if heap.size() == 0 {
+        let start = map(0, size)?;
+        unsafe { heap.init(start as *mut u8, size) };
+        return Ok(());
+    }
+    let top = heap.top() as u64;
+    // The end of the heap is only a hint, so the memory may end up elsewhere.
+    let start = map(top, size)?;
+    if start != top {
+        unsafe { syscall::call(syscall::SYS_MUNMAP, &[start, size as u64]) }?;
+        return Err(Error::OutOfMemory);
+    }
+    unsafe { heap.extend(size) };
+    Ok(())
+}
+
+/// Maps `size` bytes of private anonymous read-write memory, preferably at `hint`.
+fn map(hint: u64, size: usize) -> Result<u64, Error> {
+    let args = [
+        hint,
+        size as u64,
+        syscall::PROT_READ | syscall::PROT_WRITE,
+        syscall::MAP_PRIVATE | syscall::MAP_ANONYMOUS,
+        u64::MAX,
+        0,
+    ];
+    unsafe { syscall::call(syscall::SYS_MMAP, &args) }
+}
@@ -0,0 +1,156 @@
+//! # io
+//!
+//! File descriptors, and the `print!` macros for stdout and stderr.
+//!
+//! Every process starts with the console as [`STDIN`], [`STDOUT`] and [`STDERR`]. The macros
+//! format into a small buffer and write it with the `write` system call, so whatever the
+//! descriptor refers to gets the output, e.g. a pipe.
+
+use crate::syscall::{self, Error};
+use core::fmt;
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Reads up to `buffer.len()` bytes from `fd`. Returns the number of bytes read, which is 0 at
+/// the end of file.
+pub fn read(fd: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+    let args = [fd, buffer.as_mut_ptr() as u64, buffer.len() as u64];
+    unsafe { syscall::call(syscall::SYS_READ, &args) }.map(|read| read as usize)
+}
+
+/// Writes up to `bytes.len()` bytes to `fd`. Returns the number of bytes written.
+pub fn write(fd: u64, bytes: &[u8]) -> Result<usize, Error> {
+    let args = [fd, bytes.as_ptr() as u64, bytes.len() as u64];
+    unsafe { syscall::call(syscall::SYS_WRITE, &args) }.map(|written| written as usize)
+}
+
+/// Writes all of `bytes` to `fd`, with as many writes as it takes.
+pub fn write_all(fd: u64, mut bytes: &[u8]) -> Result<(), Error> {
+    while !bytes.is_empty() {
+        match write(fd, bytes) {
+            Ok(0) => return Err(Error::BrokenPipe),
+            Ok(written) => bytes = &bytes[written..],
+            Err(Error::Interrupted) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+pub fn close(fd: u64) -> Result<(), Error> {
+    unsafe { syscall::call(syscall::SYS_CLOSE, &[fd]) }.map(drop)
+}
+
+/// Opens the file of `fd` as the lowest free descriptor too, and returns it.
+pub fn dup(fd: u64) -> Result<u64, Error> {
+    unsafe { syscall::call(syscall::SYS_DUP, &[fd]) }
+}
+
+/// Opens the file of `fd` as `new_fd` too, closing what `new_fd` was first.
+pub fn dup2(fd: u64, new_fd: u64) -> Result<(), Error> {
+    unsafe { syscall::call(syscall::SYS_DUP2, &[fd, new_fd]) }.map(drop)
+}
+
+/// Creates a pipe. Returns its reading and its writing end.
+pub fn pipe() -> Result<(u64, u64), Error> {
+    let mut fds = [0u32; 2];
+    unsafe { syscall::call(syscall::SYS_PIPE, &[fds.as_mut_ptr() as u64, 0]) }?;
+    Ok((u64::from(fds[0]), u64::from(fds[1])))
+}
+
+/// Sets whether `exec` closes `fd`.
+pub fn set_close_on_exec(fd: u64, close_on_exec: bool) -> Result<(), Error> {
+    let flags = if close_on_exec {
+        syscall::FD_CLOEXEC
+    } else {
+        0
+    };
+    unsafe { syscall::call(syscall::SYS_FCNTL, &[fd, syscall::F_SETFD, flags]) }.map(drop)
+}
+
+/// Turns the echo of typed keys on or off, if `fd` is the console. Returns whether it was on, or
+/// [`Error::NotATerminal`] for other files.
+pub fn set_echo(fd: u64, on: bool) -> Result<bool, Error> {
+    unsafe { syscall::call(syscall::SYS_SETECHO, &[fd, u64::from(on)]) }.map(|was_on| was_on != 0)
+}
+
+/// A file descriptor that `write!` can write to, like [`STDOUT`]. Errors are ignored: there is
+/// nowhere to report them.
+pub struct Writer(pub u64);
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let _ = write_all(self.0, s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Collects formatted output, so a line is written with one system call where it fits.
+struct Buffered {
+    fd: u64,
+    buffer: [u8; 256],
+    len: usize,
+}
+
+impl Buffered {
+    fn flush(&mut self) {
+        let _ = write_all(self.fd, &self.buffer[..self.len]);
+        self.len = 0;
+    }
+}
+
+impl fmt::Write for Buffered {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.len + s.len() > self.buffer.len() {
+            self.flush();
+        }
+        if s.len() > self.buffer.len() {
+            let _ = write_all(self.fd, s.as_bytes());
+        } else {
+            self.buffer[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+            self.len += s.len();
+        }
+        Ok(())
+    }
+}
+
+/// Like the `print!` macro in the standard library, but writes to [`STDOUT`].
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDOUT, format_args!($($arg)*)));
+}
+
+/// Like the `println!` macro in the standard library, but writes to [`STDOUT`].
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Like the `eprint!` macro in the standard library, but writes to [`STDERR`].
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDERR, format_args!($($arg)*)));
+}
+
+/// Like the `eprintln!` macro in the standard library, but writes to [`STDERR`].
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
+
+/// Writes the given formatted string to `fd`, with as few system calls as possible.
+#[doc(hidden)]
+pub fn _print(fd: u64, args: fmt::Arguments) {
+    use core::fmt::Write;
+    let mut buffered = Buffered {
+        fd,
+        buffer: [0; 256],
+        len: 0,
+    };
+    buffered.write_fmt(args).unwrap();
+    buffered.flush();
+}
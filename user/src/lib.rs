@@ -0,0 +1,78 @@
+//! # user
+//!
+//! The runtime of user programs: what `std` would provide on a full operating system.
+//!
+//! A program is a `#![no_std]`, `#![no_main]` binary that depends on this crate and names its
+//! `main` function with [`entry!`]. It is built for `x86_64-user.json`, which makes the kernel's
+//! ELF loader accept it (see the kernel's `src/process/elf.rs`). This crate supplies the rest:
+//!
+//! - `_start`, which the kernel enters with the arguments on the stack (see [`env`]), calls `main`
+//!   and exits with the code it returns;
+//! - wrappers of the system calls (see [`syscall`]), e.g. [`process::fork`], [`io::pipe`] or
+//!   [`fs::open`];
+//! - [`print!`] and [`println!`], which write to stdout like the kernel's `vga_buffer` macros
+//!   write to the screen, and [`eprint!`] and [`eprintln!`] for stderr;
+//! - a heap over memory from `mmap`, which makes the `alloc` crate usable (see [`heap`]);
+//! - a panic handler, which prints the message to stderr and exits with code 101.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod env;
+pub mod fs;
+pub mod heap;
+pub mod io;
+pub mod process;
+pub mod signal;
+pub mod syscall;
+pub mod time;
+
+use core::{arch::global_asm, panic::PanicInfo};
+
+/// The exit code after a panic, like the one of Rust's standard library.
+pub const PANIC_EXIT_CODE: i32 = 101;
+
+/// Defines the `main` function of a program: `entry!(main)` for a `fn main() -> i32`, whose
+/// result is the exit code. The arguments are in [`env::args`].
+#[macro_export]
+macro_rules! entry {
+    ($path:path) => {
+        #[export_name = "__user_main"]
+        pub fn __user_main() -> i32 {
+            // Checks the signature.
+            let main: fn() -> i32 = $path;
+            main()
+        }
+    };
+}
+
+extern "Rust" {
+    /// The `main` function of the program, see [`entry!`].
+    fn __user_main() -> i32;
+}
+
+global_asm!(
+    ".global _start",
+    "_start:",
+    // The end of the chain of stack frames.
+    "xor ebp, ebp",
+    // The stack pointer points to the argument count, and is 16 byte aligned, see the kernel's
+    // `src/process/stack.rs`. The call pushes the return address, like the ABI expects.
+    "mov rdi, rsp",
+    "call {start}",
+    "ud2",
+    start = sym start,
+);
+
+/// Called by `_start` with the initial stack pointer.
+unsafe extern "C" fn start(stack: *const u64) -> ! {
+    env::init(stack);
+    process::exit(__user_main())
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}: {}", env::args().next().unwrap_or("program"), info);
+    process::exit(PANIC_EXIT_CODE)
+}
@@ -0,0 +1,121 @@
+//! # process
+//!
+//! Creating, running and waiting for processes.
+
+use crate::syscall::{self, Error};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// A process ID.
+pub type Pid = u64;
+
+/// How a process ended, decoded from the status of `waitpid`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// It called [`exit`] with this code.
+    Exited(i32),
+    /// It was killed by the signal with this number.
+    Killed(u8),
+}
+
+impl ExitStatus {
+    /// Decodes a status like `waitpid` stores it: the exit code times 256, or the signal number.
+    pub fn from_wait_status(status: u32) -> Self {
+        match status & 0x7f {
+            0 => ExitStatus::Exited((status >> 8 & 0xff) as i32),
+            signal => ExitStatus::Killed(signal as u8),
+        }
+    }
+
+    /// Whether the process exited with code 0.
+    pub fn success(self) -> bool {
+        self == ExitStatus::Exited(0)
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExitStatus::Exited(code) => write!(f, "exit code {}", code),
+            ExitStatus::Killed(signal) => match crate::signal::name(signal) {
+                Some(name) => write!(f, "killed by {}", name),
+                None => write!(f, "killed by signal {}", signal),
+            },
+        }
+    }
+}
+
+/// Ends the process with `code`.
+pub fn exit(code: i32) -> ! {
+    unsafe { syscall::syscall(syscall::SYS_EXIT, [code as u64, 0, 0, 0, 0, 0]) };
+    unreachable!("exit returned");
+}
+
+/// Creates a child with a copy of the process. Returns the child's PID, or 0 in the child.
+pub fn fork() -> Result<Pid, Error> {
+    unsafe { syscall::call(syscall::SYS_FORK, &[]) }
+}
+
+/// Replaces the program of the process with the program `path`, with the arguments `args` and
+/// the environment `env` (like `KEY=value`). Only returns if that fails.
+pub fn exec(path: &str, args: &[&str], env: &[&str]) -> Error {
+    /// The NUL-terminated copies of `strings`, which the pointers point into.
+    fn c_strings(strings: &[&str]) -> (Vec<Vec<u8>>, Vec<u64>) {
+        let strings: Vec<Vec<u8>> = strings
+            .iter()
+            .map(|string| string.bytes().chain(Some(0)).collect())
+            .collect();
+        let pointers = strings
+            .iter()
+            .map(|string| string.as_ptr() as u64)
+            .chain(Some(0))
+            .collect();
+        (strings, pointers)
+    }
+
+    let (path, _) = c_strings(&[path]);
+    let (_args, argv) = c_strings(args);
+    let (_env, envp) = c_strings(env);
+    let call_args = [
+        path[0].as_ptr() as u64,
+        argv.as_ptr() as u64,
+        envp.as_ptr() as u64,
+    ];
+    match unsafe { syscall::call(syscall::SYS_EXEC, &call_args) } {
+        Ok(_) => unreachable!("exec returned"),
+        Err(err) => err,
+    }
+}
+
+/// Waits for the child `pid` to end, or for any child if it is `None`. Returns its PID and how
+/// it ended. With `block` `false`, returns `None` instead of waiting.
+pub fn wait(pid: Option<Pid>, block: bool) -> Result<Option<(Pid, ExitStatus)>, Error> {
+    let mut status = 0u32;
+    let pid = pid.unwrap_or(u64::MAX);
+    let options = if block { 0 } else { syscall::WNOHANG };
+    let args = [pid, &mut status as *mut u32 as u64, options];
+    match unsafe { syscall::call(syscall::SYS_WAITPID, &args) }? {
+        0 => Ok(None),
+        child => Ok(Some((child, ExitStatus::from_wait_status(status)))),
+    }
+}
+
+pub fn getpid() -> Pid {
+    unsafe { syscall::syscall(syscall::SYS_GETPID, [0; 6]) }
+}
+
+/// The PID of the parent, or 0 without one.
+pub fn getppid() -> Pid {
+    unsafe { syscall::syscall(syscall::SYS_GETPPID, [0; 6]) }
+}
+
+/// Makes the process itself (for 0) or its child `pid` the foreground process, which Ctrl+C
+/// interrupts.
+pub fn set_foreground(pid: Pid) -> Result<(), Error> {
+    unsafe { syscall::call(syscall::SYS_SETFG, &[pid]) }.map(drop)
+}
+
+/// Lets other threads run.
+pub fn yield_now() {
+    unsafe { syscall::syscall(syscall::SYS_YIELD, [0; 6]) };
+}
@@ -0,0 +1,128 @@
+//! # signal
+//!
+//! Sending signals, and choosing what happens when one arrives.
+//!
+//! Signals are plain numbers here, with the names and numbers of Linux. A handler is an
+//! `extern "C"` function that gets the signal number; this crate supplies the restorer that
+//! returns from it with the `sigreturn` system call.
+
+use crate::{
+    process::Pid,
+    syscall::{self, Error},
+};
+use core::arch::global_asm;
+
+pub const SIGHUP: u8 = 1;
+pub const SIGINT: u8 = 2;
+pub const SIGQUIT: u8 = 3;
+pub const SIGILL: u8 = 4;
+pub const SIGABRT: u8 = 6;
+pub const SIGFPE: u8 = 8;
+pub const SIGKILL: u8 = 9;
+pub const SIGUSR1: u8 = 10;
+pub const SIGSEGV: u8 = 11;
+pub const SIGUSR2: u8 = 12;
+pub const SIGPIPE: u8 = 13;
+pub const SIGALRM: u8 = 14;
+pub const SIGTERM: u8 = 15;
+pub const SIGCHLD: u8 = 17;
+pub const SIGCONT: u8 = 18;
+pub const SIGSTOP: u8 = 19;
+pub const SIGTSTP: u8 = 20;
+
+const SIG_DFL: u64 = 0;
+const SIG_IGN: u64 = 1;
+const SA_RESTORER: u64 = 0x0400_0000;
+
+/// The `struct sigaction` of the kernel.
+#[repr(C)]
+struct Action {
+    handler: u64,
+    flags: u64,
+    restorer: u64,
+    mask: u64,
+}
+
+extern "C" {
+    /// Where a handler returns to.
+    fn __restore_rt();
+}
+
+global_asm!(
+    ".global __restore_rt",
+    "__restore_rt:",
+    "mov eax, {sigreturn}",
+    "syscall",
+    "ud2",
+    sigreturn = const syscall::SYS_SIGRETURN,
+);
+
+/// The name of the signal `signal`, like `SIGINT`.
+pub fn name(signal: u8) -> Option<&'static str> {
+    const NAMES: [&str; 31] = [
+        "SIGHUP",
+        "SIGINT",
+        "SIGQUIT",
+        "SIGILL",
+        "SIGTRAP",
+        "SIGABRT",
+        "SIGBUS",
+        "SIGFPE",
+        "SIGKILL",
+        "SIGUSR1",
+        "SIGSEGV",
+        "SIGUSR2",
+        "SIGPIPE",
+        "SIGALRM",
+        "SIGTERM",
+        "SIGSTKFLT",
+        "SIGCHLD",
+        "SIGCONT",
+        "SIGSTOP",
+        "SIGTSTP",
+        "SIGTTIN",
+        "SIGTTOU",
+        "SIGURG",
+        "SIGXCPU",
+        "SIGXFSZ",
+        "SIGVTALRM",
+        "SIGPROF",
+        "SIGWINCH",
+        "SIGIO",
+        "SIGPWR",
+        "SIGSYS",
+    ];
+    NAMES.get(usize::from(signal).checked_sub(1)?).copied()
+}
+
+/// Sends `signal` to the process `pid`.
+pub fn kill(pid: Pid, signal: u8) -> Result<(), Error> {
+    unsafe { syscall::call(syscall::SYS_KILL, &[pid, u64::from(signal)]) }.map(drop)
+}
+
+/// Ignores `signal` from now on.
+pub fn ignore(signal: u8) -> Result<(), Error> {
+    set_action(signal, SIG_IGN, 0)
+}
+
+/// Gives `signal` its default action again.
+pub fn set_default(signal: u8) -> Result<(), Error> {
+    set_action(signal, SIG_DFL, 0)
+}
+
+/// Calls `handler` with the signal number whenever `signal` arrives. The signal is blocked while
+/// the handler runs.
+pub fn set_handler(signal: u8, handler: extern "C" fn(u64)) -> Result<(), Error> {
+    set_action(signal, handler as usize as u64, SA_RESTORER)
+}
+
+fn set_action(signal: u8, handler: u64, flags: u64) -> Result<(), Error> {
+    let action = Action {
+        handler,
+        flags,
+        restorer: __restore_rt as *const () as u64,
+        mask: 0,
+    };
+    let args = [u64::from(signal), &action as *const Action as u64, 0];
+    unsafe { syscall::call(syscall::SYS_SIGACTION, &args) }.map(drop)
+}
@@ -0,0 +1,197 @@
+//! # syscall
+//!
+//! The raw system calls of the kernel, see the kernel's `src/syscall.rs` for what each does.
+//!
+//! The numbers and flags are the kernel's, which never change. The functions here only pass
+//! their arguments on and turn negative results into an [`Error`]; the other modules wrap them
+//! with proper types.
+
+use core::arch::asm;
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_READ: u64 = 2;
+pub const SYS_YIELD: u64 = 3;
+pub const SYS_SLEEP: u64 = 4;
+pub const SYS_TIME: u64 = 5;
+pub const SYS_FORK: u64 = 6;
+pub const SYS_EXEC: u64 = 7;
+pub const SYS_WAITPID: u64 = 8;
+pub const SYS_GETPID: u64 = 9;
+pub const SYS_GETPPID: u64 = 10;
+pub const SYS_MMAP: u64 = 11;
+pub const SYS_MUNMAP: u64 = 12;
+pub const SYS_MPROTECT: u64 = 13;
+pub const SYS_KILL: u64 = 14;
+pub const SYS_SIGACTION: u64 = 15;
+pub const SYS_SIGPROCMASK: u64 = 16;
+pub const SYS_SIGRETURN: u64 = 17;
+pub const SYS_SETFG: u64 = 18;
+pub const SYS_CLOSE: u64 = 19;
+pub const SYS_DUP: u64 = 20;
+pub const SYS_DUP2: u64 = 21;
+pub const SYS_PIPE: u64 = 22;
+pub const SYS_FCNTL: u64 = 23;
+pub const SYS_OPEN: u64 = 24;
+pub const SYS_CHDIR: u64 = 25;
+pub const SYS_GETCWD: u64 = 26;
+pub const SYS_MKDIR: u64 = 27;
+pub const SYS_SETECHO: u64 = 28;
+
+/// The option of [`SYS_WAITPID`] to return 0 instead of blocking.
+pub const WNOHANG: u64 = 1;
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_CREAT: u64 = 0x40;
+pub const O_TRUNC: u64 = 0x200;
+pub const O_APPEND: u64 = 0x400;
+pub const O_DIRECTORY: u64 = 0x10000;
+pub const O_CLOEXEC: u64 = 0x80000;
+pub const F_GETFD: u64 = 1;
+pub const F_SETFD: u64 = 2;
+pub const FD_CLOEXEC: u64 = 1;
+
+/// The errors of system calls, with the numbers Linux uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    NoSuchFile,
+    NoSuchProcess,
+    Interrupted,
+    ArgumentListTooLong,
+    ExecFormat,
+    BadFileDescriptor,
+    NoChildProcesses,
+    TryAgain,
+    OutOfMemory,
+    PermissionDenied,
+    BadAddress,
+    FileExists,
+    NoSuchDevice,
+    NotADirectory,
+    IsADirectory,
+    InvalidArgument,
+    TooManyOpenFiles,
+    NotATerminal,
+    NoSpaceLeft,
+    BrokenPipe,
+    OutOfRange,
+    NoSuchSystemCall,
+    /// An error this crate doesn't know yet, with its number.
+    Unknown(u64),
+}
+
+impl Error {
+    pub fn from_code(code: u64) -> Self {
+        match code {
+            2 => Error::NoSuchFile,
+            3 => Error::NoSuchProcess,
+            4 => Error::Interrupted,
+            7 => Error::ArgumentListTooLong,
+            8 => Error::ExecFormat,
+            9 => Error::BadFileDescriptor,
+            10 => Error::NoChildProcesses,
+            11 => Error::TryAgain,
+            12 => Error::OutOfMemory,
+            13 => Error::PermissionDenied,
+            14 => Error::BadAddress,
+            17 => Error::FileExists,
+            19 => Error::NoSuchDevice,
+            20 => Error::NotADirectory,
+            21 => Error::IsADirectory,
+            22 => Error::InvalidArgument,
+            24 => Error::TooManyOpenFiles,
+            25 => Error::NotATerminal,
+            28 => Error::NoSpaceLeft,
+            32 => Error::BrokenPipe,
+            34 => Error::OutOfRange,
+            38 => Error::NoSuchSystemCall,
+            code => Error::Unknown(code),
+        }
+    }
+
+    /// A message for users, like `strerror` gives.
+    pub fn description(self) -> &'static str {
+        match self {
+            Error::NoSuchFile => "no such file or directory",
+            Error::NoSuchProcess => "no such process",
+            Error::Interrupted => "interrupted system call",
+            Error::ArgumentListTooLong => "argument list too long",
+            Error::ExecFormat => "exec format error",
+            Error::BadFileDescriptor => "bad file descriptor",
+            Error::NoChildProcesses => "no child processes",
+            Error::TryAgain => "resource temporarily unavailable",
+            Error::OutOfMemory => "out of memory",
+            Error::PermissionDenied => "permission denied",
+            Error::BadAddress => "bad address",
+            Error::FileExists => "file exists",
+            Error::NoSuchDevice => "no such device",
+            Error::NotADirectory => "not a directory",
+            Error::IsADirectory => "is a directory",
+            Error::InvalidArgument => "invalid argument",
+            Error::TooManyOpenFiles => "too many open files",
+            Error::NotATerminal => "inappropriate ioctl for device",
+            Error::NoSpaceLeft => "no space left on device",
+            Error::BrokenPipe => "broken pipe",
+            Error::OutOfRange => "numerical result out of range",
+            Error::NoSuchSystemCall => "function not implemented",
+            Error::Unknown(_) => "unknown error",
+        }
+    }
+}
+
+/// The result of a system call: results from -4095 to -1 are errors, like on Linux.
+pub fn result(value: u64) -> Result<u64, Error> {
+    if value > -4096i64 as u64 {
+        Err(Error::from_code(value.wrapping_neg()))
+    } else {
+        Ok(value)
+    }
+}
+
+/// Makes the system call `number` with up to six arguments, and returns `rax`. The kernel
+/// preserves all registers but `rax`, `rcx` and `r11`.
+///
+/// # Safety
+///
+/// The arguments must be valid for the call, e.g. pointers to memory the kernel may write to.
+pub unsafe fn syscall(number: u64, args: [u64; 6]) -> u64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        in("r9") args[5],
+        lateout("rcx") _,
+        lateout("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+/// [`syscall`] with the arguments that are given, and 0 for the others.
+///
+/// # Safety
+///
+/// See [`syscall`].
+pub unsafe fn call(number: u64, args: &[u64]) -> Result<u64, Error> {
+    let mut all = [0; 6];
+    all[..args.len()].copy_from_slice(args);
+    result(syscall(number, all))
+}
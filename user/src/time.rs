@@ -0,0 +1,17 @@
+//! # time
+//!
+//! The monotonic clock of the kernel, and sleeping.
+
+use crate::syscall::{self, Error};
+use core::time::Duration;
+
+/// The time since boot.
+pub fn now() -> Duration {
+    Duration::from_nanos(unsafe { syscall::syscall(syscall::SYS_TIME, [0; 6]) })
+}
+
+/// Sleeps for at least `duration`, unless a signal interrupts it.
+pub fn sleep(duration: Duration) -> Result<(), Error> {
+    let nanos = duration.as_nanos().min(u128::from(u64::MAX)) as u64;
+    unsafe { syscall::call(syscall::SYS_SLEEP, &[nanos]) }.map(drop)
+}